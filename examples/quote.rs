@@ -0,0 +1,50 @@
+use anyhow::Result;
+use ethers::providers::{Middleware, Provider, Ws};
+use ethers::types::{H160, U256};
+use log::info;
+use std::{str::FromStr, sync::Arc};
+
+use evm_simulation::constants::Env;
+use evm_simulation::pools::{DexVariant, Pool};
+use evm_simulation::quoter::quote_swap;
+use evm_simulation::simulator::SimulatorConfig;
+use evm_simulation::utils::setup_logger;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    setup_logger()?;
+
+    let env = Env::new();
+    let ws = Ws::connect(&env.wss_url).await?;
+    let provider = Arc::new(Provider::new(ws));
+
+    let block_number = provider.get_block_number().await?;
+
+    // Uniswap V2 USDC/WETH
+    let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+    let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+    let pool = Pool {
+        address: H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc").unwrap(),
+        version: DexVariant::UniswapV2,
+        token0: usdc,
+        token1: weth,
+        decimals0: 6,
+        decimals1: 18,
+        fee: 300,
+    };
+
+    let amount_in = U256::from(10).pow(U256::from(18));
+    let amount_out = quote_swap(
+        provider.clone(),
+        SimulatorConfig::from_env(&env)?,
+        block_number,
+        &pool,
+        weth,
+        amount_in,
+    )
+    .await?;
+    info!("1 WETH -> {:?} USDC @ {:?}", amount_out, block_number);
+
+    Ok(())
+}
@@ -1,51 +1,454 @@
-use anyhow::Result;
-use ethers::types::{H160, U256, U64};
+use anyhow::{anyhow, Result};
+use ethers::{
+    types::{AccountState, Transaction, H160, H256, U256, U64},
+    utils::keccak256,
+};
 use ethers_providers::Middleware;
 use foundry_evm::{executor::fork::SharedBackend, revm::db::CacheDB};
 use log::info;
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::Arc,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
 
+use crate::bundle::net_of_gas;
+use crate::log_with_id;
+use crate::metrics::{Metrics, METRICS};
 use crate::paths::ArbPath;
-use crate::simulator::EvmSimulator;
+use crate::pools::{DexVariant, Pool};
+use crate::pricing::{EthPriceOracle, UsdPricer};
+use crate::simulator::{EvmSimulator, SimulatorConfig, SwapSimulator};
+use crate::streams::BlockContext;
 use crate::tokens::Token;
+use crate::utils::{correlation_id, format_token_amount, to_i128};
 
 #[derive(Debug, Clone)]
 pub struct TriangularArbitrage {
     pub amount_in: U256,
     pub path: ArbPath,
     pub balance_slot: u32,
+    // Contract holding target_token's balances when it isn't the token itself
+    pub balance_account: Option<H160>,
     pub target_token: Token,
+    // Transfer taxes in bps: token -> (buy tax, sell tax)
+    pub token_taxes: HashMap<H160, (u32, u32)>,
+    // Reports the profit in USD as well when set
+    pub usd_pricer: Option<Arc<UsdPricer>>,
+    // Reports the ETH price on the same fork when set, to value the profit net of gas in USD
+    pub eth_oracle: Option<Arc<EthPriceOracle>>,
+    // Borrow amount_in with a flashloan charging this fee (bps) instead of using our own capital
+    pub flashloan_fee_bps: Option<u32>,
+    // Abort the cycle once a hop fills this far (bps) below the pool's spot price
+    pub max_hop_slippage_bps: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArbResult {
+    pub amount_in: U256,
+    pub amount_out: U256,
+    pub profit: i128,
+    pub profit_usd: Option<f64>,
+    pub eth_price_usd: Option<f64>,
+    pub gas_used: u64,
+    pub hops: Vec<HopResult>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ArbCache {
+    // Results are only valid for the block they were simulated on
+    pub block_number: U64,
+    pub results: HashMap<(U64, H256, U256), ArbResult>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ArbCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_block(&mut self, block_number: U64) {
+        if block_number != self.block_number {
+            self.results.clear();
+            self.block_number = block_number;
+        }
+    }
+
+    pub fn get(
+        &mut self,
+        block_number: U64,
+        path_hash: H256,
+        amount_in: U256,
+    ) -> Option<ArbResult> {
+        self.set_block(block_number);
+        let result = self
+            .results
+            .get(&(block_number, path_hash, amount_in))
+            .cloned();
+        match result {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        result
+    }
+
+    pub fn insert(&mut self, block_number: U64, path_hash: H256, result: ArbResult) {
+        self.set_block(block_number);
+        self.results
+            .insert((block_number, path_hash, result.amount_in), result);
+    }
+
+    pub fn get_or_simulate<F>(
+        &mut self,
+        block_number: U64,
+        path_hash: H256,
+        amount_in: U256,
+        simulate: F,
+    ) -> Result<ArbResult>
+    where
+        F: FnOnce() -> Result<ArbResult>,
+    {
+        // Failed simulations are not cached, so they are retried
+        if let Some(result) = self.get(block_number, path_hash, amount_in) {
+            return Ok(result);
+        }
+        let result = simulate()?;
+        self.insert(block_number, path_hash, result.clone());
+        Ok(result)
+    }
+}
+
+pub fn backrun_path_hash(path: &ArbPath, meat_tx: &Transaction) -> H256 {
+    // A backrun's result depends on the victim it runs behind as well as on the path
+    H256::from(keccak256(
+        [path.hash().as_bytes(), meat_tx.hash.as_bytes()].concat(),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArbDirection {
+    Forward,
+    Reverse,
+}
+
+#[derive(Debug, Clone)]
+pub struct HopResult {
+    pub pool: H160,
+    // pool reserves right before the swap, (0, 0) for pools without getReserves
+    pub reserves: (u128, u128),
+    pub amount_in: U256,
+    pub amount_out: U256,
+}
+
+#[derive(Debug, Clone)]
+pub struct HopDiff {
+    pub pool: H160,
+    pub reserve0_delta: i128,
+    pub reserve1_delta: i128,
+    pub amount_out_delta: i128,
+}
+
+#[derive(Debug, Clone)]
+pub struct PathDiff {
+    pub block_a: U64,
+    pub block_b: U64,
+    pub hops: Vec<HopDiff>,
+    pub profit_delta: i128,
+}
+
+impl PathDiff {
+    pub fn between(block_a: U64, result_a: &ArbResult, block_b: U64, result_b: &ArbResult) -> Self {
+        // Deltas are (block_b - block_a) for every hop
+        let hops = result_a
+            .hops
+            .iter()
+            .zip(result_b.hops.iter())
+            .map(|(a, b)| HopDiff {
+                pool: a.pool,
+                reserve0_delta: b.reserves.0 as i128 - a.reserves.0 as i128,
+                reserve1_delta: b.reserves.1 as i128 - a.reserves.1 as i128,
+                amount_out_delta: b.amount_out.as_u128() as i128 - a.amount_out.as_u128() as i128,
+            })
+            .collect();
+
+        Self {
+            block_a,
+            block_b,
+            hops,
+            profit_delta: result_b.profit - result_a.profit,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ArbError {
+    EmptyPool {
+        hop: u8,
+        pool: H160,
+    },
+    ExcessiveSlippage {
+        hop: u8,
+        pool: H160,
+        slippage_bps: u64,
+    },
+}
+
+impl fmt::Display for ArbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArbError::EmptyPool { hop, pool } => {
+                write!(f, "hop #{} pool {:?} has no liquidity", hop + 1, pool)
+            }
+            ArbError::ExcessiveSlippage {
+                hop,
+                pool,
+                slippage_bps,
+            } => {
+                write!(
+                    f,
+                    "hop #{} pool {:?} slipped {} bps below its spot price",
+                    hop + 1,
+                    pool,
+                    slippage_bps
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ArbError {}
+
+pub fn validate_path<S: SwapSimulator>(simulator: &mut S, path: &ArbPath) -> Result<()> {
+    // Stale paths are common, a quick getReserves per hop is far cheaper than running every swap
+    for n in 0..path.nhop {
+        let pool = path.get_pool(n);
+        if let DexVariant::Curve = pool.version {
+            // Curve pools don't expose getReserves
+            continue;
+        }
+        let empty = match simulator.v2_pool_get_reserves(pool.address) {
+            Ok(reserves) => reserves.0 == 0 || reserves.1 == 0,
+            Err(_) => true,
+        };
+        if empty {
+            return Err(ArbError::EmptyPool {
+                hop: n,
+                pool: pool.address,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+pub fn hop_slippage_bps(
+    pool: &Pool,
+    reserves: (u128, u128),
+    input_token: H160,
+    amount_in: U256,
+    amount_out: U256,
+) -> Option<u64> {
+    // How far the swap filled below what amount_in buys at the spot price, fees included
+    let (reserve_in, reserve_out) = pool.ordered_reserves(reserves, input_token);
+    if reserve_in.is_zero() || amount_in.is_zero() {
+        return None;
+    }
+    let spot_out = amount_in * reserve_out / reserve_in;
+    if spot_out.is_zero() || amount_out >= spot_out {
+        return Some(0);
+    }
+    Some(((spot_out - amount_out) * U256::from(10000) / spot_out).as_u64())
+}
+
+pub fn apply_tax(amount: U256, tax_bps: u32) -> U256 {
+    amount - amount * U256::from(tax_bps) / U256::from(10000)
+}
+
+pub fn seed_simulator<M: Middleware + 'static>(
+    simulator: &mut EvmSimulator<M>,
+    target_token: &Token,
+    balance_slot: u32,
+    balance_account: Option<H160>,
+) {
+    let simulator_address = simulator.simulator_address;
+    if let Some(account) = balance_account {
+        simulator
+            .balance_accounts
+            .insert(target_token.address, account);
+    }
+    simulator.set_eth_balance(100000);
+    simulator.deploy_simulator();
+    simulator.set_token_balance(
+        simulator_address,
+        target_token.address,
+        target_token.decimals,
+        balance_slot,
+        100000,
+    );
 }
 
 pub fn simulate_triangular_arbitrage<M: Middleware + 'static>(
     arb: TriangularArbitrage,
     provider: Arc<M>,
     owner: H160,
-    block_number: U64,
+    config: &SimulatorConfig,
+    block: &BlockContext,
     fork_db: Option<CacheDB<SharedBackend>>,
-) -> Result<i128> {
-    info!("\n[🔮 Arbitrage Path Simulation]");
-
-    let target_token = arb.target_token;
-
-    let mut simulator = EvmSimulator::new(provider, owner, block_number);
-    let simulator_address = simulator.simulator_address;
-    match fork_db {
-        Some(db) => simulator.inject_db(db),
-        None => {
+) -> Result<ArbResult> {
+    let mut simulator =
+        EvmSimulator::new_with_config(provider, owner, block.number, config.clone());
+    simulator.set_block_context(block);
+    match (fork_db, arb.flashloan_fee_bps) {
+        (Some(db), _) => simulator.inject_db(db),
+        (None, Some(_)) => {
+            // the flashloan is all we hold: exactly amount_in of the target token
+            let simulator_address = simulator.simulator_address;
+            if let Some(account) = arb.balance_account {
+                simulator
+                    .balance_accounts
+                    .insert(arb.target_token.address, account);
+            }
             simulator.set_eth_balance(100000);
             simulator.deploy_simulator();
-            simulator.set_token_balance(
+            simulator.set_token_balance_raw(
                 simulator_address,
-                target_token.address,
-                target_token.decimals,
+                arb.target_token.address,
+                arb.balance_slot,
+                arb.amount_in,
+            );
+        }
+        (None, None) => seed_simulator(
+            &mut simulator,
+            &arb.target_token,
+            arb.balance_slot,
+            arb.balance_account,
+        ),
+    }
+    simulate_triangular_arbitrage_with(arb, &mut simulator)
+}
+
+pub fn simulate_triangular_arbitrage_cached<M: Middleware + 'static>(
+    cache: &mut ArbCache,
+    arb: TriangularArbitrage,
+    provider: Arc<M>,
+    owner: H160,
+    config: &SimulatorConfig,
+    block: &BlockContext,
+    fork_db: Option<CacheDB<SharedBackend>>,
+) -> Result<ArbResult> {
+    // Identical (path, amount) requests within a block are served from the cache
+    let (path_hash, amount_in) = (arb.path.hash(), arb.amount_in);
+    cache.get_or_simulate(block.number, path_hash, amount_in, || {
+        simulate_triangular_arbitrage(arb, provider, owner, config, block, fork_db)
+    })
+}
+
+pub fn simulate_backrun_arbitrage<M: Middleware + 'static>(
+    arbs: Vec<TriangularArbitrage>,
+    provider: Arc<M>,
+    owner: H160,
+    config: &SimulatorConfig,
+    block: &BlockContext,
+    meat_tx: &Transaction,
+    meat_prestate: &BTreeMap<H160, AccountState>,
+    cache: &mut ArbCache,
+) -> Result<Vec<(ArbPath, ArbResult)>> {
+    // Runs the pending tx first, then every path on top of the state it leaves behind
+    // All arbs should share the same target token, the simulator is seeded once for all of them
+    // Paths already simulated behind the same victim in this block come from the cache,
+    // the victim is only run when some path isn't cached
+    let mut db = None;
+    let mut results = Vec::new();
+    for arb in arbs {
+        let path = arb.path.clone();
+        let path_hash = backrun_path_hash(&path, meat_tx);
+        if let Some(result) = cache.get(block.number, path_hash, arb.amount_in) {
+            results.push((path, result));
+            continue;
+        }
+        if db.is_none() {
+            let mut simulator = EvmSimulator::new_with_config(
+                provider.clone(),
+                owner,
+                block.number,
+                config.clone(),
+            );
+            simulator.set_block_context(block);
+            seed_simulator(
+                &mut simulator,
+                &arb.target_token,
                 arb.balance_slot,
-                100000,
+                arb.balance_account,
             );
+            simulator.run_pending_tx_with_prestate(meat_tx, meat_prestate, false)?;
+            db = Some(simulator.db_snapshot());
+        }
+        if let Ok(result) =
+            simulate_triangular_arbitrage(arb, provider.clone(), owner, config, block, db.clone())
+        {
+            cache.insert(block.number, path_hash, result.clone());
+            results.push((path, result));
         }
     }
+    results.sort_by(|a, b| b.1.profit.cmp(&a.1.profit));
+    Ok(results)
+}
+
+pub fn simulate_backrun_arbitrage_with<S: SwapSimulator + Clone>(
+    arbs: Vec<TriangularArbitrage>,
+    simulator: &S,
+    meat_tx: &Transaction,
+) -> Result<Vec<(ArbPath, ArbResult)>> {
+    // Same as simulate_backrun_arbitrage without the cache,
+    // every path runs on its own clone of the simulator after the pending tx
+    let mut simulator = simulator.clone();
+    simulator.run_pending_tx(meat_tx, false)?;
+    let mut results = Vec::new();
+    for arb in arbs {
+        let path = arb.path.clone();
+        if let Ok(result) = simulate_triangular_arbitrage_with(arb, &mut simulator.clone()) {
+            results.push((path, result));
+        }
+    }
+    results.sort_by(|a, b| b.1.profit.cmp(&a.1.profit));
+    Ok(results)
+}
+
+pub fn simulate_triangular_arbitrage_with<S: SwapSimulator>(
+    arb: TriangularArbitrage,
+    simulator: &mut S,
+) -> Result<ArbResult> {
+    // Lines for the same path share an id derived from its pools
+    let id = correlation_id(
+        &[
+            arb.path.pool_1.address.as_bytes(),
+            arb.path.pool_2.address.as_bytes(),
+            arb.path.pool_3.address.as_bytes(),
+        ]
+        .concat(),
+    );
+
+    Metrics::add(&METRICS.simulations, 1);
+    log_with_id!(id, "[🔮 Arbitrage Path Simulation]");
+
+    let target_token = arb.target_token;
+
+    validate_path(simulator, &arb.path)?;
+
+    // Priced before the hops move the pools
+    let usd_price = match &arb.usd_pricer {
+        Some(pricer) => pricer.usd_price(simulator, target_token.address).ok(),
+        None => None,
+    };
+    let eth_price_usd = match &arb.eth_oracle {
+        Some(oracle) => oracle.eth_price_usd(simulator).ok(),
+        None => None,
+    };
 
     let mut amount_out = arb.amount_in;
+    let mut hops = Vec::new();
 
     for n in 0..arb.path.nhop {
         let pool = arb.path.get_pool(n);
@@ -56,24 +459,1100 @@ pub fn simulate_triangular_arbitrage<M: Middleware + 'static>(
             (pool.token1, pool.token0)
         };
 
-        let out = simulator.v2_simulate_swap(
+        // The simulator swaps against the pool directly, so transfer taxes are discounted here:
+        // the sell tax shrinks what reaches the pool, the buy tax shrinks what we receive
+        let (_, sell_tax) = arb.token_taxes.get(&input_token).unwrap_or(&(0, 0));
+        let amount_in = apply_tax(amount_out, *sell_tax);
+
+        let reserves = match pool.version {
+            DexVariant::Curve => (0, 0),
+            _ => {
+                let reserves = simulator.v2_pool_get_reserves(pool.address)?;
+                (reserves.0, reserves.1)
+            }
+        };
+
+        let out = simulator.swap_exact_in(pool, amount_in, input_token, true)?;
+        if let Some(max_slippage_bps) = arb.max_hop_slippage_bps {
+            let slippage_bps = hop_slippage_bps(pool, reserves, input_token, amount_in, out.1);
+            if let Some(slippage_bps) = slippage_bps.filter(|bps| *bps > max_slippage_bps as u64) {
+                log_with_id!(id, "❌ Swap #{}: slippage {} bps", n + 1, slippage_bps);
+                return Err(ArbError::ExcessiveSlippage {
+                    hop: n,
+                    pool: pool.address,
+                    slippage_bps,
+                }
+                .into());
+            }
+        }
+        let (buy_tax, _) = arb.token_taxes.get(&output_token).unwrap_or(&(0, 0));
+        amount_out = apply_tax(out.1, *buy_tax);
+        hops.push(HopResult {
+            pool: pool.address,
+            reserves,
+            amount_in,
             amount_out,
-            pool.address,
-            input_token,
-            output_token,
-            true,
-        )?;
-        amount_out = out.1;
-        info!("✅ Swap #{}: {:?}", n + 1, amount_out);
-    }
-
-    let profit = (amount_out.as_u64() as i128) - (arb.amount_in.as_u64() as i128);
-    let divisor = (10.0 as f64).powi(target_token.decimals as i32);
-    let profit_in_target_token = (profit as f64) / divisor;
-    info!(
-        "▶️ Profit: {:?} {}",
-        profit_in_target_token, target_token.symbol
+        });
+        log_with_id!(id, "✅ Swap #{}: {:?}", n + 1, amount_out);
+    }
+
+    // A flashloan is repaid with its fee at the end of the cycle, a negative profit means
+    // the whole tx would revert on chain
+    let flashloan_fee = match arb.flashloan_fee_bps {
+        Some(fee_bps) => arb.amount_in * U256::from(fee_bps) / U256::from(10000),
+        None => U256::zero(),
+    };
+    if !flashloan_fee.is_zero() {
+        log_with_id!(id, "✅ Flashloan fee: {:?}", flashloan_fee);
+    }
+
+    let profit = to_i128(amount_out)? - to_i128(arb.amount_in)? - to_i128(flashloan_fee)?;
+    log_with_id!(
+        id,
+        "▶️ Profit: {} {}",
+        format_token_amount(profit, target_token.decimals),
+        target_token.symbol
+    );
+    let profit_in_target_token = profit as f64 / 10f64.powi(target_token.decimals as i32);
+
+    Metrics::add(&METRICS.simulation_successes, 1);
+    Ok(ArbResult {
+        amount_in: arb.amount_in,
+        amount_out,
+        profit,
+        profit_usd: usd_price.map(|price| profit_in_target_token * price),
+        eth_price_usd,
+        gas_used: simulator.gas_used(),
+        hops,
+    })
+}
+
+pub fn simulate_both_directions<M: Middleware + 'static>(
+    arb: TriangularArbitrage,
+    provider: Arc<M>,
+    owner: H160,
+    config: &SimulatorConfig,
+    block: &BlockContext,
+    fork_db: Option<CacheDB<SharedBackend>>,
+) -> Result<(ArbDirection, ArbResult)> {
+    // Both directions run on clones of the same fork, so they see identical reserves
+    let fork_db = match fork_db {
+        Some(db) => db,
+        None => {
+            let mut simulator = EvmSimulator::new_with_config(
+                provider.clone(),
+                owner,
+                block.number,
+                config.clone(),
+            );
+            simulator.set_block_context(block);
+            seed_simulator(
+                &mut simulator,
+                &arb.target_token,
+                arb.balance_slot,
+                arb.balance_account,
+            );
+            simulator.db_snapshot()
+        }
+    };
+
+    let mut reverse_arb = arb.clone();
+    reverse_arb.path = arb.path.reverse();
+
+    let forward = simulate_triangular_arbitrage(
+        arb,
+        provider.clone(),
+        owner,
+        config,
+        block,
+        Some(fork_db.clone()),
     );
+    let reverse =
+        simulate_triangular_arbitrage(reverse_arb, provider, owner, config, block, Some(fork_db));
+
+    better_direction(forward, reverse)
+}
+
+pub fn simulate_both_directions_with<S: SwapSimulator + Clone>(
+    arb: TriangularArbitrage,
+    simulator: &S,
+) -> Result<(ArbDirection, ArbResult)> {
+    // Same as simulate_both_directions, each direction runs on its own clone of the simulator
+    let mut reverse_arb = arb.clone();
+    reverse_arb.path = arb.path.reverse();
+
+    let forward = simulate_triangular_arbitrage_with(arb, &mut simulator.clone());
+    let reverse = simulate_triangular_arbitrage_with(reverse_arb, &mut simulator.clone());
+
+    better_direction(forward, reverse)
+}
+
+fn better_direction(
+    forward: Result<ArbResult>,
+    reverse: Result<ArbResult>,
+) -> Result<(ArbDirection, ArbResult)> {
+    match (forward, reverse) {
+        (Ok(forward), Ok(reverse)) => {
+            if reverse.profit > forward.profit {
+                Ok((ArbDirection::Reverse, reverse))
+            } else {
+                Ok((ArbDirection::Forward, forward))
+            }
+        }
+        (Ok(forward), Err(_)) => Ok((ArbDirection::Forward, forward)),
+        (Err(_), Ok(reverse)) => Ok((ArbDirection::Reverse, reverse)),
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+pub fn profit_curve<M: Middleware + 'static>(
+    arb: TriangularArbitrage,
+    amounts: &[U256],
+    provider: Arc<M>,
+    owner: H160,
+    config: &SimulatorConfig,
+    block: &BlockContext,
+) -> Vec<(U256, i128)> {
+    // Simulates the same path with each input amount on clones of one seeded fork
+    // Amounts that fail to simulate are left out of the curve
+    let mut simulator =
+        EvmSimulator::new_with_config(provider.clone(), owner, block.number, config.clone());
+    simulator.set_block_context(block);
+    seed_simulator(
+        &mut simulator,
+        &arb.target_token,
+        arb.balance_slot,
+        arb.balance_account,
+    );
+    let fork_db = simulator.db_snapshot();
+
+    let mut curve = Vec::new();
+    for amount_in in amounts {
+        let mut arb = arb.clone();
+        arb.amount_in = *amount_in;
+        match simulate_triangular_arbitrage(
+            arb,
+            provider.clone(),
+            owner,
+            config,
+            block,
+            Some(fork_db.clone()),
+        ) {
+            Ok(result) => curve.push((*amount_in, result.profit)),
+            Err(e) => info!("[SIMULATION ERROR] {:?} {:?}", amount_in, e),
+        }
+    }
+    curve
+}
+
+pub fn profit_curve_with<S: SwapSimulator + Clone>(
+    arb: TriangularArbitrage,
+    amounts: &[U256],
+    simulator: &S,
+) -> Vec<(U256, i128)> {
+    // Same as profit_curve, every amount runs on its own clone of the simulator
+    let mut curve = Vec::new();
+    for amount_in in amounts {
+        let mut arb = arb.clone();
+        arb.amount_in = *amount_in;
+        match simulate_triangular_arbitrage_with(arb, &mut simulator.clone()) {
+            Ok(result) => curve.push((*amount_in, result.profit)),
+            Err(e) => info!("[SIMULATION ERROR] {:?} {:?}", amount_in, e),
+        }
+    }
+    curve
+}
+
+pub fn net_profit_wei(
+    path: &ArbPath,
+    result: &ArbResult,
+    weth: H160,
+    base_fee: U256,
+) -> Option<i128> {
+    // Profits are in the path's start token: WETH profits are already in wei,
+    // other tokens are valued through their USD price, None when that isn't known
+    let token_in = if path.zero_for_one_1 {
+        path.pool_1.token0
+    } else {
+        path.pool_1.token1
+    };
+    let profit_wei = if token_in == weth {
+        result.profit
+    } else {
+        match (result.profit_usd, result.eth_price_usd) {
+            (Some(profit_usd), Some(eth_price_usd)) if eth_price_usd > 0.0 => {
+                (profit_usd / eth_price_usd * 1e18) as i128
+            }
+            _ => return None,
+        }
+    };
+    // every path pays the same tip, so only the base fee changes the ranking
+    Some(net_of_gas(
+        profit_wei,
+        result.gas_used,
+        base_fee,
+        U256::zero(),
+    ))
+}
+
+pub fn sort_by_net_profit(results: &mut [(ArbPath, ArbResult)], weth: H160, base_fee: U256) {
+    // Most profitable after gas first, results that can't be valued in wei go last by gross profit
+    results.sort_by_key(|(path, result)| {
+        let net_profit = net_profit_wei(path, result, weth, base_fee);
+        std::cmp::Reverse((net_profit.is_some(), net_profit.unwrap_or(result.profit)))
+    });
+}
+
+pub async fn simulate_paths_batch<M: Middleware + 'static>(
+    arbs: Vec<TriangularArbitrage>,
+    provider: Arc<M>,
+    owner: H160,
+    config: &SimulatorConfig,
+    block: BlockContext,
+    base_fee: U256,
+    concurrency: usize,
+) -> Result<Vec<(ArbPath, ArbResult)>> {
+    // Seed a single fork with every target token once, then hand out clones of it
+    // The clones share the same SharedBackend, so storage fetched by one simulation is reused by the rest
+    let mut simulator =
+        EvmSimulator::new_with_config(provider.clone(), owner, block.number, config.clone());
+    simulator.set_block_context(&block);
+    for arb in &arbs {
+        seed_simulator(
+            &mut simulator,
+            &arb.target_token,
+            arb.balance_slot,
+            arb.balance_account,
+        );
+    }
+    let fork_db = simulator.db_snapshot();
+    let weth = simulator.weth_address;
+
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let mut set = JoinSet::new();
+
+    for arb in arbs {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let provider = provider.clone();
+        let fork_db = fork_db.clone();
+        let config = config.clone();
+        set.spawn_blocking(move || {
+            let path = arb.path.clone();
+            let result =
+                simulate_triangular_arbitrage(arb, provider, owner, &config, &block, Some(fork_db));
+            drop(permit);
+            (path, result)
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(res) = set.join_next().await {
+        let (path, result) = res?;
+        match result {
+            Ok(result) => results.push((path, result)),
+            Err(e) => info!("[SIMULATION ERROR] {:?}", e),
+        }
+    }
+
+    sort_by_net_profit(&mut results, weth, base_fee);
+    Ok(results)
+}
+
+pub async fn backtest_triangular_arbitrage<M: Middleware + 'static>(
+    arb: TriangularArbitrage,
+    provider: Arc<M>,
+    owner: H160,
+    config: &SimulatorConfig,
+    block_numbers: Vec<U64>,
+) -> Result<Vec<(U64, Result<ArbResult>)>> {
+    // Replays the same path on forks of past blocks, every block gets a freshly seeded fork
+    // Pools that didn't exist yet at a given block fail the path validation for that block
+    let mut results = Vec::new();
+    for block_number in block_numbers {
+        let block = provider
+            .get_block(block_number)
+            .await
+            .map_err(|e| anyhow!("get_block failed: {:?}", e))?
+            .ok_or(anyhow!("Block {:?} not found", block_number))?;
+        let block = BlockContext::from_block(&block);
+        let arb = arb.clone();
+        let provider = provider.clone();
+        let config = config.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            simulate_triangular_arbitrage(arb, provider, owner, &config, &block, None)
+        })
+        .await?;
+        results.push((block_number, result));
+    }
+    Ok(results)
+}
+
+pub async fn compare_path_across_blocks<M: Middleware + 'static>(
+    arb: TriangularArbitrage,
+    block_a: U64,
+    block_b: U64,
+    provider: Arc<M>,
+    owner: H160,
+    config: &SimulatorConfig,
+) -> Result<PathDiff> {
+    let mut results =
+        backtest_triangular_arbitrage(arb, provider, owner, config, vec![block_a, block_b]).await?;
+    let (_, result_b) = results.pop().unwrap();
+    let (_, result_a) = results.pop().unwrap();
+    Ok(PathDiff::between(block_a, &result_a?, block_b, &result_b?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::WEI;
+    use crate::paths::{generate_triangular_paths, index_paths_by_pool};
+    use crate::simulator::mock::MockSimulator;
+    use std::{
+        sync::Mutex,
+        thread::{self, ThreadId},
+    };
+
+    fn v2_pool(token0: H160, token1: H160) -> Pool {
+        Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        }
+    }
+
+    fn token(address: H160, symbol: &str) -> Token {
+        Token {
+            address,
+            implementation: None,
+            name: symbol.to_string(),
+            symbol: symbol.to_string(),
+            decimals: 18,
+        }
+    }
+
+    fn arb(path: ArbPath, target_token: Token) -> TriangularArbitrage {
+        TriangularArbitrage {
+            amount_in: *WEI,
+            path,
+            balance_slot: 0,
+            balance_account: None,
+            target_token,
+            token_taxes: HashMap::new(),
+            usd_pricer: None,
+            eth_oracle: None,
+            flashloan_fee_bps: None,
+            max_hop_slippage_bps: None,
+        }
+    }
+
+    #[test]
+    fn test_triangular_arbitrage_with_mock() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        // 1 WETH -> 2 USDC -> 2 DAI -> 1.2 WETH at spot, before fees and price impact
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+
+        let path = ArbPath {
+            nhop: 3,
+            pool_1: pool_1.clone(),
+            pool_2: pool_2.clone(),
+            pool_3: pool_3.clone(),
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        let result =
+            simulate_triangular_arbitrage_with(arb(path, token(weth, "WETH")), &mut simulator)
+                .unwrap();
+
+        // every pool is only swapped once, so each hop quotes off the initial reserves
+        let out_1 = pool_1.amount_out(*WEI, (100 * e18, 200 * e18), weth);
+        let out_2 = pool_2.amount_out(out_1, (200 * e18, 200 * e18), usdc);
+        let out_3 = pool_3.amount_out(out_2, (100 * e18, 60 * e18), dai);
+        assert_eq!(result.amount_out, out_3);
+        assert_eq!(
+            result.profit,
+            out_3.as_u128() as i128 - WEI.as_u128() as i128
+        );
+        assert!(result.profit > 0);
+        assert_eq!(result.gas_used, 3 * simulator.swap_gas);
+        assert_eq!(
+            result.hops.iter().map(|hop| hop.pool).collect::<Vec<_>>(),
+            vec![pool_1.address, pool_2.address, pool_3.address]
+        );
+
+        // the profit landed in the simulator's balance
+        let balance = simulator.token_balance_of(weth, simulator_address).unwrap();
+        assert_eq!(balance, U256::from(10) * *WEI - *WEI + out_3);
+    }
+
+    #[test]
+    fn test_flashloan_fee_lowers_the_profit() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 1);
+
+        let path = ArbPath {
+            nhop: 3,
+            pool_1,
+            pool_2,
+            pool_3,
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        let own_capital = simulate_triangular_arbitrage_with(
+            arb(path.clone(), token(weth, "WETH")),
+            &mut simulator.clone(),
+        )
+        .unwrap();
+        let mut flashloan = arb(path, token(weth, "WETH"));
+        flashloan.flashloan_fee_bps = Some(9);
+        let borrowed = simulate_triangular_arbitrage_with(flashloan, &mut simulator).unwrap();
+
+        // same swaps, the only difference is the 9 bps repaid on top of amount_in
+        assert_eq!(borrowed.amount_out, own_capital.amount_out);
+        let fee = (*WEI * U256::from(9) / U256::from(10000)).as_u128() as i128;
+        assert_eq!(borrowed.profit, own_capital.profit - fee);
+    }
+
+    #[test]
+    fn test_profit_curve_peaks_in_the_middle() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+
+        let path = ArbPath {
+            nhop: 3,
+            pool_1,
+            pool_2,
+            pool_3,
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        // too little leaves profit on the table, too much eats it in price impact
+        let amounts = [*WEI / 2, *WEI * 2, *WEI * 5];
+        let curve = profit_curve_with(arb(path, token(weth, "WETH")), &amounts, &simulator);
+
+        assert_eq!(
+            curve.iter().map(|(amount, _)| *amount).collect::<Vec<_>>(),
+            amounts.to_vec()
+        );
+        let profits: Vec<i128> = curve.iter().map(|(_, profit)| *profit).collect();
+        assert!(profits[1] > profits[0]);
+        assert!(profits[1] > profits[2]);
+        assert!(profits[2] < 0);
+        // every amount ran on its own copy, the original simulator is untouched
+        assert_eq!(simulator.gas_used, 0);
+    }
+
+    // Keeps the formatted lines with the thread that logged them,
+    // so a test can pick out its own lines while the others run in parallel
+    struct CaptureLogger;
+
+    static CAPTURED: Mutex<Vec<(ThreadId, String)>> = Mutex::new(Vec::new());
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED
+                .lock()
+                .unwrap()
+                .push((thread::current().id(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_simulation_logs_share_a_correlation_id() {
+        // the logger can only be set once per test binary, no other test sets one
+        let _ = log::set_logger(&CaptureLogger);
+        log::set_max_level(log::LevelFilter::Info);
 
-    Ok(profit)
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+
+        let id = correlation_id(
+            &[
+                pool_1.address.as_bytes(),
+                pool_2.address.as_bytes(),
+                pool_3.address.as_bytes(),
+            ]
+            .concat(),
+        );
+        let path = ArbPath {
+            nhop: 3,
+            pool_1,
+            pool_2,
+            pool_3,
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        simulate_triangular_arbitrage_with(arb(path, token(weth, "WETH")), &mut simulator).unwrap();
+
+        let this_thread = thread::current().id();
+        let lines: Vec<String> = CAPTURED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(thread_id, _)| *thread_id == this_thread)
+            .map(|(_, line)| line.clone())
+            .collect();
+        // the header, the 3 swaps and the profit, all grep-able by the path's id
+        assert!(lines.len() >= 5);
+        for line in &lines {
+            assert!(line.starts_with(&format!("[{}] ", id)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_path_diff_between_two_states() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let path = ArbPath {
+            nhop: 3,
+            pool_1: pool_1.clone(),
+            pool_2: pool_2.clone(),
+            pool_3: pool_3.clone(),
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        // the same path with pool_1 holding 200 or 220 USDC, standing in for two blocks
+        let run = |usdc_reserve: u128| {
+            let mut simulator = MockSimulator::new(weth);
+            simulator.add_pool(pool_1.clone(), 100 * e18, usdc_reserve);
+            simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+            simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+            let simulator_address = simulator.simulator_address;
+            simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+            simulate_triangular_arbitrage_with(
+                arb(path.clone(), token(weth, "WETH")),
+                &mut simulator,
+            )
+            .unwrap()
+        };
+        let (result_a, result_b) = (run(200 * e18), run(220 * e18));
+
+        let diff = PathDiff::between(U64::from(100), &result_a, U64::from(110), &result_b);
+        assert_eq!(
+            (diff.block_a, diff.block_b),
+            (U64::from(100), U64::from(110))
+        );
+        assert_eq!(diff.hops.len(), 3);
+        assert_eq!(diff.hops[0].pool, pool_1.address);
+        assert_eq!(diff.hops[0].reserve0_delta, 0);
+        assert_eq!(diff.hops[0].reserve1_delta, 20 * e18 as i128);
+        // more USDC out of the first hop carries through to the rest of the path
+        for hop in &diff.hops {
+            assert!(hop.amount_out_delta > 0);
+        }
+        assert_eq!(
+            (diff.hops[1].reserve0_delta, diff.hops[1].reserve1_delta),
+            (0, 0)
+        );
+        assert_eq!(diff.profit_delta, result_b.profit - result_a.profit);
+        assert!(diff.profit_delta > 0);
+    }
+
+    #[test]
+    fn test_only_the_reverse_direction_is_profitable() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        // WETH is cheap in the DAI/WETH pool: 1 WETH -> 2 USDC -> 2 DAI -> 0.8 WETH loses,
+        // the other way around 1 WETH -> 2.5 DAI -> 2.5 USDC -> 1.25 WETH pays, before fees
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 40 * e18);
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+
+        let path = ArbPath {
+            nhop: 3,
+            pool_1: pool_1.clone(),
+            pool_2,
+            pool_3: pool_3.clone(),
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        let forward = simulate_triangular_arbitrage_with(
+            arb(path.clone(), token(weth, "WETH")),
+            &mut simulator.clone(),
+        )
+        .unwrap();
+        assert!(forward.profit < 0);
+
+        let (direction, result) =
+            simulate_both_directions_with(arb(path, token(weth, "WETH")), &simulator).unwrap();
+        assert_eq!(direction, ArbDirection::Reverse);
+        assert!(result.profit > 0);
+        // the reverse cycle starts in the DAI/WETH pool and ends in the WETH/USDC one
+        assert_eq!(result.hops[0].pool, pool_3.address);
+        assert_eq!(result.hops[2].pool, pool_1.address);
+    }
+
+    #[test]
+    fn test_victim_swap_opens_a_backrun_arbitrage() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let weth_usdc = v2_pool(weth, usdc);
+        let pools = vec![weth_usdc.clone(), v2_pool(usdc, dai), v2_pool(dai, weth)];
+        let mut simulator = MockSimulator::new(weth);
+        for pool in &pools {
+            simulator.add_pool(pool.clone(), 100 * e18, 100 * e18);
+        }
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+
+        // the victim buys USDC with 20 WETH, USDC is now worth more WETH in that pool only
+        let victim = Transaction {
+            hash: H256::random(),
+            ..Default::default()
+        };
+        simulator.add_pending_swap(&victim, weth_usdc.address, weth, U256::from(20) * *WEI);
+
+        // the paths through the pool the victim touched
+        let paths_by_pool = index_paths_by_pool(&generate_triangular_paths(&pools, weth));
+        let arbs: Vec<TriangularArbitrage> = paths_by_pool[&weth_usdc.address]
+            .iter()
+            .map(|path| arb(path.clone(), token(weth, "WETH")))
+            .collect();
+        assert_eq!(arbs.len(), 2);
+
+        // before the victim every cycle only pays the fees
+        for arb in arbs.clone() {
+            let result = simulate_triangular_arbitrage_with(arb, &mut simulator.clone()).unwrap();
+            assert!(result.profit < 0);
+        }
+
+        let results = simulate_backrun_arbitrage_with(arbs, &simulator, &victim).unwrap();
+        let (path, best) = &results[0];
+        assert!(best.profit > 0);
+        // WETH -> DAI -> USDC, then the USDC is sold back into the pool the victim bought from
+        assert_eq!(path.pool_3.address, weth_usdc.address);
+    }
+
+    #[test]
+    fn test_triangular_arbitrage_with_mock_rejects_empty_pool() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        simulator.add_pool(pool_2.clone(), 0, 0);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+
+        let path = ArbPath {
+            nhop: 3,
+            pool_1,
+            pool_2,
+            pool_3,
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        let err =
+            simulate_triangular_arbitrage_with(arb(path, token(weth, "WETH")), &mut simulator)
+                .unwrap_err();
+        match err.downcast_ref::<ArbError>() {
+            Some(ArbError::EmptyPool { hop, .. }) => assert_eq!(*hop, 1),
+            other => panic!("expected an empty pool error, got {:?}", other),
+        }
+        // nothing was swapped
+        assert_eq!(simulator.gas_used, 0);
+    }
+
+    #[test]
+    fn test_taxed_path_is_less_profitable() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let path = ArbPath {
+            nhop: 3,
+            pool_1: pool_1.clone(),
+            pool_2: pool_2.clone(),
+            pool_3: pool_3.clone(),
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        let simulate = |token_taxes: HashMap<H160, (u32, u32)>| {
+            let mut simulator = MockSimulator::new(weth);
+            simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+            simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+            simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+            let simulator_address = simulator.simulator_address;
+            simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+            let arb = TriangularArbitrage {
+                token_taxes,
+                ..arb(path.clone(), token(weth, "WETH"))
+            };
+            simulate_triangular_arbitrage_with(arb, &mut simulator).unwrap()
+        };
+
+        let untaxed = simulate(HashMap::new());
+        // USDC taxes 5% when bought out of pool_1 and again when sold into pool_2
+        let taxed = simulate(HashMap::from([(usdc, (500, 500))]));
+        assert!(taxed.profit < untaxed.profit);
+
+        let bought = apply_tax(pool_1.amount_out(*WEI, (100 * e18, 200 * e18), weth), 500);
+        assert_eq!(taxed.hops[0].amount_out, bought);
+        assert_eq!(taxed.hops[1].amount_in, apply_tax(bought, 500));
+        let out_2 = pool_2.amount_out(apply_tax(bought, 500), (200 * e18, 200 * e18), usdc);
+        let out_3 = pool_3.amount_out(out_2, (100 * e18, 60 * e18), dai);
+        assert_eq!(taxed.amount_out, out_3);
+    }
+
+    #[test]
+    fn test_validate_path_names_the_empty_hop() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+        let path = ArbPath {
+            nhop: 3,
+            pool_1,
+            pool_2: pool_2.clone(),
+            pool_3,
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        assert!(validate_path(&mut simulator, &path).is_ok());
+
+        // one drained side is enough to make the middle hop dead
+        simulator.add_pool(pool_2.clone(), 200 * e18, 0);
+        let err = validate_path(&mut simulator, &path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("hop #2 pool {:?} has no liquidity", pool_2.address)
+        );
+
+        // a pool whose reserves can't be read is treated as empty
+        let unknown = ArbPath {
+            pool_3: v2_pool(dai, weth),
+            ..path.clone()
+        };
+        simulator.add_pool(pool_2, 200 * e18, 200 * e18);
+        match validate_path(&mut simulator, &unknown)
+            .unwrap_err()
+            .downcast_ref::<ArbError>()
+        {
+            Some(ArbError::EmptyPool { hop, pool }) => {
+                assert_eq!((*hop, *pool), (2, unknown.pool_3.address))
+            }
+            other => panic!("expected an empty pool error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hop_slippage_bps() {
+        let (weth, usdc) = (H160::random(), H160::random());
+        let pool = v2_pool(weth, usdc);
+        let reserves = (1000, 2000);
+
+        // 100 WETH buys 200 USDC at the spot price
+        assert_eq!(
+            hop_slippage_bps(&pool, reserves, weth, U256::from(100), U256::from(180)),
+            Some(1000)
+        );
+        assert_eq!(
+            hop_slippage_bps(&pool, reserves, usdc, U256::from(200), U256::from(95)),
+            Some(500)
+        );
+        // filling at or above the spot price isn't slippage
+        assert_eq!(
+            hop_slippage_bps(&pool, reserves, weth, U256::from(100), U256::from(200)),
+            Some(0)
+        );
+        assert_eq!(
+            hop_slippage_bps(&pool, (0, 0), weth, U256::from(100), U256::zero()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_triangular_arbitrage_aborts_at_the_thin_hop() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        // swapping ~2 USDC into a pool holding 2 USDC loses about half of it
+        simulator.add_pool(pool_2.clone(), 2 * e18, 2 * e18);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+
+        let path = ArbPath {
+            nhop: 3,
+            pool_1,
+            pool_2: pool_2.clone(),
+            pool_3,
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        let mut arb = arb(path, token(weth, "WETH"));
+        arb.max_hop_slippage_bps = Some(500);
+        let err = simulate_triangular_arbitrage_with(arb, &mut simulator).unwrap_err();
+        match err.downcast_ref::<ArbError>() {
+            Some(ArbError::ExcessiveSlippage {
+                hop,
+                pool,
+                slippage_bps,
+            }) => {
+                assert_eq!(*hop, 1);
+                assert_eq!(*pool, pool_2.address);
+                assert!(*slippage_bps > 4000);
+            }
+            other => panic!("expected an excessive slippage error, got {:?}", other),
+        }
+        // the last hop was never swapped
+        assert_eq!(simulator.gas_used, 2 * simulator.swap_gas);
+    }
+
+    #[test]
+    fn test_batch_is_sorted_by_net_profit() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        // the same cycle with the last pool priced further and further off
+        let batch: Vec<(ArbPath, ArbResult)> = [60, 80, 70, 50]
+            .into_iter()
+            .map(|weth_reserve| {
+                let path = ArbPath {
+                    nhop: 3,
+                    pool_1: v2_pool(weth, usdc),
+                    pool_2: v2_pool(usdc, dai),
+                    pool_3: v2_pool(dai, weth),
+                    zero_for_one_1: true,
+                    zero_for_one_2: true,
+                    zero_for_one_3: true,
+                };
+                let mut simulator = MockSimulator::new(weth);
+                simulator.add_pool(path.pool_1.clone(), 100 * e18, 200 * e18);
+                simulator.add_pool(path.pool_2.clone(), 200 * e18, 200 * e18);
+                simulator.add_pool(path.pool_3.clone(), 100 * e18, weth_reserve * e18);
+                let simulator_address = simulator.simulator_address;
+                simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+                let result = simulate_triangular_arbitrage_with(
+                    arb(path.clone(), token(weth, "WETH")),
+                    &mut simulator,
+                )
+                .unwrap();
+                (path, result)
+            })
+            .collect();
+
+        let base_fee = U256::from(30) * U256::exp10(9);
+        let mut sorted = batch.clone();
+        sort_by_net_profit(&mut sorted, weth, base_fee);
+        let net_profits: Vec<i128> = sorted
+            .iter()
+            .map(|(path, result)| net_profit_wei(path, result, weth, base_fee).unwrap())
+            .collect();
+        assert!(net_profits.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert_eq!(sorted[0].0.hash(), batch[1].0.hash());
+        assert_eq!(sorted[3].0.hash(), batch[3].0.hash());
+
+        // the most profitable path gross is the least profitable once its gas is paid
+        let mut batch = batch;
+        batch[1].1.gas_used = 50_000_000;
+        sort_by_net_profit(&mut batch, weth, base_fee);
+        assert_eq!(batch[3].1.gas_used, 50_000_000);
+        assert!(batch[3].1.profit > batch[0].1.profit);
+    }
+
+    #[test]
+    fn test_arb_cache_serves_repeated_simulations() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let e18 = 10u128.pow(18);
+        let pool_1 = v2_pool(weth, usdc);
+        let pool_2 = v2_pool(usdc, dai);
+        let pool_3 = v2_pool(dai, weth);
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(pool_1.clone(), 100 * e18, 200 * e18);
+        simulator.add_pool(pool_2.clone(), 200 * e18, 200 * e18);
+        simulator.add_pool(pool_3.clone(), 100 * e18, 60 * e18);
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 10);
+
+        let path = ArbPath {
+            nhop: 3,
+            pool_1,
+            pool_2,
+            pool_3,
+            zero_for_one_1: true,
+            zero_for_one_2: true,
+            zero_for_one_3: true,
+        };
+        let arb = arb(path.clone(), token(weth, "WETH"));
+        let mut cache = ArbCache::new();
+        let mut simulate = |cache: &mut ArbCache, block_number: u64| {
+            cache.get_or_simulate(U64::from(block_number), path.hash(), *WEI, || {
+                simulate_triangular_arbitrage_with(arb.clone(), &mut simulator)
+            })
+        };
+
+        let first = simulate(&mut cache, 100).unwrap();
+        let second = simulate(&mut cache, 100).unwrap();
+        assert_eq!((cache.hits, cache.misses), (1, 1));
+        assert_eq!(second.profit, first.profit);
+        assert_eq!(second.amount_out, first.amount_out);
+
+        // the next block starts with an empty cache
+        simulate(&mut cache, 101).unwrap();
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+        assert_eq!(cache.results.len(), 1);
+        // only the two misses were simulated
+        assert_eq!(simulator.gas_used, 2 * 3 * simulator.swap_gas);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_backtest_at_two_historical_blocks() {
+        // Needs an archive WSS_URL, both blocks are forked from their historical state
+        use ethers_providers::{Provider, Ws};
+
+        dotenv::dotenv().ok();
+        let ws = Ws::connect(std::env::var("WSS_URL").unwrap())
+            .await
+            .unwrap();
+        let provider = Arc::new(Provider::new(ws));
+
+        let weth: H160 = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse()
+            .unwrap();
+        let usdc: H160 = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"
+            .parse()
+            .unwrap();
+        let usdt: H160 = "0xdAC17F958D2ee523a2206206994597C13D831ec7"
+            .parse()
+            .unwrap();
+        let mainnet_pool = |address: &str, token0, token1, decimals0, decimals1| Pool {
+            address: address.parse().unwrap(),
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0,
+            decimals1,
+            fee: 300,
+        };
+        // WETH -> USDC -> USDT -> WETH over Uniswap V2
+        let path = ArbPath {
+            nhop: 3,
+            pool_1: mainnet_pool(
+                "0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc",
+                usdc,
+                weth,
+                6,
+                18,
+            ),
+            pool_2: mainnet_pool(
+                "0x3041CbD36888bECc7bbCBc0045E3B1f144466f5f",
+                usdc,
+                usdt,
+                6,
+                6,
+            ),
+            pool_3: mainnet_pool(
+                "0x0d4a11d5EEaaC28EC3F61d100daF4d40471f1852",
+                weth,
+                usdt,
+                18,
+                6,
+            ),
+            zero_for_one_1: false,
+            zero_for_one_2: true,
+            zero_for_one_3: false,
+        };
+        let mut arb = arb(path, token(weth, "WETH"));
+        // WETH keeps balances at slot 3
+        arb.balance_slot = 3;
+
+        let blocks = vec![U64::from(17_000_000), U64::from(18_000_000)];
+        let results = backtest_triangular_arbitrage(
+            arb,
+            provider,
+            H160::random(),
+            &SimulatorConfig::default(),
+            blocks.clone(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            results.iter().map(|(block, _)| *block).collect::<Vec<_>>(),
+            blocks
+        );
+        let profits: Vec<i128> = results
+            .into_iter()
+            .map(|(_, result)| result.unwrap().profit)
+            .collect();
+        // the reserves moved between the blocks, so the same path prices differently
+        assert_ne!(profits[0], profits[1]);
+    }
 }
@@ -0,0 +1,113 @@
+use log::warn;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    pub max_failures: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+    failures: VecDeque<Instant>,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_failures: usize, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            max_failures,
+            window,
+            cooldown,
+            failures: VecDeque::new(),
+            open_until: None,
+        }
+    }
+
+    pub fn is_open(&mut self) -> bool {
+        match self.open_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                self.open_until = None;
+                warn!("Circuit breaker closed, resuming simulations");
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        // failures have to be consecutive to open the breaker
+        self.failures.clear();
+    }
+
+    pub fn record_failure(&mut self) {
+        let now = Instant::now();
+        self.failures.push_back(now);
+        while let Some(first) = self.failures.front() {
+            if now.duration_since(*first) > self.window {
+                self.failures.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.failures.len() >= self.max_failures {
+            warn!(
+                "Circuit breaker opened after {} failures, pausing for {:?}",
+                self.failures.len(),
+                self.cooldown
+            );
+            self.failures.clear();
+            self.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(60), Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+        breaker.record_failure();
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let mut breaker = CircuitBreaker::new(3, Duration::from_secs(60), Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn test_failures_outside_the_window_are_dropped() {
+        let mut breaker =
+            CircuitBreaker::new(2, Duration::from_millis(10), Duration::from_secs(60));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(20));
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn test_closes_after_cooldown() {
+        let mut breaker =
+            CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_millis(10));
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!breaker.is_open());
+        // the failures that opened it don't count anymore
+        assert!(breaker.failures.is_empty());
+    }
+}
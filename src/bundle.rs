@@ -0,0 +1,247 @@
+use anyhow::{anyhow, Result};
+use ethers::types::{
+    transaction::{eip2718::TypedTransaction, eip2930::AccessList},
+    Bytes, Eip1559TransactionRequest, NameOrAddress, H160, U256, U64,
+};
+
+use crate::streams::BlockContext;
+
+#[derive(Debug, Clone, Copy)]
+pub struct GasParams {
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
+
+#[derive(Debug, Clone)]
+pub struct BundleSwap {
+    pub to: H160,
+    pub data: Bytes,
+    pub value: U256,
+    pub gas_limit: U256,
+    pub access_list: AccessList,
+}
+
+pub fn build_bundle_txs(
+    owner_nonce: U256,
+    chain_id: U64,
+    gas_params: GasParams,
+    swaps: Vec<BundleSwap>,
+) -> Vec<TypedTransaction> {
+    // Our txs land in the same block, so nonces count up from the on-chain transaction count
+    // ex) [frontrun, backrun] -> [nonce, nonce + 1]
+    swaps
+        .into_iter()
+        .enumerate()
+        .map(|(idx, swap)| {
+            let tx = Eip1559TransactionRequest {
+                to: Some(NameOrAddress::Address(swap.to)),
+                from: None,
+                data: Some(swap.data),
+                value: Some(swap.value),
+                chain_id: Some(chain_id),
+                max_priority_fee_per_gas: Some(gas_params.max_priority_fee_per_gas),
+                max_fee_per_gas: Some(gas_params.max_fee_per_gas),
+                gas: Some(swap.gas_limit),
+                nonce: Some(owner_nonce + U256::from(idx)),
+                access_list: swap.access_list,
+            };
+            TypedTransaction::Eip1559(tx)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TargetBlock {
+    pub block_number: U64,
+    pub base_fee: U256,
+    pub net_profit: i128,
+}
+
+pub fn best_target_block<F>(
+    context: &BlockContext,
+    blocks: u64,
+    priority_fee: U256,
+    mut simulate: F,
+) -> Result<TargetBlock>
+where
+    F: FnMut(u64, U256) -> Result<(i128, u64)>,
+{
+    // Runs `simulate(block_offset, base_fee)` against each of the next `blocks` blocks,
+    // which returns (profit_eth, gas_used), and picks the block with the highest profit net of gas
+    // Ties go to the earlier block
+    let mut best: Option<TargetBlock> = None;
+    for (offset, (block_number, base_fee)) in
+        (1..).zip(context.projected_base_fees(blocks).into_iter())
+    {
+        let (profit_eth, gas_used) = simulate(offset, base_fee)?;
+        let net_profit = net_of_gas(profit_eth, gas_used, base_fee, priority_fee);
+        if best.map_or(true, |best| net_profit > best.net_profit) {
+            best = Some(TargetBlock {
+                block_number,
+                base_fee,
+                net_profit,
+            });
+        }
+    }
+    best.ok_or(anyhow!("No target blocks to simulate"))
+}
+
+pub fn wei_to_usd(amount: i128, eth_price_usd: f64) -> f64 {
+    amount as f64 / 1e18 * eth_price_usd
+}
+
+pub fn net_of_gas(
+    profit_eth: i128,
+    gas_used: u64,
+    next_base_fee: U256,
+    priority_fee: U256,
+) -> i128 {
+    // Profit left after paying for our own txs at the next block's base fee plus the tip,
+    // a negative result means the bundle loses to gas
+    let gas_cost = U256::from(gas_used) * (next_base_fee + priority_fee);
+    profit_eth.saturating_sub(gas_cost.min(U256::from(i128::MAX as u128)).as_u128() as i128)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gwei(amount: u64) -> U256 {
+        U256::from(amount) * U256::exp10(9)
+    }
+
+    #[test]
+    fn test_build_bundle_txs() {
+        let swap = |to: H160| BundleSwap {
+            to,
+            data: Bytes::from(vec![1, 2, 3]),
+            value: U256::zero(),
+            gas_limit: U256::from(200000),
+            access_list: AccessList::default(),
+        };
+        let (frontrun, backrun) = (H160::random(), H160::random());
+        let gas_params = GasParams {
+            max_fee_per_gas: gwei(60),
+            max_priority_fee_per_gas: gwei(2),
+        };
+        let txs = build_bundle_txs(
+            U256::from(7),
+            U64::from(8453),
+            gas_params,
+            vec![swap(frontrun), swap(backrun)],
+        );
+
+        // [frontrun, backrun] get consecutive nonces from the account's count, both on the same chain
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].nonce(), Some(&U256::from(7)));
+        assert_eq!(txs[1].nonce(), Some(&U256::from(8)));
+        for (tx, to) in txs.iter().zip([frontrun, backrun]) {
+            assert_eq!(tx.chain_id(), Some(U64::from(8453)));
+            assert_eq!(tx.to_addr(), Some(&to));
+            assert_eq!(tx.gas(), Some(&U256::from(200000)));
+        }
+    }
+
+    #[test]
+    fn test_net_of_gas() {
+        // 200k gas at 30 + 2 gwei costs 0.0064 ETH
+        let profit = 10i128.pow(16);
+        let net = net_of_gas(profit, 200000, gwei(30), gwei(2));
+        assert_eq!(net, profit - 200000 * 32 * 10i128.pow(9));
+
+        // nothing to pay for without gas
+        assert_eq!(net_of_gas(profit, 0, gwei(30), gwei(2)), profit);
+    }
+
+    #[test]
+    fn test_net_of_gas_can_go_negative() {
+        let net = net_of_gas(10i128.pow(15), 200000, gwei(30), gwei(2));
+        assert!(net < 0);
+        assert_eq!(net, 10i128.pow(15) - 200000 * 32 * 10i128.pow(9));
+        // a loss only gets worse
+        assert!(net_of_gas(-1, 21000, gwei(1), U256::zero()) < -1);
+    }
+
+    #[test]
+    fn test_net_of_gas_saturates() {
+        // a gas cost beyond i128 is capped instead of overflowing
+        let net = net_of_gas(0, u64::MAX, U256::MAX / U256::from(u64::MAX), U256::zero());
+        assert_eq!(net, -i128::MAX);
+        assert_eq!(
+            net_of_gas(i128::MIN, 1, U256::one(), U256::zero()),
+            i128::MIN
+        );
+    }
+
+    fn full_block() -> BlockContext {
+        // every block full, the base fee goes up 12.5% a block: 100, 112.5, 126.5625 gwei
+        BlockContext {
+            number: U64::from(100),
+            base_fee: gwei(100),
+            next_base_fee: gwei(100),
+            gas_limit: U256::from(30000000),
+            gas_used: U256::from(30000000),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_best_target_block_prefers_cheaper_gas() {
+        // same profit in every block, the next block pays the lowest base fee
+        let mut offsets = Vec::new();
+        let target = best_target_block(&full_block(), 3, gwei(2), |offset, _| {
+            offsets.push(offset);
+            Ok((10i128.pow(17), 200000))
+        })
+        .unwrap();
+        assert_eq!(offsets, vec![1, 2, 3]);
+        assert_eq!(target.block_number, U64::from(101));
+        assert_eq!(target.base_fee, gwei(100));
+        assert_eq!(
+            target.net_profit,
+            net_of_gas(10i128.pow(17), 200000, gwei(100), gwei(2))
+        );
+    }
+
+    #[test]
+    fn test_best_target_block_waits_for_more_profit() {
+        let target = best_target_block(&full_block(), 3, gwei(2), |offset, base_fee| {
+            assert!(base_fee >= gwei(100));
+            let profit = if offset == 3 {
+                10i128.pow(18)
+            } else {
+                10i128.pow(17)
+            };
+            Ok((profit, 200000))
+        })
+        .unwrap();
+        assert_eq!(target.block_number, U64::from(103));
+        assert!(target.base_fee > gwei(112));
+    }
+
+    #[test]
+    fn test_best_target_block_ties_go_to_the_earlier_block() {
+        let target =
+            best_target_block(&full_block(), 3, gwei(2), |_, _| Ok((10i128.pow(17), 0))).unwrap();
+        assert_eq!(target.block_number, U64::from(101));
+    }
+
+    #[test]
+    fn test_best_target_block_errors() {
+        assert!(best_target_block(&full_block(), 0, gwei(2), |_, _| Ok((0, 0))).is_err());
+        let failing = best_target_block(&full_block(), 3, gwei(2), |offset, _| {
+            if offset == 2 {
+                Err(anyhow!("simulation failed"))
+            } else {
+                Ok((10i128.pow(17), 0))
+            }
+        });
+        assert!(failing.is_err());
+    }
+
+    #[test]
+    fn test_wei_to_usd() {
+        assert_eq!(wei_to_usd(10i128.pow(18), 2000.0), 2000.0);
+        assert_eq!(wei_to_usd(-5 * 10i128.pow(17), 2000.0), -1000.0);
+    }
+}
@@ -0,0 +1,256 @@
+use anyhow::{anyhow, Result};
+use cfmms::dex::DexVariant;
+use ethers::types::H160;
+use std::{str::FromStr, time::Duration};
+
+use crate::constants::Env;
+
+#[derive(Debug, Clone)]
+pub struct ChainConfig {
+    pub chain_id: u64,
+    // WETH on Ethereum/Arbitrum/Base, WBNB on BSC
+    pub wrapped_native: H160,
+    pub usdt: H160,
+    pub usdc: H160,
+    pub dai: H160,
+    // (factory address, dex variant, creation block)
+    pub factories: Vec<(H160, DexVariant, u64)>,
+    // Average time between blocks, used to project the timestamp of future blocks
+    pub block_time: Duration,
+    // Uniswap V3 QuoterV2
+    pub v3_quoter: H160,
+    // V2 router of the chain's main factory
+    pub router: H160,
+}
+
+impl ChainConfig {
+    pub fn new(chain_id: u64) -> Result<Self> {
+        // Unknown chains are rejected, mainnet's addresses would silently point at nothing there
+        match chain_id {
+            1 => Ok(Self::mainnet()),
+            56 => Ok(Self::bsc()),
+            8453 => Ok(Self::base()),
+            42161 => Ok(Self::arbitrum()),
+            _ => Err(anyhow!("Unsupported chain id: {}", chain_id)),
+        }
+    }
+
+    pub fn from_env(env: &Env) -> Result<Self> {
+        // FACTORIES replaces the chain's default factory list when set
+        let mut chain = Self::new(env.chain_id.as_u64())?;
+        if !env.factories.is_empty() {
+            chain.factories = env.factories.clone();
+        }
+        Ok(chain)
+    }
+
+    pub fn mainnet() -> Self {
+        Self {
+            chain_id: 1,
+            wrapped_native: address("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"),
+            usdt: address("0xdAC17F958D2ee523a2206206994597C13D831ec7"),
+            usdc: address("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"),
+            dai: address("0x6B175474E89094C44Da98b954EedeAC495271d0F"),
+            factories: vec![
+                (
+                    // Uniswap v2
+                    address("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"),
+                    DexVariant::UniswapV2,
+                    10000835u64,
+                ),
+                (
+                    // Sushiswap V2
+                    address("0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"),
+                    DexVariant::UniswapV2,
+                    10794229u64,
+                ),
+            ],
+            block_time: Duration::from_secs(12),
+            v3_quoter: address("0x61fFE014bA17989E743c5F6cB21bF9697530B21e"),
+            // Uniswap V2 Router02
+            router: address("0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D"),
+        }
+    }
+
+    pub fn arbitrum() -> Self {
+        Self {
+            chain_id: 42161,
+            wrapped_native: address("0x82aF49447D8a07e3bd95BD0d56f35241523fBab1"),
+            usdt: address("0xFd086bC7CD5C481DCC9C85ebE478A1C0b69FCbb9"),
+            usdc: address("0xaf88d065e77c8cC2239327C5EDb3A432268e5831"),
+            dai: address("0xDA10009cBd5D07dd0CeCc66161FC93D7c9000da1"),
+            factories: vec![(
+                // Sushiswap V2
+                address("0xc35DADB65012eC5796536bD9864eD8773aBc74C4"),
+                DexVariant::UniswapV2,
+                70u64,
+            )],
+            block_time: Duration::from_millis(250),
+            v3_quoter: address("0x61fFE014bA17989E743c5F6cB21bF9697530B21e"),
+            // Sushiswap V2 router
+            router: address("0x1b02dA8Cb0d097eB8D57A175b88c7D8b47997506"),
+        }
+    }
+
+    pub fn base() -> Self {
+        Self {
+            chain_id: 8453,
+            wrapped_native: address("0x4200000000000000000000000000000000000006"),
+            // bridged USDT, there is no native deployment
+            usdt: address("0xfde4C96c8593536E31F229EA8f37b2ADa2699bb2"),
+            usdc: address("0x833589fCD6eDb6E08f4c7C32D4f71b54bdA02913"),
+            dai: address("0x50c5725949A6F0c72E6C4a641F24049A917DB0Cb"),
+            factories: vec![(
+                // Uniswap v2
+                address("0x8909Dc15e40173Ff4699343b6eB8132c65e18eC6"),
+                DexVariant::UniswapV2,
+                6601915u64,
+            )],
+            block_time: Duration::from_secs(2),
+            v3_quoter: address("0x3d4e44Eb1374240CE5F1B871ab261CD16335B76a"),
+            // Uniswap V2 Router02
+            router: address("0x4752ba5DBc23f44D87826276BF6Fd6b1C372aD24"),
+        }
+    }
+
+    pub fn bsc() -> Self {
+        Self {
+            chain_id: 56,
+            wrapped_native: address("0xbb4CdB9CBd36B01bD1cBaEBF2De08d9173bc095c"),
+            usdt: address("0x55d398326f99059fF775485246999027B3197955"),
+            usdc: address("0x8AC76a51cc950d9822D68b83fE1Ad97B32Cd580d"),
+            dai: address("0x1AF3F329e8BE154074D8769D1FFa4eE058B1DBc3"),
+            factories: vec![(
+                // PancakeSwap V2
+                address("0xcA143Ce32Fe78f1f7019d7d551a6402fC5350c73"),
+                DexVariant::UniswapV2,
+                6809737u64,
+            )],
+            block_time: Duration::from_millis(750),
+            v3_quoter: address("0x78D78E420Da98ad378D7799bE8f4AF69033EB077"),
+            // PancakeSwap V2 router
+            router: address("0x10ED43C718714eb63d5aA57B78B54704E256024E"),
+        }
+    }
+}
+
+pub fn parse_factories(raw: &str) -> Result<Vec<(H160, DexVariant, u64)>> {
+    // Comma separated address:variant:creation_block entries, variant is v2
+    // v3 factories are rejected until V3 swaps can be simulated, their pools are only quotable
+    // ex) 0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f:v2:10000835
+    let mut factories = Vec::new();
+    for entry in raw.split(',').map(|entry| entry.trim()) {
+        if entry.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = entry.split(':').collect();
+        if parts.len() != 3 {
+            return Err(anyhow!(
+                "factory {:?} should be address:variant:creation_block",
+                entry
+            ));
+        }
+        let factory = H160::from_str(parts[0])
+            .map_err(|e| anyhow!("factory {:?} has an invalid address: {}", entry, e))?;
+        let variant = match parts[1].to_lowercase().as_str() {
+            "v2" => DexVariant::UniswapV2,
+            "v3" => {
+                return Err(anyhow!(
+                    "factory {:?} is a v3 factory, V3 pools can't be simulated yet",
+                    entry
+                ))
+            }
+            other => {
+                return Err(anyhow!(
+                    "factory {:?} has an unknown variant {:?}, expected v2",
+                    entry,
+                    other
+                ))
+            }
+        };
+        let creation_block = parts[2]
+            .parse::<u64>()
+            .map_err(|e| anyhow!("factory {:?} has an invalid creation block: {}", entry, e))?;
+        factories.push((factory, variant, creation_block));
+    }
+    Ok(factories)
+}
+
+fn address(raw: &str) -> H160 {
+    H160::from_str(raw).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::honeypot::SafeTokens;
+
+    #[test]
+    fn test_new_rejects_unknown_chains() {
+        assert!(ChainConfig::new(10).is_err());
+        assert_eq!(ChainConfig::new(1).unwrap().chain_id, 1);
+        let base = ChainConfig::new(8453).unwrap();
+        assert_eq!(
+            base.wrapped_native,
+            address("0x4200000000000000000000000000000000000006")
+        );
+    }
+
+    #[test]
+    fn test_safe_tokens_follow_the_chain() {
+        let mainnet = SafeTokens::from_chain(&ChainConfig::new(1).unwrap());
+        let arbitrum = SafeTokens::from_chain(&ChainConfig::new(42161).unwrap());
+        assert_eq!(
+            mainnet.usdc,
+            address("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48")
+        );
+        assert_eq!(
+            arbitrum.usdc,
+            address("0xaf88d065e77c8cC2239327C5EDb3A432268e5831")
+        );
+        assert!(!mainnet.contains(&arbitrum.usdc));
+        assert!(!arbitrum.contains(&mainnet.usdc));
+    }
+
+    #[test]
+    fn test_parse_factories() {
+        let factories = parse_factories(
+            "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f:v2:10000835, 0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac:V2:10794229",
+        )
+        .unwrap();
+        assert_eq!(factories.len(), 2);
+        assert_eq!(
+            factories[0].0,
+            address("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f")
+        );
+        assert!(matches!(factories[0].1, DexVariant::UniswapV2));
+        assert_eq!(factories[0].2, 10000835);
+        assert_eq!(factories[1].2, 10794229);
+
+        // empty entries are skipped, an empty string keeps the chain's defaults
+        assert!(parse_factories("").unwrap().is_empty());
+        assert_eq!(
+            parse_factories("0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f:v2:10000835,")
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_parse_factories_rejects_invalid_entries() {
+        // V3 pools can't be swapped through the simulator
+        let err =
+            parse_factories("0x1F98431c8aD98523631AE4a59f267346ea31F984:v3:12369621").unwrap_err();
+        assert!(err.to_string().contains("v3 factory"));
+
+        for raw in [
+            "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f:v2",
+            "0x5C69:v2:10000835",
+            "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f:curve:10000835",
+            "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f:v2:latest",
+        ] {
+            assert!(parse_factories(raw).is_err(), "{} should be rejected", raw);
+        }
+    }
+}
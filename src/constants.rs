@@ -1,12 +1,36 @@
+use cfmms::dex::DexVariant;
 use ethers::{
     prelude::Lazy,
-    types::{Address, Bytes, U256, U64},
+    signers::LocalWallet,
+    types::{Address, Bytes, H160, U256, U64},
+    utils::keccak256,
 };
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr};
+
+use crate::chain::parse_factories;
+use crate::trace::TraceMode;
 
 pub static WEI: Lazy<U256> = Lazy::new(|| U256::from(10).pow(U256::from(18)));
 pub static GWEI: Lazy<U256> = Lazy::new(|| U256::from(10).pow(U256::from(9)));
 
+// Uniswap V2's 0.3%, the only fee the simulator contract supports
+pub const DEFAULT_V2_FEE_BPS: u32 = 30;
+
+// Token amounts are handled for decimals up to this, 10^36 leaves plenty of room in a U256
+pub const MAX_DECIMALS: u8 = 36;
+
+// keccak256("Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)")
+pub static PERMIT_TYPEHASH: Lazy<[u8; 32]> = Lazy::new(|| {
+    keccak256("Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)")
+});
+
+// Anvil/Hardhat's first dev account, a throwaway key to sign permits in simulations
+pub static TEST_WALLET: Lazy<LocalWallet> = Lazy::new(|| {
+    "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+        .parse()
+        .unwrap()
+});
+
 pub static ZERO_ADDRESS: Lazy<Address> =
     Lazy::new(|| Address::from_str("0x0000000000000000000000000000000000000000").unwrap());
 
@@ -14,11 +38,67 @@ pub fn get_env(key: &str) -> String {
     std::env::var(key).unwrap()
 }
 
+pub fn get_env_or(key: &str, default: &str) -> String {
+    std::env::var(key).unwrap_or(String::from(default))
+}
+
 #[derive(Debug, Clone)]
 pub struct Env {
     pub https_url: String,
     pub wss_url: String,
+    // Comma separated endpoints tried in order after wss_url fails
+    pub fallback_wss_urls: Vec<String>,
     pub chain_id: U64,
+    // Max amount of safe token (in whole units) put into a single frontrun
+    pub sandwich_capital: u32,
+    // Min profit (in the target token's smallest unit) to publish an opportunity
+    pub min_profit: i128,
+    // Pending txs whose trace takes longer than this are skipped
+    pub trace_timeout_ms: u64,
+    // Tracer used on pending txs: prestate-diff, prestate or call,
+    // only prestate-diff can tell the swap direction needed for sandwiches
+    pub trace_mode: TraceMode,
+    // Pending txs declaring more gas than this are never traced or simulated
+    pub max_meat_tx_gas: u64,
+    // Pending txs waiting to be traced, the lowest scored are dropped beyond this
+    pub pending_queue_size: usize,
+    // Tip (wei per gas) paid on top of the base fee, used to check bundles net of gas
+    pub priority_fee: u64,
+    // (factory address, dex variant, creation block), empty uses the chain's defaults
+    pub factories: Vec<(H160, DexVariant, u64)>,
+    // Comma separated tokens used as safe tokens on top of WETH/USDT/USDC/DAI,
+    // each optionally followed by :amount, the honeypot test amount in whole units (10000 if omitted)
+    pub extra_safe_tokens: Vec<(H160, Option<u32>)>,
+    // Comma separated token:slot pairs used as balance slots instead of searching for them
+    pub balance_slot_overrides: HashMap<H160, u32>,
+    // Tokens csv (same format as the token cache) trusted without honeypot testing,
+    // filter_tokens is skipped when set
+    pub trusted_tokens_file: Option<String>,
+    // Seconds without a new block before the block stream is considered stalled
+    pub block_timeout_secs: u64,
+    // Address the control server listens on, only used with the `control` feature
+    pub control_addr: String,
+    // Triangular paths estimated below this profit (bps) from fetched reserves are never simulated,
+    // unset simulates every path
+    pub path_min_profit_bps: Option<u32>,
+    // WETH (in ETH) put into each backrun arbitrage after a pending tx moves a pool
+    pub arb_capital: u32,
+    // Pending txs traced at the same time
+    pub max_in_flight: usize,
+    // Pending txs taken from the queue per round, traced max_in_flight at a time
+    pub trace_batch_size: usize,
+    // Backrun arbitrage stops at the first hop slipping more than this (bps) below spot, unset never stops
+    pub max_hop_slippage_bps: Option<u32>,
+    // Fork state fetched over RPC is persisted here per block, unset keeps it in memory only
+    pub fork_cache_dir: Option<String>,
+    // Opportunities are appended to this csv when set, and flushed at least every ledger_flush_secs
+    pub ledger_file: Option<String>,
+    pub ledger_flush_secs: u64,
+    // Events buffered per subscriber, a slower subscriber loses the oldest ones
+    pub event_channel_capacity: usize,
+    // Top pending txs (by tip) fetched every block, the ones tipping more than a sandwich's victim
+    // are committed before its frontrun, 0 simulates on the clean fork
+    pub pending_block_txs: usize,
 }
 
 impl Env {
@@ -26,7 +106,81 @@ impl Env {
         Env {
             https_url: get_env("HTTPS_URL"),
             wss_url: get_env("WSS_URL"),
-            chain_id: U64::from_str(&get_env("CHAIN_ID")).unwrap(),
+            fallback_wss_urls: get_env_or("FALLBACK_WSS_URLS", "")
+                .split(',')
+                .filter(|url| !url.is_empty())
+                .map(|url| url.to_string())
+                .collect(),
+            // from_str reads hex, CHAIN_ID=56 would become 0x56
+            chain_id: U64::from_dec_str(&get_env("CHAIN_ID")).unwrap(),
+            sandwich_capital: get_env_or("SANDWICH_CAPITAL", "10000").parse().unwrap(),
+            min_profit: get_env_or("MIN_PROFIT", "0").parse().unwrap(),
+            trace_timeout_ms: get_env_or("TRACE_TIMEOUT_MS", "1000").parse().unwrap(),
+            trace_mode: get_env_or("TRACE_MODE", "prestate-diff")
+                .parse()
+                .unwrap_or_else(|e| panic!("Invalid TRACE_MODE: {}", e)),
+            max_meat_tx_gas: get_env_or("MAX_MEAT_TX_GAS", "2000000").parse().unwrap(),
+            pending_queue_size: get_env_or("PENDING_QUEUE_SIZE", "1000").parse().unwrap(),
+            priority_fee: get_env_or("PRIORITY_FEE", "1000000000").parse().unwrap(),
+            factories: parse_factories(&get_env_or("FACTORIES", ""))
+                .unwrap_or_else(|e| panic!("Invalid FACTORIES: {}", e)),
+            extra_safe_tokens: get_env_or("EXTRA_SAFE_TOKENS", "")
+                .split(',')
+                .map(|entry| entry.trim())
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| {
+                    let (token, amount) = match entry.split_once(':') {
+                        Some((token, amount)) => (token, Some(amount)),
+                        None => (entry, None),
+                    };
+                    let token = H160::from_str(token)
+                        .unwrap_or_else(|e| panic!("Invalid EXTRA_SAFE_TOKENS {:?}: {}", entry, e));
+                    let amount = amount.map(|amount| {
+                        amount.parse().unwrap_or_else(|e| {
+                            panic!("Invalid EXTRA_SAFE_TOKENS {:?}: {}", entry, e)
+                        })
+                    });
+                    (token, amount)
+                })
+                .collect(),
+            balance_slot_overrides: get_env_or("BALANCE_SLOT_OVERRIDES", "")
+                .split(',')
+                .map(|entry| entry.trim())
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| {
+                    entry
+                        .split_once(':')
+                        .and_then(|(token, slot)| {
+                            Some((H160::from_str(token).ok()?, slot.parse().ok()?))
+                        })
+                        .unwrap_or_else(|| panic!("Invalid BALANCE_SLOT_OVERRIDES {:?}", entry))
+                })
+                .collect(),
+            trusted_tokens_file: std::env::var("TRUSTED_TOKENS_FILE")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            block_timeout_secs: get_env_or("BLOCK_TIMEOUT_SECS", "30").parse().unwrap(),
+            control_addr: get_env_or("CONTROL_ADDR", "127.0.0.1:9090"),
+            path_min_profit_bps: std::env::var("PATH_MIN_PROFIT_BPS")
+                .ok()
+                .filter(|bps| !bps.is_empty())
+                .map(|bps| bps.parse().unwrap()),
+            arb_capital: get_env_or("ARB_CAPITAL", "1").parse().unwrap(),
+            max_in_flight: get_env_or("MAX_IN_FLIGHT", "8").parse().unwrap(),
+            trace_batch_size: get_env_or("TRACE_BATCH_SIZE", "32").parse().unwrap(),
+            max_hop_slippage_bps: std::env::var("MAX_HOP_SLIPPAGE_BPS")
+                .ok()
+                .filter(|bps| !bps.is_empty())
+                .map(|bps| bps.parse().unwrap()),
+            fork_cache_dir: std::env::var("FORK_CACHE_DIR")
+                .ok()
+                .filter(|dir| !dir.is_empty()),
+            ledger_file: std::env::var("LEDGER_FILE")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            ledger_flush_secs: get_env_or("LEDGER_FLUSH_SECS", "10").parse().unwrap(),
+            event_channel_capacity: get_env_or("EVENT_CHANNEL_CAPACITY", "512").parse().unwrap(),
+            pending_block_txs: get_env_or("PENDING_BLOCK_TXS", "0").parse().unwrap(),
         }
     }
 }
@@ -36,3 +190,23 @@ pub static SIMULATOR_CODE: Lazy<Bytes> = Lazy::new(|| {
         .parse()
         .unwrap()
 });
+
+// Runtime code of getAmountIn(uint256 amountOut, uint256 reserveIn, uint256 reserveOut, uint256 feeBps),
+// UniswapV2Library.getAmountIn with the fee as a parameter: reserveIn * amountOut * 10000 /
+// ((reserveOut - amountOut) * (10000 - feeBps)) + 1. Simulator.sol's code has no exact out entrypoint
+// Reverts without data on a zero amount or reserve, reserveOut <= amountOut, feeBps >= 10000
+// or reserves above uint112, which keeps the math from overflowing
+pub static AMOUNT_IN_CODE: Lazy<Bytes> = Lazy::new(|| {
+    "0x6084361060665760003560e01c63571fd012141560665760043560243560443560643583156066578215606657818410156066576127108110156066578260701c6066578160701c6066576127100383820302838302612710020460010160005260206000f35b600080fd"
+        .parse()
+        .unwrap()
+});
+
+// Runtime code that forwards a call, so msg.sender can differ from tx.origin
+// calldata: 20 byte target address followed by the call's calldata, callvalue is forwarded
+// and the target's return/revert data is passed through as is
+pub static FORWARDER_CODE: Lazy<Bytes> = Lazy::new(|| {
+    "0x60143603806014600037600060008260003460003560601c5af13d600060003e6027573d6000fd5b3d6000f3"
+        .parse()
+        .unwrap()
+});
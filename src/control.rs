@@ -0,0 +1,264 @@
+use ethers::prelude::Lazy;
+use ethers::types::U64;
+use serde_json::{json, Value};
+use std::sync::RwLock;
+
+#[cfg(feature = "control")]
+use anyhow::Result;
+#[cfg(feature = "control")]
+use log::{info, warn};
+#[cfg(feature = "control")]
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// Runtime state shared with the control server, the event handler reports into it
+// and reads the adjustable parameters back from it
+pub static CONTROL: Lazy<RwLock<ControlState>> = Lazy::new(|| RwLock::new(ControlState::default()));
+
+#[derive(Default, Debug, Clone)]
+pub struct ControlState {
+    pub verified_pools: usize,
+    pub tested_tokens: usize,
+    pub honeypots: usize,
+    pub last_block: U64,
+    // adjustable at runtime, the tax criteria apply to the tokens tested after the change,
+    // tokens that were already verified or flagged are not tested again
+    pub max_tax_escalation_bps: u32,
+    pub min_sell_fraction_bps: u32,
+    pub min_profit: i128,
+}
+
+impl ControlState {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "verified_pools": self.verified_pools,
+            "honeypot": {
+                "tested_tokens": self.tested_tokens,
+                "honeypots": self.honeypots,
+                "max_tax_escalation_bps": self.max_tax_escalation_bps,
+                "min_sell_fraction_bps": self.min_sell_fraction_bps,
+            },
+            "last_block": self.last_block.as_u64(),
+            "params": {
+                "min_profit": self.min_profit.to_string(),
+            },
+        })
+    }
+
+    pub fn update(&mut self, params: &Value) -> Result<(), String> {
+        // Only the keys present are updated, min_profit is a string since it can exceed JSON numbers
+        // Nothing is applied when one of the keys is rejected
+        let params = params.as_object().ok_or("expected a JSON object")?;
+        let bps = |key: &str, value: &Value| {
+            value
+                .as_u64()
+                .filter(|bps| *bps <= 10000)
+                .map(|bps| bps as u32)
+                .ok_or(format!("invalid {}", key))
+        };
+        let mut updated = self.clone();
+        for (key, value) in params {
+            match key.as_str() {
+                "max_tax_escalation_bps" => {
+                    updated.max_tax_escalation_bps = bps(key.as_str(), value)?
+                }
+                "min_sell_fraction_bps" => {
+                    updated.min_sell_fraction_bps = bps(key.as_str(), value)?
+                }
+                "min_profit" => {
+                    updated.min_profit = match value {
+                        Value::String(s) => s.parse().map_err(|_| "invalid min_profit")?,
+                        Value::Number(n) => n.as_i64().ok_or("invalid min_profit")? as i128,
+                        _ => return Err("invalid min_profit".to_string()),
+                    }
+                }
+                _ => return Err(format!("unknown parameter: {}", key)),
+            }
+        }
+        *self = updated;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "control")]
+pub async fn serve_control(addr: String) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Control server listening on {}", addr);
+    serve_listener(listener).await
+}
+
+#[cfg(feature = "control")]
+pub async fn serve_listener(listener: TcpListener) -> Result<()> {
+    // GET /status returns ControlState::to_json, POST /params takes a JSON object of params
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream).await {
+                warn!("Control request failed: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(feature = "control")]
+async fn handle_connection(mut stream: TcpStream) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    // read the headers, then the body up to Content-Length
+    let (header_end, content_length) = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let headers = String::from_utf8_lossy(&buf[..pos]).to_string();
+            let content_length = headers
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            break (pos + 4, content_length);
+        }
+        if buf.len() > 64 * 1024 {
+            return Err(anyhow::anyhow!("Request headers too large"));
+        }
+    };
+    while buf.len() < header_end + content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(&buf[..header_end])
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let body = &buf[header_end..std::cmp::min(buf.len(), header_end + content_length)];
+
+    let (status, response) = match (method, path) {
+        ("GET", "/status") => ("200 OK", CONTROL.read().unwrap().to_json()),
+        ("POST", "/params") => match serde_json::from_slice::<Value>(body) {
+            Ok(params) => {
+                let mut state = CONTROL.write().unwrap();
+                match state.update(&params) {
+                    Ok(_) => ("200 OK", state.to_json()),
+                    Err(e) => ("400 Bad Request", json!({ "error": e })),
+                }
+            }
+            Err(e) => ("400 Bad Request", json!({ "error": e.to_string() })),
+        },
+        _ => ("404 Not Found", json!({ "error": "not found" })),
+    };
+
+    let body = response.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_min_profit() {
+        let mut state = ControlState::default();
+        state
+            .update(&json!({ "min_profit": "1000000000000000000000" }))
+            .unwrap();
+        assert_eq!(state.min_profit, 10i128.pow(21));
+        assert_eq!(
+            state.to_json()["params"]["min_profit"],
+            "1000000000000000000000"
+        );
+    }
+
+    #[test]
+    fn test_update_tax_criteria() {
+        let mut state = ControlState::default();
+        state.min_sell_fraction_bps = 100;
+        state
+            .update(&json!({ "max_tax_escalation_bps": 250, "min_sell_fraction_bps": 50 }))
+            .unwrap();
+        assert_eq!(state.max_tax_escalation_bps, 250);
+        assert_eq!(state.min_sell_fraction_bps, 50);
+        assert_eq!(state.to_json()["honeypot"]["min_sell_fraction_bps"], 50);
+
+        // nothing is applied when one of the keys is rejected
+        for params in [
+            json!({ "min_profit": 5, "min_sell_fraction_bps": 10001 }),
+            json!({ "min_profit": 5, "max_tax_escalation_bps": "100" }),
+            json!({ "min_profit": 5, "max_sell_tax_bps": 100 }),
+        ] {
+            assert!(state.update(&params).is_err());
+        }
+        assert_eq!(state.min_profit, 0);
+        assert_eq!(state.min_sell_fraction_bps, 50);
+    }
+
+    #[cfg(feature = "control")]
+    #[tokio::test]
+    async fn test_status_and_params_endpoints() {
+        async fn request(addr: std::net::SocketAddr, request: String) -> (String, Value) {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let status = head.lines().next().unwrap().to_string();
+            (status, serde_json::from_str(body).unwrap())
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener));
+        {
+            let mut control = CONTROL.write().unwrap();
+            control.verified_pools = 12;
+            control.honeypots = 3;
+            control.last_block = U64::from(18000000);
+        }
+
+        let (status, json) = request(addr, "GET /status HTTP/1.1\r\n\r\n".to_string()).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(json["verified_pools"], 12);
+        assert_eq!(json["last_block"], 18000000);
+        assert_eq!(json["honeypot"]["honeypots"], 3);
+        assert!(json["honeypot"]["tested_tokens"].is_u64());
+        assert!(json["honeypot"]["max_tax_escalation_bps"].is_u64());
+        assert!(json["honeypot"]["min_sell_fraction_bps"].is_u64());
+        assert!(json["params"]["min_profit"].is_string());
+
+        let body = r#"{"max_tax_escalation_bps":300}"#;
+        let (status, json) = request(
+            addr,
+            format!(
+                "POST /params HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(json["honeypot"]["max_tax_escalation_bps"], 300);
+        assert_eq!(CONTROL.read().unwrap().max_tax_escalation_bps, 300);
+
+        let (status, _) = request(addr, "GET /missing HTTP/1.1\r\n\r\n".to_string()).await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+}
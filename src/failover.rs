@@ -0,0 +1,132 @@
+use async_trait::async_trait;
+use ethers::providers::{JsonRpcClient, JsonRpcError, Provider, ProviderError, RpcError, Ws};
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt, time::Duration};
+
+// Routes every request to the first client that answers in time, in the given order
+// Wrapped in a Provider, this is a Middleware that can be passed anywhere a provider is used
+pub type FailoverProvider<P> = Provider<FailoverClient<P>>;
+
+#[derive(Debug, Clone)]
+pub struct FailoverClient<P> {
+    pub clients: Vec<P>,
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct FailoverError {
+    // one entry per client that was tried
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for FailoverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "all providers failed: {:?}", self.errors)
+    }
+}
+
+impl std::error::Error for FailoverError {}
+
+impl RpcError for FailoverError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        None
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        None
+    }
+}
+
+impl From<FailoverError> for ProviderError {
+    fn from(e: FailoverError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(e))
+    }
+}
+
+impl<P: JsonRpcClient> FailoverClient<P> {
+    pub fn new(clients: Vec<P>, timeout: Duration) -> Self {
+        Self { clients, timeout }
+    }
+}
+
+#[async_trait]
+impl<P: JsonRpcClient> JsonRpcClient for FailoverClient<P> {
+    type Error = FailoverError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let mut errors = Vec::new();
+        for client in &self.clients {
+            match tokio::time::timeout(self.timeout, client.request(method, &params)).await {
+                Ok(Ok(res)) => return Ok(res),
+                Ok(Err(e)) => errors.push(format!("{:?}", e)),
+                Err(_) => errors.push(format!("{} timed out after {:?}", method, self.timeout)),
+            }
+        }
+        Err(FailoverError { errors })
+    }
+}
+
+pub async fn connect_failover(
+    urls: Vec<String>,
+    timeout: Duration,
+) -> anyhow::Result<FailoverProvider<Ws>> {
+    // Endpoints that can't be reached at startup are skipped
+    let mut clients = Vec::new();
+    let mut errors = Vec::new();
+    for url in urls {
+        if let Err(e) = Ws::connect(&url).await.map(|ws| clients.push(ws)) {
+            warn!("Could not connect to {}: {:?}", url, e);
+            errors.push(format!("{}: {:?}", url, e));
+        }
+    }
+    if clients.is_empty() {
+        return Err(anyhow::anyhow!(
+            "could not connect to any provider: {:?}",
+            errors
+        ));
+    }
+    Ok(Provider::new(FailoverClient::new(clients, timeout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{Middleware, MockProvider};
+    use ethers::types::U64;
+
+    #[tokio::test]
+    async fn test_fails_over_to_the_next_client() {
+        // the primary has no responses queued, so every request to it errors
+        let primary = MockProvider::new();
+        let secondary = MockProvider::new();
+        secondary.push(U64::from(18000000)).unwrap();
+        let provider = Provider::new(FailoverClient::new(
+            vec![primary.clone(), secondary.clone()],
+            Duration::from_secs(1),
+        ));
+
+        let block_number = provider.get_block_number().await.unwrap();
+        assert_eq!(block_number, U64::from(18000000));
+        // both were asked, in order
+        primary.assert_request("eth_blockNumber", ()).unwrap();
+        secondary.assert_request("eth_blockNumber", ()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reports_every_client_error() {
+        let client = FailoverClient::new(
+            vec![MockProvider::new(), MockProvider::new()],
+            Duration::from_secs(1),
+        );
+        let err = client
+            .request::<_, U64>("eth_blockNumber", ())
+            .await
+            .unwrap_err();
+        assert_eq!(err.errors.len(), 2);
+    }
+}
@@ -1,11 +1,24 @@
-use ethers::types::{Block, BlockId, BlockNumber, H160, H256, U256, U64};
+use anyhow::{anyhow, Result};
+use ethers::types::{Block, BlockId, BlockNumber, H160, H256, I256, U256, U64};
+use ethers::utils::get_contract_address;
 use ethers_providers::Middleware;
 use log::info;
-use std::{collections::HashMap, path::Path, str::FromStr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::pools::Pool;
-use crate::simulator::EvmSimulator;
-use crate::tokens::{get_implementation, get_token_info, Token};
+use crate::chain::ChainConfig;
+use crate::constants::ZERO_ADDRESS;
+use crate::pools::{DexVariant, Pool};
+use crate::simulator::{EvmSimulator, SimError, SimulatorConfig};
+use crate::streams::BlockContext;
+use crate::tokens::{
+    get_implementation, get_token_info, load_address_list, load_tokens_csv, save_tokens_csv, Token,
+};
 use crate::trace::EvmTracer;
 
 #[derive(Debug, Clone)]
@@ -14,56 +27,239 @@ pub struct SafeTokens {
     pub usdt: H160,
     pub usdc: H160,
     pub dai: H160,
+    // Any other tokens to anchor on, ex) FRAX, LUSD or a chain-native stable
+    pub extra: Vec<H160>,
 }
 
 impl SafeTokens {
     pub fn new() -> Self {
+        Self::from_chain(&ChainConfig::mainnet())
+    }
+
+    pub fn from_chain(chain: &ChainConfig) -> Self {
         Self {
-            usdt: H160::from_str("0xdAC17F958D2ee523a2206206994597C13D831ec7").unwrap(),
-            weth: H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap(),
-            usdc: H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap(),
-            dai: H160::from_str("0x6B175474E89094C44Da98b954EedeAC495271d0F").unwrap(),
+            usdt: chain.usdt,
+            weth: chain.wrapped_native,
+            usdc: chain.usdc,
+            dai: chain.dai,
+            extra: Vec::new(),
+        }
+    }
+
+    pub fn all(&self) -> Vec<H160> {
+        let mut tokens = vec![self.usdt, self.weth, self.usdc, self.dai];
+        for token in &self.extra {
+            if !tokens.contains(token) {
+                tokens.push(*token);
+            }
         }
+        tokens
     }
+
+    pub fn contains(&self, token: &H160) -> bool {
+        self.all().contains(token)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HoneypotResult {
+    pub token: H160,
+    pub pool: H160,
+    pub is_honeypot: bool,
+    // taxes in bps
+    pub buy_tax: u32,
+    pub sell_tax: u32,
+    // Largest amount of token that could be sold, None if no cap was hit
+    pub max_sell: Option<U256>,
+    // Sell taxes (bps) sampled at increasing sizes, smallest first
+    pub sell_taxes: Vec<u32>,
+    // Balances change without transfers, ex) AMPL
+    pub is_rebasing: bool,
+    // The sell only goes through some time after the buy. These tokens are still flagged as honeypots,
+    // sandwiches and backruns sell in the buy's block, this only tells them apart from hard honeypots
+    pub has_cooldown: bool,
+    // approve reverts, returns false or leaves the allowance at 0, so routers can't sell it
+    pub blocks_approve: bool,
 }
 
 pub struct HoneypotFilter<M> {
     pub simulator: EvmSimulator<M>,
+    pub chain: ChainConfig,
     pub safe_tokens: SafeTokens,
     pub token_info: HashMap<H160, Token>,
     pub safe_token_info: HashMap<H160, Token>,
     pub balance_slots: HashMap<H160, u32>,
-    pub honeypot: HashMap<H160, bool>,
+    // Known balance slots used instead of auto-detection, for tokens the tracer can't handle
+    pub balance_slot_overrides: HashMap<H160, u32>,
+    // honeypot token -> unix timestamp of when it was flagged
+    pub honeypot: HashMap<H160, u64>,
+    // Cached honeypots older than this are dropped and tested again
+    pub honeypot_expiry_days: u64,
+    // Pools must hold at least this many times the test amount of safe token to be tested
+    pub min_reserve_multiplier: u32,
+    // Test swaps use this fraction (bps) of the pool's safe token reserve, capped at test_amount,
+    // and never less than min_test_amount_bps of test_amount
+    pub test_amount_fraction_bps: u32,
+    pub min_test_amount_bps: u32,
+    // Trusted tokens skip honeypot testing, blacklisted tokens are always dropped
+    pub whitelist: HashSet<H160>,
+    pub blacklist: HashSet<H160>,
+    // Tokens that can't sell at least this fraction (bps) of the pool's reserve are flagged
+    pub min_sell_fraction_bps: u32,
+    // Upper bound of the storage slots scanned for balance mappings
+    pub max_balance_slot: u32,
+    // Tokens whose sell tax rises with size, by more than this (bps) from the smallest sampled size
+    // to the largest, are flagged
+    pub max_tax_escalation_bps: u32,
+    // name/symbol/decimals of every token fetched so far, these never change
+    pub token_info_cache: HashMap<H160, Token>,
+    // Measured (buy tax, sell tax) in bps of every token that passed
+    pub token_taxes: HashMap<H160, (u32, u32)>,
+    // Sells failing in the buy's block are retried this many seconds later to detect cooldowns
+    pub cooldown_test_secs: u64,
+    // Bought tokens are approved to this address (the chain's V2 router) to catch approval blocking
+    pub approve_spender: H160,
+    // Test amount (whole units) of extra safe tokens, the ones without are sized like USDC/USDT
+    pub extra_test_amounts: HashMap<H160, u32>,
 }
 
 impl<M: Middleware + 'static> HoneypotFilter<M> {
-    pub fn new(provider: Arc<M>, block: Block<H256>) -> Self {
+    pub fn new(provider: Arc<M>, block: Block<H256>, config: SimulatorConfig) -> Self {
         let owner = H160::from_str("0x001a06BF8cE4afdb3f5618f6bafe35e9Fc09F187").unwrap();
-        let simulator = EvmSimulator::new(provider.clone(), owner, block.number.unwrap());
-        let safe_tokens = SafeTokens::new();
+        let mut simulator =
+            EvmSimulator::new_with_config(provider.clone(), owner, block.number.unwrap(), config);
+        simulator.set_block_context(&BlockContext::from_block(&block));
+        let chain = simulator.config.chain.clone();
+        let approve_spender = chain.router;
+        let safe_tokens = SafeTokens::from_chain(&chain);
         let token_info = HashMap::new();
         let safe_token_info = HashMap::new();
         let balance_slots = HashMap::new();
         let honeypot = HashMap::new();
         Self {
             simulator,
+            chain,
             safe_tokens,
             token_info,
             safe_token_info,
             balance_slots,
+            balance_slot_overrides: HashMap::new(),
             honeypot,
+            honeypot_expiry_days: 7,
+            min_reserve_multiplier: 2,
+            test_amount_fraction_bps: 100,
+            min_test_amount_bps: 100,
+            whitelist: HashSet::new(),
+            blacklist: HashSet::new(),
+            min_sell_fraction_bps: 100,
+            max_balance_slot: 20,
+            max_tax_escalation_bps: 100,
+            token_info_cache: HashMap::new(),
+            token_taxes: HashMap::new(),
+            cooldown_test_secs: 600,
+            approve_spender,
+            extra_test_amounts: HashMap::new(),
+        }
+    }
+
+    pub fn load_lists(&mut self) {
+        // Both files are optional: one address per line
+        let whitelist_file_path = Path::new("src/.whitelist.txt");
+        let blacklist_file_path = Path::new("src/.blacklist.txt");
+
+        self.whitelist = load_address_list(whitelist_file_path).unwrap();
+        self.blacklist = load_address_list(blacklist_file_path).unwrap();
+        info!(
+            "✔️ Loaded {:?} whitelisted / {:?} blacklisted tokens",
+            self.whitelist.len(),
+            self.blacklist.len()
+        );
+    }
+
+    pub async fn get_token_info_cached(&mut self, token: H160) -> Result<Token> {
+        if let Some(info) = self
+            .safe_token_info
+            .get(&token)
+            .or(self.token_info.get(&token))
+            .or(self.token_info_cache.get(&token))
+        {
+            return Ok(info.clone());
+        }
+        let info = get_token_info(self.simulator.provider.clone(), token).await?;
+        self.token_info_cache.insert(token, info.clone());
+        Ok(info)
+    }
+
+    pub fn is_verified(&self, token: &H160) -> bool {
+        if self.blacklist.contains(token) {
+            return false;
         }
+        self.whitelist.contains(token)
+            || self.safe_token_info.contains_key(token)
+            || self.token_info.contains_key(token)
+    }
+
+    pub fn needs_test(&self, token: &H160) -> bool {
+        // Tokens already tested are skipped, and listed tokens are never tested:
+        // whitelisted ones are trusted as they are, blacklisted ones are dropped anyway
+        !(self.token_info.contains_key(token)
+            || self.honeypot.contains_key(token)
+            || self.whitelist.contains(token)
+            || self.blacklist.contains(token))
     }
 
-    pub async fn setup(&mut self) {
+    pub fn is_verified_pool(&self, pool: &Pool) -> bool {
+        self.is_verified(&pool.token0) && self.is_verified(&pool.token1)
+    }
+
+    pub fn trust_tokens(&mut self, tokens: Vec<Token>) {
+        // Trusted tokens are verified as they are, for curated pool sets filter_tokens can be skipped
+        for token in tokens {
+            self.token_info_cache.insert(token.address, token.clone());
+            self.token_info.insert(token.address, token);
+        }
+    }
+
+    pub fn add_safe_token(&mut self, token: H160, test_amount: Option<u32>) {
+        // Call before setup, so that its balance slot and token info are loaded with the rest
+        if !self.safe_tokens.contains(&token) {
+            self.safe_tokens.extra.push(token);
+            if let Some(test_amount) = test_amount {
+                self.extra_test_amounts.insert(token, test_amount);
+            }
+        }
+    }
+
+    pub fn balance_account(&self, token: H160) -> Option<H160> {
+        // Contract holding token's balances, None when the token keeps them itself
+        self.simulator.balance_accounts.get(&token).copied()
+    }
+
+    pub fn set_balance_slot_overrides(&mut self, balance_slots: HashMap<H160, u32>) {
+        self.simulator.set_balance_slots(balance_slots.clone());
+        self.balance_slots.extend(balance_slots.clone());
+        self.balance_slot_overrides.extend(balance_slots);
+    }
+
+    pub async fn setup(&mut self) -> Result<()> {
         // Get safe_token_info using the four following tokens that are widely used as safe tokens
         let provider = &self.simulator.provider;
         let owner = self.simulator.owner;
         let block_number = &self.simulator.block_number;
 
-        let tracer = EvmTracer::new(provider.clone());
+        let mut tracer = EvmTracer::new(provider.clone());
+        tracer.max_balance_slot = self.max_balance_slot;
 
+        // the chain comes from the config, a provider on another chain would test the wrong tokens
         let chain_id = provider.get_chainid().await.unwrap();
+        if chain_id.as_u64() != self.chain.chain_id {
+            return Err(anyhow!(
+                "Provider is on chain {}, the config is for chain {}",
+                chain_id,
+                self.chain.chain_id
+            ));
+        }
+
         let nonce = self
             .simulator
             .provider
@@ -74,16 +270,27 @@ impl<M: Middleware + 'static> HoneypotFilter<M> {
             .await
             .unwrap();
 
-        for token in vec![
-            self.safe_tokens.usdt,
-            self.safe_tokens.weth,
-            self.safe_tokens.usdc,
-            self.safe_tokens.dai,
-        ] {
+        for token in self.safe_tokens.all() {
             if !self.safe_token_info.contains_key(&token) {
+                // Look up the implementation first so proxied balances can be located
+                let implementation = get_implementation(provider.clone(), token, *block_number)
+                    .await
+                    .unwrap_or(None);
+
+                if let Some(slot) = self.balance_slot_overrides.get(&token) {
+                    self.balance_slots.insert(token, *slot);
+                    let mut info = get_token_info(provider.clone(), token).await.unwrap();
+                    info!("{} ({:?}): {:?} (override)", info.name, token, slot);
+                    info.add_implementation(implementation);
+                    self.token_info_cache.insert(token, info.clone());
+                    self.safe_token_info.insert(token, info);
+                    continue;
+                }
+
                 match tracer
                     .find_balance_slot(
                         token,
+                        implementation,
                         owner,
                         nonce,
                         U64::from(chain_id.as_u64()),
@@ -93,13 +300,14 @@ impl<M: Middleware + 'static> HoneypotFilter<M> {
                 {
                     Ok(slot) => {
                         if slot.0 {
-                            self.balance_slots.insert(token, slot.1);
-                            let mut info = get_token_info(provider.clone(), token).await.unwrap();
-                            info!("{} ({:?}): {:?}", info.name, token, slot.1);
-                            match get_implementation(provider.clone(), token, *block_number).await {
-                                Ok(implementation) => info.add_implementation(implementation),
-                                Err(_) => {}
+                            self.balance_slots.insert(token, slot.2);
+                            if slot.1 != token {
+                                self.simulator.balance_accounts.insert(token, slot.1);
                             }
+                            let mut info = get_token_info(provider.clone(), token).await.unwrap();
+                            info!("{} ({:?}): {:?} @ {:?}", info.name, token, slot.2, slot.1);
+                            info.add_implementation(implementation);
+                            self.token_info_cache.insert(token, info.clone());
                             self.safe_token_info.insert(token, info);
                         }
                     }
@@ -107,31 +315,24 @@ impl<M: Middleware + 'static> HoneypotFilter<M> {
                 }
             }
         }
+        Ok(())
     }
 
     pub async fn filter_tokens(&mut self, pools: &Vec<Pool>) {
+        self.load_lists();
+
         // load cached
         let token_file_path = Path::new("src/.cached-tokens.csv");
         let honeypot_file_path = Path::new("src/.cached-honeypot.csv");
 
-        if token_file_path.exists() {
-            let mut reader = csv::Reader::from_path(token_file_path).unwrap();
-            for row in reader.records() {
-                let row = row.unwrap();
-                let token = Token::from(row);
-                self.token_info.insert(token.address, token);
-            }
+        for token in load_tokens_csv(token_file_path).unwrap() {
+            self.token_info.insert(token.address, token);
         }
         info!("✔️ Loaded {:?} token info from cache", self.token_info.len());
 
-        if honeypot_file_path.exists() {
-            let mut reader = csv::Reader::from_path(honeypot_file_path).unwrap();
-            for row in reader.records() {
-                let row = row.unwrap();
-                let honeypot_address = H160::from_str(row.get(0).unwrap()).unwrap();
-                self.honeypot.insert(honeypot_address, true);
-            }
-        }
+        let expiry = self.honeypot_expiry_days * 24 * 60 * 60;
+        self.honeypot
+            .extend(load_honeypot_csv(honeypot_file_path, expiry).unwrap());
         info!(
             "✔️ Loaded {:?} honeypot info from cache",
             self.honeypot.len()
@@ -144,6 +345,7 @@ impl<M: Middleware + 'static> HoneypotFilter<M> {
             let token1_is_safe = self.safe_token_info.contains_key(&pool.token1);
 
             if token0_is_safe && token1_is_safe {
+                // nothing to test, safe/safe pools are verified as they are
                 continue;
             }
 
@@ -155,116 +357,1557 @@ impl<M: Middleware + 'static> HoneypotFilter<M> {
                     (pool.token1, pool.token0)
                 };
 
-                if self.token_info.contains_key(&test_token)
-                    || self.honeypot.contains_key(&test_token)
+                if !self.needs_test(&test_token) {
+                    continue;
+                }
+
+                // Skip pools that can't absorb the test swap before spending simulations on them,
+                // these would revert and be wrongly flagged as honeypots
+                if !self.is_liquid(pool, safe_token) {
+                    continue;
+                }
+
+                info!("✅ [{}] {:?} -> {:?}", idx, safe_token, test_token);
+                self.test_pool(pool, safe_token, test_token).await;
+            }
+        }
+
+        // cache to csv files
+        save_tokens_csv(token_file_path, self.token_info.values()).unwrap();
+
+        save_honeypot_csv(honeypot_file_path, &self.honeypot).unwrap();
+    }
+
+    pub async fn check_token(&mut self, token: H160, pool: Option<Pool>) -> Result<HoneypotResult> {
+        self.simulator.deploy_simulator();
+
+        let (pool, safe_token) = match pool {
+            Some(pool) => {
+                let safe_token = pool.token_out(token).ok_or(anyhow!(
+                    "{:?} is not traded in pool {:?}",
+                    token,
+                    pool.address
+                ))?;
+                if !self.safe_token_info.contains_key(&safe_token) {
+                    return Err(anyhow!(
+                        "{:?} is not paired with a safe token",
+                        pool.address
+                    ));
+                }
+                (pool, safe_token)
+            }
+            None => self.find_safe_pool(token).await?,
+        };
+
+        Ok(self.test_pool(&pool, safe_token, token).await)
+    }
+
+    pub async fn find_safe_pool(&mut self, token: H160) -> Result<(Pool, H160)> {
+        // Look up every (safe token, factory) pair and keep the pool that is deepest
+        // relative to the amount we'd use to test it
+        let safe_tokens: Vec<H160> = self.safe_token_info.keys().cloned().collect();
+        let mut best: Option<(H160, H160, U256)> = None;
+
+        for safe_token in safe_tokens {
+            for (factory, _, _) in self.chain.factories.clone() {
+                let pair = match self
+                    .simulator
+                    .v2_factory_get_pair(factory, token, safe_token)
                 {
-                    // skip if test_tokens was already tested
+                    Ok(pair) => pair,
+                    Err(_) => continue,
+                };
+                if pair == *ZERO_ADDRESS {
                     continue;
                 }
 
-                // We take extra measures to filter out the pools with too little liquidity
-                // Using the below amount to test swaps, we know that there's enough liquidity in the pool
-                let mut amount_in_u32 = 1;
-
-                if safe_token == self.safe_tokens.weth {
-                    amount_in_u32 = 20;
-                } else if safe_token == self.safe_tokens.usdt {
-                    amount_in_u32 = 10000;
-                } else if safe_token == self.safe_tokens.usdc {
-                    amount_in_u32 = 10000;
-                } else if safe_token == self.safe_tokens.dai {
-                    amount_in_u32 = 10000
+                let (token0, _) = if token < safe_token {
+                    (token, safe_token)
+                } else {
+                    (safe_token, token)
+                };
+                let reserves = match self.simulator.v2_pool_get_reserves(pair) {
+                    Ok(reserves) => reserves,
+                    Err(_) => continue,
+                };
+                let safe_reserve = if token0 == safe_token {
+                    reserves.0
+                } else {
+                    reserves.1
+                };
+
+                let decimals = self.safe_token_info.get(&safe_token).unwrap().decimals;
+                let test_amount = U256::from(self.test_amount(safe_token))
+                    * U256::from(10).pow(U256::from(decimals));
+                let depth = U256::from(safe_reserve) / test_amount;
+
+                match best {
+                    Some((_, _, best_depth)) if best_depth >= depth => {}
+                    _ => best = Some((pair, safe_token, depth)),
                 }
+            }
+        }
 
-                // seed the simulator with some safe token balance
-                let safe_token_info = self.safe_token_info.get(&safe_token).unwrap();
-                let safe_token_slot = self.balance_slots.get(&safe_token).unwrap();
+        let (address, safe_token, _) = best.ok_or(anyhow!("no safe token pool for {:?}", token))?;
+        let safe_decimals = self.safe_token_info.get(&safe_token).unwrap().decimals;
+        let token_decimals = self.get_token_info_cached(token).await?.decimals;
+        let (token0, token1, decimals0, decimals1) = if token < safe_token {
+            (token, safe_token, token_decimals, safe_decimals)
+        } else {
+            (safe_token, token, safe_decimals, token_decimals)
+        };
+
+        let pool = Pool {
+            address,
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0,
+            decimals1,
+            fee: 300,
+        };
+        Ok((pool, safe_token))
+    }
+
+    pub fn test_amount(&self, safe_token: H160) -> u32 {
+        // We take extra measures to filter out the pools with too little liquidity
+        // Using the below amount to test swaps, we know that there's enough liquidity in the pool
+        if safe_token == self.safe_tokens.weth {
+            20
+        } else if safe_token == self.safe_tokens.usdt {
+            10000
+        } else if safe_token == self.safe_tokens.usdc {
+            10000
+        } else if safe_token == self.safe_tokens.dai {
+            10000
+        } else {
+            // extra safe tokens are mostly stables (FRAX, LUSD...), 1 unit would barely test anything
+            *self.extra_test_amounts.get(&safe_token).unwrap_or(&10000)
+        }
+    }
+
+    pub fn adaptive_test_amount(&self, safe_token: H160, safe_reserve: U256) -> U256 {
+        // A fixed amount swamps thin pools, whose slippage then looks like a honeypot,
+        // so the test amount scales with the pool's safe token reserve
+        let decimals = self.safe_token_info.get(&safe_token).unwrap().decimals;
+        let max_amount =
+            U256::from(self.test_amount(safe_token)) * U256::from(10).pow(U256::from(decimals));
+        let min_amount = max_amount * U256::from(self.min_test_amount_bps) / U256::from(10000);
+        let amount = safe_reserve.saturating_mul(U256::from(self.test_amount_fraction_bps))
+            / U256::from(10000);
+        amount.clamp(min_amount, max_amount)
+    }
+
+    pub fn is_liquid(&mut self, pool: &Pool, safe_token: H160) -> bool {
+        // The pool has to hold min_reserve_multiplier times the test amount of safe token
+        match self.get_safe_reserve(pool, safe_token) {
+            Ok(safe_reserve) => {
+                let amount_in = self.adaptive_test_amount(safe_token, safe_reserve);
+                safe_reserve >= amount_in * U256::from(self.min_reserve_multiplier)
+            }
+            Err(_) => false,
+        }
+    }
 
-                self.simulator.set_token_balance(
-                    self.simulator.simulator_address,
+    pub fn get_safe_reserve(&mut self, pool: &Pool, safe_token: H160) -> Result<U256> {
+        let reserves = self.simulator.v2_pool_get_reserves(pool.address)?;
+        let safe_reserve = if pool.token0 == safe_token {
+            reserves.0
+        } else {
+            reserves.1
+        };
+        Ok(U256::from(safe_reserve))
+    }
+
+    pub async fn test_pool(
+        &mut self,
+        pool: &Pool,
+        safe_token: H160,
+        test_token: H160,
+    ) -> HoneypotResult {
+        let mut result = HoneypotResult {
+            token: test_token,
+            pool: pool.address,
+            is_honeypot: true,
+            buy_tax: 0,
+            sell_tax: 0,
+            max_sell: None,
+            sell_taxes: Vec::new(),
+            is_rebasing: false,
+            has_cooldown: false,
+            blocks_approve: false,
+        };
+
+        // seed the simulator with some safe token balance
+        let amount_in = match self.get_safe_reserve(pool, safe_token) {
+            Ok(safe_reserve) => self.adaptive_test_amount(safe_token, safe_reserve),
+            Err(_) => self.adaptive_test_amount(safe_token, U256::MAX),
+        };
+        let safe_token_slot = *self.balance_slots.get(&safe_token).unwrap();
+        self.simulator.set_token_balance_raw(
+            self.simulator.simulator_address,
+            safe_token,
+            safe_token_slot,
+            amount_in,
+        );
+
+        // Buy Test
+        let buy_output = self.simulator.v2_simulate_swap(
+            amount_in,
+            pool.address,
+            safe_token,
+            test_token,
+            pool.fee_bps(),
+            true,
+        );
+        let out = match buy_output {
+            Ok(out) => out,
+            Err(e) => {
+                info!("<BUY ERROR> {:?}", e);
+                self.honeypot.insert(test_token, unix_timestamp());
+                return result;
+            }
+        };
+        result.buy_tax = get_tax(out.0, out.1);
+
+        if out.0 == out.1 {
+            // Seeded balances are assumed to stay put, which rebasing tokens break
+            match self.test_rebasing(test_token) {
+                Ok(false) => {}
+                Ok(true) => {
+                    info!("<REBASING> {:?}", test_token);
+                    result.is_rebasing = true;
+                    self.honeypot.insert(test_token, unix_timestamp());
+                    return result;
+                }
+                // couldn't read the balance back, the sell tests still catch broken tokens
+                Err(e) => info!("<REBASING CHECK INCONCLUSIVE> {:?} {:?}", test_token, e),
+            }
+
+            // The simulator swaps against the pool directly, real sells go through a router's transferFrom
+            if let Err(e) = self.test_approve(test_token) {
+                info!("<APPROVE BLOCKED> {:?} {}", test_token, e);
+                result.blocks_approve = true;
+                self.honeypot.insert(test_token, unix_timestamp());
+                return result;
+            }
+
+            // Some tokens only let the buyer sell, so sell from another address before the real sell test
+            if let Err(e) = self.test_cross_address_sell(pool, safe_token, test_token, out.1) {
+                info!("<BUYER ONLY SELL> {:?} {:?}", test_token, e);
+                if self.test_cooldown(pool, safe_token, test_token, out.1) {
+                    info!("<SELL COOLDOWN> {:?}", test_token);
+                    result.has_cooldown = true;
+                }
+                self.honeypot.insert(test_token, unix_timestamp());
+                return result;
+            }
+
+            // Sell Test
+            let amount_in = out.1;
+            let sell_output = self.simulator.v2_simulate_swap(
+                amount_in,
+                pool.address,
+                test_token,
+                safe_token,
+                pool.fee_bps(),
+                true,
+            );
+            let out = match sell_output {
+                Ok(out) => out,
+                Err(e) => {
+                    info!("<SELL ERROR> {:?}", e);
+                    if self.test_cooldown(pool, safe_token, test_token, amount_in) {
+                        info!("<SELL COOLDOWN> {:?}", test_token);
+                        result.has_cooldown = true;
+                    }
+                    self.honeypot.insert(test_token, unix_timestamp());
+                    return result;
+                }
+            };
+            result.sell_tax = get_tax(out.0, out.1);
+
+            if out.0 == out.1 {
+                // Small test swaps pass max-tx limited tokens, so retry with larger sells
+                let (max_sell, max_sell_bps) = self.test_max_sell(pool, safe_token, test_token);
+                result.max_sell = max_sell;
+                if max_sell_bps < self.min_sell_fraction_bps {
+                    info!(
+                        "<MAX TX LIMIT> {:?} sells capped at {:?}",
+                        test_token, max_sell
+                    );
+                    self.honeypot.insert(test_token, unix_timestamp());
+                    return result;
+                }
+
+                // Dynamic taxes stay low for small sells and only kick in for larger ones
+                result.sell_taxes = self.test_sell_taxes(pool, safe_token, test_token);
+                let max_tax = result.sell_taxes.iter().max().copied().unwrap_or(0);
+                result.sell_tax = std::cmp::max(result.sell_tax, max_tax);
+                if is_escalating_tax(&result.sell_taxes, self.max_tax_escalation_bps) {
+                    info!(
+                        "<DYNAMIC TAX> {:?} sell taxes {:?}",
+                        test_token, result.sell_taxes
+                    );
+                    self.honeypot.insert(test_token, unix_timestamp());
+                    return result;
+                }
+
+                result.is_honeypot = false;
+                self.token_taxes
+                    .insert(test_token, (result.buy_tax, result.sell_tax));
+                match self.get_token_info_cached(test_token).await {
+                    Ok(info) => {
+                        info!(
+                            "Added safe token info ({}). Total: {:?} tokens",
+                            info.symbol,
+                            self.token_info.len()
+                        );
+                        self.token_info.insert(test_token, info);
+                    }
+                    Err(_) => {}
+                }
+            } else {
+                self.honeypot.insert(test_token, unix_timestamp());
+            }
+        } else {
+            self.honeypot.insert(test_token, unix_timestamp());
+        }
+
+        result
+    }
+
+    pub fn test_cross_address_sell(
+        &mut self,
+        pool: &Pool,
+        safe_token: H160,
+        test_token: H160,
+        amount: U256,
+    ) -> Result<(), SimError> {
+        // Moves the bought tokens to a second simulator contract and sells them from there
+        // The fork is restored afterwards, so the regular sell test still sees the bought tokens
+        let simulator_address = self.simulator.simulator_address;
+        // where the simulator would CREATE its first contract, nothing lives there on the fork
+        let second_seller = get_contract_address(simulator_address, U256::one());
+        let snapshot = self.simulator.db_snapshot();
+
+        self.simulator.deploy_simulator_at(second_seller);
+        let sold = self
+            .simulator
+            .token_transfer(test_token, simulator_address, second_seller, amount)
+            .and_then(|_| {
+                let balance = self
+                    .simulator
+                    .token_balance_of(test_token, second_seller)
+                    .map_err(|e| SimError::Decode(format!("{:?}", e)))?;
+                self.simulator.simulator_address = second_seller;
+                let sold = self.simulator.v2_simulate_swap(
+                    balance,
+                    pool.address,
+                    test_token,
                     safe_token,
-                    safe_token_info.decimals,
-                    *safe_token_slot,
-                    amount_in_u32,
+                    pool.fee_bps(),
+                    true,
                 );
+                self.simulator.simulator_address = simulator_address;
+                sold
+            });
 
-                info!(
-                    "✅ [{}] {} -> {:?}",
-                    idx, safe_token_info.symbol, test_token
-                );
+        self.simulator.inject_db(snapshot);
+        sold.map(|_| ())
+    }
+
+    pub fn test_approve(&mut self, test_token: H160) -> Result<(), String> {
+        // Approves approve_spender from the buyer and reads the allowance back
+        // The fork is restored afterwards
+        let simulator_address = self.simulator.simulator_address;
+        let spender = self.approve_spender;
+        let snapshot = self.simulator.db_snapshot();
+
+        let approved = match self
+            .simulator
+            .token_approve(test_token, simulator_address, spender)
+        {
+            Ok(true) => match self
+                .simulator
+                .token_allowance(test_token, simulator_address, spender)
+            {
+                Ok(allowance) if allowance.is_zero() => Err("allowance is still 0".to_string()),
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("allowance failed: {:?}", e)),
+            },
+            Ok(false) => Err("approve returned false".to_string()),
+            Err(e) => Err(format!("approve failed: {:?}", e)),
+        };
+
+        self.simulator.inject_db(snapshot);
+        approved
+    }
+
+    pub fn test_cooldown(
+        &mut self,
+        pool: &Pool,
+        safe_token: H160,
+        test_token: H160,
+        amount: U256,
+    ) -> bool {
+        // Called after a sell failed in the buy's block: sells again cooldown_test_secs later
+        // A sell that only works after the delay means the token enforces a cooldown
+        // The fork and env are restored afterwards
+        let snapshot = self.simulator.db_snapshot();
+        let number = self.simulator.evm.env.block.number;
+        let timestamp = self.simulator.evm.env.block.timestamp;
 
-                let amount_in = U256::from(amount_in_u32)
-                    .checked_mul(U256::from(10).pow(U256::from(safe_token_info.decimals)))
-                    .unwrap();
+        // cooldowns may count blocks instead of seconds, so both move at the chain's pace
+        let seconds = self.cooldown_test_secs;
+        let block_time_ms = std::cmp::max(self.chain.block_time.as_millis() as u64, 1);
+        let blocks = std::cmp::max(seconds * 1000 / block_time_ms, 1);
+        self.simulator.advance_block(blocks, seconds);
+        let sold = self.simulator.v2_simulate_swap(
+            amount,
+            pool.address,
+            test_token,
+            safe_token,
+            pool.fee_bps(),
+            true,
+        );
+
+        self.simulator.evm.env.block.number = number;
+        self.simulator.evm.env.block.timestamp = timestamp;
+        self.simulator.inject_db(snapshot);
+        sold.is_ok()
+    }
+
+    pub fn test_rebasing(&mut self, test_token: H160) -> Result<bool> {
+        // A balance that changes without any transfer means the token rebases, the bought balance is
+        // re-read after moving the env a day ahead, then after each rebase we can trigger:
+        // a public rebase(), and AMPL's rebase(epoch, supplyDelta) sent as its monetaryPolicy()
+        // Rebases run on a snapshot, one that reverts proves nothing and is skipped
+        // Limitation: tokens only rebased by an oracle-gated keeper we can't call as pass this test
+        let simulator_address = self.simulator.simulator_address;
+        let owner = self.simulator.owner;
+        let number = self.simulator.evm.env.block.number;
+        let timestamp = self.simulator.evm.env.block.timestamp;
+
+        let before = self
+            .simulator
+            .token_balance_of(test_token, simulator_address)?;
+        self.simulator.advance_block(7200, 86400);
+        let after = self
+            .simulator
+            .token_balance_of(test_token, simulator_address);
+
+        self.simulator.evm.env.block.number = number;
+        self.simulator.evm.env.block.timestamp = timestamp;
+        if after? != before {
+            return Ok(true);
+        }
 
-                // Buy Test
-                let buy_output = self.simulator.v2_simulate_swap(
+        let mut rebases = vec![(owner, self.simulator.token.rebase_input()?)];
+        if let Ok(policy) = self.simulator.token_monetary_policy(test_token) {
+            // a 10% supply increase
+            let supply = self.simulator.token_total_supply(test_token)?;
+            let calldata = self
+                .simulator
+                .token
+                .rebase_supply_input(U256::one(), I256::from_raw(supply / 10))?;
+            rebases.push((policy, calldata));
+        }
+        for (caller, calldata) in rebases {
+            let snapshot = self.simulator.db_snapshot();
+            let rebased =
+                self.simulator
+                    .call_as(owner, caller, test_token, calldata.0, U256::zero(), true);
+            let after = self
+                .simulator
+                .token_balance_of(test_token, simulator_address);
+            self.simulator.inject_db(snapshot);
+            if rebased.is_ok() && after? != before {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn test_sell_taxes(&mut self, pool: &Pool, safe_token: H160, test_token: H160) -> Vec<u32> {
+        // Buy then sell 0.1%, 1% and 5% of the pool's test token reserve, and record the sell tax of each
+        // Sizes that fail to sell are left out, test_max_sell already covers those
+        let stages = [10, 100, 500];
+        let safe_token_slot = *self.balance_slots.get(&safe_token).unwrap();
+        let simulator_address = self.simulator.simulator_address;
+
+        let reserves = match self.simulator.v2_pool_get_reserves(pool.address) {
+            Ok(reserves) => reserves,
+            Err(_) => return Vec::new(),
+        };
+        let (safe_reserve, test_reserve) = pool.ordered_reserves(reserves, safe_token);
+
+        let snapshot = self.simulator.db_snapshot();
+        let mut sell_taxes = Vec::new();
+
+        for bps in stages {
+            self.simulator.inject_db(snapshot.clone());
+
+            let amount_out = test_reserve * U256::from(bps) / U256::from(10000);
+            let amount_in = match self.simulator.get_amount_in(
+                amount_out,
+                safe_reserve,
+                test_reserve,
+                pool.fee_bps(),
+            ) {
+                Ok(amount_in) => amount_in,
+                Err(_) => break,
+            };
+            self.simulator.set_token_balance_raw(
+                simulator_address,
+                safe_token,
+                safe_token_slot,
+                amount_in,
+            );
+
+            let sold = self
+                .simulator
+                .v2_simulate_swap(
                     amount_in,
                     pool.address,
                     safe_token,
                     test_token,
+                    pool.fee_bps(),
                     true,
-                );
-                let out = match buy_output {
-                    Ok(out) => out,
-                    Err(e) => {
-                        info!("<BUY ERROR> {:?}", e);
-                        self.honeypot.insert(test_token, true);
-                        continue;
-                    }
-                };
-
-                if out.0 == out.1 {
-                    // Sell Test
-                    let amount_in = out.1;
-                    let sell_output = self.simulator.v2_simulate_swap(
-                        amount_in,
+                )
+                .and_then(|bought| {
+                    self.simulator.v2_simulate_swap(
+                        bought.1,
                         pool.address,
                         test_token,
                         safe_token,
+                        pool.fee_bps(),
                         true,
-                    );
-                    let out = match sell_output {
-                        Ok(out) => out,
-                        Err(e) => {
-                            info!("<SELL ERROR> {:?}", e);
-                            self.honeypot.insert(test_token, true);
-                            continue;
-                        }
-                    };
-
-                    if out.0 == out.1 {
-                        match get_token_info(self.simulator.provider.clone(), test_token).await {
-                            Ok(info) => {
-                                info!(
-                                    "Added safe token info ({}). Total: {:?} tokens",
-                                    info.symbol,
-                                    self.token_info.len()
-                                );
-                                self.token_info.insert(test_token, info);
-                            }
-                            Err(_) => {}
-                        }
-                    } else {
-                        self.honeypot.insert(test_token, true);
-                    }
-                } else {
-                    self.honeypot.insert(test_token, true);
+                    )
+                });
+            match sold {
+                Ok(out) => sell_taxes.push(get_tax(out.0, out.1)),
+                Err(_) => break,
+            }
+        }
+
+        self.simulator.inject_db(snapshot);
+        sell_taxes
+    }
+
+    pub fn test_max_sell(
+        &mut self,
+        pool: &Pool,
+        safe_token: H160,
+        test_token: H160,
+    ) -> (Option<U256>, u32) {
+        // Buy and sell progressively larger fractions (bps) of the pool's test token reserve
+        // Returns the largest amount sold and its fraction, or (None, u32::MAX) when no cap was hit
+        let stages = [10, 50, 100, 500, 1000];
+        let safe_token_slot = *self.balance_slots.get(&safe_token).unwrap();
+        let simulator_address = self.simulator.simulator_address;
+
+        let reserves = match self.simulator.v2_pool_get_reserves(pool.address) {
+            Ok(reserves) => reserves,
+            Err(_) => return (None, u32::MAX),
+        };
+        let (safe_reserve, test_reserve) = pool.ordered_reserves(reserves, safe_token);
+
+        let snapshot = self.simulator.db_snapshot();
+        let mut max_sell = (U256::zero(), 0);
+        let mut capped = false;
+
+        for bps in stages {
+            self.simulator.inject_db(snapshot.clone());
+
+            let amount_out = test_reserve * U256::from(bps) / U256::from(10000);
+            let amount_in = match self.simulator.get_amount_in(
+                amount_out,
+                safe_reserve,
+                test_reserve,
+                pool.fee_bps(),
+            ) {
+                Ok(amount_in) => amount_in,
+                Err(_) => break,
+            };
+            self.simulator.set_token_balance_raw(
+                simulator_address,
+                safe_token,
+                safe_token_slot,
+                amount_in,
+            );
+
+            let sold = self
+                .simulator
+                .v2_simulate_swap(
+                    amount_in,
+                    pool.address,
+                    safe_token,
+                    test_token,
+                    pool.fee_bps(),
+                    true,
+                )
+                .and_then(|bought| {
+                    self.simulator
+                        .v2_simulate_swap(
+                            bought.1,
+                            pool.address,
+                            test_token,
+                            safe_token,
+                            pool.fee_bps(),
+                            true,
+                        )
+                        .map(|_| bought.1)
+                });
+            match sold {
+                Ok(amount) => max_sell = (amount, bps),
+                Err(_) => {
+                    capped = true;
+                    break;
                 }
             }
         }
 
-        // cache to csv files
-        let mut token_writer = csv::Writer::from_path(token_file_path).unwrap();
-        for (_, info) in &self.token_info {
-            token_writer.serialize(info.cache_row()).unwrap();
+        self.simulator.inject_db(snapshot);
+
+        if capped {
+            (Some(max_sell.0), max_sell.1)
+        } else {
+            (None, u32::MAX)
+        }
+    }
+}
+
+pub fn get_tax(expected: U256, actual: U256) -> u32 {
+    // Tax in bps taken from the amount we expected to receive
+    if expected.is_zero() || actual >= expected {
+        return 0;
+    }
+    ((expected - actual) * U256::from(10000) / expected).as_u32()
+}
+
+pub fn is_escalating_tax(sell_taxes: &[u32], max_escalation_bps: u32) -> bool {
+    // Sell taxes sampled smallest size first: flagged when the tax never drops as the size grows
+    // and rises by more than max_escalation_bps overall, a noisy spread alone isn't escalation
+    let rising = sell_taxes.windows(2).all(|pair| pair[1] >= pair[0]);
+    match (sell_taxes.first(), sell_taxes.last()) {
+        (Some(first), Some(last)) => rising && last - first > max_escalation_bps,
+        _ => false,
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub fn load_honeypot_csv(path: &Path, expiry: u64) -> Result<HashMap<H160, u64>> {
+    // Rows are "address,detected_at", older caches only have the address column
+    // Those are treated as freshly detected so they expire after a full period
+    let mut honeypot = HashMap::new();
+    if !path.exists() {
+        return Ok(honeypot);
+    }
+
+    let now = unix_timestamp();
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)?;
+    for row in reader.records() {
+        let row = row?;
+        let address = row.get(0).unwrap_or_default();
+        if address == "address" {
+            continue;
+        }
+        let detected_at = match row.get(1) {
+            Some(timestamp) => timestamp.parse()?,
+            None => now,
+        };
+        if now.saturating_sub(detected_at) < expiry {
+            honeypot.insert(H160::from_str(address)?, detected_at);
+        }
+    }
+    Ok(honeypot)
+}
+
+pub fn save_honeypot_csv(path: &Path, honeypot: &HashMap<H160, u64>) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(&["address", "detected_at"])?;
+    for (token, detected_at) in honeypot {
+        writer.serialize((format!("{:?}", token), detected_at))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pools::build_verified_pools;
+    use ethers::{
+        abi::{self, Token as AbiToken},
+        providers::{JsonRpcClient, MockError, MockProvider, Provider, Ws},
+        types::Bytes,
+    };
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::{
+        fmt,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    // Counts every RPC request sent to the mock
+    #[derive(Debug)]
+    struct CountingClient {
+        inner: MockProvider,
+        requests: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl JsonRpcClient for CountingClient {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, method: &str, params: T) -> Result<R, MockError>
+        where
+            T: fmt::Debug + Serialize + Send + Sync,
+            R: DeserializeOwned + Send,
+        {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.inner.request(method, params).await
+        }
+    }
+
+    fn mocked_filter() -> HoneypotFilter<Provider<MockProvider>> {
+        // Nothing is served by the mock, only the logic that doesn't touch the fork can run
+        let (provider, _) = Provider::mocked();
+        let block = Block {
+            number: Some(U64::from(1)),
+            gas_limit: U256::from(30000000),
+            ..Default::default()
+        };
+        HoneypotFilter::new(Arc::new(provider), block, SimulatorConfig::default())
+    }
+
+    #[tokio::test]
+    async fn test_token_info_is_fetched_once() {
+        let mock = MockProvider::new();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let provider = Provider::new(CountingClient {
+            inner: mock.clone(),
+            requests: requests.clone(),
+        });
+        let block = Block {
+            number: Some(U64::from(1)),
+            gas_limit: U256::from(30000000),
+            ..Default::default()
+        };
+        let mut filter = HoneypotFilter::new(Arc::new(provider), block, SimulatorConfig::default());
+
+        // get_token_info is a multicall: the chain id, then one aggregate3 call for
+        // name/symbol/decimals (served last in, first out)
+        let result = |value: AbiToken| {
+            AbiToken::Tuple(vec![
+                AbiToken::Bool(true),
+                AbiToken::Bytes(abi::encode(&[value])),
+            ])
+        };
+        let aggregated = abi::encode(&[AbiToken::Array(vec![
+            result(AbiToken::String("Uniswap".to_string())),
+            result(AbiToken::String("UNI".to_string())),
+            result(AbiToken::Uint(U256::from(18))),
+        ])]);
+        mock.push::<Bytes, _>(Bytes::from(aggregated)).unwrap();
+        mock.push::<U256, _>(U256::one()).unwrap();
+
+        let token = H160::random();
+        let info = filter.get_token_info_cached(token).await.unwrap();
+        assert_eq!(info.symbol, "UNI");
+        assert_eq!(info.decimals, 18);
+        let fetched = requests.load(Ordering::SeqCst);
+        assert!(fetched > 0);
+
+        // the second lookup is served from the cache
+        let cached = filter.get_token_info_cached(token).await.unwrap();
+        assert_eq!(cached.name, "Uniswap");
+        assert_eq!(requests.load(Ordering::SeqCst), fetched);
+
+        // so are the safe tokens loaded in setup
+        let weth = H160::random();
+        filter.safe_token_info.insert(
+            weth,
+            Token {
+                address: weth,
+                implementation: None,
+                name: "Wrapped Ether".to_string(),
+                symbol: "WETH".to_string(),
+                decimals: 18,
+            },
+        );
+        assert_eq!(
+            filter.get_token_info_cached(weth).await.unwrap().symbol,
+            "WETH"
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), fetched);
+    }
+
+    #[test]
+    fn test_new_uses_the_configured_chain() {
+        let (provider, _) = Provider::mocked();
+        let block = Block {
+            number: Some(U64::from(1)),
+            gas_limit: U256::from(30000000),
+            ..Default::default()
+        };
+        let config = SimulatorConfig {
+            chain: ChainConfig::arbitrum(),
+            ..SimulatorConfig::default()
+        };
+        let filter = HoneypotFilter::new(Arc::new(provider), block, config);
+        let arbitrum = ChainConfig::arbitrum();
+        assert_eq!(filter.chain.chain_id, 42161);
+        assert_eq!(filter.safe_tokens.usdc, arbitrum.usdc);
+        assert_eq!(filter.safe_tokens.weth, arbitrum.wrapped_native);
+        assert_eq!(filter.simulator.weth_address, arbitrum.wrapped_native);
+        assert_eq!(filter.approve_spender, arbitrum.router);
+    }
+
+    #[test]
+    fn test_whitelist_and_blacklist() {
+        let dir = std::env::temp_dir().join(format!("token-lists-{:?}", H160::random()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (weth, trusted, scam, untested) = (
+            H160::random(),
+            H160::random(),
+            H160::random(),
+            H160::random(),
+        );
+        let list_path = dir.join(".blacklist.txt");
+        std::fs::write(&list_path, format!("# known scams\n\n{:?}\n", scam)).unwrap();
+
+        let mut filter = mocked_filter();
+        filter.safe_token_info.insert(
+            weth,
+            Token {
+                address: weth,
+                implementation: None,
+                name: "Wrapped Ether".to_string(),
+                symbol: "WETH".to_string(),
+                decimals: 18,
+            },
+        );
+        filter.whitelist = HashSet::from([trusted]);
+        filter.blacklist = load_address_list(&list_path).unwrap();
+        // the lists are optional
+        assert!(load_address_list(&dir.join(".whitelist.txt"))
+            .unwrap()
+            .is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the scam passed the honeypot test, the blacklist still drops it
+        filter.token_info.insert(
+            scam,
+            Token {
+                address: scam,
+                implementation: None,
+                name: "Scam".to_string(),
+                symbol: "SCAM".to_string(),
+                decimals: 18,
+            },
+        );
+        // listed tokens are never tested, the untested one still is
+        assert!(!filter.needs_test(&trusted));
+        assert!(!filter.needs_test(&scam));
+        assert!(filter.needs_test(&untested));
+
+        let pool = |token: H160| Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: weth,
+            token1: token,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let pools = vec![pool(trusted), pool(scam), pool(untested)];
+        let (verified, _) = build_verified_pools(pools.clone(), &filter);
+        assert_eq!(
+            verified.iter().map(|pool| pool.address).collect::<Vec<_>>(),
+            vec![pools[0].address]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trusted_tokens_verify_without_simulating() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let provider = Provider::new(CountingClient {
+            inner: MockProvider::new(),
+            requests: requests.clone(),
+        });
+        let block = Block {
+            number: Some(U64::from(1)),
+            gas_limit: U256::from(30000000),
+            ..Default::default()
+        };
+        let mut filter = HoneypotFilter::new(Arc::new(provider), block, SimulatorConfig::default());
+
+        let token = |address: H160, symbol: &str| Token {
+            address,
+            implementation: None,
+            name: symbol.to_string(),
+            symbol: symbol.to_string(),
+            decimals: 18,
+        };
+        let (weth, uni, link) = (H160::random(), H160::random(), H160::random());
+        let path = std::env::temp_dir().join(format!("trusted-tokens-{:?}.csv", H160::random()));
+        save_tokens_csv(
+            &path,
+            &[token(weth, "WETH"), token(uni, "UNI"), token(link, "LINK")],
+        )
+        .unwrap();
+        filter.trust_tokens(load_tokens_csv(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let pool = |token0: H160, token1: H160| Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let pools = vec![pool(weth, uni), pool(uni, link), pool(weth, H160::random())];
+        let (verified, _) = build_verified_pools(pools.clone(), &filter);
+        assert_eq!(
+            verified.iter().map(|pool| pool.address).collect::<Vec<_>>(),
+            vec![pools[0].address, pools[1].address]
+        );
+        // token info lookups are served from the trusted set too
+        assert_eq!(
+            filter.get_token_info_cached(uni).await.unwrap().symbol,
+            "UNI"
+        );
+        // nothing was simulated or fetched
+        assert_eq!(requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_extra_safe_token_test_amount() {
+        let mut filter = mocked_filter();
+        let (frax, wbtc) = (H160::random(), H160::random());
+        filter.add_safe_token(frax, None);
+        filter.add_safe_token(wbtc, Some(1));
+
+        assert!(filter.safe_tokens.contains(&frax));
+        assert_eq!(filter.safe_tokens.all().len(), 6);
+        // stables are sized like USDC/USDT unless the config says otherwise
+        assert_eq!(
+            filter.test_amount(frax),
+            filter.test_amount(filter.safe_tokens.usdc)
+        );
+        assert_eq!(filter.test_amount(wbtc), 1);
+
+        // the common four keep their amounts, even when added again with one
+        let weth = filter.safe_tokens.weth;
+        filter.add_safe_token(weth, Some(1));
+        assert_eq!(filter.test_amount(weth), 20);
+        assert_eq!(filter.safe_tokens.all().len(), 6);
+    }
+
+    #[test]
+    fn test_adaptive_test_amount() {
+        let mut filter = mocked_filter();
+        let usdc = filter.safe_tokens.usdc;
+        filter.safe_token_info.insert(
+            usdc,
+            Token {
+                address: usdc,
+                implementation: None,
+                name: "USD Coin".to_string(),
+                symbol: "USDC".to_string(),
+                decimals: 6,
+            },
+        );
+        let usdc_amount = |amount: u64| U256::from(amount) * U256::exp10(6);
+
+        // 1% of the reserve, between 1% of test_amount (100 USDC) and test_amount (10000 USDC)
+        assert_eq!(
+            filter.adaptive_test_amount(usdc, usdc_amount(500000)),
+            usdc_amount(5000)
+        );
+        // a thin pool isn't swamped by the full test amount, but is still tested with the minimum
+        assert_eq!(
+            filter.adaptive_test_amount(usdc, usdc_amount(2000)),
+            usdc_amount(100)
+        );
+        // a deep pool is capped at test_amount
+        assert_eq!(
+            filter.adaptive_test_amount(usdc, usdc_amount(10000000)),
+            usdc_amount(10000)
+        );
+    }
+
+    #[test]
+    fn test_only_liquid_pools_are_tested() {
+        use foundry_evm::executor::Bytecode;
+        use foundry_evm::revm::primitives::{AccountInfo, U256 as rU256};
+
+        // getReserves returns (slot 0, slot 1, 0) whatever the calldata
+        let code: ethers::types::Bytes = "0x60005460005260015460205260606000f3".parse().unwrap();
+
+        let mut filter = mocked_filter();
+        let usdc = filter.safe_tokens.usdc;
+        filter.safe_token_info.insert(
+            usdc,
+            Token {
+                address: usdc,
+                implementation: None,
+                name: "USD Coin".to_string(),
+                symbol: "USDC".to_string(),
+                decimals: 6,
+            },
+        );
+        let (token, funded, dust) = (H160::random(), H160::random(), H160::random());
+        let owner = filter.simulator.owner;
+        let db = filter.simulator.evm.db.as_mut().unwrap();
+        db.insert_account_info(owner.into(), AccountInfo::default());
+        for pair in [funded, dust] {
+            db.insert_account_info(
+                pair.into(),
+                AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+            );
+        }
+
+        let (token0, token1) = if token < usdc {
+            (token, usdc)
+        } else {
+            (usdc, token)
+        };
+        let usdc_slot = if token0 == usdc { 0 } else { 1 };
+        let usdc_amount = |amount: u64| rU256::from(amount) * rU256::from(1000000);
+        filter
+            .simulator
+            .set_storage(funded, rU256::from(usdc_slot), usdc_amount(1000000))
+            .unwrap();
+        filter
+            .simulator
+            .set_storage(dust, rU256::from(usdc_slot), usdc_amount(50))
+            .unwrap();
+
+        let pool = |address: H160| Pool {
+            address,
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        // 50 USDC can't absorb even the 100 USDC minimum test swap twice over
+        assert!(filter.is_liquid(&pool(funded), usdc));
+        assert!(!filter.is_liquid(&pool(dust), usdc));
+    }
+
+    #[test]
+    fn test_is_escalating_tax() {
+        // taxes of the 0.1%, 1% and 5% sells
+        assert!(is_escalating_tax(&[100, 500, 2500], 100));
+        assert!(is_escalating_tax(&[100, 100, 300], 100));
+        // a rise within the threshold is fine
+        assert!(!is_escalating_tax(&[100, 150, 200], 100));
+        // a spread that doesn't grow with size isn't escalation
+        assert!(!is_escalating_tax(&[2500, 100, 300], 100));
+        assert!(!is_escalating_tax(&[100, 2500, 300], 100));
+        assert!(!is_escalating_tax(&[500, 100], 100));
+        // a single size can't show a trend
+        assert!(!is_escalating_tax(&[2500], 100));
+        assert!(!is_escalating_tax(&[], 100));
+    }
+
+    #[test]
+    fn test_approve_honeypots_are_flagged() {
+        use foundry_evm::executor::Bytecode;
+        use foundry_evm::revm::primitives::{AccountInfo, U256 as rU256};
+
+        // approve stores allowance[caller][spender] at slot 1 unless slot 0 is set, then it only
+        // returns true. allowance(owner, spender) reads it back
+        let code: ethers::types::Bytes = "0x60003560e01c8063095ea7b314601b578063dd62ed3e14604e57005b6000546043573360005260016020526040600020602052600435600052604060002060243590555b600160005260206000f35b6004356000526001602052604060002060205260243560005260406000205460005260206000f3"
+            .parse()
+            .unwrap();
+        // PUSH1 0 DUP1 REVERT
+        let reverting: ethers::types::Bytes = "0x600080fd".parse().unwrap();
+        // returns a zero word: PUSH1 32 PUSH1 0 RETURN
+        let returns_false: ethers::types::Bytes = "0x60206000f3".parse().unwrap();
+
+        let mut filter = mocked_filter();
+        let (token, no_allowance, reverts, false_approve) = (
+            H160::random(),
+            H160::random(),
+            H160::random(),
+            H160::random(),
+        );
+        let owner = filter.simulator.owner;
+        let db = filter.simulator.evm.db.as_mut().unwrap();
+        db.insert_account_info(owner.into(), AccountInfo::default());
+        for (address, code) in [
+            (token, &code),
+            (no_allowance, &code),
+            (reverts, &reverting),
+            (false_approve, &returns_false),
+        ] {
+            db.insert_account_info(
+                address.into(),
+                AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+            );
+        }
+        filter
+            .simulator
+            .set_storage(no_allowance, rU256::ZERO, rU256::from(1))
+            .unwrap();
+        filter.simulator.deploy_simulator();
+
+        assert!(filter.test_approve(token).is_ok());
+        assert!(filter
+            .test_approve(reverts)
+            .unwrap_err()
+            .starts_with("approve failed"));
+        assert_eq!(
+            filter.test_approve(false_approve).unwrap_err(),
+            "approve returned false"
+        );
+        assert_eq!(
+            filter.test_approve(no_allowance).unwrap_err(),
+            "allowance is still 0"
+        );
+    }
+
+    #[test]
+    fn test_rebasing_balance_drift_is_detected() {
+        use foundry_evm::executor::Bytecode;
+        use foundry_evm::revm::primitives::{AccountInfo, U256 as rU256};
+
+        // balanceOf returns slot 0 for every holder, rebase() doubles it when slot 1 is set
+        // and reverts otherwise, anything else reverts
+        let code: ethers::types::Bytes = "0x60003560e01c806370a0823114601e5763af14052c14602a575b600080fd5b60005460005260206000f35b60015415601957600054800160005500"
+            .parse()
+            .unwrap();
+        // balanceOf returns the block timestamp: TIMESTAMP PUSH1 0 MSTORE PUSH1 32 PUSH1 0 RETURN
+        let drifting_code: ethers::types::Bytes = "0x4260005260206000f3".parse().unwrap();
+
+        let mut filter = mocked_filter();
+        let (plain, rebasing, drifting) = (H160::random(), H160::random(), H160::random());
+        let owner = filter.simulator.owner;
+        let db = filter.simulator.evm.db.as_mut().unwrap();
+        db.insert_account_info(owner.into(), AccountInfo::default());
+        for (address, code) in [
+            (plain, &code),
+            (rebasing, &code),
+            (drifting, &drifting_code),
+        ] {
+            db.insert_account_info(
+                address.into(),
+                AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+            );
+        }
+        for (address, slot, value) in [(plain, 0, 1000), (rebasing, 0, 1000), (rebasing, 1, 1)] {
+            filter
+                .simulator
+                .set_storage(address, rU256::from(slot), rU256::from(value))
+                .unwrap();
+        }
+
+        let (number, timestamp) = (
+            filter.simulator.evm.env.block.number,
+            filter.simulator.evm.env.block.timestamp,
+        );
+        // the balance stays put and rebase() reverts
+        assert!(!filter.test_rebasing(plain).unwrap());
+        // the balance only moves once rebase() is called
+        assert!(filter.test_rebasing(rebasing).unwrap());
+        // the balance moves with time alone
+        assert!(filter.test_rebasing(drifting).unwrap());
+
+        // the env and the state are put back after each check
+        assert_eq!(filter.simulator.evm.env.block.number, number);
+        assert_eq!(filter.simulator.evm.env.block.timestamp, timestamp);
+        assert_eq!(
+            filter
+                .simulator
+                .token_balance_of(rebasing, filter.simulator.simulator_address)
+                .unwrap(),
+            U256::from(1000)
+        );
+    }
+
+    #[test]
+    fn test_cooldown_token_sells_after_the_delay() {
+        use foundry_evm::executor::Bytecode;
+        use foundry_evm::revm::primitives::{AccountInfo, U256 as rU256};
+
+        // A token and V2 pair in one: transfer reverts before the timestamp in slot 0
+        // and the block number in slot 1, getReserves is (1e18, 1e18, 0), balanceOf always 2e18
+        // and anything else (swap) is a no-op
+        let code: ethers::types::Bytes = "0x60003560e01c8063a9059cbb1460255780630902f1ac14604057806370a0823114605657005b60005442106068576001544310606857600160005260206000f35b670de0b6b3a76400008060005260205260606000f35b671bc16d674ec8000060005260206000f35b600080fd"
+            .parse()
+            .unwrap();
+
+        let mut filter = mocked_filter();
+        // BSC's 3s blocks: 600 seconds are 200 blocks, mainnet's 12s would only be 50
+        filter.chain = ChainConfig::bsc();
+        filter.cooldown_test_secs = 600;
+        let (token, safe_token, pair) = (H160::random(), H160::random(), H160::random());
+        let owner = filter.simulator.owner;
+        let db = filter.simulator.evm.db.as_mut().unwrap();
+        db.insert_account_info(owner.into(), AccountInfo::default());
+        for address in [token, safe_token, pair] {
+            db.insert_account_info(
+                address.into(),
+                AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+            );
+        }
+        filter.simulator.deploy_simulator();
+
+        let number = filter.simulator.evm.env.block.number;
+        let timestamp = filter.simulator.evm.env.block.timestamp;
+        filter
+            .simulator
+            .set_storage(token, rU256::from(0), timestamp + rU256::from(300))
+            .unwrap();
+        filter
+            .simulator
+            .set_storage(token, rU256::from(1), number + rU256::from(100))
+            .unwrap();
+
+        let (token0, token1) = if token < safe_token {
+            (token, safe_token)
+        } else {
+            (safe_token, token)
+        };
+        let pool = Pool {
+            address: pair,
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let amount = U256::from(10).pow(U256::from(18));
+
+        // sold in the buy's block the transfer reverts, 600 seconds later it goes through
+        assert!(filter
+            .simulator
+            .v2_simulate_swap(amount, pair, token, safe_token, pool.fee_bps(), false)
+            .is_err());
+        assert!(filter.test_cooldown(&pool, safe_token, token, amount));
+        assert_eq!(filter.simulator.evm.env.block.number, number);
+        assert_eq!(filter.simulator.evm.env.block.timestamp, timestamp);
+
+        // a sell that never goes through isn't a cooldown
+        filter
+            .simulator
+            .set_storage(token, rU256::from(0), rU256::MAX)
+            .unwrap();
+        assert!(!filter.test_cooldown(&pool, safe_token, token, amount));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_check_token() {
+        use foundry_evm::executor::Bytecode;
+        use foundry_evm::revm::primitives::AccountInfo;
+
+        // Needs a mainnet WSS_URL with debug_traceCall, setup() traces the safe tokens' balance slots
+        dotenv::dotenv().ok();
+        let ws = Ws::connect(std::env::var("WSS_URL").unwrap())
+            .await
+            .unwrap();
+        let provider = Arc::new(Provider::new(ws));
+        let block = provider
+            .get_block(BlockNumber::Latest)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut filter = HoneypotFilter::new(provider, block, SimulatorConfig::default());
+        filter.setup().await.unwrap();
+
+        // UNI, the pool is found through the factories
+        let uni = H160::from_str("0x1f9840a85d5aF5bf1D1762F925BDADdC4201F984").unwrap();
+        let clean = filter.check_token(uni, None).await.unwrap();
+        assert!(!clean.is_honeypot);
+        assert_eq!(clean.token, uni);
+        assert!(filter.token_info.contains_key(&uni));
+
+        // the same pool with a token that reverts every call can't even be bought
+        let pool = filter.find_safe_pool(uni).await.unwrap().0;
+        assert_eq!(pool.address, clean.pool);
+        let revert: ethers::types::Bytes = "0x60006000fd".parse().unwrap();
+        filter
+            .simulator
+            .evm
+            .db
+            .as_mut()
+            .unwrap()
+            .insert_account_info(
+                uni.into(),
+                AccountInfo::new(Default::default(), 0, Bytecode::new_raw(revert.0)),
+            );
+        let honeypot = filter.check_token(uni, Some(pool.clone())).await.unwrap();
+        assert!(honeypot.is_honeypot);
+        assert!(filter.honeypot.contains_key(&uni));
+
+        // a pool that doesn't trade the token is rejected
+        assert!(filter
+            .check_token(H160::random(), Some(pool))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_balance_slot_override_round_trips() {
+        use foundry_evm::executor::Bytecode;
+        use foundry_evm::revm::primitives::{AccountInfo, U256 as rU256};
+
+        // balanceOf(owner) returns the mapping at slot 7: sload(keccak256(owner . 7))
+        let code: ethers::types::Bytes = "0x600435600052600760205260406000205460005260206000f3"
+            .parse()
+            .unwrap();
+        let mut filter = mocked_filter();
+        let token = H160::random();
+        let owner = filter.simulator.owner;
+        let db = filter.simulator.evm.db.as_mut().unwrap();
+        db.insert_account_info(owner.into(), AccountInfo::default());
+        db.insert_account_info(
+            token.into(),
+            AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0)),
+        );
+
+        filter.set_balance_slot_overrides(HashMap::from([(token, 7)]));
+        assert_eq!(filter.balance_slots.get(&token), Some(&7));
+
+        // the slot passed in is ignored for a token with a known slot
+        filter.simulator.set_token_balance(owner, token, 18, 0, 5);
+        assert_eq!(
+            filter.simulator.token_balance_of(token, owner).unwrap(),
+            U256::from(5) * U256::exp10(18)
+        );
+    }
+
+    #[test]
+    fn test_max_sell_detects_a_max_tx_limit() {
+        use foundry_evm::executor::Bytecode;
+        use foundry_evm::revm::primitives::{AccountInfo, U256 as rU256};
+
+        // A token and V2 pair in one. As a token: transfer reverts above the cap in slot 2 and adds
+        // the amount to slot 3, which balanceOf returns for every holder. As a pair: getReserves is
+        // (slot 0, slot 1, 0) and swap transfers the non zero amount of token0 (slot 4) or token1 (slot 5)
+        let code: ethers::types::Bytes = "0x60003560e01c8063a9059cbb14602f5780630902f1ac14604c57806370a0823114605e578063022c0d9f14606a57005b602435806002541060a95760035401600355600160005260206000f35b60005460005260015460205260606000f35b60035460005260206000f35b60043580156079576004546081565b506024356005545b63a9059cbb60e01b6000526044356004529060245260206000604460006000855af11560a957005b600080fd"
+            .parse()
+            .unwrap();
+
+        let mut filter = mocked_filter();
+        let (token, safe_token, pair) = (H160::random(), H160::random(), H160::random());
+        let owner = filter.simulator.owner;
+        let db = filter.simulator.evm.db.as_mut().unwrap();
+        db.insert_account_info(owner.into(), AccountInfo::default());
+        for address in [token, safe_token, pair] {
+            db.insert_account_info(
+                address.into(),
+                AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+            );
+        }
+        filter.simulator.deploy_simulator();
+        filter.balance_slots.insert(safe_token, 10);
+
+        let (token0, token1) = if token < safe_token {
+            (token, safe_token)
+        } else {
+            (safe_token, token)
+        };
+        let reserve = rU256::from(1000) * rU256::from(10).pow(rU256::from(18));
+        let word = |address: H160| -> rU256 {
+            U256::from_big_endian(H256::from(address).as_bytes()).into()
+        };
+        for (slot, value) in [
+            (0, reserve),
+            (1, reserve),
+            (4, word(token0)),
+            (5, word(token1)),
+        ] {
+            filter
+                .simulator
+                .set_storage(pair, rU256::from(slot), value)
+                .unwrap();
+        }
+        // the pair's balances start at its reserves, so the simulator sees exactly what it sent in
+        for address in [token, safe_token] {
+            filter
+                .simulator
+                .set_storage(address, rU256::from(3), reserve)
+                .unwrap();
+        }
+        filter
+            .simulator
+            .set_storage(safe_token, rU256::from(2), rU256::MAX)
+            .unwrap();
+        let pool = Pool {
+            address: pair,
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+
+        // without a cap every stage goes through
+        filter
+            .simulator
+            .set_storage(token, rU256::from(2), rU256::MAX)
+            .unwrap();
+        assert_eq!(
+            filter.test_max_sell(&pool, safe_token, token),
+            (None, u32::MAX)
+        );
+
+        // capped at 0.6% of the reserve, the 0.5% stage is the largest that sells
+        filter
+            .simulator
+            .set_storage(
+                token,
+                rU256::from(2),
+                reserve * rU256::from(6) / rU256::from(1000),
+            )
+            .unwrap();
+        let (max_sell, max_sell_bps) = filter.test_max_sell(&pool, safe_token, token);
+        assert_eq!(max_sell, Some(U256::from(5) * U256::exp10(18)));
+        assert_eq!(max_sell_bps, 50);
+        assert!(max_sell_bps < filter.min_sell_fraction_bps);
+    }
+
+    #[test]
+    fn test_buyer_only_sell_is_flagged() {
+        use foundry_evm::executor::Bytecode;
+        use foundry_evm::revm::primitives::{AccountInfo, U256 as rU256};
+
+        // The max tx token/pair mock with one more rule: when slot 7 holds a buyer, transfers
+        // to the pair (slot 6) from anyone else revert, so only the buyer can sell
+        let code: ethers::types::Bytes = "0x60003560e01c8063a9059cbb14602f5780630902f1ac14606957806370a0823114607b578063022c0d9f14608757005b6007548015604a573314604c576004356006541460c657604c565b505b602435806002541060c65760035401600355600160005260206000f35b60005460005260015460205260606000f35b60035460005260206000f35b6004358015609657600454609e565b506024356005545b63a9059cbb60e01b6000526044356004529060245260206000604460006000855af11560c657005b600080fd"
+            .parse()
+            .unwrap();
+
+        let mut filter = mocked_filter();
+        let (token, safe_token, pair) = (H160::random(), H160::random(), H160::random());
+        let owner = filter.simulator.owner;
+        let db = filter.simulator.evm.db.as_mut().unwrap();
+        db.insert_account_info(owner.into(), AccountInfo::default());
+        for address in [token, safe_token, pair] {
+            db.insert_account_info(
+                address.into(),
+                AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+            );
         }
-        token_writer.flush().unwrap();
+        filter.simulator.deploy_simulator();
 
-        let mut honeypot_writer = csv::Writer::from_path(honeypot_file_path).unwrap();
-        for (token, _) in &self.honeypot {
-            honeypot_writer.serialize(token).unwrap();
+        let (token0, token1) = if token < safe_token {
+            (token, safe_token)
+        } else {
+            (safe_token, token)
+        };
+        let reserve = rU256::from(1000) * rU256::from(10).pow(rU256::from(18));
+        let word = |address: H160| -> rU256 {
+            U256::from_big_endian(H256::from(address).as_bytes()).into()
+        };
+        for (address, slot, value) in [
+            (pair, 0, reserve),
+            (pair, 1, reserve),
+            (pair, 4, word(token0)),
+            (pair, 5, word(token1)),
+            (token, 2, rU256::MAX),
+            (token, 3, reserve),
+            (token, 6, word(pair)),
+            (safe_token, 2, rU256::MAX),
+            (safe_token, 3, reserve),
+        ] {
+            filter
+                .simulator
+                .set_storage(address, rU256::from(slot), value)
+                .unwrap();
         }
-        honeypot_writer.flush().unwrap();
+        let pool = Pool {
+            address: pair,
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let buy = |filter: &mut HoneypotFilter<Provider<MockProvider>>| {
+            filter
+                .simulator
+                .v2_simulate_swap(
+                    U256::exp10(18),
+                    pair,
+                    safe_token,
+                    token,
+                    pool.fee_bps(),
+                    true,
+                )
+                .unwrap()
+                .1
+        };
+
+        // anyone can sell, the bought tokens sell from a second address too
+        let bought = buy(&mut filter);
+        assert!(filter
+            .test_cross_address_sell(&pool, safe_token, token, bought)
+            .is_ok());
+
+        // only the simulator that bought can sell, the second address is reverted
+        let simulator_address = filter.simulator.simulator_address;
+        filter
+            .simulator
+            .set_storage(token, rU256::from(7), word(simulator_address))
+            .unwrap();
+        let bought = buy(&mut filter);
+        assert!(filter
+            .test_cross_address_sell(&pool, safe_token, token, bought)
+            .is_err());
+        // the fork is restored, so the buyer's own sell still goes through
+        assert!(filter
+            .simulator
+            .v2_simulate_swap(bought, pair, token, safe_token, pool.fee_bps(), true)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_honeypot_cache_round_trip() {
+        let dir = std::env::temp_dir().join(format!("honeypot-cache-{:?}", H160::random()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".honeypot.csv");
+        let week = 7 * 24 * 60 * 60;
+        let now = unix_timestamp();
+
+        // nothing cached yet
+        assert!(load_honeypot_csv(&path, week).unwrap().is_empty());
+
+        let (fresh, stale) = (H160::random(), H160::random());
+        let honeypot = HashMap::from([(fresh, now - 60), (stale, now - week - 60)]);
+        save_honeypot_csv(&path, &honeypot).unwrap();
+
+        // a restart reads back the fresh one with its detection time, the stale one is tested again
+        let loaded = load_honeypot_csv(&path, week).unwrap();
+        assert_eq!(loaded, HashMap::from([(fresh, now - 60)]));
+
+        // rows from older caches have no timestamp, they start a full period now
+        let legacy = H160::random();
+        std::fs::write(&path, format!("{:?}\n", legacy)).unwrap();
+        let loaded = load_honeypot_csv(&path, week).unwrap();
+        assert!(loaded.get(&legacy).unwrap() >= &now);
+
+        _ = std::fs::remove_dir_all(dir);
     }
 }
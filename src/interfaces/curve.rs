@@ -0,0 +1,52 @@
+use anyhow::Result;
+use bytes::Bytes as OutputBytes;
+use ethers::abi::parse_abi;
+use ethers::prelude::BaseContract;
+use ethers::types::{Bytes, H160, U256};
+
+use crate::interfaces::decode_output;
+
+#[derive(Clone)]
+pub struct CurvePoolABI {
+    pub abi: BaseContract,
+}
+
+impl CurvePoolABI {
+    pub fn new() -> Self {
+        let abi = BaseContract::from(
+            parse_abi(&[
+                "function get_dy(int128,int128,uint256) external view returns (uint256)",
+                "function coins(uint256) external view returns (address)",
+                "function exchange(int128,int128,uint256,uint256) external",
+            ])
+            .unwrap(),
+        );
+        Self { abi }
+    }
+
+    pub fn get_dy_input(&self, i: i128, j: i128, dx: U256) -> Result<Bytes> {
+        let calldata = self.abi.encode("get_dy", (i, j, dx))?;
+        Ok(calldata)
+    }
+
+    pub fn get_dy_output(&self, output: OutputBytes) -> Result<U256> {
+        let out = decode_output(&self.abi, "get_dy", output)?;
+        Ok(out)
+    }
+
+    pub fn coins_input(&self, i: U256) -> Result<Bytes> {
+        let calldata = self.abi.encode("coins", i)?;
+        Ok(calldata)
+    }
+
+    pub fn coins_output(&self, output: OutputBytes) -> Result<H160> {
+        let out = decode_output(&self.abi, "coins", output)?;
+        Ok(out)
+    }
+
+    pub fn exchange_input(&self, i: i128, j: i128, dx: U256, min_dy: U256) -> Result<Bytes> {
+        // exchange's return value differs between pool versions, so the output is read from balances
+        let calldata = self.abi.encode("exchange", (i, j, dx, min_dy))?;
+        Ok(calldata)
+    }
+}
@@ -0,0 +1,32 @@
+use anyhow::Result;
+use bytes::Bytes as OutputBytes;
+use ethers::abi::parse_abi;
+use ethers::prelude::BaseContract;
+use ethers::types::{Bytes, H160};
+
+use crate::interfaces::decode_output;
+
+#[derive(Clone)]
+pub struct V2FactoryABI {
+    pub abi: BaseContract,
+}
+
+impl V2FactoryABI {
+    pub fn new() -> Self {
+        let abi = BaseContract::from(
+            parse_abi(&["function getPair(address,address) external view returns (address)"])
+                .unwrap(),
+        );
+        Self { abi }
+    }
+
+    pub fn get_pair_input(&self, token_a: H160, token_b: H160) -> Result<Bytes> {
+        let calldata = self.abi.encode("getPair", (token_a, token_b))?;
+        Ok(calldata)
+    }
+
+    pub fn get_pair_output(&self, output: OutputBytes) -> Result<H160> {
+        let out = decode_output(&self.abi, "getPair", output)?;
+        Ok(out)
+    }
+}
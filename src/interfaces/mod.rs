@@ -1,3 +1,48 @@
+pub mod curve;
+pub mod factory;
+pub mod permit;
 pub mod pool;
+pub mod quoter;
 pub mod simulator;
 pub mod token;
+pub mod weth;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes as OutputBytes;
+use ethers::abi::Detokenize;
+use ethers::prelude::BaseContract;
+use ethers::utils::hex;
+
+pub fn decode_output<D: Detokenize>(
+    abi: &BaseContract,
+    name: &str,
+    output: OutputBytes,
+) -> Result<D> {
+    // Keep the function name and the raw bytes around, the abi error alone doesn't say which call failed
+    abi.decode_output(name, &output).map_err(|e| {
+        anyhow!(
+            "failed to decode {} output: {:?} / raw: 0x{}",
+            name,
+            e,
+            hex::encode(&output)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaces::pool::V2PoolABI;
+
+    #[test]
+    fn test_decode_error_names_the_function() {
+        // getReserves returns three words, a pool returning less can't be decoded
+        let output = OutputBytes::from(vec![0xab; 40]);
+        let err = V2PoolABI::new()
+            .get_reserves_output(output)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("getReserves"));
+        assert!(err.contains(&format!("0x{}", "ab".repeat(40))));
+    }
+}
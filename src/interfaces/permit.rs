@@ -0,0 +1,65 @@
+use anyhow::Result;
+use bytes::Bytes as OutputBytes;
+use ethers::abi::parse_abi;
+use ethers::prelude::BaseContract;
+use ethers::types::{Bytes, Signature, H160, U256};
+
+use crate::interfaces::decode_output;
+
+#[derive(Clone)]
+pub struct PermitABI {
+    pub abi: BaseContract,
+}
+
+impl PermitABI {
+    pub fn new() -> Self {
+        let abi = BaseContract::from(
+            parse_abi(&[
+                "function permit(address owner, address spender, uint256 value, uint256 deadline, uint8 v, bytes32 r, bytes32 s) external",
+                "function nonces(address owner) external view returns (uint256)",
+                "function DOMAIN_SEPARATOR() external view returns (bytes32)",
+            ])
+            .unwrap(),
+        );
+        Self { abi }
+    }
+
+    pub fn permit_input(
+        &self,
+        owner: H160,
+        spender: H160,
+        value: U256,
+        deadline: U256,
+        signature: Signature,
+    ) -> Result<Bytes> {
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        signature.r.to_big_endian(&mut r);
+        signature.s.to_big_endian(&mut s);
+        let calldata = self.abi.encode(
+            "permit",
+            (owner, spender, value, deadline, signature.v as u8, r, s),
+        )?;
+        Ok(calldata)
+    }
+
+    pub fn nonces_input(&self, owner: H160) -> Result<Bytes> {
+        let calldata = self.abi.encode("nonces", owner)?;
+        Ok(calldata)
+    }
+
+    pub fn nonces_output(&self, output: OutputBytes) -> Result<U256> {
+        let out = decode_output(&self.abi, "nonces", output)?;
+        Ok(out)
+    }
+
+    pub fn domain_separator_input(&self) -> Result<Bytes> {
+        let calldata = self.abi.encode("DOMAIN_SEPARATOR", ())?;
+        Ok(calldata)
+    }
+
+    pub fn domain_separator_output(&self, output: OutputBytes) -> Result<[u8; 32]> {
+        let out = decode_output(&self.abi, "DOMAIN_SEPARATOR", output)?;
+        Ok(out)
+    }
+}
@@ -2,7 +2,9 @@ use anyhow::Result;
 use bytes::Bytes as OutputBytes;
 use ethers::abi::parse_abi;
 use ethers::prelude::BaseContract;
-use ethers::types::Bytes;
+use ethers::types::{Bytes, H160, U256};
+
+use crate::interfaces::decode_output;
 
 #[derive(Clone)]
 pub struct V2PoolABI {
@@ -12,8 +14,13 @@ pub struct V2PoolABI {
 impl V2PoolABI {
     pub fn new() -> Self {
         let abi = BaseContract::from(
-            parse_abi(&["function getReserves() external view returns (uint112,uint112,uint32)"])
-                .unwrap(),
+            parse_abi(&[
+                "function getReserves() external view returns (uint112,uint112,uint32)",
+                "function token0() external view returns (address)",
+                "function token1() external view returns (address)",
+                "function swap(uint256 amount0Out, uint256 amount1Out, address to, bytes data) external",
+            ])
+            .unwrap(),
         );
         Self { abi }
     }
@@ -24,7 +31,34 @@ impl V2PoolABI {
     }
 
     pub fn get_reserves_output(&self, output: OutputBytes) -> Result<(u128, u128, u32)> {
-        let out = self.abi.decode_output("getReserves", output)?;
+        let out = decode_output(&self.abi, "getReserves", output)?;
+        Ok(out)
+    }
+
+    pub fn token0_input(&self) -> Result<Bytes> {
+        let calldata = self.abi.encode("token0", ())?;
+        Ok(calldata)
+    }
+
+    pub fn token0_output(&self, output: OutputBytes) -> Result<H160> {
+        let out = decode_output(&self.abi, "token0", output)?;
+        Ok(out)
+    }
+
+    pub fn token1_input(&self) -> Result<Bytes> {
+        let calldata = self.abi.encode("token1", ())?;
+        Ok(calldata)
+    }
+
+    pub fn token1_output(&self, output: OutputBytes) -> Result<H160> {
+        let out = decode_output(&self.abi, "token1", output)?;
         Ok(out)
     }
+
+    pub fn swap_input(&self, amount0_out: U256, amount1_out: U256, to: H160) -> Result<Bytes> {
+        let calldata = self
+            .abi
+            .encode("swap", (amount0_out, amount1_out, to, Bytes::new()))?;
+        Ok(calldata)
+    }
 }
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use bytes::Bytes as OutputBytes;
+use ethers::abi::parse_abi;
+use ethers::prelude::BaseContract;
+use ethers::types::{Bytes, H160, U256};
+
+use crate::interfaces::decode_output;
+
+#[derive(Clone)]
+pub struct V3QuoterABI {
+    pub abi: BaseContract,
+}
+
+impl V3QuoterABI {
+    pub fn new() -> Self {
+        // Uniswap V3 QuoterV2, params are (tokenIn, tokenOut, amount, fee, sqrtPriceLimitX96)
+        // quoteExactInput takes a V3 encoded path: token / fee / token / fee / ... / token
+        let abi = BaseContract::from(
+            parse_abi(&[
+                "function quoteExactInputSingle((address,address,uint256,uint24,uint160)) external returns (uint256,uint160,uint32,uint256)",
+                "function quoteExactOutputSingle((address,address,uint256,uint24,uint160)) external returns (uint256,uint160,uint32,uint256)",
+                "function quoteExactInput(bytes,uint256) external returns (uint256,uint160[],uint32[],uint256)",
+            ])
+            .unwrap(),
+        );
+        Self { abi }
+    }
+
+    pub fn quote_exact_input_single_input(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_in: U256,
+        fee: u32,
+    ) -> Result<Bytes> {
+        let calldata = self.abi.encode(
+            "quoteExactInputSingle",
+            ((token_in, token_out, amount_in, fee, U256::zero()),),
+        )?;
+        Ok(calldata)
+    }
+
+    pub fn quote_exact_input_single_output(&self, output: OutputBytes) -> Result<U256> {
+        let out: (U256, U256, u32, U256) =
+            decode_output(&self.abi, "quoteExactInputSingle", output)?;
+        Ok(out.0)
+    }
+
+    pub fn quote_exact_input_input(&self, path: &[u8], amount_in: U256) -> Result<Bytes> {
+        let calldata = self
+            .abi
+            .encode("quoteExactInput", (Bytes::from(path.to_vec()), amount_in))?;
+        Ok(calldata)
+    }
+
+    pub fn quote_exact_input_output(&self, output: OutputBytes) -> Result<U256> {
+        let out: (U256, Vec<U256>, Vec<u32>, U256) =
+            decode_output(&self.abi, "quoteExactInput", output)?;
+        Ok(out.0)
+    }
+
+    pub fn quote_exact_output_single_input(
+        &self,
+        token_in: H160,
+        token_out: H160,
+        amount_out: U256,
+        fee: u32,
+    ) -> Result<Bytes> {
+        let calldata = self.abi.encode(
+            "quoteExactOutputSingle",
+            ((token_in, token_out, amount_out, fee, U256::zero()),),
+        )?;
+        Ok(calldata)
+    }
+
+    pub fn quote_exact_output_single_output(&self, output: OutputBytes) -> Result<U256> {
+        let out: (U256, U256, u32, U256) =
+            decode_output(&self.abi, "quoteExactOutputSingle", output)?;
+        Ok(out.0)
+    }
+}
@@ -4,6 +4,8 @@ use ethers::abi::parse_abi;
 use ethers::prelude::BaseContract;
 use ethers::types::{Bytes, H160, U256};
 
+use crate::interfaces::decode_output;
+
 #[derive(Clone)]
 pub struct SimulatorABI {
     pub abi: BaseContract,
@@ -15,6 +17,7 @@ impl SimulatorABI {
             parse_abi(&[
                 "function v2SimulateSwap(uint256,address,address,address) external returns (uint256, uint256)",
                 "function getAmountOut(uint256,uint256,uint256) external returns (uint256)",
+                "function getAmountIn(uint256,uint256,uint256,uint256) external returns (uint256)",
             ]).unwrap()
         );
         Self { abi }
@@ -35,7 +38,7 @@ impl SimulatorABI {
     }
 
     pub fn v2_simulate_swap_output(&self, output: OutputBytes) -> Result<(U256, U256)> {
-        let out = self.abi.decode_output("v2SimulateSwap", output)?;
+        let out = decode_output(&self.abi, "v2SimulateSwap", output)?;
         Ok(out)
     }
 
@@ -52,7 +55,26 @@ impl SimulatorABI {
     }
 
     pub fn get_amount_out_output(&self, output: OutputBytes) -> Result<U256> {
-        let out = self.abi.decode_output("getAmountOut", output)?;
+        let out = decode_output(&self.abi, "getAmountOut", output)?;
+        Ok(out)
+    }
+
+    pub fn get_amount_in_input(
+        &self,
+        amount_out: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+        fee_bps: u32,
+    ) -> Result<Bytes> {
+        let calldata = self.abi.encode(
+            "getAmountIn",
+            (amount_out, reserve_in, reserve_out, U256::from(fee_bps)),
+        )?;
+        Ok(calldata)
+    }
+
+    pub fn get_amount_in_output(&self, output: OutputBytes) -> Result<U256> {
+        let out = decode_output(&self.abi, "getAmountIn", output)?;
         Ok(out)
     }
 }
@@ -2,7 +2,10 @@ use anyhow::Result;
 use bytes::Bytes as OutputBytes;
 use ethers::abi::parse_abi;
 use ethers::prelude::BaseContract;
-use ethers::types::{Bytes, H160, U256};
+use ethers::types::{Bytes, H160, I256, U256};
+use ethers::utils::id;
+
+use crate::interfaces::decode_output;
 
 #[derive(Clone)]
 pub struct TokenABI {
@@ -15,6 +18,13 @@ impl TokenABI {
             parse_abi(&[
                 "function balanceOf(address) external view returns (uint256)",
                 "function approve(address spender, uint256 value) external view returns (bool)",
+                "function transfer(address to, uint256 value) external returns (bool)",
+                "function transferFrom(address from, address to, uint256 value) external returns (bool)",
+                "function allowance(address owner, address spender) external view returns (uint256)",
+                "function totalSupply() external view returns (uint256)",
+                "function monetaryPolicy() external view returns (address)",
+                "function rebase() external",
+                "function rebase(uint256 epoch, int256 supplyDelta) external returns (uint256)",
             ])
             .unwrap(),
         );
@@ -27,7 +37,7 @@ impl TokenABI {
     }
 
     pub fn balance_of_output(&self, output: OutputBytes) -> Result<U256> {
-        let out = self.abi.decode_output("balanceOf", output)?;
+        let out = decode_output(&self.abi, "balanceOf", output)?;
         Ok(out)
     }
 
@@ -37,7 +47,165 @@ impl TokenABI {
     }
 
     pub fn approve_output(&self, output: OutputBytes) -> Result<bool> {
-        let out = self.abi.decode_output("approve", output)?;
+        // same as transfer, no return data means success
+        if output.is_empty() {
+            return Ok(true);
+        }
+        let out = decode_output(&self.abi, "approve", output)?;
+        Ok(out)
+    }
+
+    pub fn transfer_input(&self, to: H160, amount: U256) -> Result<Bytes> {
+        let calldata = self.abi.encode("transfer", (to, amount))?;
+        Ok(calldata)
+    }
+
+    pub fn transfer_output(&self, output: OutputBytes) -> Result<bool> {
+        // tokens that don't return a bool (ex. USDT) are treated as successful
+        if output.is_empty() {
+            return Ok(true);
+        }
+        let out = decode_output(&self.abi, "transfer", output)?;
+        Ok(out)
+    }
+
+    pub fn transfer_from_input(&self, from: H160, to: H160, amount: U256) -> Result<Bytes> {
+        let calldata = self.abi.encode("transferFrom", (from, to, amount))?;
+        Ok(calldata)
+    }
+
+    pub fn transfer_from_output(&self, output: OutputBytes) -> Result<bool> {
+        // same as transfer, no return data means success
+        if output.is_empty() {
+            return Ok(true);
+        }
+        let out = decode_output(&self.abi, "transferFrom", output)?;
+        Ok(out)
+    }
+
+    pub fn allowance_input(&self, owner: H160, spender: H160) -> Result<Bytes> {
+        let calldata = self.abi.encode("allowance", (owner, spender))?;
+        Ok(calldata)
+    }
+
+    pub fn allowance_output(&self, output: OutputBytes) -> Result<U256> {
+        let out = decode_output(&self.abi, "allowance", output)?;
+        Ok(out)
+    }
+
+    pub fn total_supply_input(&self) -> Result<Bytes> {
+        let calldata = self.abi.encode("totalSupply", ())?;
+        Ok(calldata)
+    }
+
+    pub fn total_supply_output(&self, output: OutputBytes) -> Result<U256> {
+        let out = decode_output(&self.abi, "totalSupply", output)?;
+        Ok(out)
+    }
+
+    pub fn monetary_policy_input(&self) -> Result<Bytes> {
+        let calldata = self.abi.encode("monetaryPolicy", ())?;
+        Ok(calldata)
+    }
+
+    pub fn monetary_policy_output(&self, output: OutputBytes) -> Result<H160> {
+        let out = decode_output(&self.abi, "monetaryPolicy", output)?;
         Ok(out)
     }
+
+    pub fn rebase_input(&self) -> Result<Bytes> {
+        // rebase is overloaded, so both are encoded by selector
+        let calldata = self.abi.encode_with_selector(id("rebase()"), ())?;
+        Ok(calldata)
+    }
+
+    pub fn rebase_supply_input(&self, epoch: U256, supply_delta: I256) -> Result<Bytes> {
+        // AMPL's rebase, only callable by its monetary policy
+        let calldata = self
+            .abi
+            .encode_with_selector(id("rebase(uint256,int256)"), (epoch, supply_delta))?;
+        Ok(calldata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{self, Token};
+
+    fn output(tokens: &[Token]) -> OutputBytes {
+        OutputBytes::from(abi::encode(tokens))
+    }
+
+    #[test]
+    fn test_token_abi_inputs() {
+        let token = TokenABI::new();
+        let (owner, spender) = (H160::random(), H160::random());
+        let amount = U256::from(1000);
+
+        let transfer = token.transfer_input(spender, amount).unwrap();
+        assert_eq!(transfer[..4], id("transfer(address,uint256)"));
+        assert_eq!(
+            transfer[4..],
+            abi::encode(&[Token::Address(spender), Token::Uint(amount)])
+        );
+
+        let transfer_from = token.transfer_from_input(owner, spender, amount).unwrap();
+        assert_eq!(
+            transfer_from[..4],
+            id("transferFrom(address,address,uint256)")
+        );
+        assert_eq!(
+            transfer_from[4..],
+            abi::encode(&[
+                Token::Address(owner),
+                Token::Address(spender),
+                Token::Uint(amount)
+            ])
+        );
+
+        let allowance = token.allowance_input(owner, spender).unwrap();
+        assert_eq!(allowance[..4], id("allowance(address,address)"));
+        assert_eq!(
+            allowance[4..],
+            abi::encode(&[Token::Address(owner), Token::Address(spender)])
+        );
+
+        let total_supply = token.total_supply_input().unwrap();
+        assert_eq!(total_supply[..], id("totalSupply()"));
+    }
+
+    #[test]
+    fn test_token_abi_outputs() {
+        let token = TokenABI::new();
+        let amount = U256::from(1000);
+
+        assert!(token.transfer_output(output(&[Token::Bool(true)])).unwrap());
+        assert!(!token
+            .transfer_output(output(&[Token::Bool(false)]))
+            .unwrap());
+        assert!(!token
+            .transfer_from_output(output(&[Token::Bool(false)]))
+            .unwrap());
+        assert_eq!(
+            token
+                .allowance_output(output(&[Token::Uint(amount)]))
+                .unwrap(),
+            amount
+        );
+        assert_eq!(
+            token
+                .total_supply_output(output(&[Token::Uint(amount)]))
+                .unwrap(),
+            amount
+        );
+
+        // tokens like USDT return nothing from transfer, transferFrom and approve
+        assert!(token.transfer_output(OutputBytes::new()).unwrap());
+        assert!(token.transfer_from_output(OutputBytes::new()).unwrap());
+        assert!(token.approve_output(OutputBytes::new()).unwrap());
+        // but a view returning nothing is an error
+        assert!(token.allowance_output(OutputBytes::new()).is_err());
+        assert!(token.total_supply_output(OutputBytes::new()).is_err());
+    }
 }
@@ -0,0 +1,32 @@
+use anyhow::Result;
+use ethers::abi::parse_abi;
+use ethers::prelude::BaseContract;
+use ethers::types::{Bytes, U256};
+
+#[derive(Clone)]
+pub struct WethABI {
+    pub abi: BaseContract,
+}
+
+impl WethABI {
+    pub fn new() -> Self {
+        let abi = BaseContract::from(
+            parse_abi(&[
+                "function deposit() external payable",
+                "function withdraw(uint256) external",
+            ])
+            .unwrap(),
+        );
+        Self { abi }
+    }
+
+    pub fn deposit_input(&self) -> Result<Bytes> {
+        let calldata = self.abi.encode("deposit", ())?;
+        Ok(calldata)
+    }
+
+    pub fn withdraw_input(&self, amount: U256) -> Result<Bytes> {
+        let calldata = self.abi.encode("withdraw", amount)?;
+        Ok(calldata)
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Result};
+use csv::{StringRecord, Writer};
+use ethers::types::{H160, U256, U64};
+use std::{
+    fs::{File, OpenOptions},
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::streams::OpportunityKind;
+
+const HEADER: [&str; 8] = [
+    "timestamp",
+    "block_number",
+    "kind",
+    "pool",
+    "token",
+    "amount_in",
+    "profit",
+    "gas_used",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerRecord {
+    pub timestamp: u64,
+    pub block_number: U64,
+    pub kind: OpportunityKind,
+    // the sandwiched pool, or the first pool of an arbitrage path
+    pub pool: H160,
+    // token amount_in is denominated in
+    pub token: H160,
+    pub amount_in: U256,
+    // in wei, net of gas
+    pub profit: i128,
+    pub gas_used: u64,
+}
+
+impl LedgerRecord {
+    fn to_record(&self) -> Vec<String> {
+        vec![
+            self.timestamp.to_string(),
+            self.block_number.to_string(),
+            match self.kind {
+                OpportunityKind::Sandwich => "sandwich".to_string(),
+                OpportunityKind::Arbitrage => "arbitrage".to_string(),
+            },
+            format!("{:?}", self.pool),
+            format!("{:?}", self.token),
+            self.amount_in.to_string(),
+            self.profit.to_string(),
+            self.gas_used.to_string(),
+        ]
+    }
+}
+
+impl TryFrom<StringRecord> for LedgerRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(record: StringRecord) -> Result<Self> {
+        let field = |i: usize| {
+            record
+                .get(i)
+                .ok_or(anyhow!("ledger record is missing {}", HEADER[i]))
+        };
+        Ok(Self {
+            timestamp: field(0)?.parse()?,
+            block_number: U64::from_dec_str(field(1)?)?,
+            kind: match field(2)? {
+                "sandwich" => OpportunityKind::Sandwich,
+                "arbitrage" => OpportunityKind::Arbitrage,
+                kind => return Err(anyhow!("unknown opportunity kind: {}", kind)),
+            },
+            pool: H160::from_str(field(3)?)?,
+            token: H160::from_str(field(4)?)?,
+            amount_in: U256::from_dec_str(field(5)?)?,
+            profit: field(6)?.parse()?,
+            gas_used: field(7)?.parse()?,
+        })
+    }
+}
+
+pub struct ProfitLedger {
+    writer: Writer<File>,
+    // buffered records are written out at most this long after being recorded
+    pub flush_interval: Duration,
+    last_flush: Instant,
+}
+
+impl ProfitLedger {
+    pub fn open(file_path: &Path, flush_interval: Duration) -> Result<Self> {
+        // Appends to an existing ledger, the header is only written to a new file
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path)?;
+        let is_new = file.metadata()?.len() == 0;
+        let mut writer = Writer::from_writer(file);
+        if is_new {
+            writer.write_record(&HEADER)?;
+            writer.flush()?;
+        }
+        Ok(Self {
+            writer,
+            flush_interval,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, record: &LedgerRecord) -> Result<()> {
+        self.writer.write_record(&record.to_record())?;
+        self.flush_if_due()
+    }
+
+    pub fn flush_if_due(&mut self) -> Result<()> {
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for ProfitLedger {
+    fn drop(&mut self) {
+        _ = self.writer.flush();
+    }
+}
+
+pub fn load_ledger(file_path: &Path) -> Result<Vec<LedgerRecord>> {
+    let mut reader = csv::Reader::from_path(file_path)?;
+    reader
+        .records()
+        .map(|record| LedgerRecord::try_from(record?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(block_number: u64, kind: OpportunityKind, profit: i128) -> LedgerRecord {
+        LedgerRecord {
+            timestamp: 1700000000 + block_number,
+            block_number: U64::from(block_number),
+            kind,
+            pool: H160::random(),
+            token: H160::random(),
+            amount_in: U256::MAX - U256::from(block_number),
+            profit,
+            gas_used: 250000,
+        }
+    }
+
+    #[test]
+    fn test_ledger_round_trip() {
+        let file_path = std::env::temp_dir().join(format!("ledger-{:?}.csv", H160::random()));
+        let records = vec![
+            record(18000000, OpportunityKind::Sandwich, 12345678901234567890),
+            record(18000001, OpportunityKind::Arbitrage, -42),
+        ];
+
+        let mut ledger = ProfitLedger::open(&file_path, Duration::from_secs(3600)).unwrap();
+        for record in &records {
+            ledger.record(record).unwrap();
+        }
+        // buffered until the flush interval passes
+        assert!(load_ledger(&file_path).unwrap().is_empty());
+        ledger.flush().unwrap();
+        assert_eq!(load_ledger(&file_path).unwrap(), records);
+
+        // reopening appends below the existing records without a second header
+        drop(ledger);
+        let appended = record(18000002, OpportunityKind::Sandwich, 0);
+        let mut ledger = ProfitLedger::open(&file_path, Duration::ZERO).unwrap();
+        ledger.record(&appended).unwrap();
+        let loaded = load_ledger(&file_path).unwrap();
+        std::fs::remove_file(&file_path).unwrap();
+
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded[..2], records[..]);
+        assert_eq!(loaded[2], appended);
+    }
+}
@@ -1,9 +1,20 @@
 pub mod arbitrage;
+pub mod breaker;
+pub mod bundle;
+pub mod chain;
 pub mod constants;
+pub mod control;
+pub mod failover;
 pub mod honeypot;
 pub mod interfaces;
+pub mod ledger;
+pub mod mempool;
+pub mod metrics;
 pub mod paths;
 pub mod pools;
+pub mod pricing;
+pub mod quoter;
+pub mod route;
 pub mod sandwich;
 pub mod simulator;
 pub mod strategy;
@@ -11,3 +22,4 @@ pub mod streams;
 pub mod tokens;
 pub mod trace;
 pub mod utils;
+pub mod watchdog;
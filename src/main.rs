@@ -1,20 +1,25 @@
 use anyhow::Result;
-use cfmms::dex::DexVariant;
 use ethers::providers::{Middleware, Provider, Ws};
 use ethers::types::{BlockNumber, H160, U256};
 use log::info;
-use std::{str::FromStr, sync::Arc};
+use std::{collections::HashMap, path::Path, str::FromStr, sync::Arc, time::Duration};
 use tokio::sync::broadcast::{self, Sender};
 use tokio::task::JoinSet;
 
-use evm_simulation::arbitrage::{simulate_triangular_arbitrage, TriangularArbitrage};
+use evm_simulation::arbitrage::{simulate_paths_batch, TriangularArbitrage};
 use evm_simulation::constants::Env;
+use evm_simulation::failover::connect_failover;
 use evm_simulation::honeypot::HoneypotFilter;
-use evm_simulation::paths::generate_triangular_paths;
-use evm_simulation::pools::{load_all_pools, Pool};
+use evm_simulation::paths::{fetch_path_reserves, generate_triangular_paths, prescreen_paths};
+use evm_simulation::pools::{build_verified_pools, load_all_pools};
+use evm_simulation::pricing::{EthPriceOracle, UsdPricer};
+use evm_simulation::simulator::{EvmSimulator, SimulatorConfig};
 use evm_simulation::strategy::event_handler;
-use evm_simulation::streams::{stream_new_blocks, stream_pending_transactions, Event};
-use evm_simulation::utils::setup_logger;
+use evm_simulation::streams::{
+    stream_new_blocks, stream_pending_transactions, BlockContext, Event,
+};
+use evm_simulation::tokens::load_tokens_csv;
+use evm_simulation::utils::{run_until_shutdown, setup_logger};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -27,88 +32,126 @@ async fn main() -> Result<()> {
     let ws = Ws::connect(&env.wss_url).await.unwrap();
     let provider = Arc::new(Provider::new(ws));
 
+    // Every RPC call goes through the failover provider, only the subscriptions stay on the main endpoint
+    let mut urls = vec![env.wss_url.clone()];
+    urls.extend(env.fallback_wss_urls.clone());
+    let sim_provider = Arc::new(connect_failover(urls, Duration::from_secs(5)).await?);
+
     let block = provider
         .get_block(BlockNumber::Latest)
         .await
         .unwrap()
         .unwrap();
 
-    let factories = vec![
-        (
-            // Uniswap v2
-            "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f",
-            DexVariant::UniswapV2,
-            10000835u64,
-        ),
-        (
-            // Sushiswap V2
-            "0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac",
-            DexVariant::UniswapV2,
-            10794229u64,
-        ),
-    ];
-    let pools = load_all_pools(env.wss_url.clone(), factories).await?;
-
-    let mut honeypot_filter = HoneypotFilter::new(provider.clone(), block.clone());
-    honeypot_filter.setup().await;
+    let sim_config = SimulatorConfig::from_env(&env)?;
+    let chain = sim_config.chain.clone();
+    let pools = load_all_pools(env.wss_url.clone(), chain.factories.clone()).await?;
+
+    let mut honeypot_filter =
+        HoneypotFilter::new(sim_provider.clone(), block.clone(), sim_config.clone());
     honeypot_filter
-        .filter_tokens(&pools[0..5000].to_vec())
-        .await;
-
-    let verified_pools: Vec<Pool> = pools
-        .into_iter()
-        .filter(|pool| {
-            let token0_verified = honeypot_filter.safe_token_info.contains_key(&pool.token0)
-                || honeypot_filter.token_info.contains_key(&pool.token0);
-            let token1_verified = honeypot_filter.safe_token_info.contains_key(&pool.token1)
-                || honeypot_filter.token_info.contains_key(&pool.token1);
-            token0_verified && token1_verified
-        })
-        .collect();
+        .simulator
+        .health_check(Duration::from_secs(5))
+        .await?;
+    honeypot_filter.simulator.warmup()?;
+    for (token, test_amount) in &env.extra_safe_tokens {
+        honeypot_filter.add_safe_token(*token, *test_amount);
+    }
+    honeypot_filter.set_balance_slot_overrides(env.balance_slot_overrides.clone());
+    honeypot_filter.setup().await?;
+    match &env.trusted_tokens_file {
+        Some(path) => honeypot_filter.trust_tokens(load_tokens_csv(Path::new(path))?),
+        None => {
+            honeypot_filter
+                .filter_tokens(&pools[0..5000].to_vec())
+                .await
+        }
+    }
+
+    let (verified_pools, _) = build_verified_pools(pools, &honeypot_filter);
     info!("Verified pools: {:?} pools", verified_pools.len());
 
-    let usdt = H160::from_str("0xdAC17F958D2ee523a2206206994597C13D831ec7").unwrap();
-    let arb_paths = generate_triangular_paths(&verified_pools, usdt);
+    let usd_pricer = Arc::new(UsdPricer::new(&chain, &verified_pools));
+    // USDT profits are valued in wei through the ETH price, so gas can be taken off them
+    let eth_oracle = Arc::new(EthPriceOracle::new(&chain, &verified_pools));
 
+    let usdt = chain.usdt;
     let owner = H160::from_str("0x001a06BF8cE4afdb3f5618f6bafe35e9Fc09F187").unwrap();
+    let arb_paths = generate_triangular_paths(&verified_pools, usdt);
+    honeypot_filter.simulator.flush_cache();
+    let arb_paths = match env.path_min_profit_bps {
+        Some(min_profit_bps) => {
+            // the honeypot simulator's pools were moved by the test swaps, read from a fresh fork
+            let mut simulator = EvmSimulator::new_with_config(
+                sim_provider.clone(),
+                owner,
+                block.number.unwrap(),
+                sim_config.clone(),
+            );
+            let reserves = fetch_path_reserves(&arb_paths, &mut simulator);
+            prescreen_paths(arb_paths, &reserves, min_profit_bps)
+        }
+        None => arb_paths,
+    };
+
     let amount_in = U256::from(10)
         .checked_mul(U256::from(10).pow(U256::from(6)))
         .unwrap();
     let balance_slot = honeypot_filter.balance_slots.get(&usdt).unwrap();
     let target_token = honeypot_filter.safe_token_info.get(&usdt).unwrap();
-    for path in &arb_paths {
-        let arb = TriangularArbitrage {
+    let arbs = arb_paths
+        .iter()
+        .map(|path| TriangularArbitrage {
             amount_in,
             path: path.clone(),
             balance_slot: *balance_slot,
+            balance_account: honeypot_filter.balance_account(usdt),
             target_token: target_token.clone(),
-        };
-        match simulate_triangular_arbitrage(
-            arb,
-            provider.clone(),
-            owner,
-            block.number.unwrap(),
-            None,
-        ) {
-            Ok(profit) => {}
-            Err(e) => {}
-        }
+            token_taxes: HashMap::new(),
+            usd_pricer: Some(usd_pricer.clone()),
+            eth_oracle: Some(eth_oracle.clone()),
+            flashloan_fee_bps: None,
+            max_hop_slippage_bps: None,
+        })
+        .collect();
+    let block_context = BlockContext::from_block(&block);
+    let results = simulate_paths_batch(
+        arbs,
+        sim_provider.clone(),
+        owner,
+        &sim_config,
+        block_context,
+        block_context.next_base_fee,
+        16,
+    )
+    .await?;
+    if let Some((path, result)) = results.first() {
+        info!(
+            "Best path: {:?} -> {:?} -> {:?} / Profit: {:?}",
+            path.pool_1.address, path.pool_2.address, path.pool_3.address, result.profit
+        );
     }
 
-    // let (event_sender, _): (Sender<Event>, _) = broadcast::channel(512);
+    let (event_sender, _): (Sender<Event>, _) = broadcast::channel(env.event_channel_capacity);
+    // the handler's opportunities, subscribe to this one to act on them
+    let (opportunity_sender, _): (Sender<Event>, _) =
+        broadcast::channel(env.event_channel_capacity);
 
-    // let mut set = JoinSet::new();
+    let mut set = JoinSet::new();
 
-    // set.spawn(stream_new_blocks(provider.clone(), event_sender.clone()));
-    // set.spawn(stream_pending_transactions(
-    //     provider.clone(),
-    //     event_sender.clone(),
-    // ));
-    // set.spawn(event_handler(provider.clone(), event_sender.clone()));
+    set.spawn(stream_new_blocks(provider.clone(), event_sender.clone()));
+    set.spawn(stream_pending_transactions(
+        provider.clone(),
+        event_sender.clone(),
+    ));
+    set.spawn(event_handler(
+        sim_provider.clone(),
+        event_sender.clone(),
+        opportunity_sender,
+    ));
 
-    // while let Some(res) = set.join_next().await {
-    //     info!("{:?}", res);
-    // }
+    drop(event_sender);
+    run_until_shutdown(set).await;
 
     Ok(())
 }
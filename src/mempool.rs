@@ -0,0 +1,564 @@
+use ethers::{
+    types::{Transaction, H160, H256, U256},
+    utils::id,
+};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone)]
+pub struct TxClassifier {
+    pub routers: HashSet<H160>,
+    pub selectors: HashSet<[u8; 4]>,
+}
+
+impl TxClassifier {
+    pub fn new(routers: Vec<H160>, signatures: Vec<&str>) -> Self {
+        Self {
+            routers: routers.into_iter().collect(),
+            selectors: signatures.into_iter().map(|sig| id(sig)).collect(),
+        }
+    }
+
+    pub fn default_mainnet() -> Self {
+        let routers = vec![
+            // Uniswap V2 Router
+            H160::from_str("0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D").unwrap(),
+            // Sushiswap Router
+            H160::from_str("0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F").unwrap(),
+            // Uniswap Universal Router
+            H160::from_str("0x3fC91A3afd70395Cd496C647d5a6CC9D4B2b7FAD").unwrap(),
+        ];
+        let signatures = vec![
+            "swap(uint256,uint256,address,bytes)",
+            "swapExactTokensForTokens(uint256,uint256,address[],address,uint256)",
+            "swapTokensForExactTokens(uint256,uint256,address[],address,uint256)",
+            "swapExactETHForTokens(uint256,address[],address,uint256)",
+            "swapTokensForExactETH(uint256,uint256,address[],address,uint256)",
+            "swapExactTokensForETH(uint256,uint256,address[],address,uint256)",
+            "swapETHForExactTokens(uint256,address[],address,uint256)",
+            "swapExactTokensForTokensSupportingFeeOnTransferTokens(uint256,uint256,address[],address,uint256)",
+            "swapExactETHForTokensSupportingFeeOnTransferTokens(uint256,address[],address,uint256)",
+            "swapExactTokensForETHSupportingFeeOnTransferTokens(uint256,uint256,address[],address,uint256)",
+        ];
+        Self::new(routers, signatures)
+    }
+
+    pub fn add_router(&mut self, router: H160) {
+        self.routers.insert(router);
+    }
+
+    pub fn add_selector(&mut self, signature: &str) {
+        self.selectors.insert(id(signature));
+    }
+
+    pub fn is_relevant(&self, tx: &Transaction) -> bool {
+        // A cheap check done before tracing: the tx should either call a known router/pool,
+        // or call a function with a known swap selector
+        let to = match tx.to {
+            Some(to) => to,
+            None => return false,
+        };
+        if self.routers.contains(&to) {
+            return true;
+        }
+        if tx.input.len() < 4 {
+            return false;
+        }
+        let mut selector = [0u8; 4];
+        selector.copy_from_slice(&tx.input[0..4]);
+        self.selectors.contains(&selector)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ScoredTx {
+    pub score: U256,
+    // arrival order, earlier txs win ties
+    pub seq: u64,
+    pub tx: Transaction,
+}
+
+impl PartialEq for ScoredTx {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ScoredTx {}
+
+impl PartialOrd for ScoredTx {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScoredTx {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .cmp(&other.score)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+// Nodes only accept a replacement raising both its fee cap and tip by this much (%),
+// geth's default txpool.pricebump
+pub const REPLACEMENT_PRICE_BUMP: u64 = 10;
+
+pub fn is_price_bump(old: U256, new: U256) -> bool {
+    // same rounding as geth: old * (100 + bump) / 100
+    let threshold = old.saturating_mul(U256::from(100 + REPLACEMENT_PRICE_BUMP)) / U256::from(100);
+    new >= threshold
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingTxQueue {
+    pub capacity: usize,
+    // pool/token address -> rough liquidity weight, ex) the number of pools a token trades in
+    pub liquidity: HashMap<H160, U256>,
+    pub heap: BinaryHeap<ScoredTx>,
+    pub seq: u64,
+    // (from, nonce) -> (hash, fee cap, tip) of the latest version of a tx
+    // Replaced versions are left in the heap and skipped when popped
+    pub latest: HashMap<(H160, U256), (H256, U256, U256)>,
+}
+
+impl PendingTxQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            liquidity: HashMap::new(),
+            heap: BinaryHeap::new(),
+            seq: 0,
+            latest: HashMap::new(),
+        }
+    }
+
+    pub fn add_liquidity(&mut self, address: H160, weight: U256) {
+        *self.liquidity.entry(address).or_default() += weight;
+    }
+
+    pub fn score(&self, tx: &Transaction) -> U256 {
+        // A cheap estimate of how much a tx could be worth, no tracing involved:
+        // gas price x liquidity of the pools/tokens it calls or mentions in its calldata
+        let gas_price = tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default();
+
+        let mut liquidity = U256::zero();
+        if let Some(to) = tx.to {
+            liquidity += self.liquidity.get(&to).copied().unwrap_or_default();
+        }
+        if tx.input.len() > 4 {
+            for word in tx.input[4..].chunks(32) {
+                // abi encoded addresses are left padded with 12 zero bytes
+                if word.len() == 32 && word[..12].iter().all(|b| *b == 0) {
+                    let address = H160::from_slice(&word[12..]);
+                    liquidity += self.liquidity.get(&address).copied().unwrap_or_default();
+                }
+            }
+        }
+
+        gas_price.saturating_mul(std::cmp::max(liquidity, U256::one()))
+    }
+
+    pub fn push(&mut self, tx: Transaction) -> bool {
+        // Returns false if the tx was shed because the queue is full of better candidates,
+        // or if it isn't a replacement nodes would accept for the version of it we already have
+        let key = (tx.from, tx.nonce);
+        let fee = tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default();
+        let tip = tx
+            .max_priority_fee_per_gas
+            .or(tx.gas_price)
+            .unwrap_or_default();
+        if let Some((_, latest_fee, latest_tip)) = self.latest.get(&key) {
+            if !is_price_bump(*latest_fee, fee) || !is_price_bump(*latest_tip, tip) {
+                return false;
+            }
+        }
+        let hash = tx.hash;
+
+        let scored = ScoredTx {
+            score: self.score(&tx),
+            seq: self.seq,
+            tx,
+        };
+        self.seq += 1;
+
+        if self.heap.len() >= self.capacity {
+            // the heap only exposes its max, finding the lowest is linear but only runs when full
+            let mut txs = std::mem::take(&mut self.heap).into_vec();
+            let lowest = match txs.iter().enumerate().min_by(|a, b| a.1.cmp(b.1)) {
+                Some((i, lowest)) if *lowest < scored => Some(i),
+                _ => None,
+            };
+            let queued = match lowest {
+                Some(i) => {
+                    let evicted = txs.swap_remove(i);
+                    if self.is_latest(&evicted.tx) {
+                        self.latest.remove(&(evicted.tx.from, evicted.tx.nonce));
+                    }
+                    txs.push(scored);
+                    true
+                }
+                None => false,
+            };
+            self.heap = BinaryHeap::from(txs);
+            // a shed tx never becomes the latest version, the one already queued stays poppable
+            if queued {
+                self.latest.insert(key, (hash, fee, tip));
+            }
+            return queued;
+        }
+
+        self.heap.push(scored);
+        self.latest.insert(key, (hash, fee, tip));
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<Transaction> {
+        while let Some(scored) = self.heap.pop() {
+            if self.is_latest(&scored.tx) {
+                return Some(scored.tx);
+            }
+        }
+        None
+    }
+
+    pub fn is_latest(&self, tx: &Transaction) -> bool {
+        match self.latest.get(&(tx.from, tx.nonce)) {
+            Some((hash, _, _)) => *hash == tx.hash,
+            None => true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+        self.latest.clear();
+    }
+}
+
+pub fn effective_priority_fee(tx: &Transaction, base_fee: U256) -> Option<U256> {
+    // The tip per gas the block builder would earn, None if the tx can't pay the base fee
+    let max_fee = tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default();
+    if max_fee < base_fee {
+        return None;
+    }
+    let tip = max_fee - base_fee;
+    Some(match tx.max_priority_fee_per_gas {
+        Some(max_priority_fee) => std::cmp::min(max_priority_fee, tip),
+        None => tip,
+    })
+}
+
+pub fn order_pending_block(
+    txs: Vec<Transaction>,
+    base_fee: U256,
+    max_txs: usize,
+) -> Vec<Transaction> {
+    // Orders txs the way a builder fills a block: highest tip first,
+    // but a sender's txs always go in nonce order, so only each sender's lowest nonce competes
+    // A sender's txs stop at the first one that can't pay the base fee
+    let mut senders: HashMap<H160, BTreeMap<U256, Transaction>> = HashMap::new();
+    for tx in txs {
+        let queue = senders.entry(tx.from).or_default();
+        // replacements of the same nonce keep the one paying more
+        let replace = match queue.get(&tx.nonce) {
+            Some(queued) => {
+                tx.max_fee_per_gas.or(tx.gas_price) > queued.max_fee_per_gas.or(queued.gas_price)
+            }
+            None => true,
+        };
+        if replace {
+            queue.insert(tx.nonce, tx);
+        }
+    }
+
+    let mut queues: HashMap<H160, std::vec::IntoIter<Transaction>> = senders
+        .into_iter()
+        .map(|(sender, queue)| (sender, queue.into_values().collect::<Vec<_>>().into_iter()))
+        .collect();
+
+    let mut heap = BinaryHeap::new();
+    let mut seq = 0;
+    for queue in queues.values_mut() {
+        push_next(&mut heap, queue, base_fee, &mut seq);
+    }
+
+    let mut ordered = Vec::new();
+    while ordered.len() < max_txs {
+        let scored = match heap.pop() {
+            Some(scored) => scored,
+            None => break,
+        };
+        if let Some(queue) = queues.get_mut(&scored.tx.from) {
+            push_next(&mut heap, queue, base_fee, &mut seq);
+        }
+        ordered.push(scored.tx);
+    }
+    ordered
+}
+
+pub fn txs_ahead_of(
+    ordered: &[Transaction],
+    victim: &Transaction,
+    base_fee: U256,
+) -> Vec<Transaction> {
+    // The txs of an ordered pending block that tip more than victim, which a builder puts before it
+    let victim_tip = effective_priority_fee(victim, base_fee).unwrap_or_default();
+    ordered
+        .iter()
+        .filter(|tx| tx.hash != victim.hash)
+        .filter(|tx| effective_priority_fee(tx, base_fee).unwrap_or_default() > victim_tip)
+        .cloned()
+        .collect()
+}
+
+fn push_next(
+    heap: &mut BinaryHeap<ScoredTx>,
+    queue: &mut std::vec::IntoIter<Transaction>,
+    base_fee: U256,
+    seq: &mut u64,
+) {
+    if let Some(tx) = queue.next() {
+        if let Some(score) = effective_priority_fee(&tx, base_fee) {
+            heap.push(ScoredTx {
+                score,
+                seq: *seq,
+                tx,
+            });
+            *seq += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gwei(amount: u64) -> U256 {
+        U256::from(amount) * U256::exp10(9)
+    }
+
+    fn pending_tx(from: H160, nonce: u64, max_fee: u64, tip: u64) -> Transaction {
+        Transaction {
+            hash: H256::random(),
+            from,
+            nonce: U256::from(nonce),
+            max_fee_per_gas: Some(gwei(max_fee)),
+            max_priority_fee_per_gas: Some(gwei(tip)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_only_swaps_are_forwarded_for_tracing() {
+        let call = |to: Option<H160>, signature: &str| Transaction {
+            hash: H256::random(),
+            to,
+            input: [id(signature).to_vec(), vec![0u8; 64]].concat().into(),
+            ..Default::default()
+        };
+        let router = H160::from_str("0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D").unwrap();
+        let (token, pair) = (H160::random(), H160::random());
+
+        let router_swap = call(
+            Some(router),
+            "swapExactETHForTokens(uint256,address[],address,uint256)",
+        );
+        // an unknown pair is still recognized by the swap selector
+        let pair_swap = call(Some(pair), "swap(uint256,uint256,address,bytes)");
+        let transfer = call(Some(token), "transfer(address,uint256)");
+        let deployment = call(None, "swap(uint256,uint256,address,bytes)");
+        let txs = vec![
+            router_swap.clone(),
+            transfer.clone(),
+            pair_swap.clone(),
+            deployment,
+        ];
+
+        let classifier = TxClassifier::default_mainnet();
+        let forwarded: Vec<H256> = txs
+            .iter()
+            .filter(|tx| classifier.is_relevant(tx))
+            .map(|tx| tx.hash)
+            .collect();
+        assert_eq!(forwarded, vec![router_swap.hash, pair_swap.hash]);
+
+        // routers and selectors are configurable
+        let mut classifier = TxClassifier::new(vec![], vec![]);
+        assert!(!classifier.is_relevant(&router_swap));
+        classifier.add_router(token);
+        assert!(classifier.is_relevant(&transfer));
+        classifier.add_selector("swap(uint256,uint256,address,bytes)");
+        assert!(classifier.is_relevant(&pair_swap));
+    }
+
+    #[test]
+    fn test_order_pending_block() {
+        let (a, b, c) = (H160::random(), H160::random(), H160::random());
+        let a0 = pending_tx(a, 0, 100, 1);
+        let a1 = pending_tx(a, 1, 100, 5);
+        let b0 = pending_tx(b, 0, 100, 3);
+        // can't pay the base fee, never included
+        let c0 = pending_tx(c, 0, 5, 50);
+        // a replacement of a0 paying more wins
+        let a0_replaced = pending_tx(a, 0, 120, 2);
+        let txs = vec![a1.clone(), c0, a0, b0.clone(), a0_replaced.clone()];
+
+        let ordered = order_pending_block(txs.clone(), gwei(10), 10);
+        let hashes: Vec<H256> = ordered.iter().map(|tx| tx.hash).collect();
+        // a1 tips the most, but has to wait for a's nonce 0
+        assert_eq!(hashes, vec![b0.hash, a0_replaced.hash, a1.hash]);
+
+        let ordered = order_pending_block(txs, gwei(10), 2);
+        assert_eq!(ordered.len(), 2);
+        assert_eq!(ordered[1].hash, a0_replaced.hash);
+    }
+
+    #[test]
+    fn test_queue_pops_by_score() {
+        let (pool, token) = (H160::random(), H160::random());
+        let mut queue = PendingTxQueue::new(10);
+        queue.add_liquidity(pool, U256::from(10));
+        queue.add_liquidity(token, U256::from(3));
+
+        // 100 gwei x 10 (calls the pool)
+        let mut to_pool = pending_tx(H160::random(), 0, 100, 1);
+        to_pool.to = Some(pool);
+        // 200 gwei x 3 (token in the calldata)
+        let mut mentions_token = pending_tx(H160::random(), 0, 200, 1);
+        let mut input = vec![0u8; 4];
+        input.extend_from_slice(H256::from(token).as_bytes());
+        mentions_token.input = input.into();
+        // 500 gwei x 1, nothing we know of
+        let unrelated = pending_tx(H160::random(), 0, 500, 1);
+
+        assert_eq!(queue.score(&to_pool), gwei(1000));
+        assert_eq!(queue.score(&mentions_token), gwei(600));
+        assert_eq!(queue.score(&unrelated), gwei(500));
+
+        for tx in [unrelated.clone(), mentions_token.clone(), to_pool.clone()] {
+            assert!(queue.push(tx));
+        }
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(to_pool.hash));
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(mentions_token.hash));
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(unrelated.hash));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_queue_ties_pop_in_arrival_order() {
+        let mut queue = PendingTxQueue::new(10);
+        let first = pending_tx(H160::random(), 0, 100, 1);
+        let second = pending_tx(H160::random(), 0, 100, 1);
+        queue.push(first.clone());
+        queue.push(second.clone());
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(first.hash));
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(second.hash));
+    }
+
+    #[test]
+    fn test_full_queue_sheds_the_lowest() {
+        let mut queue = PendingTxQueue::new(2);
+        let low = pending_tx(H160::random(), 0, 100, 1);
+        let mid = pending_tx(H160::random(), 0, 200, 1);
+        let high = pending_tx(H160::random(), 0, 300, 1);
+        let lowest = pending_tx(H160::random(), 0, 50, 1);
+        assert!(queue.push(low));
+        assert!(queue.push(mid.clone()));
+
+        // evicts low, then lowest doesn't beat anything queued
+        assert!(queue.push(high.clone()));
+        assert!(!queue.push(lowest));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(high.hash));
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(mid.hash));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_replacements() {
+        let sender = H160::random();
+        let mut queue = PendingTxQueue::new(10);
+        let original = pending_tx(sender, 0, 100, 1);
+        assert!(queue.push(original.clone()));
+
+        // the same fee isn't a replacement
+        let mut same_fee = original.clone();
+        same_fee.hash = H256::random();
+        assert!(!queue.push(same_fee));
+
+        // nodes reject bumps under 10%, on the fee cap as well as on the tip
+        assert!(!queue.push(pending_tx(sender, 0, 105, 2)));
+        assert!(!queue.push(pending_tx(sender, 0, 150, 1)));
+
+        // a 10% bump on both replaces it, the original is skipped when popped
+        let replacement = pending_tx(sender, 0, 110, 2);
+        assert!(queue.push(replacement.clone()));
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(replacement.hash));
+        assert!(queue.pop().is_none());
+
+        // clear forgets the versions seen so far
+        queue.clear();
+        assert!(queue.push(original.clone()));
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(original.hash));
+    }
+
+    #[test]
+    fn test_is_price_bump() {
+        assert!(is_price_bump(gwei(100), gwei(110)));
+        assert!(!is_price_bump(gwei(100), gwei(109)));
+        // rounds down like geth, 10 wei needs 11 but 9 wei only needs 9
+        assert!(is_price_bump(U256::from(10), U256::from(11)));
+        assert!(!is_price_bump(U256::from(10), U256::from(10)));
+        assert!(is_price_bump(U256::from(9), U256::from(9)));
+    }
+
+    #[test]
+    fn test_shed_replacement_keeps_the_queued_version() {
+        let pool = H160::random();
+        let mut queue = PendingTxQueue::new(2);
+        queue.add_liquidity(pool, U256::from(10));
+
+        let mut queued = pending_tx(H160::random(), 0, 100, 1);
+        queued.to = Some(pool);
+        let other = pending_tx(H160::random(), 0, 500, 1);
+        assert!(queue.push(queued.clone()));
+        assert!(queue.push(other.clone()));
+
+        // pays more, but without the pool's liquidity it scores below everything queued
+        let mut replacement = queued.clone();
+        replacement.hash = H256::random();
+        replacement.max_fee_per_gas = Some(gwei(110));
+        replacement.max_priority_fee_per_gas = Some(gwei(2));
+        replacement.to = None;
+        assert!(!queue.push(replacement));
+
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(queued.hash));
+        assert_eq!(queue.pop().map(|tx| tx.hash), Some(other.hash));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_txs_ahead_of() {
+        let first = pending_tx(H160::random(), 0, 100, 5);
+        let same_tip = pending_tx(H160::random(), 0, 100, 3);
+        let victim = pending_tx(H160::random(), 0, 100, 3);
+        let last = pending_tx(H160::random(), 0, 100, 1);
+        let ordered = vec![first.clone(), same_tip, victim.clone(), last];
+
+        let ahead = txs_ahead_of(&ordered, &victim, gwei(10));
+        let hashes: Vec<H256> = ahead.iter().map(|tx| tx.hash).collect();
+        assert_eq!(hashes, vec![first.hash]);
+    }
+}
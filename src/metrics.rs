@@ -0,0 +1,98 @@
+use ethers::prelude::Lazy;
+use log::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics::default());
+
+#[derive(Default, Debug)]
+pub struct Metrics {
+    pub pending_txs: AtomicU64,
+    pub traced_txs: AtomicU64,
+    pub touched_pools: AtomicU64,
+    pub simulations: AtomicU64,
+    pub simulation_successes: AtomicU64,
+    // events the handler fell too far behind to receive
+    pub dropped_events: AtomicU64,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct MetricsSnapshot {
+    pub pending_txs: u64,
+    pub traced_txs: u64,
+    pub touched_pools: u64,
+    pub simulations: u64,
+    pub simulation_successes: u64,
+    pub simulation_failures: u64,
+    pub dropped_events: u64,
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let simulations = self.simulations.load(Ordering::Relaxed);
+        let simulation_successes = self.simulation_successes.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            pending_txs: self.pending_txs.load(Ordering::Relaxed),
+            traced_txs: self.traced_txs.load(Ordering::Relaxed),
+            touched_pools: self.touched_pools.load(Ordering::Relaxed),
+            simulations,
+            simulation_successes,
+            // simulations that returned early with an error never reach the success counter
+            simulation_failures: simulations.saturating_sub(simulation_successes),
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn log(&self) {
+        let s = self.snapshot();
+        info!(
+            "📊 Pending: {} / Traced: {} / Touched pools: {} / Simulations: {} ({} ok, {} failed) / Dropped events: {}",
+            s.pending_txs,
+            s.traced_txs,
+            s.touched_pools,
+            s.simulations,
+            s.simulation_successes,
+            s.simulation_failures,
+            s.dropped_events
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_add_up_over_an_event_flow() {
+        let metrics = Metrics::default();
+
+        // three pending txs, two of them traced and touching pools
+        for touched in [Some(2), None, Some(1)] {
+            Metrics::add(&metrics.pending_txs, 1);
+            if let Some(pools) = touched {
+                Metrics::add(&metrics.traced_txs, 1);
+                Metrics::add(&metrics.touched_pools, pools);
+            }
+        }
+        // each touched pool is simulated once, two of them succeed
+        for ok in [true, false, true] {
+            Metrics::add(&metrics.simulations, 1);
+            if ok {
+                Metrics::add(&metrics.simulation_successes, 1);
+            }
+        }
+        Metrics::add(&metrics.dropped_events, 4);
+
+        let s = metrics.snapshot();
+        assert_eq!(s.pending_txs, 3);
+        assert_eq!(s.traced_txs, 2);
+        assert_eq!(s.touched_pools, 3);
+        assert_eq!(s.simulations, 3);
+        assert_eq!(s.simulation_successes, 2);
+        assert_eq!(s.simulation_failures, 1);
+        assert_eq!(s.dropped_events, 4);
+    }
+}
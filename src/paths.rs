@@ -1,9 +1,14 @@
-use ethers::types::H160;
+use ethers::{
+    types::{H160, H256},
+    utils::keccak256,
+};
 use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
-use std::time::Instant;
+use log::info;
+use std::{collections::HashMap, time::Instant};
 
-use crate::pools::Pool;
+use crate::pools::{DexVariant, Pool};
+use crate::simulator::SwapSimulator;
 
 #[derive(Debug, Clone)]
 pub struct ArbPath {
@@ -36,15 +41,203 @@ impl ArbPath {
         }
         .unwrap()
     }
+
+    pub fn hash(&self) -> H256 {
+        // Identifies the pools and the direction of every hop, reversed paths hash differently
+        let mut seed = Vec::new();
+        for n in 0..self.nhop {
+            seed.extend_from_slice(self.get_pool(n).address.as_bytes());
+            seed.push(self.get_zero_for_one(n) as u8);
+        }
+        H256::from(keccak256(seed))
+    }
+
+    pub fn estimate_rate(&self, reserves: &HashMap<H160, (u128, u128)>) -> Option<f64> {
+        // Product of the fee-adjusted spot prices of every hop, above 1.0 looks profitable
+        // None if a pool's reserves aren't known, ex) V3 and Curve pools
+        let mut rate = 1.0;
+        for n in 0..self.nhop {
+            let pool = self.get_pool(n);
+            let (reserve0, reserve1) = reserves.get(&pool.address)?;
+            let token_in = if self.get_zero_for_one(n) {
+                pool.token0
+            } else {
+                pool.token1
+            };
+            let price = pool.spot_price(*reserve0, *reserve1, token_in);
+            rate *= price * (1.0 - pool.fee_bps() as f64 / 10000.0);
+        }
+        Some(rate)
+    }
+
+    pub fn reverse(&self) -> Self {
+        // Same cycle traded the other way around: hops in reverse order, each in the other direction
+        Self {
+            nhop: self.nhop,
+            pool_1: self.pool_3.clone(),
+            pool_2: self.pool_2.clone(),
+            pool_3: self.pool_1.clone(),
+            zero_for_one_1: !self.zero_for_one_3,
+            zero_for_one_2: !self.zero_for_one_2,
+            zero_for_one_3: !self.zero_for_one_1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PoolGraph {
+    pub pools: Vec<Pool>,
+    // token -> indices of the pools trading it
+    pub adjacency: HashMap<H160, Vec<usize>>,
+    // pool address -> index, so pools added again are skipped
+    pub index: HashMap<H160, usize>,
+}
+
+impl PoolGraph {
+    pub fn new(pools: &Vec<Pool>) -> Self {
+        let mut graph = Self::default();
+        for pool in pools {
+            graph.add_pool(pool.clone());
+        }
+        graph
+    }
+
+    pub fn add_pool(&mut self, pool: Pool) {
+        if self.index.contains_key(&pool.address) {
+            return;
+        }
+        let idx = self.pools.len();
+        self.index.insert(pool.address, idx);
+        self.adjacency.entry(pool.token0).or_default().push(idx);
+        self.adjacency.entry(pool.token1).or_default().push(idx);
+        self.pools.push(pool);
+    }
+
+    pub fn get_pool(&self, address: H160) -> Option<&Pool> {
+        self.index.get(&address).map(|idx| &self.pools[*idx])
+    }
+
+    pub fn pools_of(&self, token: H160) -> Vec<&Pool> {
+        self.adjacency
+            .get(&token)
+            .into_iter()
+            .flatten()
+            .map(|idx| &self.pools[*idx])
+            .collect()
+    }
+
+    pub fn neighbors(&self, token: H160) -> Vec<H160> {
+        // tokens one swap away, each listed once
+        self.pools_of(token)
+            .into_iter()
+            .filter_map(|pool| pool.token_out(token))
+            .unique()
+            .collect()
+    }
+
+    pub fn pools_between(&self, token_a: H160, token_b: H160) -> Vec<&Pool> {
+        self.pools_of(token_a)
+            .into_iter()
+            .filter(|pool| pool.token_out(token_a) == Some(token_b))
+            .collect()
+    }
+
+    pub fn triangular_paths(&self, token_in: H160) -> Vec<ArbPath> {
+        // token_in -> token_1 -> token_2 -> token_in, only walking pools that trade each token
+        let mut paths = Vec::new();
+        for pool_1 in self.pools_of(token_in) {
+            let token_1 = pool_1.token_out(token_in).unwrap();
+            for pool_2 in self.pools_of(token_1) {
+                let token_2 = pool_2.token_out(token_1).unwrap();
+                for pool_3 in self.pools_between(token_2, token_in) {
+                    if let Some(path) =
+                        triangular_path([(pool_1, token_in), (pool_2, token_1), (pool_3, token_2)])
+                    {
+                        paths.push(path);
+                    }
+                }
+            }
+        }
+        paths
+    }
+
+    pub fn paths_through(&self, pool: H160, token_in: H160) -> Vec<ArbPath> {
+        // The triangular paths from token_in trading through pool at any hop, in either direction
+        let target = match self.get_pool(pool) {
+            Some(target) => target,
+            None => return Vec::new(),
+        };
+        let mut paths = Vec::new();
+        for (a, b) in [
+            (target.token0, target.token1),
+            (target.token1, target.token0),
+        ] {
+            // first hop: token_in = a -> b -> token_2 -> token_in
+            if a == token_in {
+                for pool_2 in self.pools_of(b) {
+                    let token_2 = pool_2.token_out(b).unwrap();
+                    for pool_3 in self.pools_between(token_2, token_in) {
+                        paths.extend(triangular_path([
+                            (target, a),
+                            (pool_2, b),
+                            (pool_3, token_2),
+                        ]));
+                    }
+                }
+            }
+            // second hop: token_in -> a -> b -> token_in
+            for pool_1 in self.pools_between(token_in, a) {
+                for pool_3 in self.pools_between(b, token_in) {
+                    paths.extend(triangular_path([
+                        (pool_1, token_in),
+                        (target, a),
+                        (pool_3, b),
+                    ]));
+                }
+            }
+            // third hop: token_in -> token_1 -> a -> b = token_in
+            if b == token_in {
+                for pool_1 in self.pools_of(token_in) {
+                    let token_1 = pool_1.token_out(token_in).unwrap();
+                    for pool_2 in self.pools_between(token_1, a) {
+                        paths.extend(triangular_path([
+                            (pool_1, token_in),
+                            (pool_2, token_1),
+                            (target, a),
+                        ]));
+                    }
+                }
+            }
+        }
+        paths.into_iter().unique_by(|path| path.hash()).collect()
+    }
+}
+
+fn triangular_path(hops: [(&Pool, H160); 3]) -> Option<ArbPath> {
+    // (pool, token sold into it) for every hop, a pool can only be used once
+    let unique_pool_cnt = hops.iter().map(|(pool, _)| pool.address).unique().count();
+    if unique_pool_cnt < 3 {
+        return None;
+    }
+    let [(pool_1, token_1), (pool_2, token_2), (pool_3, token_3)] = hops;
+    Some(ArbPath {
+        nhop: 3,
+        pool_1: pool_1.clone(),
+        pool_2: pool_2.clone(),
+        pool_3: pool_3.clone(),
+        zero_for_one_1: pool_1.token0 == token_1,
+        zero_for_one_2: pool_2.token0 == token_2,
+        zero_for_one_3: pool_3.token0 == token_3,
+    })
 }
 
 pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPath> {
     let start_time = Instant::now();
 
-    let token_out = token_in.clone();
-    let mut paths = Vec::new();
+    let graph = PoolGraph::new(pools);
+    let first_pools = graph.pools_of(token_in);
 
-    let pb = ProgressBar::new(pools.len() as u64);
+    let pb = ProgressBar::new(first_pools.len() as u64);
     pb.set_style(
         ProgressStyle::with_template(
             "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
@@ -53,77 +246,34 @@ pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPa
         .progress_chars("##-"),
     );
 
-    for i in 0..pools.len() {
-        let pool_1 = &pools[i];
-        let can_trade_1 = (pool_1.token0 == token_in) || (pool_1.token1 == token_in);
-
-        if can_trade_1 {
-            let zero_for_one_1 = pool_1.token0 == token_in;
-            let token_out_1 = if zero_for_one_1 {
-                pool_1.token1
-            } else {
-                pool_1.token0
-            };
-
-            for j in 0..pools.len() {
-                let pool_2 = &pools[j];
-                let can_trade_2 = (pool_2.token0 == token_out_1) || (pool_2.token1 == token_out_1);
-
-                if can_trade_2 {
-                    let zero_for_one_2 = pool_2.token0 == token_out_1;
-                    let token_out_2 = if zero_for_one_2 {
-                        pool_2.token1
-                    } else {
-                        pool_2.token0
-                    };
-
-                    for k in 0..pools.len() {
-                        let pool_3 = &pools[k];
-                        let can_trade_3 =
-                            (pool_3.token0 == token_out_2) || (pool_3.token1 == token_out_2);
-
-                        if can_trade_3 {
-                            let zero_for_one_3 =
-                                (pool_3.token0 == token_out_2) || (pool_3.token1 == token_out_2);
-                            let token_out_3 = if zero_for_one_3 {
-                                pool_3.token1
-                            } else {
-                                pool_3.token0
-                            };
-
-                            if token_out_3 == token_out {
-                                let unique_pool_cnt =
-                                    vec![pool_1.address, pool_2.address, pool_3.address]
-                                        .into_iter()
-                                        .unique()
-                                        .collect::<Vec<H160>>()
-                                        .len();
-
-                                if unique_pool_cnt < 3 {
-                                    continue;
-                                }
-
-                                let arb_path = ArbPath {
-                                    nhop: 3,
-                                    pool_1: pool_1.clone(),
-                                    pool_2: pool_2.clone(),
-                                    pool_3: pool_3.clone(),
-                                    zero_for_one_1: zero_for_one_1,
-                                    zero_for_one_2: zero_for_one_2,
-                                    zero_for_one_3: zero_for_one_3,
-                                };
-
-                                paths.push(arb_path);
-                            }
-                        }
-                    }
+    let mut paths = Vec::new();
+    for pool_1 in first_pools {
+        let token_1 = pool_1.token_out(token_in).unwrap();
+        for pool_2 in graph.pools_of(token_1) {
+            let token_2 = pool_2.token_out(token_1).unwrap();
+            for pool_3 in graph.pools_between(token_2, token_in) {
+                if let Some(path) =
+                    triangular_path([(pool_1, token_in), (pool_2, token_1), (pool_3, token_2)])
+                {
+                    paths.push(path);
                 }
             }
         }
-
         pb.inc(1);
     }
 
+    // The pools usually come from a HashMap, so order by the pools to make runs reproducible
+    paths.sort_by_key(|path| {
+        (
+            path.pool_1.address,
+            path.pool_2.address,
+            path.pool_3.address,
+            path.zero_for_one_1,
+            path.zero_for_one_2,
+            path.zero_for_one_3,
+        )
+    });
+
     pb.finish_with_message(format!(
         "Generated {} 3-hop arbitrage paths in {} seconds",
         paths.len(),
@@ -131,3 +281,201 @@ pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPa
     ));
     paths
 }
+
+pub fn fetch_path_reserves<S: SwapSimulator>(
+    paths: &Vec<ArbPath>,
+    simulator: &mut S,
+) -> HashMap<H160, (u128, u128)> {
+    // Reserves of every V2 pool used by the paths, each pool is read once
+    let mut reserves = HashMap::new();
+    for path in paths {
+        for n in 0..path.nhop {
+            let pool = path.get_pool(n);
+            if !matches!(pool.version, DexVariant::UniswapV2)
+                || reserves.contains_key(&pool.address)
+            {
+                continue;
+            }
+            if let Ok(r) = simulator.v2_pool_get_reserves(pool.address) {
+                reserves.insert(pool.address, (r.0, r.1));
+            }
+        }
+    }
+    reserves
+}
+
+pub fn prescreen_paths(
+    paths: Vec<ArbPath>,
+    reserves: &HashMap<H160, (u128, u128)>,
+    min_profit_bps: u32,
+) -> Vec<ArbPath> {
+    // Drops paths whose estimated rate is below 1.0 + min_profit_bps before any EVM simulation
+    // Paths that can't be estimated are kept, the simulation decides for those
+    let min_rate = 1.0 + min_profit_bps as f64 / 10000.0;
+    let total = paths.len();
+    let paths: Vec<ArbPath> = paths
+        .into_iter()
+        .filter(|path| match path.estimate_rate(reserves) {
+            Some(rate) => rate >= min_rate,
+            None => true,
+        })
+        .collect();
+    info!("Pre-screened paths: {} of {} kept", paths.len(), total);
+    paths
+}
+
+pub fn index_paths_by_pool(paths: &Vec<ArbPath>) -> HashMap<H160, Vec<ArbPath>> {
+    // pool -> every path trading through it, to look up the paths a moved pool affects
+    let mut index: HashMap<H160, Vec<ArbPath>> = HashMap::new();
+    for path in paths {
+        for n in 0..path.nhop {
+            let pools = index.entry(path.get_pool(n).address).or_default();
+            if !pools.iter().any(|p| p.hash() == path.hash()) {
+                pools.push(path.clone());
+            }
+        }
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_pool(token0: H160, token1: H160) -> Pool {
+        Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        }
+    }
+
+    #[test]
+    fn test_paths_order_ignores_pool_order() {
+        let (weth, usdc, dai, usdt) = (
+            H160::random(),
+            H160::random(),
+            H160::random(),
+            H160::random(),
+        );
+        let pools = vec![
+            v2_pool(weth, usdc),
+            v2_pool(usdc, weth),
+            v2_pool(usdc, dai),
+            v2_pool(dai, weth),
+            v2_pool(weth, dai),
+            v2_pool(usdt, usdc),
+            v2_pool(weth, usdt),
+            v2_pool(dai, usdt),
+        ];
+        let hashes = |pools: &Vec<Pool>| {
+            generate_triangular_paths(pools, weth)
+                .iter()
+                .map(|path| path.hash())
+                .collect::<Vec<_>>()
+        };
+        let expected = hashes(&pools);
+        assert!(expected.len() > 1);
+
+        // the same pools in other orders, as they'd come out of a HashMap
+        let mut reversed = pools.clone();
+        reversed.reverse();
+        assert_eq!(hashes(&reversed), expected);
+        let mut rotated = pools.clone();
+        rotated.rotate_left(3);
+        assert_eq!(hashes(&rotated), expected);
+        let mut interleaved: Vec<Pool> = pools.iter().step_by(2).cloned().collect();
+        interleaved.extend(pools.iter().skip(1).step_by(2).cloned());
+        assert_eq!(hashes(&interleaved), expected);
+    }
+
+    #[test]
+    fn test_pools_between() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let weth_usdc = v2_pool(weth, usdc);
+        let usdc_weth = v2_pool(usdc, weth);
+        let usdc_dai = v2_pool(usdc, dai);
+        let mut graph = PoolGraph::new(&vec![
+            weth_usdc.clone(),
+            usdc_weth.clone(),
+            usdc_dai.clone(),
+        ]);
+        // adding a pool again doesn't list it twice
+        graph.add_pool(weth_usdc.clone());
+
+        let between = |a: H160, b: H160| {
+            graph
+                .pools_between(a, b)
+                .into_iter()
+                .map(|pool| pool.address)
+                .collect::<Vec<_>>()
+        };
+        // either token order finds both pools of the pair
+        assert_eq!(
+            between(weth, usdc),
+            vec![weth_usdc.address, usdc_weth.address]
+        );
+        assert_eq!(
+            between(usdc, weth),
+            vec![weth_usdc.address, usdc_weth.address]
+        );
+        assert_eq!(between(dai, usdc), vec![usdc_dai.address]);
+        assert!(between(weth, dai).is_empty());
+        assert!(between(H160::random(), weth).is_empty());
+
+        assert_eq!(graph.neighbors(usdc), vec![weth, dai]);
+        assert_eq!(
+            graph.get_pool(usdc_dai.address).map(|pool| pool.token1),
+            Some(dai)
+        );
+    }
+
+    #[test]
+    fn test_prescreen_keeps_the_only_profitable_looking_cycle() {
+        let (weth, usdc, dai, usdt, pepe) = (
+            H160::random(),
+            H160::random(),
+            H160::random(),
+            H160::random(),
+            H160::random(),
+        );
+        let e18 = 10u128.pow(18);
+        let weth_usdc = v2_pool(weth, usdc);
+        let usdc_pepe = v2_pool(usdc, pepe);
+        // PEPE sells for 1.2 WETH here and 1 USDC elsewhere
+        let pepe_weth = v2_pool(pepe, weth);
+        let pools = vec![
+            weth_usdc.clone(),
+            v2_pool(usdc, dai),
+            v2_pool(dai, weth),
+            v2_pool(weth, usdt),
+            v2_pool(usdt, usdc),
+            v2_pool(dai, usdt),
+            usdc_pepe.clone(),
+            pepe_weth.clone(),
+        ];
+        let mut reserves: HashMap<H160, (u128, u128)> = pools
+            .iter()
+            .map(|pool| (pool.address, (100 * e18, 100 * e18)))
+            .collect();
+        reserves.insert(pepe_weth.address, (100 * e18, 120 * e18));
+
+        let paths = generate_triangular_paths(&pools, weth);
+        assert!(paths.len() > 4);
+        let kept = prescreen_paths(paths, &reserves, 10);
+        assert_eq!(kept.len(), 1);
+        // WETH -> USDC -> PEPE -> WETH, the reverse cycle loses the same 20%
+        assert_eq!(
+            (
+                kept[0].pool_1.address,
+                kept[0].pool_2.address,
+                kept[0].pool_3.address
+            ),
+            (weth_usdc.address, usdc_pepe.address, pepe_weth.address)
+        );
+    }
+}
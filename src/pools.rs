@@ -10,16 +10,22 @@ use cfmms::{
 };
 use csv::StringRecord;
 use ethers::{
-    providers::{Provider, Ws},
-    types::H160,
+    abi::Token as AbiToken,
+    providers::{Middleware, Provider, Ws},
+    types::{H160, U256, U64},
 };
+use ethers_contract::{Contract, Multicall};
 use log::info;
 use std::{collections::HashMap, path::Path, str::FromStr, sync::Arc};
 
+use crate::honeypot::HoneypotFilter;
+use crate::interfaces::pool::V2PoolABI;
+
 #[derive(Debug, Clone)]
 pub enum DexVariant {
     UniswapV2,
     UniswapV3,
+    Curve,
 }
 
 #[derive(Debug, Clone)]
@@ -35,10 +41,10 @@ pub struct Pool {
 
 impl From<StringRecord> for Pool {
     fn from(record: StringRecord) -> Self {
-        let version = if record.get(1).unwrap() == "2" {
-            DexVariant::UniswapV2
-        } else {
-            DexVariant::UniswapV3
+        let version = match record.get(1).unwrap() {
+            "2" => DexVariant::UniswapV2,
+            "4" => DexVariant::Curve,
+            _ => DexVariant::UniswapV3,
         };
         Self {
             address: H160::from_str(record.get(0).unwrap()).unwrap(),
@@ -59,6 +65,7 @@ impl Pool {
             match self.version {
                 DexVariant::UniswapV2 => 2,
                 DexVariant::UniswapV3 => 3,
+                DexVariant::Curve => 4,
             },
             format!("{:?}", self.token0),
             format!("{:?}", self.token1),
@@ -71,11 +78,79 @@ impl Pool {
     pub fn has_token(&self, token: H160) -> bool {
         self.token0 == token || self.token1 == token
     }
+
+    pub fn token_out(&self, token_in: H160) -> Option<H160> {
+        if token_in == self.token0 {
+            Some(self.token1)
+        } else if token_in == self.token1 {
+            Some(self.token0)
+        } else {
+            None
+        }
+    }
+
+    pub fn ordered_reserves(&self, reserves: (u128, u128), token_in: H160) -> (U256, U256) {
+        // (reserve_in, reserve_out)
+        if token_in == self.token0 {
+            (U256::from(reserves.0), U256::from(reserves.1))
+        } else {
+            (U256::from(reserves.1), U256::from(reserves.0))
+        }
+    }
+
+    pub fn fee_bps(&self) -> u32 {
+        // fee is in units of 1/100000, ex) 300 -> 30 bps
+        self.fee / 10
+    }
+
+    pub fn spot_price(&self, reserve0: u128, reserve1: u128, token_in: H160) -> f64 {
+        // Price of token_in quoted in the other token, adjusted for decimals
+        let reserve0 = reserve0 as f64 / 10f64.powi(self.decimals0 as i32);
+        let reserve1 = reserve1 as f64 / 10f64.powi(self.decimals1 as i32);
+        if reserve0 == 0.0 || reserve1 == 0.0 {
+            return 0.0;
+        }
+        if token_in == self.token0 {
+            reserve1 / reserve0
+        } else {
+            reserve0 / reserve1
+        }
+    }
+
+    pub fn amount_out(&self, amount_in: U256, reserves: (u128, u128), token_in: H160) -> U256 {
+        // Constant product formula, fee is in units of 1/100000 (300 = 0.3%)
+        let (reserve_in, reserve_out) = self.ordered_reserves(reserves, token_in);
+        if amount_in.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() {
+            return U256::zero();
+        }
+        let amount_in_with_fee = amount_in * U256::from(100000 - self.fee);
+        let numerator = amount_in_with_fee * reserve_out;
+        let denominator = reserve_in * U256::from(100000) + amount_in_with_fee;
+        numerator / denominator
+    }
+}
+
+pub fn build_verified_pools<M: Middleware + 'static>(
+    pools: Vec<Pool>,
+    honeypot_filter: &HoneypotFilter<M>,
+) -> (Vec<Pool>, HashMap<H160, Pool>) {
+    // filter out pools that use unverified tokens
+    let verified_pools: Vec<Pool> = pools
+        .into_iter()
+        .filter(|pool| honeypot_filter.is_verified_pool(pool))
+        .collect();
+
+    let mut verified_pools_map = HashMap::new();
+    for pool in &verified_pools {
+        verified_pools_map.insert(pool.address, pool.clone());
+    }
+
+    (verified_pools, verified_pools_map)
 }
 
 pub async fn load_all_pools(
     wss_url: String,
-    factories: Vec<(&str, CfmmsDexVariant, u64)>,
+    factories: Vec<(H160, CfmmsDexVariant, u64)>,
 ) -> Result<Vec<Pool>> {
     // Load from cached file if the file exists
     let file_path = Path::new("src/.cached-pools.csv");
@@ -96,14 +171,7 @@ pub async fn load_all_pools(
 
     let dexes: Vec<_> = factories
         .into_iter()
-        .map(|(address, variant, number)| {
-            Dex::new(
-                H160::from_str(&address).unwrap(),
-                variant,
-                number,
-                Some(3000),
-            )
-        })
+        .map(|(address, variant, number)| Dex::new(address, variant, number, Some(3000)))
         .collect();
 
     let pools_vec: Vec<CfmmsPool> = sync_pairs(dexes.clone(), provider.clone(), None).await?;
@@ -151,6 +219,100 @@ pub async fn load_all_pools(
     Ok(pools_vec)
 }
 
+pub async fn export_pool_snapshot<M: Middleware + 'static>(
+    pools: &Vec<Pool>,
+    provider: Arc<M>,
+    block_number: U64,
+    file_path: &Path,
+) -> Result<usize> {
+    // Writes the tokens, decimals and reserves of every V2 pool at block_number to a csv
+    // token0/token1 are read from the pools as well, reserves only make sense with the on-chain order
+    // Pools whose calls fail are left out, returns the number of pools written
+    let abi = V2PoolABI::new().abi;
+
+    let mut writer = csv::Writer::from_path(file_path)?;
+    writer.write_record(&[
+        "block_number",
+        "address",
+        "token0",
+        "token1",
+        "decimals0",
+        "decimals1",
+        "reserve0",
+        "reserve1",
+    ])?;
+
+    let v2_pools: Vec<&Pool> = pools
+        .iter()
+        .filter(|pool| matches!(pool.version, DexVariant::UniswapV2))
+        .collect();
+
+    let mut written = 0;
+    for chunk in v2_pools.chunks(100) {
+        let mut multicall = Multicall::new(provider.clone(), None)
+            .await?
+            .block(block_number.as_u64());
+        for pool in chunk {
+            let contract = Contract::new(pool.address, abi.abi().clone(), provider.clone());
+            multicall.add_call(
+                contract.method::<_, (u128, u128, u32)>("getReserves", ())?,
+                true,
+            );
+            multicall.add_call(contract.method::<_, H160>("token0", ())?, true);
+            multicall.add_call(contract.method::<_, H160>("token1", ())?, true);
+        }
+        let results: Vec<Option<AbiToken>> = multicall
+            .call_raw()
+            .await?
+            .into_iter()
+            .map(|result| result.ok())
+            .collect();
+
+        for (pool, calls) in chunk.iter().zip(results.chunks(3)) {
+            let reserves = match &calls[0] {
+                Some(AbiToken::Tuple(values)) if values.len() == 3 => {
+                    (values[0].clone().into_uint(), values[1].clone().into_uint())
+                }
+                _ => continue,
+            };
+            let (token0, token1) = match (&calls[1], &calls[2]) {
+                (Some(AbiToken::Address(token0)), Some(AbiToken::Address(token1))) => {
+                    (*token0, *token1)
+                }
+                _ => continue,
+            };
+            let (reserve0, reserve1) = match reserves {
+                (Some(reserve0), Some(reserve1)) => (reserve0, reserve1),
+                _ => continue,
+            };
+            // the cached decimals follow the cached token order, which should match the pool's
+            let (decimals0, decimals1) = if token0 == pool.token0 {
+                (pool.decimals0, pool.decimals1)
+            } else {
+                (pool.decimals1, pool.decimals0)
+            };
+            writer.write_record(&[
+                block_number.to_string(),
+                format!("{:?}", pool.address),
+                format!("{:?}", token0),
+                format!("{:?}", token1),
+                decimals0.to_string(),
+                decimals1.to_string(),
+                reserve0.to_string(),
+                reserve1.to_string(),
+            ])?;
+            written += 1;
+        }
+    }
+    writer.flush()?;
+
+    info!(
+        "Exported {} pools at block {} to {:?}",
+        written, block_number, file_path
+    );
+    Ok(written)
+}
+
 pub fn get_tokens(pools: &Vec<Pool>) -> HashMap<H160, u8> {
     let mut tokens = HashMap::new();
     for pool in pools {
@@ -159,3 +321,148 @@ pub fn get_tokens(pools: &Vec<Pool>) -> HashMap<H160, u8> {
     }
     tokens
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimulatorConfig;
+    use crate::tokens::Token;
+    use ethers::types::Block;
+
+    #[test]
+    fn test_build_verified_pools() {
+        let (provider, _) = Provider::mocked();
+        let block = Block {
+            number: Some(U64::from(1)),
+            gas_limit: U256::from(30000000),
+            ..Default::default()
+        };
+        let mut filter = HoneypotFilter::new(Arc::new(provider), block, SimulatorConfig::default());
+        let (weth, tested, untested) = (H160::random(), H160::random(), H160::random());
+        for (address, symbol) in [(weth, "WETH"), (tested, "TKN")] {
+            let token = Token {
+                address,
+                implementation: None,
+                name: symbol.to_string(),
+                symbol: symbol.to_string(),
+                decimals: 18,
+            };
+            if address == weth {
+                filter.safe_token_info.insert(address, token);
+            } else {
+                filter.token_info.insert(address, token);
+            }
+        }
+
+        let pool = |token0: H160, token1: H160| Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let verified = pool(weth, tested);
+        let pools = vec![
+            pool(weth, untested),
+            verified.clone(),
+            pool(untested, tested),
+        ];
+
+        // only the pool with both tokens verified survives, in the vec and the map alike
+        let (verified_pools, verified_pools_map) = build_verified_pools(pools, &filter);
+        assert_eq!(verified_pools.len(), 1);
+        assert_eq!(verified_pools[0].address, verified.address);
+        assert_eq!(verified_pools_map.len(), 1);
+        assert_eq!(verified_pools_map[&verified.address].token1, tested);
+    }
+
+    #[tokio::test]
+    async fn test_export_pool_snapshot_round_trip() {
+        let (weth, usdc, dai) = (H160::random(), H160::random(), H160::random());
+        let pool = |token0: H160, token1: H160, decimals0: u8, decimals1: u8| Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0,
+            decimals1,
+            fee: 300,
+        };
+        let weth_usdc = pool(weth, usdc, 18, 6);
+        let dai_weth = pool(dai, weth, 18, 18);
+        let mut v3_pool = pool(weth, dai, 18, 18);
+        v3_pool.version = DexVariant::UniswapV3;
+
+        // one multicall for both V2 pools: the chain id, then getReserves/token0/token1 of each
+        // (served last in, first out)
+        let result = |tokens: Vec<AbiToken>| {
+            AbiToken::Tuple(vec![
+                AbiToken::Bool(true),
+                AbiToken::Bytes(ethers::abi::encode(&tokens)),
+            ])
+        };
+        let reserves = |reserve0: u128, reserve1: u128| {
+            result(vec![
+                AbiToken::Uint(U256::from(reserve0)),
+                AbiToken::Uint(U256::from(reserve1)),
+                AbiToken::Uint(U256::from(1700000000u64)),
+            ])
+        };
+        let address = |token: H160| result(vec![AbiToken::Address(token)]);
+        let aggregated = ethers::abi::encode(&[AbiToken::Array(vec![
+            reserves(500 * 10u128.pow(18), 1000000 * 10u128.pow(6)),
+            address(weth),
+            address(usdc),
+            reserves(2000000 * 10u128.pow(18), 1000 * 10u128.pow(18)),
+            address(dai),
+            address(weth),
+        ])]);
+        let (provider, mock) = Provider::mocked();
+        mock.push::<ethers::types::Bytes, _>(aggregated.into())
+            .unwrap();
+        mock.push::<U256, _>(U256::one()).unwrap();
+
+        let file_path =
+            std::env::temp_dir().join(format!("pool-snapshot-{:?}.csv", H160::random()));
+        let pools = vec![weth_usdc.clone(), v3_pool, dai_weth.clone()];
+        let written =
+            export_pool_snapshot(&pools, Arc::new(provider), U64::from(18000000), &file_path)
+                .await
+                .unwrap();
+        // the V3 pool has no getReserves and is left out
+        assert_eq!(written, 2);
+
+        let mut reader = csv::Reader::from_path(&file_path).unwrap();
+        let rows: Vec<StringRecord> = reader.records().map(|row| row.unwrap()).collect();
+        std::fs::remove_file(&file_path).unwrap();
+        assert_eq!(rows.len(), 2);
+        let expected = [
+            (
+                &weth_usdc,
+                18,
+                6,
+                500 * 10u128.pow(18),
+                1000000 * 10u128.pow(6),
+            ),
+            (
+                &dai_weth,
+                18,
+                18,
+                2000000 * 10u128.pow(18),
+                1000 * 10u128.pow(18),
+            ),
+        ];
+        for (row, (pool, decimals0, decimals1, reserve0, reserve1)) in rows.iter().zip(expected) {
+            assert_eq!(&row[0], "18000000");
+            assert_eq!(H160::from_str(&row[1]).unwrap(), pool.address);
+            assert_eq!(H160::from_str(&row[2]).unwrap(), pool.token0);
+            assert_eq!(H160::from_str(&row[3]).unwrap(), pool.token1);
+            assert_eq!(row[4].parse::<u8>().unwrap(), decimals0);
+            assert_eq!(row[5].parse::<u8>().unwrap(), decimals1);
+            assert_eq!(row[6].parse::<u128>().unwrap(), reserve0);
+            assert_eq!(row[7].parse::<u128>().unwrap(), reserve1);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::{anyhow, Result};
+use ethers::types::H160;
+use std::collections::HashMap;
+
+use crate::chain::ChainConfig;
+use crate::pools::{DexVariant, Pool};
+use crate::simulator::SwapSimulator;
+use crate::tokens::Token;
+
+#[derive(Debug, Clone)]
+pub struct UsdPricer {
+    pub stables: Vec<H160>,
+    pub weth: H160,
+    // token -> V2 pools pairing it with a stable or WETH, the only pools prices are read from
+    pub pools: HashMap<H160, Vec<Pool>>,
+}
+
+impl UsdPricer {
+    pub fn new(chain: &ChainConfig, pools: &Vec<Pool>) -> Self {
+        let stables = vec![chain.usdt, chain.usdc, chain.dai];
+        let weth = chain.wrapped_native;
+
+        let mut pricing_pools: HashMap<H160, Vec<Pool>> = HashMap::new();
+        for pool in pools {
+            if !matches!(pool.version, DexVariant::UniswapV2) {
+                continue;
+            }
+            for (token, other) in [(pool.token0, pool.token1), (pool.token1, pool.token0)] {
+                if stables.contains(&other) || other == weth {
+                    pricing_pools.entry(token).or_default().push(pool.clone());
+                }
+            }
+        }
+
+        Self {
+            stables,
+            weth,
+            pools: pricing_pools,
+        }
+    }
+
+    pub fn price_in<S: SwapSimulator>(
+        &self,
+        simulator: &mut S,
+        token: H160,
+        quote: H160,
+    ) -> Option<f64> {
+        // Spot price of token in quote, read from the first pool that trades the pair
+        let pool = self
+            .pools
+            .get(&token)?
+            .iter()
+            .find(|pool| pool.has_token(quote))?;
+        let reserves = simulator.v2_pool_get_reserves(pool.address).ok()?;
+        let price = pool.spot_price(reserves.0, reserves.1, token);
+        if price > 0.0 {
+            Some(price)
+        } else {
+            None
+        }
+    }
+
+    pub fn usd_price<S: SwapSimulator>(&self, simulator: &mut S, token: H160) -> Result<f64> {
+        // Stables are taken at $1, other tokens go through a stable pool,
+        // or through WETH when there is no direct stable pool
+        if self.stables.contains(&token) {
+            return Ok(1.0);
+        }
+        for stable in &self.stables {
+            if let Some(price) = self.price_in(simulator, token, *stable) {
+                return Ok(price);
+            }
+        }
+        if token != self.weth {
+            if let Some(weth_price) = self.price_in(simulator, token, self.weth) {
+                let weth_usd = self.usd_price(simulator, self.weth)?;
+                return Ok(weth_price * weth_usd);
+            }
+        }
+        Err(anyhow!("no stable or WETH pool to price {:?}", token))
+    }
+
+    pub fn to_usd<S: SwapSimulator>(
+        &self,
+        simulator: &mut S,
+        amount: i128,
+        token: &Token,
+    ) -> Result<f64> {
+        // Prices at the simulator's fork block, call before the simulation's own swaps move the pools
+        let price = self.usd_price(simulator, token.address)?;
+        Ok(amount as f64 / 10f64.powi(token.decimals as i32) * price)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EthPriceOracle {
+    pub weth: H160,
+    pub usdc: H160,
+    // V2 WETH/USDC pools, the one with the most USDC sets the price
+    pub pools: Vec<Pool>,
+}
+
+impl EthPriceOracle {
+    pub fn new(chain: &ChainConfig, pools: &Vec<Pool>) -> Self {
+        let weth = chain.wrapped_native;
+        let usdc = chain.usdc;
+        let pools = pools
+            .iter()
+            .filter(|pool| {
+                matches!(pool.version, DexVariant::UniswapV2)
+                    && pool.has_token(weth)
+                    && pool.has_token(usdc)
+            })
+            .cloned()
+            .collect();
+        Self { weth, usdc, pools }
+    }
+
+    pub fn eth_price_usd<S: SwapSimulator>(&self, simulator: &mut S) -> Result<f64> {
+        // Read from the pools' reserves on the simulator the profit was simulated with,
+        // no external price feed and no fork of its own
+        // Call before the simulation's own swaps move the pools
+        let mut deepest: Option<(u128, f64)> = None;
+        for pool in &self.pools {
+            let reserves = match simulator.v2_pool_get_reserves(pool.address) {
+                Ok(reserves) => reserves,
+                Err(_) => continue,
+            };
+            let usdc_reserve = if pool.token0 == self.usdc {
+                reserves.0
+            } else {
+                reserves.1
+            };
+            let price = pool.spot_price(reserves.0, reserves.1, self.weth);
+            if price > 0.0 && deepest.map_or(true, |(depth, _)| usdc_reserve > depth) {
+                deepest = Some((usdc_reserve, price));
+            }
+        }
+        deepest
+            .map(|(_, price)| price)
+            .ok_or(anyhow!("no WETH/USDC pool to price ETH"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::mock::MockSimulator;
+
+    fn weth_usdc_pool(
+        chain: &ChainConfig,
+        weth_reserve: u128,
+        usdc_reserve: u128,
+    ) -> (Pool, u128, u128) {
+        // mainnet USDC sorts before WETH
+        let pool = Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: chain.usdc,
+            token1: chain.wrapped_native,
+            decimals0: 6,
+            decimals1: 18,
+            fee: 300,
+        };
+        (
+            pool,
+            usdc_reserve * 10u128.pow(6),
+            weth_reserve * 10u128.pow(18),
+        )
+    }
+
+    #[test]
+    fn test_eth_price_from_the_deepest_pool() {
+        let chain = ChainConfig::mainnet();
+        let deep = weth_usdc_pool(&chain, 1000, 3000000);
+        let shallow = weth_usdc_pool(&chain, 1, 5000);
+        let pools = vec![deep.0.clone(), shallow.0.clone()];
+        let oracle = EthPriceOracle::new(&chain, &pools);
+
+        let mut simulator = MockSimulator::new(chain.wrapped_native);
+        simulator.add_pool(deep.0, deep.1, deep.2);
+        simulator.add_pool(shallow.0, shallow.1, shallow.2);
+        let price = oracle.eth_price_usd(&mut simulator).unwrap();
+        assert!((price - 3000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_eth_price_without_pools() {
+        let chain = ChainConfig::mainnet();
+        let oracle = EthPriceOracle::new(&chain, &Vec::new());
+        let mut simulator = MockSimulator::new(chain.wrapped_native);
+        assert!(oracle.eth_price_usd(&mut simulator).is_err());
+    }
+
+    #[test]
+    fn test_weth_and_usdc_profits_in_usd() {
+        let chain = ChainConfig::mainnet();
+        let token = |address: H160, decimals: u8| Token {
+            address,
+            implementation: None,
+            name: String::new(),
+            symbol: String::new(),
+            decimals,
+        };
+        // a token with only a WETH pool, 2 of it for 1 WETH
+        let other = H160::random();
+        let other_pool = Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: other,
+            token1: chain.wrapped_native,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let (pool, usdc_reserve, weth_reserve) = weth_usdc_pool(&chain, 1000, 3000000);
+        let pricer = UsdPricer::new(&chain, &vec![pool.clone(), other_pool.clone()]);
+
+        let mut simulator = MockSimulator::new(chain.wrapped_native);
+        simulator.add_pool(pool, usdc_reserve, weth_reserve);
+        simulator.add_pool(other_pool, 2000 * 10u128.pow(18), 1000 * 10u128.pow(18));
+
+        // 1 WETH and 3000 USDC are worth the same
+        let weth_usd = pricer
+            .to_usd(
+                &mut simulator,
+                10i128.pow(18),
+                &token(chain.wrapped_native, 18),
+            )
+            .unwrap();
+        let usdc_usd = pricer
+            .to_usd(&mut simulator, 3000 * 10i128.pow(6), &token(chain.usdc, 6))
+            .unwrap();
+        assert!((weth_usd - 3000.0).abs() < 1e-6);
+        assert!((weth_usd - usdc_usd).abs() < 1e-6);
+
+        // without a stable pool the price goes through WETH
+        let other_usd = pricer
+            .to_usd(&mut simulator, 2 * 10i128.pow(18), &token(other, 18))
+            .unwrap();
+        assert!((other_usd - 3000.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{anyhow, Result};
+use ethers::types::{BlockId, BlockNumber, H160, U256, U64};
+use ethers_providers::Middleware;
+use std::{str::FromStr, sync::Arc};
+
+use crate::pools::Pool;
+use crate::simulator::{EvmSimulator, SimulatorConfig};
+use crate::tokens::get_implementation;
+use crate::trace::EvmTracer;
+
+pub async fn quote_swap<M: Middleware + 'static>(
+    provider: Arc<M>,
+    config: SimulatorConfig,
+    block_number: U64,
+    pool: &Pool,
+    token_in: H160,
+    amount_in: U256,
+) -> Result<U256> {
+    // Quotes a single swap against the forked state, without any honeypot/arbitrage setup
+    // The input token's balance slot is traced first so the simulator contract can be funded
+    let owner = H160::from_str("0x001a06BF8cE4afdb3f5618f6bafe35e9Fc09F187").unwrap();
+
+    let chain_id = provider.get_chainid().await?;
+    let nonce = provider
+        .get_transaction_count(
+            owner,
+            Some(BlockId::Number(BlockNumber::Number(block_number))),
+        )
+        .await?;
+    let implementation = get_implementation(provider.clone(), token_in, block_number)
+        .await
+        .unwrap_or(None);
+
+    let tracer = EvmTracer::new(provider.clone());
+    let (found, account, slot) = tracer
+        .find_balance_slot(
+            token_in,
+            implementation,
+            owner,
+            nonce,
+            U64::from(chain_id.as_u64()),
+            block_number.as_u64(),
+        )
+        .await?;
+    if !found {
+        return Err(anyhow!("balance slot not found for {:?}", token_in));
+    }
+
+    let mut simulator = EvmSimulator::new_with_config(provider, owner, block_number, config);
+    if account != token_in {
+        simulator.balance_accounts.insert(token_in, account);
+    }
+    simulator.deploy_simulator();
+
+    let simulator_address = simulator.simulator_address;
+    simulator.set_token_balance_raw(simulator_address, token_in, slot, amount_in);
+
+    let out = simulator.swap_exact_in(pool, amount_in, token_in, false)?;
+    Ok(out.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pools::DexVariant;
+    use ethers::providers::{Provider, Ws};
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_quote_swap() {
+        // Needs a mainnet WSS_URL with debug_traceCall, the input token's balance slot is traced
+        dotenv::dotenv().ok();
+        let ws = Ws::connect(std::env::var("WSS_URL").unwrap())
+            .await
+            .unwrap();
+        let provider = Arc::new(Provider::new(ws));
+        let block_number = provider.get_block_number().await.unwrap();
+
+        // Uniswap V2 USDC/WETH
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+        let pool = Pool {
+            address: H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc").unwrap(),
+            version: DexVariant::UniswapV2,
+            token0: usdc,
+            token1: weth,
+            decimals0: 6,
+            decimals1: 18,
+            fee: 300,
+        };
+        let amount_in = U256::exp10(18);
+
+        let amount_out = quote_swap(
+            provider.clone(),
+            SimulatorConfig::default(),
+            block_number,
+            &pool,
+            weth,
+            amount_in,
+        )
+        .await
+        .unwrap();
+        assert!(!amount_out.is_zero());
+
+        // the same as the constant product formula on the block's reserves
+        let mut simulator = EvmSimulator::new(provider, H160::random(), block_number);
+        let reserves = simulator.v2_pool_get_reserves(pool.address).unwrap();
+        assert_eq!(
+            amount_out,
+            pool.amount_out(amount_in, (reserves.0, reserves.1), weth)
+        );
+    }
+}
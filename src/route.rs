@@ -0,0 +1,266 @@
+use ethers::types::{AccountState, H160, H256, U256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::pools::{DexVariant, Pool};
+
+// UniswapV2Pair packs reserve0 (112 bits), reserve1 (112 bits), blockTimestampLast (32 bits) in slot 8
+pub const V2_RESERVES_SLOT: u64 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapLeg {
+    pub pool: H160,
+    pub token_in: H160,
+    pub token_out: H160,
+    pub amount_in: U256,
+    pub amount_out: U256,
+    // how much the price of token_out moved against token_in
+    pub price_impact_bps: u64,
+}
+
+pub fn decode_v2_reserves(value: H256) -> (U256, U256) {
+    let packed = U256::from(value.to_fixed_bytes());
+    let mask = (U256::one() << 112) - 1;
+    (packed & mask, (packed >> 112) & mask)
+}
+
+pub fn swap_leg(pool: &Pool, pre: (U256, U256), post: (U256, U256)) -> Option<SwapLeg> {
+    // the reserve that went up was paid in, the other one was paid out
+    let (token_in, token_out, pre_in, pre_out, post_in, post_out) =
+        if post.0 > pre.0 && post.1 < pre.1 {
+            (pool.token0, pool.token1, pre.0, pre.1, post.0, post.1)
+        } else if post.1 > pre.1 && post.0 < pre.0 {
+            (pool.token1, pool.token0, pre.1, pre.0, post.1, post.0)
+        } else {
+            return None;
+        };
+    if pre_in.is_zero() || post_out.is_zero() {
+        return None;
+    }
+    // (post_in / post_out) / (pre_in / pre_out) - 1
+    let ratio = post_in * pre_out * U256::from(10000) / (post_out * pre_in);
+    let price_impact_bps = ratio
+        .saturating_sub(U256::from(10000))
+        .min(U256::from(u64::MAX));
+    Some(SwapLeg {
+        pool: pool.address,
+        token_in,
+        token_out,
+        amount_in: post_in - pre_in,
+        amount_out: pre_out - post_out,
+        price_impact_bps: price_impact_bps.as_u64(),
+    })
+}
+
+pub fn reconstruct_victim_path(
+    pre: &BTreeMap<H160, AccountState>,
+    post: &BTreeMap<H160, AccountState>,
+    pools_map: &HashMap<H160, Pool>,
+) -> Vec<SwapLeg> {
+    // Every V2 pool the tx swapped through shows up as a reserve change in the diff,
+    // the legs are then chained by matching one leg's token_out to the next leg's token_in
+    let slot = H256::from_low_u64_be(V2_RESERVES_SLOT);
+    let mut legs = Vec::new();
+    for (address, post_state) in post {
+        let pool = match pools_map.get(address) {
+            Some(pool) if matches!(pool.version, DexVariant::UniswapV2) => pool,
+            _ => continue,
+        };
+        let pre_value = pre
+            .get(address)
+            .and_then(|state| state.storage.as_ref())
+            .and_then(|storage| storage.get(&slot));
+        let post_value = post_state
+            .storage
+            .as_ref()
+            .and_then(|storage| storage.get(&slot));
+        if let (Some(pre_value), Some(post_value)) = (pre_value, post_value) {
+            let leg = swap_leg(
+                pool,
+                decode_v2_reserves(*pre_value),
+                decode_v2_reserves(*post_value),
+            );
+            if let Some(leg) = leg {
+                legs.push(leg);
+            }
+        }
+    }
+    order_legs(legs)
+}
+
+pub fn order_legs(mut legs: Vec<SwapLeg>) -> Vec<SwapLeg> {
+    // starts from the leg whose input isn't produced by any other leg,
+    // legs that can't be chained are kept at the end in their original order
+    let outputs: HashSet<H160> = legs.iter().map(|leg| leg.token_out).collect();
+    let start = legs
+        .iter()
+        .position(|leg| !outputs.contains(&leg.token_in))
+        .unwrap_or(0);
+
+    let mut ordered = Vec::new();
+    if legs.is_empty() {
+        return ordered;
+    }
+    let mut current = legs.remove(start);
+    loop {
+        let next = legs
+            .iter()
+            .position(|leg| leg.token_in == current.token_out);
+        ordered.push(current);
+        match next {
+            Some(idx) => current = legs.remove(idx),
+            None => break,
+        }
+    }
+    ordered.extend(legs);
+    ordered
+}
+
+pub fn most_slipped_leg<'a, F>(legs: &'a [SwapLeg], sandwichable: F) -> Option<&'a SwapLeg>
+where
+    F: Fn(&SwapLeg) -> bool,
+{
+    legs.iter()
+        .filter(|leg| sandwichable(leg))
+        .max_by_key(|leg| leg.price_impact_bps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_pool(token0: H160, token1: H160) -> Pool {
+        Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        }
+    }
+
+    fn leg(token_in: H160, token_out: H160, price_impact_bps: u64) -> SwapLeg {
+        SwapLeg {
+            pool: H160::random(),
+            token_in,
+            token_out,
+            amount_in: U256::from(100),
+            amount_out: U256::from(90),
+            price_impact_bps,
+        }
+    }
+
+    #[test]
+    fn test_swap_leg() {
+        let pool = v2_pool(H160::random(), H160::random());
+        let pre = (U256::from(1000), U256::from(1000));
+
+        let zero_for_one = swap_leg(&pool, pre, (U256::from(1100), U256::from(910))).unwrap();
+        assert_eq!(zero_for_one.pool, pool.address);
+        assert_eq!(zero_for_one.token_in, pool.token0);
+        assert_eq!(zero_for_one.token_out, pool.token1);
+        assert_eq!(zero_for_one.amount_in, U256::from(100));
+        assert_eq!(zero_for_one.amount_out, U256::from(90));
+        // (1100 / 910) / (1000 / 1000) - 1
+        assert_eq!(zero_for_one.price_impact_bps, 2087);
+
+        let one_for_zero = swap_leg(&pool, pre, (U256::from(910), U256::from(1100))).unwrap();
+        assert_eq!(one_for_zero.token_in, pool.token1);
+        assert_eq!(one_for_zero.token_out, pool.token0);
+        assert_eq!(one_for_zero.price_impact_bps, 2087);
+
+        // reserves that didn't move in opposite directions weren't swapped against
+        assert_eq!(swap_leg(&pool, pre, pre), None);
+        assert_eq!(
+            swap_leg(&pool, pre, (U256::from(1100), U256::from(1100))),
+            None
+        );
+        assert_eq!(
+            swap_leg(
+                &pool,
+                (U256::zero(), U256::from(1000)),
+                (U256::from(100), U256::from(900))
+            ),
+            None
+        );
+    }
+
+    fn reserves_state(reserve0: u64, reserve1: u64) -> AccountState {
+        let packed = U256::from(reserve0) | (U256::from(reserve1) << 112);
+        serde_json::from_value(serde_json::json!({
+            "storage": {
+                format!("{:?}", H256::from_low_u64_be(V2_RESERVES_SLOT)): format!("{:?}", H256::from_uint(&packed)),
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_reconstruct_victim_path() {
+        // WETH -> X through the first pool, then X -> USDC through the second one
+        let (weth, x, usdc) = (H160::random(), H160::random(), H160::random());
+        let weth_x = v2_pool(weth, x);
+        let usdc_x = v2_pool(usdc, x);
+        let pools_map: HashMap<H160, Pool> = [weth_x.clone(), usdc_x.clone()]
+            .into_iter()
+            .map(|pool| (pool.address, pool))
+            .collect();
+
+        let pre = BTreeMap::from([
+            (weth_x.address, reserves_state(1000, 1000)),
+            (usdc_x.address, reserves_state(1000, 1000)),
+        ]);
+        let post = BTreeMap::from([
+            (weth_x.address, reserves_state(1100, 910)),
+            (usdc_x.address, reserves_state(990, 1090)),
+            (
+                H160::random(),
+                serde_json::from_value(serde_json::json!({})).unwrap(),
+            ),
+        ]);
+
+        let legs = reconstruct_victim_path(&pre, &post, &pools_map);
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[0].pool, weth_x.address);
+        assert_eq!((legs[0].token_in, legs[0].token_out), (weth, x));
+        assert_eq!(legs[0].amount_out, U256::from(90));
+        assert_eq!(legs[1].pool, usdc_x.address);
+        assert_eq!((legs[1].token_in, legs[1].token_out), (x, usdc));
+        assert_eq!(legs[1].amount_in, U256::from(90));
+        assert_eq!(legs[1].amount_out, U256::from(10));
+    }
+
+    #[test]
+    fn test_order_legs() {
+        let (a, b, c, d) = (
+            H160::random(),
+            H160::random(),
+            H160::random(),
+            H160::random(),
+        );
+        let (x, y) = (H160::random(), H160::random());
+        let ab = leg(a, b, 0);
+        let bc = leg(b, c, 0);
+        let cd = leg(c, d, 0);
+        let xy = leg(x, y, 0);
+
+        let ordered = order_legs(vec![bc.clone(), xy.clone(), cd.clone(), ab.clone()]);
+        assert_eq!(ordered, vec![ab, bc, cd, xy]);
+        assert!(order_legs(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_most_slipped_leg() {
+        let (a, b, c) = (H160::random(), H160::random(), H160::random());
+        let legs = vec![leg(a, b, 50), leg(b, c, 300), leg(c, a, 120)];
+
+        assert_eq!(most_slipped_leg(&legs, |_| true), Some(&legs[1]));
+        // the most slipped leg that can actually be sandwiched
+        assert_eq!(
+            most_slipped_leg(&legs, |leg| leg.token_in != b),
+            Some(&legs[2])
+        );
+        assert_eq!(most_slipped_leg(&legs, |_| false), None);
+    }
+}
@@ -1,46 +1,123 @@
-use anyhow::Result;
-use ethers::types::{Transaction, H160, U256, U64};
+use anyhow::{anyhow, Result};
+use ethers::types::{AccountState, Transaction, H160, H256, U256};
 use ethers_providers::Middleware;
 use foundry_evm::{executor::fork::SharedBackend, revm::db::CacheDB};
 use log::info;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
 
+use crate::arbitrage::apply_tax;
+use crate::constants::ZERO_ADDRESS;
 use crate::honeypot::HoneypotFilter;
-use crate::pools::Pool;
-use crate::simulator::EvmSimulator;
+use crate::log_with_id;
+use crate::mempool::{order_pending_block, txs_ahead_of};
+use crate::metrics::{Metrics, METRICS};
+use crate::pools::{DexVariant, Pool};
+use crate::pricing::{EthPriceOracle, UsdPricer};
+use crate::simulator::{EvmSimulator, SimError, SimulatorConfig, SwapSimulator};
+use crate::streams::BlockContext;
 use crate::tokens::Token;
+use crate::utils::{correlation_id, format_token_amount, to_i128, token_unit};
 
 #[derive(Debug, Clone)]
 pub struct Sandwich {
     pub amount_in: U256,
     pub balance_slot: u32,
+    // Contract holding target_token's balances when it isn't the token itself
+    pub balance_account: Option<H160>,
     pub target_token: Token,
     pub target_pool: Pool,
+    // Pool pairing target_token with WETH, used to price the profit in ETH
+    pub eth_pool: Option<Pool>,
+    // Reports the profit in USD as well when set
+    pub usd_pricer: Option<Arc<UsdPricer>>,
+    // Prices ETH in USD when set, so a target_token without eth_pool is valued through its USD price
+    pub eth_oracle: Option<Arc<EthPriceOracle>>,
     pub meat_tx: Transaction,
+    // State the meat tx was traced with, applied before the bundle runs, can be empty
+    pub meat_prestate: BTreeMap<H160, AccountState>,
+    // (buy tax, sell tax) in bps of the token traded against target_token
+    pub token_taxes: (u32, u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct SandwichResult {
+    // Profit in target_token, and the same profit converted to wei
+    pub profit_token: i128,
+    pub profit_eth: i128,
+    pub profit_usd: Option<f64>,
+    // ETH price read on the same fork, before our swaps
+    pub eth_price_usd: Option<f64>,
+    // gas used by the frontrun and backrun, the meat tx is not ours to pay for
+    pub gas_used: u64,
+    pub breakdown: SandwichBreakdown,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SandwichBreakdown {
+    // amounts after transfer taxes, backrun_in is always frontrun_out
+    pub frontrun_in: U256,
+    pub frontrun_out: U256,
+    pub backrun_in: U256,
+    pub backrun_out: U256,
+    // How far the meat tx moved the pool's price in our favor (bps), 0 for pools without reserves
+    pub meat_price_impact_bps: f64,
+    pub gross_profit: i128,
+    // Share of the meat's price move our position realized: gross profit / (frontrun_in x impact)
+    pub capture_rate: f64,
 }
 
 pub struct SandwichSimulator<M> {
     pub simulator: EvmSimulator<M>,
+    pub block: BlockContext,
 }
 
 impl<M: Middleware + 'static> SandwichSimulator<M> {
-    pub fn new(provider: Arc<M>, owner: H160, block_number: U64) -> Self {
-        let simulator = EvmSimulator::new(provider, owner, block_number);
-        Self { simulator }
+    pub fn new(
+        provider: Arc<M>,
+        owner: H160,
+        config: SimulatorConfig,
+        block: BlockContext,
+    ) -> Self {
+        let mut simulator = EvmSimulator::new_with_config(provider, owner, block.number, config);
+        simulator.set_block_context(&block);
+        Self { simulator, block }
     }
 
     pub fn db_snapshot(&mut self) -> CacheDB<SharedBackend> {
         self.simulator.evm.db.as_mut().unwrap().clone()
     }
 
+    pub fn approve_for_router(&mut self, tokens: &[H160], owners: &[H160], router: H160) {
+        // Router based meat txs pull tokens with transferFrom, so allowances have to be in place
+        // the same way they are on chain. Failed approvals are logged and skipped
+        for token in tokens {
+            for owner in owners {
+                match self.simulator.token_approve(*token, *owner, router) {
+                    Ok(true) => {}
+                    Ok(false) => info!("[APPROVE ERROR] {:?} {:?} returned false", token, owner),
+                    Err(e) => info!("[APPROVE ERROR] {:?} {:?} {:?}", token, owner, e),
+                }
+            }
+        }
+    }
+
     pub async fn simulate(
         &mut self,
         tx: &Transaction,
-        sandwichable_pools: &HashMap<H160, Option<H160>>,
+        sandwichable_pools: &HashMap<H160, Option<(H160, U256)>>,
         verified_pools_map: &HashMap<H160, Pool>,
         honeypot_filter: &HoneypotFilter<M>,
+        capital: u32,
     ) -> Result<()> {
         // Setup DB and retrieve storage values required to run simulation
+        // capital is the most each frontrun commits, in whole units of the safe token
+        // tokens keeping balances in another contract are seeded there, like in the honeypot filter
+        self.simulator
+            .balance_accounts
+            .extend(honeypot_filter.simulator.balance_accounts.clone());
         self.simulator.set_eth_balance(10000);
         self.simulator.deploy_simulator();
 
@@ -49,7 +126,7 @@ impl<M: Middleware + 'static> SandwichSimulator<M> {
         for (touched_pool, used_token) in sandwichable_pools {
             // if used_token is not None, we can sandwich this tx
             match used_token {
-                Some(safe_token) => {
+                Some((safe_token, victim_amount)) => {
                     // seed simulator contract with some used_token balance
                     let simulator_address = self.simulator.simulator_address;
                     let token_info = honeypot_filter.safe_token_info.get(safe_token).unwrap();
@@ -59,7 +136,7 @@ impl<M: Middleware + 'static> SandwichSimulator<M> {
                         *safe_token,
                         token_info.decimals,
                         *balance_slot,
-                        10000,
+                        capital,
                     );
 
                     // load storage values before cloning db
@@ -73,12 +150,36 @@ impl<M: Middleware + 'static> SandwichSimulator<M> {
                         .token_balance_of(pool.token1, simulator_address);
                     _ = self.simulator.v2_pool_get_reserves(*touched_pool);
 
+                    // let the meat tx's router move the tokens we seeded, the sender's own
+                    // allowances are already on chain and must not be forged
+                    if let Some(router) = tx.to {
+                        self.approve_for_router(
+                            &[pool.token0, pool.token1],
+                            &[simulator_address],
+                            router,
+                        );
+                    }
+
                     let sandwich = Sandwich {
-                        amount_in: U256::zero(),
+                        amount_in: get_frontrun_amount(
+                            *victim_amount,
+                            token_info.decimals,
+                            capital,
+                        ),
                         balance_slot: *balance_slot,
+                        balance_account: honeypot_filter.balance_account(*safe_token),
                         target_token: token_info.clone(),
                         target_pool: pool.clone(),
+                        eth_pool: find_eth_pool(
+                            token_info.address,
+                            self.simulator.config.chain.wrapped_native,
+                            verified_pools_map,
+                        ),
+                        usd_pricer: None,
+                        eth_oracle: None,
                         meat_tx: tx.clone(),
+                        meat_prestate: BTreeMap::new(),
+                        token_taxes: (0, 0),
                     };
                     sandwiches.push(sandwich);
                 }
@@ -90,15 +191,13 @@ impl<M: Middleware + 'static> SandwichSimulator<M> {
         let fork_db = self.db_snapshot();
 
         // Try running simulations one by one at first
-        for mut sandwich in sandwiches {
-            let amount_in =
-                U256::from(1) * U256::from(10).pow(U256::from(sandwich.target_token.decimals));
-            sandwich.amount_in = amount_in;
+        for sandwich in sandwiches {
             match simulate_sandwich_bundle(
                 sandwich.clone(),
                 self.simulator.provider.clone(),
                 self.simulator.owner,
-                self.simulator.block_number,
+                &self.simulator.config,
+                &self.block,
                 Some(fork_db.clone()),
             ) {
                 Ok(_) => {}
@@ -110,77 +209,923 @@ impl<M: Middleware + 'static> SandwichSimulator<M> {
     }
 }
 
+pub fn get_frontrun_amount(victim_amount: U256, decimals: u8, capital: u32) -> U256 {
+    // Match the victim's size so the frontrun moves the price as much as the victim will,
+    // but never commit more than the capital we hold
+    let max_amount = U256::from(capital) * U256::from(10).pow(U256::from(decimals));
+    std::cmp::min(victim_amount, max_amount)
+}
+
+pub fn find_eth_pool(token: H160, weth: H160, pools: &HashMap<H160, Pool>) -> Option<Pool> {
+    // Any V2 pool trading token against WETH, None when token is WETH itself
+    if token == weth {
+        return None;
+    }
+    pools
+        .values()
+        .find(|pool| {
+            matches!(pool.version, DexVariant::UniswapV2)
+                && pool.has_token(token)
+                && pool.has_token(weth)
+        })
+        .cloned()
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingBlockState {
+    pub db: CacheDB<SharedBackend>,
+    // hashes of the committed txs, in block order
+    pub txs: Vec<H256>,
+    pub gas_used: u64,
+}
+
+pub async fn fetch_pending_block<M: Middleware + 'static>(
+    provider: Arc<M>,
+    block: &BlockContext,
+    max_txs: usize,
+) -> Result<Vec<Transaction>> {
+    // The top of the mempool, ordered the way a builder would fill the next block
+    let content = provider
+        .txpool_content()
+        .await
+        .map_err(|e| anyhow!("txpool_content failed: {:?}", e))?;
+    let txs = content
+        .pending
+        .into_values()
+        .flat_map(|txs| txs.into_values())
+        .collect();
+    Ok(order_pending_block(txs, block.next_base_fee, max_txs))
+}
+
+pub fn build_pending_block_state<M: Middleware + 'static>(
+    provider: Arc<M>,
+    config: &SimulatorConfig,
+    block: &BlockContext,
+    pending: &[Transaction],
+    victim: &Transaction,
+) -> PendingBlockState {
+    // Commits the pending txs a builder would place ahead of victim into a fork of the latest block,
+    // the frontrun lands right before victim, so it's simulated against the block we're competing for
+    // Reverted txs still land on chain and are kept, invalid txs are skipped
+    let txs = txs_ahead_of(pending, victim, block.next_base_fee);
+
+    let mut simulator =
+        EvmSimulator::new_with_config(provider, *ZERO_ADDRESS, block.number, config.clone());
+    simulator.set_block_context(block);
+
+    let mut committed = Vec::new();
+    let mut gas_used = 0;
+    for tx in &txs {
+        match simulator.run_pending_tx(tx, true) {
+            Ok(result) => gas_used += result.gas_used,
+            Err(SimError::Revert { gas_used: used, .. }) => gas_used += used,
+            Err(SimError::Halt(_)) => gas_used += tx.gas.as_u64(),
+            Err(e) => {
+                info!("[PENDING BLOCK] skipped {:?}: {}", tx.hash, e);
+                continue;
+            }
+        }
+        committed.push(tx.hash);
+    }
+
+    PendingBlockState {
+        db: simulator.db_snapshot(),
+        txs: committed,
+        gas_used,
+    }
+}
+
 pub fn simulate_sandwich_bundle<M: Middleware + 'static>(
     sandwich: Sandwich,
     provider: Arc<M>,
     owner: H160,
-    block_number: U64,
+    config: &SimulatorConfig,
+    block: &BlockContext,
     fork_db: Option<CacheDB<SharedBackend>>,
-) -> Result<i128> {
-    // Create a simulator instance and inject the forked db
+) -> Result<SandwichResult> {
+    // Create a simulator instance running in the block after `block`, and inject the forked db
+    let mut simulator =
+        EvmSimulator::new_with_config(provider, owner, block.number, config.clone());
+    simulator.set_block_context(block);
+    let simulator_address = simulator.simulator_address;
+    // an injected db (ex. a pending block state) is seeded too, the simulator isn't redeployed
+    if let Some(db) = fork_db {
+        simulator.inject_db(db);
+    }
+    // seed at least enough to cover the frontrun, in raw units so any decimals fit
+    let unit = token_unit(sandwich.target_token.decimals)?;
+    let seed_amount = std::cmp::max(
+        U256::from(10000) * unit,
+        sandwich.amount_in.saturating_add(unit),
+    );
+    if let Some(account) = sandwich.balance_account {
+        simulator
+            .balance_accounts
+            .insert(sandwich.target_token.address, account);
+    }
+    simulator.set_eth_balance(10000);
+    simulator.deploy_simulator();
+    simulator.set_token_balance_raw(
+        simulator_address,
+        sandwich.target_token.address,
+        sandwich.balance_slot,
+        seed_amount,
+    );
+    // on chain, the meat tx runs on the state left by earlier txs of the block, not just the fork's
+    simulator.apply_prestate(&sandwich.meat_prestate)?;
+    simulate_sandwich_bundle_with(sandwich, &mut simulator)
+}
+
+pub fn simulate_sandwich_bundle_with<S: SwapSimulator>(
+    sandwich: Sandwich,
+    simulator: &mut S,
+) -> Result<SandwichResult> {
     let amount_in = sandwich.amount_in;
     let target_token = sandwich.target_token;
     let target_pool = sandwich.target_pool;
 
-    info!("\n[🔮 Sandwich Bundle Simulation]");
-    info!(
+    // Lines for the same pending tx share its id
+    let id = correlation_id(sandwich.meat_tx.hash.as_bytes());
+
+    Metrics::add(&METRICS.simulations, 1);
+    log_with_id!(id, "[🔮 Sandwich Bundle Simulation]");
+    log_with_id!(
+        id,
         "- Pool: {:?} / Token: {:?}",
-        target_pool.address, target_token.symbol
+        target_pool.address,
+        target_token.symbol
     );
-    info!("- Amount in: {:?} {:?}", amount_in, target_token.symbol);
+    log_with_id!(id, "- Amount in: {:?} {:?}", amount_in, target_token.symbol);
 
-    let (input_token, output_token) = if target_pool.token0 == target_token.address {
-        (target_pool.token0, target_pool.token1)
-    } else {
-        (target_pool.token1, target_pool.token0)
+    // Price the target token before our own swaps move the pools
+    let usd_price = match &sandwich.usd_pricer {
+        Some(pricer) => pricer.usd_price(simulator, target_token.address).ok(),
+        None => None,
+    };
+    let eth_price_usd = match &sandwich.eth_oracle {
+        Some(oracle) => oracle.eth_price_usd(simulator).ok(),
+        None => None,
     };
 
-    let mut simulator = EvmSimulator::new(provider, owner, block_number);
-    let simulator_address = simulator.simulator_address;
-    match fork_db {
-        Some(db) => simulator.inject_db(db),
-        None => {
-            simulator.set_eth_balance(10000);
-            simulator.deploy_simulator();
-            simulator.set_token_balance(
-                simulator_address,
-                target_token.address,
-                target_token.decimals,
-                sandwich.balance_slot,
-                10000,
-            );
+    // weth_address is the chain config's wrapped_native, the same token eth_pool was found with
+    let eth_price = if target_token.address == simulator.weth_address() {
+        1.0
+    } else {
+        match &sandwich.eth_pool {
+            Some(eth_pool) => {
+                let reserves = simulator.v2_pool_get_reserves(eth_pool.address)?;
+                eth_pool.spot_price(reserves.0, reserves.1, target_token.address)
+            }
+            None => match (usd_price, eth_price_usd) {
+                (Some(usd_price), Some(eth_price_usd)) => usd_price / eth_price_usd,
+                _ => {
+                    log_with_id!(id, "No WETH pool for {:?}", target_token.symbol);
+                    0.0
+                }
+            },
         }
-    }
+    };
+
+    // A taxed token loses the tax on both legs on top of the pool fee
+    // The simulated amounts already include taxes taken on transfer, so the lower of the two is kept
+    let (buy_tax, sell_tax) = sandwich.token_taxes;
 
     // Frontrun tx
-    let frontrun_out = simulator.v2_simulate_swap(
-        amount_in,
-        target_pool.address,
-        input_token,
-        output_token,
-        true,
-    )?;
-    info!("✅ Frontrun out: {:?}", frontrun_out.1);
+    let frontrun_out =
+        simulator.swap_exact_in(&target_pool, amount_in, target_token.address, true)?;
+    let frontrun_received = std::cmp::min(frontrun_out.1, apply_tax(frontrun_out.0, buy_tax));
+    log_with_id!(id, "✅ Frontrun out: {:?}", frontrun_received);
 
     // Meat tx
-    match simulator.run_pending_tx(&sandwich.meat_tx) {
-        Ok(_) => info!("✅ Meat TX Successful"),
-        Err(e) => info!("✖️ Meat TX Failed: {:?}", e),
+    let price_before = target_price(simulator, &target_pool, target_token.address);
+    match simulator.run_pending_tx(&sandwich.meat_tx, false) {
+        Ok(_) => log_with_id!(id, "✅ Meat TX Successful"),
+        Err(e) => log_with_id!(id, "✖️ Meat TX Failed: {:?}", e),
     }
+    let price_after = target_price(simulator, &target_pool, target_token.address);
+    // the victim swaps target_token in as well, making it cheaper for our backrun to buy back
+    let meat_price_impact_bps = match (price_before, price_after) {
+        (Some(before), Some(after)) if before > 0.0 => (before - after) / before * 10000.0,
+        _ => 0.0,
+    };
 
     // Backrun tx
-    let backrun_out = simulator.v2_simulate_swap(
-        frontrun_out.1,
-        target_pool.address,
-        output_token,
-        input_token,
-        true,
-    )?;
-    info!("✅ Backrun out: {:?}", backrun_out.1);
+    let output_token = target_pool.token_out(target_token.address).unwrap();
+    let backrun_out =
+        simulator.swap_exact_in(&target_pool, frontrun_received, output_token, true)?;
+    let amount_out = std::cmp::min(backrun_out.1, apply_tax(backrun_out.0, sell_tax));
+    log_with_id!(id, "✅ Backrun out: {:?}", amount_out);
+
+    let profit = to_i128(amount_out)? - to_i128(amount_in)?;
+    log_with_id!(
+        id,
+        "▶️ Profit: {} {}",
+        format_token_amount(profit, target_token.decimals),
+        target_token.symbol
+    );
+
+    let profit_eth =
+        (profit as f64 / 10f64.powi(target_token.decimals as i32) * eth_price * 1e18) as i128;
+    log_with_id!(id, "▶️ Profit: {:?} wei", profit_eth);
+
+    let captured = to_i128(amount_in)? as f64 * meat_price_impact_bps / 10000.0;
+    let breakdown = SandwichBreakdown {
+        frontrun_in: amount_in,
+        frontrun_out: frontrun_received,
+        backrun_in: frontrun_received,
+        backrun_out: amount_out,
+        meat_price_impact_bps,
+        gross_profit: profit,
+        capture_rate: if captured > 0.0 {
+            profit as f64 / captured
+        } else {
+            0.0
+        },
+    };
+    log_with_id!(id, "▶️ Breakdown: {:?}", breakdown);
+
+    Metrics::add(&METRICS.simulation_successes, 1);
+    Ok(SandwichResult {
+        profit_token: profit,
+        profit_eth,
+        profit_usd: usd_price
+            .map(|price| profit as f64 / 10f64.powi(target_token.decimals as i32) * price),
+        eth_price_usd,
+        gas_used: simulator.gas_used(),
+        breakdown,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct MultiSandwichResult {
+    // one result per sandwich, in the order they were given
+    pub results: Vec<SandwichResult>,
+    pub profit_eth: i128,
+    pub profit_usd: Option<f64>,
+    pub eth_price_usd: Option<f64>,
+    pub gas_used: u64,
+}
+
+pub fn simulate_multi_pool_sandwich<M: Middleware + 'static>(
+    sandwiches: Vec<Sandwich>,
+    provider: Arc<M>,
+    owner: H160,
+    config: &SimulatorConfig,
+    block: &BlockContext,
+    fork_db: Option<CacheDB<SharedBackend>>,
+) -> Result<MultiSandwichResult> {
+    let mut simulator =
+        EvmSimulator::new_with_config(provider, owner, block.number, config.clone());
+    simulator.set_block_context(block);
+    let simulator_address = simulator.simulator_address;
+    // an injected db (ex. a pending block state) is seeded too, like in simulate_sandwich_bundle
+    if let Some(db) = fork_db {
+        simulator.inject_db(db);
+    }
+    // the frontruns share one balance per target token, seeded to cover all of them
+    let mut amounts: HashMap<H160, (u32, U256, U256)> = HashMap::new();
+    for sandwich in &sandwiches {
+        let unit = token_unit(sandwich.target_token.decimals)?;
+        let entry = amounts.entry(sandwich.target_token.address).or_insert((
+            sandwich.balance_slot,
+            unit,
+            U256::zero(),
+        ));
+        entry.2 = entry.2.saturating_add(sandwich.amount_in);
+        if let Some(account) = sandwich.balance_account {
+            simulator
+                .balance_accounts
+                .insert(sandwich.target_token.address, account);
+        }
+    }
+    simulator.set_eth_balance(10000);
+    simulator.deploy_simulator();
+    for (token, (balance_slot, unit, total_in)) in amounts {
+        let seed_amount = std::cmp::max(U256::from(10000) * unit, total_in.saturating_add(unit));
+        simulator.set_token_balance_raw(simulator_address, token, balance_slot, seed_amount);
+    }
+    for sandwich in &sandwiches {
+        simulator.apply_prestate(&sandwich.meat_prestate)?;
+    }
+    simulate_multi_pool_sandwich_with(sandwiches, &mut simulator)
+}
 
-    let amount_out = backrun_out.1;
-    let profit = (amount_out.as_u64() as i128) - (amount_in.as_u64() as i128);
-    info!("▶️ Profit: {:?} {:?}", profit, target_token.symbol);
+pub fn simulate_multi_pool_sandwich_with<S: SwapSimulator>(
+    sandwiches: Vec<Sandwich>,
+    simulator: &mut S,
+) -> Result<MultiSandwichResult> {
+    // All frontruns, then the meat txs in the order given (mempool order), then all backruns,
+    // so overlapping pools see each other's swaps like they would in a single bundle
+    if sandwiches.is_empty() {
+        return Ok(MultiSandwichResult {
+            results: Vec::new(),
+            profit_eth: 0,
+            profit_usd: None,
+            eth_price_usd: None,
+            gas_used: 0,
+        });
+    }
+    let id = correlation_id(
+        &sandwiches
+            .iter()
+            .map(|sandwich| sandwich.meat_tx.hash.as_bytes())
+            .collect::<Vec<&[u8]>>()
+            .concat(),
+    );
+
+    Metrics::add(&METRICS.simulations, 1);
+    log_with_id!(
+        id,
+        "[🔮 Multi Pool Sandwich Simulation] {} sandwiches",
+        sandwiches.len()
+    );
+
+    // Priced before any of our swaps move the pools
+    let mut prices = Vec::new();
+    let mut eth_price_usd = None;
+    for sandwich in &sandwiches {
+        let target_token = sandwich.target_token.address;
+        let usd_price = match &sandwich.usd_pricer {
+            Some(pricer) => pricer.usd_price(simulator, target_token).ok(),
+            None => None,
+        };
+        if let (None, Some(oracle)) = (eth_price_usd, &sandwich.eth_oracle) {
+            eth_price_usd = oracle.eth_price_usd(simulator).ok();
+        }
+        let eth_price = if target_token == simulator.weth_address() {
+            1.0
+        } else {
+            match &sandwich.eth_pool {
+                Some(eth_pool) => {
+                    let reserves = simulator.v2_pool_get_reserves(eth_pool.address)?;
+                    eth_pool.spot_price(reserves.0, reserves.1, target_token)
+                }
+                None => match (usd_price, eth_price_usd) {
+                    (Some(usd_price), Some(eth_price_usd)) => usd_price / eth_price_usd,
+                    _ => 0.0,
+                },
+            }
+        };
+        prices.push((eth_price, usd_price));
+    }
+
+    // Frontrun txs
+    let mut frontruns = Vec::new();
+    for (n, sandwich) in sandwiches.iter().enumerate() {
+        let (buy_tax, _) = sandwich.token_taxes;
+        let gas_before = simulator.gas_used();
+        let out = simulator.swap_exact_in(
+            &sandwich.target_pool,
+            sandwich.amount_in,
+            sandwich.target_token.address,
+            true,
+        )?;
+        let received = std::cmp::min(out.1, apply_tax(out.0, buy_tax));
+        log_with_id!(id, "✅ Frontrun #{} out: {:?}", n + 1, received);
+        frontruns.push((received, simulator.gas_used() - gas_before));
+    }
+
+    // Meat txs, a tx sandwiched on several pools only runs once
+    let price_before: Vec<Option<f64>> = sandwiches
+        .iter()
+        .map(|sandwich| {
+            target_price(
+                simulator,
+                &sandwich.target_pool,
+                sandwich.target_token.address,
+            )
+        })
+        .collect();
+    let mut executed = Vec::new();
+    for sandwich in &sandwiches {
+        if executed.contains(&sandwich.meat_tx.hash) {
+            continue;
+        }
+        executed.push(sandwich.meat_tx.hash);
+        match simulator.run_pending_tx(&sandwich.meat_tx, false) {
+            Ok(_) => log_with_id!(id, "✅ Meat TX Successful: {:?}", sandwich.meat_tx.hash),
+            Err(e) => log_with_id!(id, "✖️ Meat TX Failed: {:?} {:?}", sandwich.meat_tx.hash, e),
+        }
+    }
+
+    // Backrun txs
+    let mut results = Vec::new();
+    for (n, sandwich) in sandwiches.iter().enumerate() {
+        let target_token = &sandwich.target_token;
+        let target_pool = &sandwich.target_pool;
+        let (frontrun_received, frontrun_gas) = frontruns[n];
+        let (eth_price, usd_price) = prices[n];
+        let (_, sell_tax) = sandwich.token_taxes;
+
+        let price_after = target_price(simulator, target_pool, target_token.address);
+        let meat_price_impact_bps = match (price_before[n], price_after) {
+            (Some(before), Some(after)) if before > 0.0 => (before - after) / before * 10000.0,
+            _ => 0.0,
+        };
+
+        let gas_before = simulator.gas_used();
+        let output_token = target_pool.token_out(target_token.address).unwrap();
+        let out = simulator.swap_exact_in(target_pool, frontrun_received, output_token, true)?;
+        let amount_out = std::cmp::min(out.1, apply_tax(out.0, sell_tax));
+        log_with_id!(id, "✅ Backrun #{} out: {:?}", n + 1, amount_out);
+
+        let profit = to_i128(amount_out)? - to_i128(sandwich.amount_in)?;
+        let profit_float = profit as f64 / 10f64.powi(target_token.decimals as i32);
+        let captured = to_i128(sandwich.amount_in)? as f64 * meat_price_impact_bps / 10000.0;
+        results.push(SandwichResult {
+            profit_token: profit,
+            profit_eth: (profit_float * eth_price * 1e18) as i128,
+            profit_usd: usd_price.map(|price| profit_float * price),
+            eth_price_usd,
+            gas_used: frontrun_gas + simulator.gas_used() - gas_before,
+            breakdown: SandwichBreakdown {
+                frontrun_in: sandwich.amount_in,
+                frontrun_out: frontrun_received,
+                backrun_in: frontrun_received,
+                backrun_out: amount_out,
+                meat_price_impact_bps,
+                gross_profit: profit,
+                capture_rate: if captured > 0.0 {
+                    profit as f64 / captured
+                } else {
+                    0.0
+                },
+            },
+        });
+    }
+
+    let profit_eth = results.iter().map(|result| result.profit_eth).sum();
+    // only summed when every sandwich could be priced in USD
+    let profit_usd = results
+        .iter()
+        .map(|result| result.profit_usd)
+        .sum::<Option<f64>>();
+    log_with_id!(id, "▶️ Aggregate profit: {:?} wei", profit_eth);
+
+    Metrics::add(&METRICS.simulation_successes, 1);
+    Ok(MultiSandwichResult {
+        results,
+        profit_eth,
+        profit_usd,
+        eth_price_usd,
+        gas_used: simulator.gas_used(),
+    })
+}
+
+fn target_price<S: SwapSimulator>(
+    simulator: &mut S,
+    pool: &Pool,
+    target_token: H160,
+) -> Option<f64> {
+    // Price of target_token in the pool's other token, None for pools without getReserves
+    if let DexVariant::Curve = pool.version {
+        return None;
+    }
+    let reserves = simulator.v2_pool_get_reserves(pool.address).ok()?;
+    Some(pool.spot_price(reserves.0, reserves.1, target_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::WEI;
+    use crate::simulator::mock::MockSimulator;
+    use ethers::providers::Provider;
+    use ethers::types::U64;
+    use foundry_evm::executor::Bytecode;
+    use foundry_evm::revm::primitives::{AccountInfo, U256 as rU256};
+
+    fn weth_token(address: H160) -> Token {
+        Token {
+            address,
+            implementation: None,
+            name: "Wrapped Ether".to_string(),
+            symbol: "WETH".to_string(),
+            decimals: 18,
+        }
+    }
+
+    fn sandwich(weth: H160, target_pool: Pool, meat_tx: Transaction) -> Sandwich {
+        Sandwich {
+            amount_in: *WEI,
+            balance_slot: 0,
+            balance_account: None,
+            target_token: weth_token(weth),
+            target_pool,
+            eth_pool: None,
+            usd_pricer: None,
+            eth_oracle: None,
+            meat_tx,
+            meat_prestate: BTreeMap::new(),
+            token_taxes: (0, 0),
+        }
+    }
+
+    fn mock_with(
+        weth: H160,
+        pools: &[(Pool, u128, u128)],
+        victims: &[(Transaction, H160, U256)],
+    ) -> MockSimulator {
+        let mut simulator = MockSimulator::new(weth);
+        for (pool, reserve0, reserve1) in pools {
+            simulator.add_pool(pool.clone(), *reserve0, *reserve1);
+        }
+        for (tx, pool, amount_in) in victims {
+            // the victims buy with WETH too, right behind our frontruns
+            simulator.add_pending_swap(tx, *pool, weth, *amount_in);
+        }
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, weth, 18, 0, 100);
+        simulator
+    }
+
+    #[test]
+    fn test_frontrun_amount_scales_with_the_victim() {
+        // a USDT victim: the frontrun follows its size, not 1 USDT
+        let usdt = U256::from(10).pow(U256::from(6));
+        let small = get_frontrun_amount(U256::from(500) * usdt, 6, 10000);
+        let large = get_frontrun_amount(U256::from(5000) * usdt, 6, 10000);
+        assert_eq!(small, U256::from(500) * usdt);
+        assert_eq!(large, U256::from(5000) * usdt);
 
-    Ok(profit)
+        // a victim bigger than our capital is matched with all of it
+        let capped = get_frontrun_amount(U256::from(2_000_000) * usdt, 6, 10000);
+        assert_eq!(capped, U256::from(10000) * usdt);
+
+        // capital is in whole units of the token, 18 decimals included
+        let weth = get_frontrun_amount(U256::from(50) * *WEI, 18, 10);
+        assert_eq!(weth, U256::from(10) * *WEI);
+    }
+
+    #[test]
+    fn test_multi_pool_sandwich_sums_independent_sandwiches() {
+        let weth = H160::random();
+        let e18 = 10u128.pow(18);
+        let v2_pool = |token1: H160| Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: weth,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let pools = vec![
+            (v2_pool(H160::random()), 100 * e18, 200000 * e18),
+            (v2_pool(H160::random()), 50 * e18, 10000 * e18),
+        ];
+        let victims: Vec<(Transaction, H160, U256)> = pools
+            .iter()
+            .zip([10u64, 4])
+            .map(|((pool, _, _), eth)| {
+                let tx = Transaction {
+                    hash: H256::random(),
+                    ..Default::default()
+                };
+                (tx, pool.address, U256::from(eth) * *WEI)
+            })
+            .collect();
+        let sandwiches: Vec<Sandwich> = pools
+            .iter()
+            .zip(&victims)
+            .map(|((pool, _, _), (tx, _, _))| sandwich(weth, pool.clone(), tx.clone()))
+            .collect();
+
+        // each sandwich alone, on its own fresh state
+        let singles: Vec<SandwichResult> = sandwiches
+            .iter()
+            .map(|sandwich| {
+                let mut simulator = mock_with(weth, &pools, &victims);
+                simulate_sandwich_bundle_with(sandwich.clone(), &mut simulator).unwrap()
+            })
+            .collect();
+        assert!(singles.iter().all(|result| result.profit_token > 0));
+
+        let mut simulator = mock_with(weth, &pools, &victims);
+        let multi = simulate_multi_pool_sandwich_with(sandwiches, &mut simulator).unwrap();
+
+        // the pools don't overlap, bundling them changes nothing for either sandwich
+        assert_eq!(multi.results.len(), 2);
+        for (single, bundled) in singles.iter().zip(&multi.results) {
+            assert_eq!(bundled.profit_token, single.profit_token);
+            assert_eq!(bundled.profit_eth, single.profit_eth);
+            assert_eq!(bundled.gas_used, single.gas_used);
+        }
+        assert_eq!(
+            multi.profit_eth,
+            singles.iter().map(|result| result.profit_eth).sum::<i128>()
+        );
+        assert_eq!(
+            multi.gas_used,
+            singles.iter().map(|result| result.gas_used).sum::<u64>()
+        );
+        assert_eq!(multi.profit_usd, None);
+    }
+
+    #[test]
+    fn test_sandwich_breakdown_is_consistent() {
+        let weth = H160::random();
+        let e18 = 10u128.pow(18);
+        let pool = Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: weth,
+            token1: H160::random(),
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let meat_tx = Transaction {
+            hash: H256::random(),
+            ..Default::default()
+        };
+        let mut simulator = mock_with(
+            weth,
+            &[(pool.clone(), 100 * e18, 200000 * e18)],
+            &[(meat_tx.clone(), pool.address, U256::from(10) * *WEI)],
+        );
+
+        let result =
+            simulate_sandwich_bundle_with(sandwich(weth, pool.clone(), meat_tx), &mut simulator)
+                .unwrap();
+        let breakdown = &result.breakdown;
+
+        // the whole frontrun position is sold back in the backrun
+        assert_eq!(breakdown.frontrun_in, *WEI);
+        assert_eq!(
+            breakdown.frontrun_out,
+            pool.amount_out(*WEI, (100 * e18, 200000 * e18), weth)
+        );
+        assert_eq!(breakdown.backrun_in, breakdown.frontrun_out);
+        assert_eq!(
+            breakdown.gross_profit,
+            breakdown.backrun_out.as_u128() as i128 - breakdown.frontrun_in.as_u128() as i128
+        );
+        assert_eq!(breakdown.gross_profit, result.profit_token);
+        // WETH is priced at 1
+        assert_eq!(result.profit_eth, result.profit_token);
+
+        // the victim's WETH buy made the token cheaper in WETH, and part of that move was captured
+        assert!(breakdown.meat_price_impact_bps > 0.0);
+        assert!(breakdown.gross_profit > 0);
+        let captured =
+            breakdown.frontrun_in.as_u128() as f64 * breakdown.meat_price_impact_bps / 10000.0;
+        assert!((breakdown.capture_rate - breakdown.gross_profit as f64 / captured).abs() < 1e-9);
+        assert!(breakdown.capture_rate > 0.0);
+    }
+
+    #[test]
+    fn test_taxed_token_sandwich_loses_the_tax() {
+        let weth = H160::random();
+        let e18 = 10u128.pow(18);
+        let token = H160::random();
+        let pool = Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: weth,
+            token1: token,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let meat_tx = Transaction {
+            hash: H256::random(),
+            ..Default::default()
+        };
+        let victim_in = U256::from(10) * *WEI;
+        let run = |token_taxes: (u32, u32)| {
+            let mut simulator = mock_with(
+                weth,
+                &[(pool.clone(), 100 * e18, 200000 * e18)],
+                &[(meat_tx.clone(), pool.address, victim_in)],
+            );
+            let mut sandwich = sandwich(weth, pool.clone(), meat_tx.clone());
+            sandwich.token_taxes = token_taxes;
+            simulate_sandwich_bundle_with(sandwich, &mut simulator).unwrap()
+        };
+
+        let untaxed = run((0, 0));
+        let taxed = run((1000, 1000));
+        assert!(untaxed.profit_token > 0);
+        assert!(taxed.profit_token < 0);
+
+        // 10% of the frontrun's tokens never arrive, then 10% of the backrun's WETH
+        let (r0, r1) = (100 * e18, 200000 * e18);
+        let frontrun_out = pool.amount_out(*WEI, (r0, r1), weth);
+        let (r0, r1) = (r0 + WEI.as_u128(), r1 - frontrun_out.as_u128());
+        let victim_out = pool.amount_out(victim_in, (r0, r1), weth);
+        let (r0, r1) = (r0 + victim_in.as_u128(), r1 - victim_out.as_u128());
+        let backrun_in = apply_tax(frontrun_out, 1000);
+        let backrun_out = apply_tax(pool.amount_out(backrun_in, (r0, r1), token), 1000);
+        assert_eq!(taxed.breakdown.frontrun_out, backrun_in);
+        assert_eq!(taxed.breakdown.backrun_out, backrun_out);
+        assert_eq!(
+            taxed.profit_token,
+            backrun_out.as_u128() as i128 - WEI.as_u128() as i128
+        );
+    }
+
+    #[test]
+    fn test_usdc_sandwich_profit_in_eth() {
+        let (weth, usdc) = (H160::random(), H160::random());
+        let pool = |token1: H160| Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: usdc,
+            token1,
+            decimals0: 6,
+            decimals1: 18,
+            fee: 300,
+        };
+        // USDC/TOKEN is sandwiched, USDC/WETH at 2000 USDC per WETH converts the profit
+        let target_pool = pool(H160::random());
+        let eth_pool = pool(weth);
+        let meat_tx = Transaction {
+            hash: H256::random(),
+            ..Default::default()
+        };
+
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(
+            target_pool.clone(),
+            1000000 * 10u128.pow(6),
+            1000000 * 10u128.pow(18),
+        );
+        simulator.add_pool(
+            eth_pool.clone(),
+            2000000 * 10u128.pow(6),
+            1000 * 10u128.pow(18),
+        );
+        simulator.add_pending_swap(
+            &meat_tx,
+            target_pool.address,
+            usdc,
+            U256::from(100000) * U256::from(10).pow(U256::from(6)),
+        );
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, usdc, 6, 0, 100000);
+
+        let sandwich = Sandwich {
+            amount_in: U256::from(10000) * U256::from(10).pow(U256::from(6)),
+            balance_slot: 0,
+            balance_account: None,
+            target_token: Token {
+                address: usdc,
+                implementation: None,
+                name: "USD Coin".to_string(),
+                symbol: "USDC".to_string(),
+                decimals: 6,
+            },
+            target_pool,
+            eth_pool: Some(eth_pool),
+            usd_pricer: None,
+            eth_oracle: None,
+            meat_tx,
+            meat_prestate: BTreeMap::new(),
+            token_taxes: (0, 0),
+        };
+        let result = simulate_sandwich_bundle_with(sandwich, &mut simulator).unwrap();
+
+        // 1 USDC is 0.0005 WETH in the ETH pool
+        assert!(result.profit_token > 0);
+        let expected = (result.profit_token as f64 / 1e6 * 0.0005 * 1e18) as i128;
+        assert_eq!(result.profit_eth, expected);
+        assert!(result.profit_eth < result.profit_token * 10i128.pow(12));
+    }
+
+    #[test]
+    fn test_sandwich_without_eth_pool_is_valued_through_the_eth_oracle() {
+        let chain = crate::chain::ChainConfig::mainnet();
+        let (weth, usdc) = (chain.wrapped_native, chain.usdc);
+        let pool = |token1: H160, decimals1: u8| Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: usdc,
+            token1,
+            decimals0: 6,
+            decimals1,
+            fee: 300,
+        };
+        // USDC/TOKEN is sandwiched, WETH/USDC at $2000 only prices ETH
+        let target_pool = pool(H160::random(), 18);
+        let eth_usdc_pool = pool(weth, 18);
+        let pools = vec![target_pool.clone(), eth_usdc_pool.clone()];
+        let meat_tx = Transaction {
+            hash: H256::random(),
+            ..Default::default()
+        };
+
+        let mut simulator = MockSimulator::new(weth);
+        simulator.add_pool(
+            target_pool.clone(),
+            1000000 * 10u128.pow(6),
+            1000000 * 10u128.pow(18),
+        );
+        simulator.add_pool(
+            eth_usdc_pool,
+            2000000 * 10u128.pow(6),
+            1000 * 10u128.pow(18),
+        );
+        simulator.add_pending_swap(
+            &meat_tx,
+            target_pool.address,
+            usdc,
+            U256::from(100000) * U256::from(10).pow(U256::from(6)),
+        );
+        let simulator_address = simulator.simulator_address;
+        simulator.set_token_balance(simulator_address, usdc, 6, 0, 100000);
+
+        let sandwich = Sandwich {
+            amount_in: U256::from(10000) * U256::from(10).pow(U256::from(6)),
+            balance_slot: 0,
+            balance_account: None,
+            target_token: Token {
+                address: usdc,
+                implementation: None,
+                name: "USD Coin".to_string(),
+                symbol: "USDC".to_string(),
+                decimals: 6,
+            },
+            target_pool,
+            eth_pool: None,
+            usd_pricer: Some(Arc::new(UsdPricer::new(&chain, &pools))),
+            eth_oracle: Some(Arc::new(EthPriceOracle::new(&chain, &pools))),
+            meat_tx,
+            meat_prestate: BTreeMap::new(),
+            token_taxes: (0, 0),
+        };
+        let result = simulate_sandwich_bundle_with(sandwich, &mut simulator).unwrap();
+
+        assert!(result.profit_token > 0);
+        let eth_price_usd = result.eth_price_usd.unwrap();
+        assert!((eth_price_usd - 2000.0).abs() < 1e-6);
+        // USDC is taken at $1, so the profit is worth profit / 2000 ETH
+        let expected = (result.profit_token as f64 / 1e6 * (1.0 / eth_price_usd) * 1e18) as i128;
+        assert_eq!(result.profit_eth, expected);
+    }
+
+    #[test]
+    fn test_router_swap_needs_the_approval_step() {
+        // A token whose approve stores allowance[caller][spender] at slot 1 and whose transferFrom
+        // reverts when allowance[from][caller] is below the amount
+        let token_code: ethers::types::Bytes = "0x60003560e01c8063095ea7b314601b57806323b872dd14604857005b3360005260016020526040600020602052600435600052604060002060243590555b600160005260206000f35b600435600052600160205260406000206020523360005260406000205460443511607057603d565b600080fd"
+            .parse()
+            .unwrap();
+        // A router that pulls 1 wei of the token in slot 0 from the holder in slot 1, reverting if it can't
+        let router_code: ethers::types::Bytes = "0x6323b872dd60e01b600052600154600452306024526001604452602060006064600060006000545af115602e57005b600080fd"
+            .parse()
+            .unwrap();
+
+        let (token, router, sender) = (H160::random(), H160::random(), H160::random());
+        let meat_tx = Transaction {
+            from: sender,
+            to: Some(router),
+            gas: U256::from(100000),
+            ..Default::default()
+        };
+        let word = |address: H160| -> rU256 {
+            U256::from_big_endian(H256::from(address).as_bytes()).into()
+        };
+
+        let run = |approve: bool| {
+            let (provider, _) = Provider::mocked();
+            let block = BlockContext {
+                number: U64::from(1),
+                ..Default::default()
+            };
+            let mut sandwich = SandwichSimulator::new(
+                Arc::new(provider),
+                H160::random(),
+                SimulatorConfig::default(),
+                block,
+            );
+            let db = sandwich.simulator.evm.db.as_mut().unwrap();
+            for (address, code) in [
+                (token, Bytecode::new_raw(token_code.0.clone())),
+                (router, Bytecode::new_raw(router_code.0.clone())),
+                (sender, Bytecode::default()),
+                (*ZERO_ADDRESS, Bytecode::default()),
+            ] {
+                db.insert_account_info(address.into(), AccountInfo::new(rU256::ZERO, 0, code));
+            }
+            sandwich
+                .simulator
+                .set_storage(router, rU256::ZERO, word(token))
+                .unwrap();
+            sandwich
+                .simulator
+                .set_storage(router, rU256::from(1), word(sender))
+                .unwrap();
+
+            if approve {
+                sandwich.approve_for_router(&[token], &[sender], router);
+            }
+            sandwich.simulator.run_pending_tx(&meat_tx, false)
+        };
+
+        // without the allowance the router's transferFrom fails and the meat tx reverts
+        assert!(matches!(run(false), Err(SimError::Revert { .. })));
+        // after the approval step the same swap goes through
+        assert!(run(true).is_ok());
+    }
 }
@@ -1,7 +1,8 @@
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use ethers::abi;
-use ethers::types::{Transaction, H160, U256, U64};
+use ethers::signers::{LocalWallet, Signer};
+use ethers::types::{AccountState, Transaction, H160, H256, U256, U64};
 use ethers_providers::Middleware;
 use foundry_evm::{
     executor::{
@@ -9,15 +10,32 @@ use foundry_evm::{
         Bytecode, ExecutionResult, Output, TransactTo,
     },
     revm::{
-        db::{CacheDB, Database},
-        primitives::{keccak256, AccountInfo, U256 as rU256},
+        db::{CacheDB, Database, DatabaseRef},
+        primitives::{keccak256, AccountInfo, Address as rAddress, EVMError, U256 as rU256},
         EVM,
     },
 };
-use std::{collections::BTreeSet, str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::constants::SIMULATOR_CODE;
-use crate::interfaces::{pool::V2PoolABI, simulator::SimulatorABI, token::TokenABI};
+use crate::chain::ChainConfig;
+use crate::constants::{
+    Env, AMOUNT_IN_CODE, DEFAULT_V2_FEE_BPS, FORWARDER_CODE, PERMIT_TYPEHASH, SIMULATOR_CODE,
+};
+use crate::interfaces::{
+    curve::CurvePoolABI, factory::V2FactoryABI, permit::PermitABI, pool::V2PoolABI,
+    quoter::V3QuoterABI, simulator::SimulatorABI, token::TokenABI, weth::WethABI,
+};
+use crate::pools::{DexVariant, Pool};
+use crate::streams::BlockContext;
+use crate::trace::allowance_key;
+use crate::utils::token_unit;
 
 #[derive(Clone)]
 pub struct EvmSimulator<M> {
@@ -25,12 +43,164 @@ pub struct EvmSimulator<M> {
     pub owner: H160,
     pub evm: EVM<CacheDB<SharedBackend>>,
     pub block_number: U64,
+    // State fetched through the backend, shared by every clone of the fork
+    pub blockchain_db: BlockchainDb,
 
     pub token: TokenABI,
     pub v2_pool: V2PoolABI,
+    pub v2_factory: V2FactoryABI,
+    pub curve_pool: CurvePoolABI,
     pub simulator: SimulatorABI,
+    pub weth: WethABI,
+    pub v3_quoter: V3QuoterABI,
+    pub permit: PermitABI,
 
     pub simulator_address: H160,
+    // Where AMOUNT_IN_CODE is deployed, the exact out entrypoint
+    pub amount_in_address: H160,
+    // The chain's wrapped native token, WBNB on BSC
+    pub weth_address: H160,
+    // Uniswap V3 QuoterV2, already deployed on the forked chain, from the chain config
+    pub v3_quoter_address: H160,
+
+    // Total gas used by committed calls, used to price the whole bundle
+    pub gas_used: u64,
+
+    // Tokens whose balances live in another contract's storage (token -> storage account)
+    pub balance_accounts: HashMap<H160, H160>,
+    // User supplied balance slots, these take precedence over the slot passed to set_token_balance
+    pub balance_slots: HashMap<H160, u32>,
+
+    pub config: SimulatorConfig,
+}
+
+#[derive(Debug, Clone)]
+pub enum SimError {
+    Revert { output: Bytes, gas_used: u64 },
+    Halt(String),
+    Encode(String),
+    Decode(String),
+    // the fork backend couldn't fetch state
+    Backend(String),
+    // the tx was rejected before execution (nonce, base fee, gas limit...)
+    Invalid(String),
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimError::Revert { output, gas_used } => {
+                write!(f, "EVM REVERT: {:?} / Gas used: {:?}", output, gas_used)
+            }
+            SimError::Halt(reason) => write!(f, "EVM HALT: {}", reason),
+            SimError::Encode(e) => write!(f, "Encode error: {}", e),
+            SimError::Decode(e) => write!(f, "Decode error: {}", e),
+            SimError::Backend(e) => write!(f, "Backend error: {}", e),
+            SimError::Invalid(e) => write!(f, "Invalid tx: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+impl<DBError: fmt::Debug> From<EVMError<DBError>> for SimError {
+    fn from(e: EVMError<DBError>) -> Self {
+        match e {
+            EVMError::Database(e) => SimError::Backend(format!("{:?}", e)),
+            e => SimError::Invalid(format!("{:?}", e)),
+        }
+    }
+}
+
+pub fn handle_result(result: ExecutionResult) -> Result<TxResult, SimError> {
+    match result {
+        ExecutionResult::Success {
+            gas_used,
+            gas_refunded,
+            output,
+            ..
+        } => match output {
+            Output::Call(o) => Ok(TxResult {
+                output: o,
+                gas_used,
+                gas_refunded,
+            }),
+            Output::Create(o, _) => Ok(TxResult {
+                output: o,
+                gas_used,
+                gas_refunded,
+            }),
+        },
+        ExecutionResult::Revert { gas_used, output } => Err(SimError::Revert { output, gas_used }),
+        ExecutionResult::Halt { reason, .. } => Err(SimError::Halt(format!("{:?}", reason))),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    pub disable_block_gas_limit: bool,
+    pub disable_base_fee: bool,
+    pub limit_contract_code_size: Option<usize>,
+    // Pending txs declaring more gas than this are not replayed
+    pub max_pending_tx_gas: Option<u64>,
+    // When set, v2 swaps are also run through the pool's own swap() and must agree within this (bps)
+    pub swap_tolerance_bps: Option<u32>,
+    // Blocks after the fork block that simulations run in, 0 simulates the fork block itself
+    pub block_offset: u64,
+    // Accounts and storage fetched from the RPC are kept in <cache_dir>/<block>.json,
+    // so later runs at the same block read them from disk, see flush_cache
+    pub cache_dir: Option<PathBuf>,
+    // Chain being forked, mainnet by default, see from_env
+    pub chain: ChainConfig,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            disable_block_gas_limit: true,
+            disable_base_fee: true,
+            limit_contract_code_size: Some(0x100000),
+            max_pending_tx_gas: None,
+            swap_tolerance_bps: None,
+            block_offset: 1,
+            cache_dir: None,
+            chain: ChainConfig::mainnet(),
+        }
+    }
+}
+
+impl SimulatorConfig {
+    pub fn from_env(env: &Env) -> Result<Self> {
+        // Built once at startup and passed to every simulator, nothing here reads the process env
+        Ok(Self {
+            cache_dir: env.fork_cache_dir.as_ref().map(PathBuf::from),
+            chain: ChainConfig::from_env(env)?,
+            ..Self::default()
+        })
+    }
+
+    pub fn realistic() -> Self {
+        // Enforce the real block gas limit and base fee, used to validate a bundle before sending
+        Self {
+            disable_block_gas_limit: false,
+            disable_base_fee: false,
+            limit_contract_code_size: Some(0x100000),
+            max_pending_tx_gas: None,
+            swap_tolerance_bps: None,
+            block_offset: 1,
+            cache_dir: None,
+            chain: ChainConfig::mainnet(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccountOverride {
+    pub balance: Option<U256>,
+    pub nonce: Option<u64>,
+    pub code: Option<Bytes>,
+    // slot -> value, slots that aren't listed keep their forked value
+    pub storage: HashMap<U256, U256>,
 }
 
 #[derive(Debug, Clone)]
@@ -42,6 +212,29 @@ pub struct Tx {
     pub gas_limit: u64,
 }
 
+#[derive(Debug, Clone)]
+pub struct BundleTxResult {
+    pub hash: H256,
+    pub gas_used: u64,
+    // effective gas price paid at the bundle's base fee
+    pub gas_price: U256,
+    // priority fees, the part of the gas cost the coinbase receives
+    pub gas_fees: U256,
+    pub coinbase_diff: U256,
+    // direct payments to the coinbase, on top of gas fees
+    pub eth_sent_to_coinbase: U256,
+    // None when the tx succeeded
+    pub error: Option<SimError>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BundleResult {
+    pub results: Vec<BundleTxResult>,
+    pub total_gas_used: u64,
+    pub coinbase_diff: U256,
+    pub bundle_gas_price: U256,
+}
+
 #[derive(Debug, Clone)]
 pub struct TxResult {
     pub output: Bytes,
@@ -51,16 +244,30 @@ pub struct TxResult {
 
 impl<M: Middleware + 'static> EvmSimulator<M> {
     pub fn new(provider: Arc<M>, owner: H160, block_number: U64) -> Self {
+        Self::new_with_config(provider, owner, block_number, SimulatorConfig::default())
+    }
+
+    pub fn new_with_config(
+        provider: Arc<M>,
+        owner: H160,
+        block_number: U64,
+        config: SimulatorConfig,
+    ) -> Self {
+        let cache_path = config
+            .cache_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", block_number)));
+        let blockchain_db = BlockchainDb::new(
+            BlockchainDbMeta {
+                cfg_env: Default::default(),
+                block_env: Default::default(),
+                hosts: BTreeSet::from(["".to_string()]),
+            },
+            cache_path,
+        );
         let shared_backend = SharedBackend::spawn_backend_thread(
             provider.clone(),
-            BlockchainDb::new(
-                BlockchainDbMeta {
-                    cfg_env: Default::default(),
-                    block_env: Default::default(),
-                    hosts: BTreeSet::from(["".to_string()]),
-                },
-                None,
-            ),
+            blockchain_db.clone(),
             Some(block_number.into()),
         );
         let db = CacheDB::new(shared_backend);
@@ -68,33 +275,146 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
         let mut evm = EVM::new();
         evm.database(db);
 
-        evm.env.cfg.limit_contract_code_size = Some(0x100000);
-        evm.env.cfg.disable_block_gas_limit = true;
-        evm.env.cfg.disable_base_fee = true;
+        evm.env.cfg.limit_contract_code_size = config.limit_contract_code_size;
+        evm.env.cfg.disable_block_gas_limit = config.disable_block_gas_limit;
+        evm.env.cfg.disable_base_fee = config.disable_base_fee;
 
-        evm.env.block.number = rU256::from(block_number.as_u64() + 1);
+        evm.env.block.number = rU256::from(block_number.as_u64() + config.block_offset);
 
         Self {
             provider,
             owner,
             evm,
             block_number,
+            blockchain_db,
 
             token: TokenABI::new(),
             v2_pool: V2PoolABI::new(),
+            v2_factory: V2FactoryABI::new(),
+            curve_pool: CurvePoolABI::new(),
             simulator: SimulatorABI::new(),
+            weth: WethABI::new(),
+            v3_quoter: V3QuoterABI::new(),
+            permit: PermitABI::new(),
 
             simulator_address: H160::from_str("0x4E17607Fb72C01C280d7b5c41Ba9A2109D74a32C")
                 .unwrap(),
+            amount_in_address: H160::from_str("0x4E17607Fb72C01C280d7b5c41Ba9A2109D74a32D")
+                .unwrap(),
+            weth_address: config.chain.wrapped_native,
+            v3_quoter_address: config.chain.v3_quoter,
+
+            gas_used: 0,
+
+            balance_accounts: HashMap::new(),
+            balance_slots: HashMap::new(),
+
+            config,
+        }
+    }
+
+    pub fn set_block_offset(&mut self, block_offset: u64) {
+        self.config.block_offset = block_offset;
+        self.evm.env.block.number = rU256::from(self.block_number.as_u64() + block_offset);
+    }
+
+    pub fn advance_block(&mut self, blocks: u64, seconds: u64) {
+        // Moves the env forward without mining anything, state is left as is
+        self.evm.env.block.number += rU256::from(blocks);
+        self.evm.env.block.timestamp += rU256::from(seconds);
+    }
+
+    pub fn set_chain(&mut self, chain: &ChainConfig) {
+        self.weth_address = chain.wrapped_native;
+        self.v3_quoter_address = chain.v3_quoter;
+        self.config.chain = chain.clone();
+    }
+
+    pub fn set_block_context(&mut self, context: &BlockContext) {
+        // Simulations run on top of the context block, so the env is set to block_offset blocks after it
+        // The base fee is only known one block ahead, so later blocks reuse the next block's
+        let block_offset = self.config.block_offset;
+        self.evm.env.block.number = rU256::from(context.number.as_u64() + block_offset);
+        let elapsed = self.config.chain.block_time.as_millis() as u64 * block_offset / 1000;
+        self.evm.env.block.timestamp = (context.timestamp + U256::from(elapsed)).into();
+        self.evm.env.block.basefee = if block_offset == 0 {
+            context.base_fee.into()
+        } else {
+            context.next_base_fee.into()
+        };
+        self.evm.env.block.gas_limit = context.gas_limit.into();
+    }
+
+    pub async fn health_check(&self, timeout: Duration) -> Result<()> {
+        // Fails fast on an unreachable/slow RPC instead of blocking inside the backend thread
+        match tokio::time::timeout(timeout, self.provider.get_block_number()).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(anyhow!("Provider error: {:?}", e)),
+            Err(_) => Err(anyhow!("Provider did not respond within {:?}", timeout)),
+        }
+    }
+
+    pub fn warmup(&mut self) -> Result<()> {
+        // A trivial fetch through the SharedBackend, surfaces backend errors before simulating
+        self.evm
+            .db
+            .as_mut()
+            .unwrap()
+            .basic(self.owner.into())
+            .map_err(|e| anyhow!("SharedBackend fetch failed: {:?}", e))?;
+        Ok(())
+    }
+
+    pub fn flush_cache(&self) {
+        // Writes everything fetched so far to the cache file, no-op without a cache_dir
+        if let Some(dir) = &self.config.cache_dir {
+            _ = std::fs::create_dir_all(dir);
         }
+        self.blockchain_db.cache().flush();
     }
 
     pub fn inject_db(&mut self, db: CacheDB<SharedBackend>) {
         self.evm.database(db);
     }
 
-    pub fn run_pending_tx(&mut self, tx: &Transaction) -> Result<TxResult> {
+    pub fn db_snapshot(&mut self) -> CacheDB<SharedBackend> {
+        self.evm.db.as_mut().unwrap().clone()
+    }
+
+    pub fn run_pending_tx(
+        &mut self,
+        tx: &Transaction,
+        enforce_base_fee: bool,
+    ) -> Result<TxResult, SimError> {
         // We simply need to commit changes to the DB
+        // With enforce_base_fee, txs that don't pay the simulated block's base fee are rejected
+        // like they would be on chain
+        if let Some(max_gas) = self.config.max_pending_tx_gas {
+            if tx.gas > U256::from(max_gas) {
+                return Err(SimError::Invalid(format!(
+                    "gas limit {:?} above cap {:?}",
+                    tx.gas, max_gas
+                )));
+            }
+        }
+
+        self.set_tx_env(tx);
+
+        let disable_base_fee = self.evm.env.cfg.disable_base_fee;
+        if enforce_base_fee {
+            self.evm.env.cfg.disable_base_fee = false;
+        }
+        let result = self.evm.transact_commit();
+        self.evm.env.cfg.disable_base_fee = disable_base_fee;
+
+        let result = result.map_err(SimError::from)?;
+
+        let output = handle_result(result)?;
+
+        Ok(output)
+    }
+
+    fn set_tx_env(&mut self, tx: &Transaction) {
         self.evm.env.tx.caller = tx.from.0.into();
         self.evm.env.tx.transact_to = TransactTo::Call(tx.to.unwrap_or_default().0.into());
         self.evm.env.tx.data = tx.input.0.clone();
@@ -111,44 +431,161 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
             }
             None => self.evm.env.tx.gas_price = tx.gas_price.unwrap_or_default().into(),
         }
+    }
 
-        let result = match self.evm.transact_commit() {
-            Ok(result) => result,
-            Err(e) => return Err(anyhow!("EVM call failed: {:?}", e)),
-        };
+    pub fn call_bundle(
+        &mut self,
+        txs: &[Transaction],
+        block_number: U64,
+        base_fee: U256,
+    ) -> Result<BundleResult, SimError> {
+        // Runs the txs back to back the way a builder's eth_callBundle would: signed nonces,
+        // gas limits and the base fee are all enforced, and the coinbase's balance is tracked per tx
+        // Reverted txs are reported and the bundle goes on, invalid txs fail the whole bundle
+        // Like eth_callBundle nothing is committed, the DB is restored whether the bundle ran or not
+        let snapshot = self.db_snapshot();
+        let block_env = self.evm.env.block.clone();
+        let disable_base_fee = self.evm.env.cfg.disable_base_fee;
+        let disable_block_gas_limit = self.evm.env.cfg.disable_block_gas_limit;
+        self.evm.env.cfg.disable_base_fee = false;
+        self.evm.env.cfg.disable_block_gas_limit = false;
+        self.evm.env.block.number = rU256::from(block_number.as_u64());
+        self.evm.env.block.basefee = base_fee.into();
+
+        let result = self.call_bundle_txs(txs, base_fee);
+
+        self.inject_db(snapshot);
+        self.evm.env.tx.nonce = None;
+        self.evm.env.block = block_env;
+        self.evm.env.cfg.disable_base_fee = disable_base_fee;
+        self.evm.env.cfg.disable_block_gas_limit = disable_block_gas_limit;
+        result
+    }
 
-        let output = match result {
-            ExecutionResult::Success {
+    fn call_bundle_txs(
+        &mut self,
+        txs: &[Transaction],
+        base_fee: U256,
+    ) -> Result<BundleResult, SimError> {
+        let coinbase = self.evm.env.block.coinbase;
+        let mut results = Vec::new();
+        for tx in txs {
+            self.set_tx_env(tx);
+            self.evm.env.tx.nonce = Some(tx.nonce.as_u64());
+
+            let balance_before = self.coinbase_balance(coinbase)?;
+            let result = self.evm.transact_commit().map_err(SimError::from)?;
+            let balance_after = self.coinbase_balance(coinbase)?;
+
+            let gas_used = result.gas_used();
+            let gas_price = match tx.transaction_type {
+                Some(U64([0])) | None => tx.gas_price.unwrap_or_default(),
+                Some(_) => std::cmp::min(
+                    tx.max_fee_per_gas.unwrap_or_default(),
+                    base_fee + tx.max_priority_fee_per_gas.unwrap_or_default(),
+                ),
+            };
+            // the base fee is burnt, the coinbase only gets the priority fee from gas
+            let gas_fees = U256::from(gas_used) * gas_price.saturating_sub(base_fee);
+            let coinbase_diff = balance_after.saturating_sub(balance_before);
+            results.push(BundleTxResult {
+                hash: tx.hash,
                 gas_used,
-                gas_refunded,
-                output,
-                ..
-            } => match output {
-                Output::Call(o) => TxResult {
-                    output: o,
-                    gas_used,
-                    gas_refunded,
-                },
-                Output::Create(o, _) => TxResult {
-                    output: o,
-                    gas_used,
-                    gas_refunded,
-                },
+                gas_price,
+                gas_fees,
+                coinbase_diff,
+                eth_sent_to_coinbase: coinbase_diff.saturating_sub(gas_fees),
+                error: handle_result(result).err(),
+            });
+        }
+
+        let total_gas_used: u64 = results.iter().map(|tx| tx.gas_used).sum();
+        let coinbase_diff = results
+            .iter()
+            .fold(U256::zero(), |total, tx| total + tx.coinbase_diff);
+        Ok(BundleResult {
+            bundle_gas_price: if total_gas_used > 0 {
+                coinbase_diff / U256::from(total_gas_used)
+            } else {
+                U256::zero()
             },
-            ExecutionResult::Revert { gas_used, output } => {
-                return Err(anyhow!(
-                    "EVM REVERT: {:?} / Gas used: {:?}",
-                    output,
-                    gas_used
-                ))
+            total_gas_used,
+            coinbase_diff,
+            results,
+        })
+    }
+
+    fn coinbase_balance(&mut self, coinbase: rAddress) -> Result<U256, SimError> {
+        let info = self
+            .evm
+            .db
+            .as_mut()
+            .unwrap()
+            .basic(coinbase)
+            .map_err(|e| SimError::Backend(format!("{:?}", e)))?;
+        Ok(info.map(|info| info.balance.into()).unwrap_or_default())
+    }
+
+    pub fn run_pending_tx_with_prestate(
+        &mut self,
+        tx: &Transaction,
+        prestate: &BTreeMap<H160, AccountState>,
+        enforce_base_fee: bool,
+    ) -> Result<TxResult, SimError> {
+        self.apply_prestate(prestate)
+            .map_err(|e| SimError::Backend(format!("{:?}", e)))?;
+        self.run_pending_tx(tx, enforce_base_fee)
+    }
+
+    pub fn apply_prestate(&mut self, prestate: &BTreeMap<H160, AccountState>) -> Result<()> {
+        // Sets the balances/slots a pending tx read to the values it was traced with,
+        // ex) an approval from an earlier tx in the same block that the fork doesn't have yet
+        // Anything the simulation already changed, like a frontrun's reserves, is left alone
+        let db = self.evm.db.as_mut().unwrap();
+        for (address, state) in prestate {
+            if let Some(balance) = state.balance {
+                let mut info = db
+                    .basic((*address).into())
+                    .map_err(|e| anyhow!("SharedBackend fetch failed: {:?}", e))?
+                    .unwrap_or_default();
+                let fork_info = DatabaseRef::basic(&db.db, (*address).into())
+                    .map_err(|e| anyhow!("SharedBackend fetch failed: {:?}", e))?
+                    .unwrap_or_default();
+                if info.balance == fork_info.balance {
+                    info.balance = balance.into();
+                    db.insert_account_info((*address).into(), info);
+                }
             }
-            ExecutionResult::Halt { reason, .. } => return Err(anyhow!("EVM HALT: {:?}", reason)),
-        };
 
-        Ok(output)
+            for (slot, value) in state.storage.iter().flatten() {
+                let slot: rU256 = U256::from(slot.to_fixed_bytes()).into();
+                let current = db
+                    .storage((*address).into(), slot)
+                    .map_err(|e| anyhow!("SharedBackend fetch failed: {:?}", e))?;
+                let fork = DatabaseRef::storage(&db.db, (*address).into(), slot)
+                    .map_err(|e| anyhow!("SharedBackend fetch failed: {:?}", e))?;
+                if current == fork {
+                    db.insert_account_storage(
+                        (*address).into(),
+                        slot,
+                        U256::from(value.to_fixed_bytes()).into(),
+                    )
+                    .map_err(|e| anyhow!("Storage override failed: {:?}", e))?;
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn _call(&mut self, tx: Tx, commit: bool) -> Result<TxResult> {
+    pub fn apply_pending_txs(&mut self, txs: &[Transaction]) -> Vec<Result<TxResult, SimError>> {
+        // Build a pending block on top of the fork by committing txs in order
+        // Failed txs are kept in the results, since they would still be included on chain
+        txs.iter()
+            .map(|tx| self.run_pending_tx(tx, false))
+            .collect()
+    }
+
+    pub fn _call(&mut self, tx: Tx, commit: bool) -> Result<TxResult, SimError> {
         self.evm.env.tx.caller = tx.caller.into();
         self.evm.env.tx.transact_to = TransactTo::Call(tx.transact_to.into());
         self.evm.env.tx.data = tx.data;
@@ -158,57 +595,138 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
         let result;
 
         if commit {
-            result = match self.evm.transact_commit() {
-                Ok(result) => result,
-                Err(e) => return Err(anyhow!("EVM call failed: {:?}", e)),
-            };
+            result = self.evm.transact_commit().map_err(SimError::from)?;
         } else {
-            let ref_tx = self
-                .evm
-                .transact_ref()
-                .map_err(|e| anyhow!("EVM staticcall failed: {:?}", e))?;
+            let ref_tx = self.evm.transact_ref().map_err(SimError::from)?;
             result = ref_tx.result;
         }
 
-        let output = match result {
-            ExecutionResult::Success {
-                gas_used,
-                gas_refunded,
-                output,
-                ..
-            } => match output {
-                Output::Call(o) => TxResult {
-                    output: o,
-                    gas_used,
-                    gas_refunded,
-                },
-                Output::Create(o, _) => TxResult {
-                    output: o,
-                    gas_used,
-                    gas_refunded,
-                },
-            },
-            ExecutionResult::Revert { gas_used, output } => {
-                return Err(anyhow!(
-                    "EVM REVERT: {:?} / Gas used: {:?}",
-                    output,
-                    gas_used
-                ))
-            }
-            ExecutionResult::Halt { reason, .. } => return Err(anyhow!("EVM HALT: {:?}", reason)),
-        };
+        let output = handle_result(result)?;
+
+        if commit {
+            self.gas_used += output.gas_used;
+        }
 
         Ok(output)
     }
 
-    pub fn staticcall(&mut self, tx: Tx) -> Result<TxResult> {
+    pub fn staticcall(&mut self, tx: Tx) -> Result<TxResult, SimError> {
         self._call(tx, false)
     }
 
-    pub fn call(&mut self, tx: Tx) -> Result<TxResult> {
+    pub fn call(&mut self, tx: Tx) -> Result<TxResult, SimError> {
         self._call(tx, true)
     }
 
+    pub fn call_as(
+        &mut self,
+        origin: H160,
+        caller: H160,
+        transact_to: H160,
+        data: Bytes,
+        value: U256,
+        commit: bool,
+    ) -> Result<TxResult, SimError> {
+        // Runs a call with tx.origin set to origin and msg.sender set to caller
+        // When they differ the call goes through a forwarder placed at caller for the one call,
+        // the caller's own code is put back afterwards
+        // EIP-3607 rejects txs sent from accounts with code, it's only lifted for this call
+        let disable_eip3607 = self.evm.env.cfg.disable_eip3607;
+        self.evm.env.cfg.disable_eip3607 = true;
+        let result = if origin == caller {
+            self._call(
+                Tx {
+                    caller,
+                    transact_to,
+                    data,
+                    value,
+                    gas_limit: 0,
+                },
+                commit,
+            )
+        } else {
+            self.deploy_forwarder_at(caller).and_then(|original| {
+                let data = [transact_to.as_bytes(), &data[..]].concat();
+                let result = self._call(
+                    Tx {
+                        caller: origin,
+                        transact_to: caller,
+                        data: data.into(),
+                        value,
+                        gas_limit: 0,
+                    },
+                    commit,
+                );
+                self.restore_code(caller, original)?;
+                result
+            })
+        };
+        self.evm.env.cfg.disable_eip3607 = disable_eip3607;
+        result
+    }
+
+    pub fn deploy_forwarder_at(&mut self, address: H160) -> Result<AccountInfo, SimError> {
+        // keeps the account's balance and nonce, only the code is replaced
+        // Returns the account as it was, for restore_code
+        let db = self.evm.db.as_mut().unwrap();
+        let original = db
+            .basic(address.into())
+            .map_err(|e| SimError::Backend(format!("{:?}", e)))?
+            .unwrap_or_default();
+        let mut info = original.clone();
+        let bytecode = Bytecode::new_raw(FORWARDER_CODE.0.clone());
+        info.code_hash = bytecode.hash_slow();
+        info.code = Some(bytecode);
+        db.insert_account_info(address.into(), info);
+        Ok(original)
+    }
+
+    fn restore_code(&mut self, address: H160, original: AccountInfo) -> Result<(), SimError> {
+        // balance and nonce changes made since are kept
+        let db = self.evm.db.as_mut().unwrap();
+        let mut info = db
+            .basic(address.into())
+            .map_err(|e| SimError::Backend(format!("{:?}", e)))?
+            .unwrap_or_default();
+        info.code_hash = original.code_hash;
+        info.code = original.code;
+        db.insert_account_info(address.into(), info);
+        Ok(())
+    }
+
+    pub fn estimate_gas(&mut self, tx: Tx) -> Result<u64> {
+        // Runs without committing, with the base fee and block gas limit checks turned on
+        // The fee fields are put back afterwards, later calls shouldn't pay the base fee
+        let disable_base_fee = self.evm.env.cfg.disable_base_fee;
+        let disable_block_gas_limit = self.evm.env.cfg.disable_block_gas_limit;
+        let gas_price = self.evm.env.tx.gas_price;
+        let gas_priority_fee = self.evm.env.tx.gas_priority_fee;
+        self.evm.env.cfg.disable_base_fee = false;
+        self.evm.env.cfg.disable_block_gas_limit = false;
+
+        self.evm.env.tx.caller = tx.caller.into();
+        self.evm.env.tx.transact_to = TransactTo::Call(tx.transact_to.into());
+        self.evm.env.tx.data = tx.data;
+        self.evm.env.tx.value = tx.value.into();
+        self.evm.env.tx.gas_price = self.evm.env.block.basefee;
+        self.evm.env.tx.gas_priority_fee = None;
+        self.evm.env.tx.gas_limit = if tx.gas_limit > 0 {
+            tx.gas_limit
+        } else {
+            5000000
+        };
+
+        let result = self.evm.transact_ref();
+
+        self.evm.env.cfg.disable_base_fee = disable_base_fee;
+        self.evm.env.cfg.disable_block_gas_limit = disable_block_gas_limit;
+        self.evm.env.tx.gas_price = gas_price;
+        self.evm.env.tx.gas_priority_fee = gas_priority_fee;
+
+        let result = result.map_err(SimError::from)?.result;
+        Ok(handle_result(result)?.gas_used)
+    }
+
     pub fn get_eth_balance(&mut self) -> U256 {
         let acc = self
             .evm
@@ -221,6 +739,38 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
         acc.balance.into()
     }
 
+    pub fn apply_state_overrides(
+        &mut self,
+        overrides: HashMap<H160, AccountOverride>,
+    ) -> Result<()> {
+        let db = self.evm.db.as_mut().unwrap();
+        for (address, account) in overrides {
+            // start from the forked account so fields that aren't overridden are preserved
+            let mut info = db
+                .basic(address.into())
+                .map_err(|e| anyhow!("SharedBackend fetch failed: {:?}", e))?
+                .unwrap_or_default();
+            if let Some(balance) = account.balance {
+                info.balance = balance.into();
+            }
+            if let Some(nonce) = account.nonce {
+                info.nonce = nonce;
+            }
+            if let Some(code) = account.code {
+                let bytecode = Bytecode::new_raw(code.into());
+                info.code_hash = bytecode.hash_slow();
+                info.code = Some(bytecode);
+            }
+            db.insert_account_info(address.into(), info);
+
+            for (slot, value) in account.storage {
+                db.insert_account_storage(address.into(), slot.into(), value.into())
+                    .map_err(|e| anyhow!("Storage override failed: {:?}", e))?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn set_eth_balance(&mut self, balance: u32) {
         let user_balance = rU256::from(balance)
             .checked_mul(rU256::from(10).pow(rU256::from(18)))
@@ -242,21 +792,177 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
         slot: u32,
         balance: u32,
     ) {
+        let target_balance = token_unit(decimals)
+            .and_then(|unit| {
+                U256::from(balance)
+                    .checked_mul(unit)
+                    .ok_or(anyhow!("Token balance overflow"))
+            })
+            .unwrap_or_else(|e| panic!("Invalid token balance for {:?}: {}", token, e));
+        self.set_token_balance_raw(account, token, slot, target_balance);
+    }
+
+    pub fn set_balance_slots(&mut self, balance_slots: HashMap<H160, u32>) {
+        self.balance_slots.extend(balance_slots);
+    }
+
+    pub fn set_token_balance_raw(&mut self, account: H160, token: H160, slot: u32, balance: U256) {
+        let slot = *self.balance_slots.get(&token).unwrap_or(&slot);
         let slot = keccak256(&abi::encode(&[
             abi::Token::Address(account.into()),
             abi::Token::Uint(U256::from(slot)),
         ]));
-        let target_balance = rU256::from(balance)
-            .checked_mul(rU256::from(10).pow(rU256::from(decimals)))
-            .unwrap();
+        let target_balance: rU256 = balance.into();
+        let storage_account = *self.balance_accounts.get(&token).unwrap_or(&token);
         self.evm
             .db
             .as_mut()
             .unwrap()
-            .insert_account_storage(token.into(), slot.into(), target_balance)
+            .insert_account_storage(storage_account.into(), slot.into(), target_balance)
             .unwrap();
     }
 
+    pub fn set_token_allowance_raw(
+        &mut self,
+        token: H160,
+        owner: H160,
+        spender: H160,
+        slot: u32,
+        amount: U256,
+    ) -> Result<()> {
+        // Seeds an allowance without sending approve, slot comes from EvmTracer::find_allowance_slot
+        let key = allowance_key(owner, spender, slot);
+        let storage_account = *self.balance_accounts.get(&token).unwrap_or(&token);
+        self.set_storage(storage_account, U256::from(key.0).into(), amount.into())
+    }
+
+    pub fn token_transfer(
+        &mut self,
+        token: H160,
+        from: H160,
+        to: H160,
+        amount: U256,
+    ) -> Result<(), SimError> {
+        let calldata = self
+            .token
+            .transfer_input(to, amount)
+            .map_err(|e| SimError::Encode(format!("{:?}", e)))?;
+        // from is usually a contract holding the tokens, like the simulator
+        let value = self.call_as(from, from, token, calldata.0, U256::zero(), true)?;
+        match self.token.transfer_output(value.output) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(SimError::Invalid("transfer returned false".to_string())),
+            Err(e) => Err(SimError::Decode(format!("{:?}", e))),
+        }
+    }
+
+    pub fn token_approve(
+        &mut self,
+        token: H160,
+        owner: H160,
+        spender: H160,
+    ) -> Result<bool, SimError> {
+        // Approves the max amount, the same as most frontends do
+        let calldata = self
+            .token
+            .approve_input(spender)
+            .map_err(|e| SimError::Encode(format!("{:?}", e)))?;
+        let value = self.call_as(owner, owner, token, calldata.0, U256::zero(), true)?;
+        self.token
+            .approve_output(value.output)
+            .map_err(|e| SimError::Decode(format!("{:?}", e)))
+    }
+
+    pub fn token_permit(
+        &mut self,
+        token: H160,
+        wallet: &LocalWallet,
+        spender: H160,
+        value: U256,
+        deadline: U256,
+    ) -> Result<(), SimError> {
+        // Signs an EIP-2612 permit for the token's own DOMAIN_SEPARATOR and nonce, then submits it
+        // The wallet owns the tokens, anyone can send the permit so it's sent from self.owner
+        let owner = wallet.address();
+        let calldata = self
+            .permit
+            .domain_separator_input()
+            .map_err(|e| SimError::Encode(format!("{:?}", e)))?;
+        let value_out = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: token,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let domain_separator = self
+            .permit
+            .domain_separator_output(value_out.output)
+            .map_err(|e| SimError::Decode(format!("{:?}", e)))?;
+
+        let calldata = self
+            .permit
+            .nonces_input(owner)
+            .map_err(|e| SimError::Encode(format!("{:?}", e)))?;
+        let value_out = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: token,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let nonce = self
+            .permit
+            .nonces_output(value_out.output)
+            .map_err(|e| SimError::Decode(format!("{:?}", e)))?;
+
+        let struct_hash = keccak256(&abi::encode(&[
+            abi::Token::FixedBytes(PERMIT_TYPEHASH.to_vec()),
+            abi::Token::Address(owner),
+            abi::Token::Address(spender),
+            abi::Token::Uint(value),
+            abi::Token::Uint(nonce),
+            abi::Token::Uint(deadline),
+        ]));
+        let digest = keccak256(
+            &[
+                &[0x19u8, 0x01][..],
+                &domain_separator[..],
+                &struct_hash.0[..],
+            ]
+            .concat(),
+        );
+        let signature = wallet
+            .sign_hash(H256::from(digest.0))
+            .map_err(|e| SimError::Encode(format!("{:?}", e)))?;
+
+        let calldata = self
+            .permit
+            .permit_input(owner, spender, value, deadline, signature)
+            .map_err(|e| SimError::Encode(format!("{:?}", e)))?;
+        self.call(Tx {
+            caller: self.owner,
+            transact_to: token,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 5000000,
+        })?;
+        Ok(())
+    }
+
+    pub fn token_allowance(&mut self, token: H160, owner: H160, spender: H160) -> Result<U256> {
+        let calldata = self.token.allowance_input(owner, spender)?;
+        let value = self.staticcall(Tx {
+            caller: self.owner.into(),
+            transact_to: token,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let out = self.token.allowance_output(value.output)?;
+        Ok(out)
+    }
+
     pub fn token_balance_of(&mut self, token: H160, account: H160) -> Result<U256> {
         let calldata = self.token.balance_of_input(account)?;
         let value = self.staticcall(Tx {
@@ -270,6 +976,33 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
         Ok(out)
     }
 
+    pub fn token_total_supply(&mut self, token: H160) -> Result<U256> {
+        let calldata = self.token.total_supply_input()?;
+        let value = self.staticcall(Tx {
+            caller: self.owner.into(),
+            transact_to: token,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let out = self.token.total_supply_output(value.output)?;
+        Ok(out)
+    }
+
+    pub fn token_monetary_policy(&mut self, token: H160) -> Result<H160> {
+        // AMPL and its forks, tokens without one revert
+        let calldata = self.token.monetary_policy_input()?;
+        let value = self.staticcall(Tx {
+            caller: self.owner.into(),
+            transact_to: token,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let out = self.token.monetary_policy_output(value.output)?;
+        Ok(out)
+    }
+
     // V2 Pool functions
     pub fn set_v2_pool_reserves(&mut self, pool: H160, reserves: rU256) {
         let slot = rU256::from(8);
@@ -281,8 +1014,27 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
             .unwrap();
     }
 
-    pub fn v2_pool_get_reserves(&mut self, pool: H160) -> Result<(u128, u128, u32)> {
-        let calldata = self.v2_pool.get_reserves_input()?;
+    // Raw storage access, for inspecting or poking any slot during a simulation
+    pub fn get_storage(&mut self, address: H160, slot: rU256) -> Result<rU256> {
+        self.evm
+            .db
+            .as_mut()
+            .unwrap()
+            .storage(address.into(), slot)
+            .map_err(|e| anyhow!("SharedBackend fetch failed: {:?}", e))
+    }
+
+    pub fn set_storage(&mut self, address: H160, slot: rU256, value: rU256) -> Result<()> {
+        self.evm
+            .db
+            .as_mut()
+            .unwrap()
+            .insert_account_storage(address.into(), slot, value)
+            .map_err(|e| anyhow!("Storage override failed: {:?}", e))
+    }
+
+    pub fn v2_pool_get_reserves(&mut self, pool: H160) -> Result<(u128, u128, u32)> {
+        let calldata = self.v2_pool.get_reserves_input()?;
         let value = self.staticcall(Tx {
             caller: self.owner,
             transact_to: pool,
@@ -294,8 +1046,100 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
         Ok(out)
     }
 
+    pub fn v2_pool_get_token_reserves(&mut self, pool: H160) -> Result<HashMap<H160, u128>> {
+        // Reads token0/token1 from the pool itself so reserves can never be mis-ordered
+        let reserves = self.v2_pool_get_reserves(pool)?;
+
+        let calldata = self.v2_pool.token0_input()?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: pool,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let token0 = self.v2_pool.token0_output(value.output)?;
+
+        let calldata = self.v2_pool.token1_input()?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: pool,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let token1 = self.v2_pool.token1_output(value.output)?;
+
+        Ok(HashMap::from([(token0, reserves.0), (token1, reserves.1)]))
+    }
+
+    // V2 Factory functions
+    pub fn v2_factory_get_pair(
+        &mut self,
+        factory: H160,
+        token_a: H160,
+        token_b: H160,
+    ) -> Result<H160> {
+        let calldata = self.v2_factory.get_pair_input(token_a, token_b)?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: factory,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let out = self.v2_factory.get_pair_output(value.output)?;
+        Ok(out)
+    }
+
+    // WETH functions
+    pub fn wrap_eth(&mut self, amount: U256) -> Result<()> {
+        // Goes through the real WETH contract, owner's ETH is converted to WETH
+        let calldata = self.weth.deposit_input()?;
+        self.call(Tx {
+            caller: self.owner,
+            transact_to: self.weth_address,
+            data: calldata.0,
+            value: amount,
+            gas_limit: 0,
+        })?;
+        Ok(())
+    }
+
+    pub fn unwrap_weth(&mut self, amount: U256) -> Result<()> {
+        let calldata = self.weth.withdraw_input(amount)?;
+        self.call(Tx {
+            caller: self.owner,
+            transact_to: self.weth_address,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        Ok(())
+    }
+
     // Simulator functions
     pub fn deploy_simulator(&mut self) {
+        self.deploy_simulator_at(self.simulator_address);
+    }
+
+    pub fn is_simulator_deployed(&mut self) -> bool {
+        self.is_simulator_deployed_at(self.simulator_address)
+    }
+
+    pub fn is_simulator_deployed_at(&mut self, address: H160) -> bool {
+        let code_hash = Bytecode::new_raw((*SIMULATOR_CODE.0).into()).hash_slow();
+        match self.evm.db.as_mut().unwrap().basic(address.into()) {
+            Ok(Some(info)) => info.code_hash == code_hash,
+            _ => false,
+        }
+    }
+
+    pub fn deploy_simulator_at(&mut self, address: H160) {
+        // an injected DB may already have the simulator, redeploying would reset its balance
+        if self.is_simulator_deployed_at(address) {
+            return;
+        }
         let contract_info = AccountInfo::new(
             rU256::ZERO,
             0,
@@ -305,7 +1149,7 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
             .db
             .as_mut()
             .unwrap()
-            .insert_account_info(self.simulator_address.into(), contract_info);
+            .insert_account_info(address.into(), contract_info);
     }
 
     pub fn v2_simulate_swap(
@@ -314,14 +1158,62 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
         target_pool: H160,
         input_token: H160,
         output_token: H160,
+        fee_bps: u32,
         commit: bool,
-    ) -> Result<(U256, U256)> {
-        let calldata = self.simulator.v2_simulate_swap_input(
+    ) -> Result<(U256, U256), SimError> {
+        if let Some(tolerance_bps) = self.config.swap_tolerance_bps {
+            self.v2_verify_swap(
+                amount_in,
+                target_pool,
+                input_token,
+                output_token,
+                fee_bps,
+                tolerance_bps,
+            )?;
+        }
+        self._v2_simulate_swap(
             amount_in,
             target_pool,
             input_token,
             output_token,
-        )?;
+            fee_bps,
+            commit,
+        )
+    }
+
+    pub fn _v2_simulate_swap(
+        &mut self,
+        amount_in: U256,
+        target_pool: H160,
+        input_token: H160,
+        output_token: H160,
+        fee_bps: u32,
+        commit: bool,
+    ) -> Result<(U256, U256), SimError> {
+        // The simulator contract hardcodes the 0.3% fee, pools with other fees are swapped
+        // through their own swap() with the amount out computed at their fee
+        if fee_bps != DEFAULT_V2_FEE_BPS {
+            if commit {
+                return self.v2_pool_swap(
+                    amount_in,
+                    target_pool,
+                    input_token,
+                    output_token,
+                    fee_bps,
+                );
+            }
+            let snapshot = self.db_snapshot();
+            let gas_used = self.gas_used;
+            let out = self.v2_pool_swap(amount_in, target_pool, input_token, output_token, fee_bps);
+            self.inject_db(snapshot);
+            self.gas_used = gas_used;
+            return out;
+        }
+
+        let calldata = self
+            .simulator
+            .v2_simulate_swap_input(amount_in, target_pool, input_token, output_token)
+            .map_err(|e| SimError::Encode(format!("{:?}", e)))?;
         let tx = Tx {
             caller: self.owner,
             transact_to: self.simulator_address,
@@ -334,27 +1226,2005 @@ impl<M: Middleware + 'static> EvmSimulator<M> {
         } else {
             self.staticcall(tx)?
         };
-        let out = self.simulator.v2_simulate_swap_output(value.output)?;
+        let out = self
+            .simulator
+            .v2_simulate_swap_output(value.output)
+            .map_err(|e| SimError::Decode(format!("{:?}", e)))?;
         Ok(out)
     }
 
-    pub fn get_amount_out(
+    pub fn v2_verify_swap(
         &mut self,
         amount_in: U256,
-        reserve_in: U256,
-        reserve_out: U256,
+        target_pool: H160,
+        input_token: H160,
+        output_token: H160,
+        fee_bps: u32,
+        tolerance_bps: u32,
+    ) -> Result<(), SimError> {
+        // The simulator contract does the AMM math itself, so fee-on-transfer tokens and
+        // non-standard pools can make it pay out more than the pool really would
+        // Both swaps run on a snapshot, the fork is left untouched
+        let simulated = self._v2_simulate_swap(
+            amount_in,
+            target_pool,
+            input_token,
+            output_token,
+            fee_bps,
+            false,
+        );
+        let snapshot = self.db_snapshot();
+        let gas_used = self.gas_used;
+        let real = self.v2_pool_swap(amount_in, target_pool, input_token, output_token, fee_bps);
+        self.inject_db(snapshot);
+        self.gas_used = gas_used;
+
+        let simulated = simulated?.1;
+        let real = real.map(|out| out.1).map_err(|e| {
+            SimError::Invalid(format!(
+                "simulator paid {:?} but the pool swap failed: {}",
+                simulated, e
+            ))
+        })?;
+
+        let diff = if simulated > real {
+            simulated - real
+        } else {
+            real - simulated
+        };
+        let diff_bps = diff * U256::from(10000) / std::cmp::max(simulated, U256::one());
+        if diff_bps > U256::from(tolerance_bps) {
+            return Err(SimError::Invalid(format!(
+                "simulator paid {:?} but the pool paid {:?} ({:?} bps apart)",
+                simulated, real, diff_bps
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn v2_pool_swap(
+        &mut self,
+        amount_in: U256,
+        target_pool: H160,
+        input_token: H160,
+        output_token: H160,
+        fee_bps: u32,
+    ) -> Result<(U256, U256), SimError> {
+        // Swaps through the pool's own swap() the way a router would: transfer in, then swap
+        // Returns the amount out asked from the pool and what the simulator contract actually received
+        let to_sim_error = |e: anyhow::Error| SimError::Invalid(format!("{:?}", e));
+        let simulator_address = self.simulator_address;
+
+        let reserves = self
+            .v2_pool_get_token_reserves(target_pool)
+            .map_err(to_sim_error)?;
+        let reserve_in = U256::from(*reserves.get(&input_token).unwrap_or(&0));
+        let reserve_out = U256::from(*reserves.get(&output_token).unwrap_or(&0));
+
+        self.token_transfer(input_token, simulator_address, target_pool, amount_in)?;
+
+        // fee-on-transfer tokens deliver less than was sent
+        let pool_balance = self
+            .token_balance_of(input_token, target_pool)
+            .map_err(to_sim_error)?;
+        let received_in = pool_balance.saturating_sub(reserve_in);
+        let amount_out = self
+            .get_amount_out(received_in, reserve_in, reserve_out, fee_bps)
+            .map_err(to_sim_error)?;
+
+        // V2 pools sort their tokens by address
+        let (amount0_out, amount1_out) = if input_token < output_token {
+            (U256::zero(), amount_out)
+        } else {
+            (amount_out, U256::zero())
+        };
+        let calldata = self
+            .v2_pool
+            .swap_input(amount0_out, amount1_out, simulator_address)
+            .map_err(|e| SimError::Encode(format!("{:?}", e)))?;
+
+        let balance_before = self
+            .token_balance_of(output_token, simulator_address)
+            .map_err(to_sim_error)?;
+        self.call_as(
+            simulator_address,
+            simulator_address,
+            target_pool,
+            calldata.0,
+            U256::zero(),
+            true,
+        )?;
+        let balance_after = self
+            .token_balance_of(output_token, simulator_address)
+            .map_err(to_sim_error)?;
+
+        Ok((amount_out, balance_after.saturating_sub(balance_before)))
+    }
+
+    pub fn swap_exact_in(
+        &mut self,
+        pool: &Pool,
+        amount_in: U256,
+        token_in: H160,
+        commit: bool,
+    ) -> Result<(U256, U256)> {
+        let token_out = pool.token_out(token_in).ok_or(anyhow!(
+            "{:?} is not traded in pool {:?}",
+            token_in,
+            pool.address
+        ))?;
+        match pool.version {
+            DexVariant::Curve if commit => self.stable_swap(amount_in, pool, token_in, token_out),
+            DexVariant::Curve => {
+                let amount_out = self.stable_simulate_swap(amount_in, pool, token_in)?;
+                Ok((amount_out, amount_out))
+            }
+            // the simulator contract only speaks V2, V3 pools can be quoted with v3_quote_* instead
+            DexVariant::UniswapV3 => Err(anyhow!(
+                "UniswapV3 pool {:?} can't be swapped through the simulator",
+                pool.address
+            )),
+            DexVariant::UniswapV2 => Ok(self.v2_simulate_swap(
+                amount_in,
+                pool.address,
+                token_in,
+                token_out,
+                pool.fee_bps(),
+                commit,
+            )?),
+        }
+    }
+
+    pub fn v3_quote_exact_input_single(
+        &mut self,
+        token_in: H160,
+        token_out: H160,
+        fee: u32,
+        amount_in: U256,
     ) -> Result<U256> {
         let calldata = self
-            .simulator
-            .get_amount_out_input(amount_in, reserve_in, reserve_out)?;
+            .v3_quoter
+            .quote_exact_input_single_input(token_in, token_out, amount_in, fee)?;
         let value = self.staticcall(Tx {
             caller: self.owner,
-            transact_to: self.simulator_address,
+            transact_to: self.v3_quoter_address,
             data: calldata.0,
             value: U256::zero(),
-            gas_limit: 5000000,
+            gas_limit: 0,
+        })?;
+        self.v3_quoter.quote_exact_input_single_output(value.output)
+    }
+
+    pub fn v3_quote_exact_output_single(
+        &mut self,
+        token_in: H160,
+        token_out: H160,
+        fee: u32,
+        amount_out: U256,
+    ) -> Result<U256> {
+        // Returns the amount of token_in needed to receive amount_out
+        let calldata = self
+            .v3_quoter
+            .quote_exact_output_single_input(token_in, token_out, amount_out, fee)?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: self.v3_quoter_address,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        self.v3_quoter
+            .quote_exact_output_single_output(value.output)
+    }
+
+    pub fn v3_quote_path(&mut self, path: &[u8], amount_in: U256) -> Result<(U256, Vec<U256>)> {
+        // path is V3's encoding: token (20 bytes) / fee (3 bytes) / token / fee / ... / token
+        // The final output is QuoterV2's quoteExactInput over the whole path. It doesn't return
+        // per-hop amounts, those are chained single hop quotes, which only differ from the path's
+        // when a pool is crossed twice
+        let hops = decode_v3_path(path)?;
+        let mut amounts = Vec::new();
+        let mut amount = amount_in;
+        for (token_in, fee, token_out) in hops {
+            amount = self.v3_quote_exact_input_single(token_in, token_out, fee, amount)?;
+            amounts.push(amount);
+        }
+
+        let calldata = self.v3_quoter.quote_exact_input_input(path, amount_in)?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: self.v3_quoter_address,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        let amount_out = self.v3_quoter.quote_exact_input_output(value.output)?;
+        Ok((amount_out, amounts))
+    }
+
+    pub fn stable_simulate_swap(
+        &mut self,
+        amount_in: U256,
+        pool: &Pool,
+        token_in: H160,
+    ) -> Result<U256> {
+        // Quote only: the simulator contract can't trade on Curve, so the pool's own get_dy is used
+        let token_out = pool.token_out(token_in).ok_or(anyhow!(
+            "{:?} is not traded in pool {:?}",
+            token_in,
+            pool.address
+        ))?;
+        let i = self.curve_coin_index(pool.address, token_in)?;
+        let j = self.curve_coin_index(pool.address, token_out)?;
+        let calldata = self.curve_pool.get_dy_input(i, j, amount_in)?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: pool.address,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
         })?;
-        let out = self.simulator.get_amount_out_output(value.output)?;
+        let out = self.curve_pool.get_dy_output(value.output)?;
         Ok(out)
     }
+
+    pub fn stable_swap(
+        &mut self,
+        amount_in: U256,
+        pool: &Pool,
+        token_in: H160,
+        token_out: H160,
+    ) -> Result<(U256, U256)> {
+        // Trades through the pool's exchange() from the simulator contract, which holds the tokens
+        // Returns the get_dy quote and what the simulator contract actually received
+        let simulator_address = self.simulator_address;
+        let i = self.curve_coin_index(pool.address, token_in)?;
+        let j = self.curve_coin_index(pool.address, token_out)?;
+        let quoted = self.stable_simulate_swap(amount_in, pool, token_in)?;
+
+        // USDT-like tokens revert on approving over a non-zero allowance, so only approve when short
+        if self.token_allowance(token_in, simulator_address, pool.address)? < amount_in {
+            self.token_approve(token_in, simulator_address, pool.address)?;
+        }
+
+        let balance_before = self.token_balance_of(token_out, simulator_address)?;
+        let calldata = self
+            .curve_pool
+            .exchange_input(i, j, amount_in, U256::zero())?;
+        self.call_as(
+            simulator_address,
+            simulator_address,
+            pool.address,
+            calldata.0,
+            U256::zero(),
+            true,
+        )?;
+        let balance_after = self.token_balance_of(token_out, simulator_address)?;
+
+        Ok((quoted, balance_after.saturating_sub(balance_before)))
+    }
+
+    pub fn curve_coin_index(&mut self, pool: H160, token: H160) -> Result<i128> {
+        // Curve pools list their coins by index, coins(i) reverts past the last one
+        for i in 0..8u64 {
+            let calldata = self.curve_pool.coins_input(U256::from(i))?;
+            let value = match self.staticcall(Tx {
+                caller: self.owner,
+                transact_to: pool,
+                data: calldata.0,
+                value: U256::zero(),
+                gas_limit: 0,
+            }) {
+                Ok(value) => value,
+                Err(SimError::Revert { .. }) => break,
+                Err(e) => return Err(e.into()),
+            };
+            if self.curve_pool.coins_output(value.output)? == token {
+                return Ok(i as i128);
+            }
+        }
+        Err(anyhow!(
+            "{:?} is not a coin of Curve pool {:?}",
+            token,
+            pool
+        ))
+    }
+
+    pub fn get_amount_out(
+        &self,
+        amount_in: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+        fee_bps: u32,
+    ) -> Result<U256> {
+        // Same math as UniswapV2Library.getAmountOut with the fee as a parameter,
+        // the simulator contract's getAmountOut only knows the 0.3% fee
+        if amount_in.is_zero() {
+            return Err(anyhow!("UniswapV2Library: INSUFFICIENT_INPUT_AMOUNT"));
+        }
+        if reserve_in.is_zero() || reserve_out.is_zero() {
+            return Err(anyhow!("UniswapV2Library: INSUFFICIENT_LIQUIDITY"));
+        }
+        if fee_bps >= 10000 {
+            return Err(anyhow!("Invalid V2 fee: {} bps", fee_bps));
+        }
+        let amount_in_with_fee = amount_in * (10000 - fee_bps);
+        let numerator = amount_in_with_fee * reserve_out;
+        let denominator = reserve_in * 10000 + amount_in_with_fee;
+        Ok(numerator / denominator)
+    }
+
+    pub fn get_amount_in(
+        &self,
+        amount_out: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+        fee_bps: u32,
+    ) -> Result<U256> {
+        // Same math as UniswapV2Library.getAmountIn with the fee as a parameter
+        if amount_out.is_zero() {
+            return Err(anyhow!("UniswapV2Library: INSUFFICIENT_OUTPUT_AMOUNT"));
+        }
+        if reserve_in.is_zero() || reserve_out <= amount_out {
+            return Err(anyhow!("UniswapV2Library: INSUFFICIENT_LIQUIDITY"));
+        }
+        if fee_bps >= 10000 {
+            return Err(anyhow!("Invalid V2 fee: {} bps", fee_bps));
+        }
+        let numerator = reserve_in * amount_out * 10000;
+        let denominator = (reserve_out - amount_out) * (10000 - fee_bps);
+        Ok(numerator / denominator + 1)
+    }
+
+    pub fn v2_simulate_swap_exact_out(
+        &mut self,
+        amount_out: U256,
+        target_pool: H160,
+        input_token: H160,
+        output_token: H160,
+        fee_bps: u32,
+    ) -> Result<U256> {
+        let reserves = self.v2_pool_get_token_reserves(target_pool)?;
+        let reserve_in = *reserves.get(&input_token).ok_or(anyhow!(
+            "{:?} is not traded in pool {:?}",
+            input_token,
+            target_pool
+        ))?;
+        let reserve_out = *reserves.get(&output_token).ok_or(anyhow!(
+            "{:?} is not traded in pool {:?}",
+            output_token,
+            target_pool
+        ))?;
+        if amount_out.is_zero() {
+            return Err(anyhow!("UniswapV2Library: INSUFFICIENT_OUTPUT_AMOUNT"));
+        }
+        if reserve_in == 0 || U256::from(reserve_out) <= amount_out {
+            return Err(anyhow!("UniswapV2Library: INSUFFICIENT_LIQUIDITY"));
+        }
+        if fee_bps >= 10000 {
+            return Err(anyhow!("Invalid V2 fee: {} bps", fee_bps));
+        }
+
+        self.deploy_amount_in_code();
+        let calldata = self.simulator.get_amount_in_input(
+            amount_out,
+            U256::from(reserve_in),
+            U256::from(reserve_out),
+            fee_bps,
+        )?;
+        let value = self.staticcall(Tx {
+            caller: self.owner,
+            transact_to: self.amount_in_address,
+            data: calldata.0,
+            value: U256::zero(),
+            gas_limit: 0,
+        })?;
+        self.simulator.get_amount_in_output(value.output)
+    }
+
+    fn deploy_amount_in_code(&mut self) {
+        // like deploy_simulator_at, an already deployed copy is left as is
+        let bytecode = Bytecode::new_raw(AMOUNT_IN_CODE.0.clone());
+        let db = self.evm.db.as_mut().unwrap();
+        if let Ok(Some(info)) = db.basic(self.amount_in_address.into()) {
+            if info.code_hash == bytecode.hash_slow() {
+                return;
+            }
+        }
+        db.insert_account_info(
+            self.amount_in_address.into(),
+            AccountInfo::new(rU256::ZERO, 0, bytecode),
+        );
+    }
+}
+
+pub fn decode_v3_path(path: &[u8]) -> Result<Vec<(H160, u32, H160)>> {
+    // (token in, fee, token out) of every hop in a V3 encoded path
+    if path.len() < 43 || (path.len() - 20) % 23 != 0 {
+        return Err(anyhow!("Invalid V3 path length: {}", path.len()));
+    }
+    let mut hops = Vec::new();
+    let mut i = 0;
+    while i + 20 < path.len() {
+        let token_in = H160::from_slice(&path[i..i + 20]);
+        let fee = u32::from_be_bytes([0, path[i + 20], path[i + 21], path[i + 22]]);
+        let token_out = H160::from_slice(&path[i + 23..i + 43]);
+        hops.push((token_in, fee, token_out));
+        i += 23;
+    }
+    Ok(hops)
+}
+
+impl<M> Drop for EvmSimulator<M> {
+    fn drop(&mut self) {
+        // Every simulator persists what it fetched, not just the ones flushed by hand,
+        // same as flush_cache but without its Middleware bound
+        if let Some(dir) = &self.config.cache_dir {
+            _ = std::fs::create_dir_all(dir);
+            self.blockchain_db.cache().flush();
+        }
+    }
+}
+
+pub trait SwapSimulator {
+    // The swap/balance operations the arbitrage and sandwich logic needs,
+    // so that it can run against something other than a live fork
+    fn simulator_address(&self) -> H160;
+    fn weth_address(&self) -> H160;
+    fn gas_used(&self) -> u64;
+    fn set_token_balance(
+        &mut self,
+        account: H160,
+        token: H160,
+        decimals: u8,
+        slot: u32,
+        balance: u32,
+    );
+    fn token_balance_of(&mut self, token: H160, account: H160) -> Result<U256>;
+    fn v2_pool_get_reserves(&mut self, pool: H160) -> Result<(u128, u128, u32)>;
+    fn v2_simulate_swap(
+        &mut self,
+        amount_in: U256,
+        target_pool: H160,
+        input_token: H160,
+        output_token: H160,
+        fee_bps: u32,
+        commit: bool,
+    ) -> Result<(U256, U256), SimError>;
+    fn swap_exact_in(
+        &mut self,
+        pool: &Pool,
+        amount_in: U256,
+        token_in: H160,
+        commit: bool,
+    ) -> Result<(U256, U256)>;
+    fn run_pending_tx(
+        &mut self,
+        tx: &Transaction,
+        enforce_base_fee: bool,
+    ) -> Result<TxResult, SimError>;
+}
+
+impl<M: Middleware + 'static> SwapSimulator for EvmSimulator<M> {
+    // Inherent methods take precedence, so each of these forwards to the method of the same name
+    fn simulator_address(&self) -> H160 {
+        self.simulator_address
+    }
+
+    fn weth_address(&self) -> H160 {
+        self.weth_address
+    }
+
+    fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    fn set_token_balance(
+        &mut self,
+        account: H160,
+        token: H160,
+        decimals: u8,
+        slot: u32,
+        balance: u32,
+    ) {
+        self.set_token_balance(account, token, decimals, slot, balance)
+    }
+
+    fn token_balance_of(&mut self, token: H160, account: H160) -> Result<U256> {
+        self.token_balance_of(token, account)
+    }
+
+    fn v2_pool_get_reserves(&mut self, pool: H160) -> Result<(u128, u128, u32)> {
+        self.v2_pool_get_reserves(pool)
+    }
+
+    fn v2_simulate_swap(
+        &mut self,
+        amount_in: U256,
+        target_pool: H160,
+        input_token: H160,
+        output_token: H160,
+        fee_bps: u32,
+        commit: bool,
+    ) -> Result<(U256, U256), SimError> {
+        self.v2_simulate_swap(
+            amount_in,
+            target_pool,
+            input_token,
+            output_token,
+            fee_bps,
+            commit,
+        )
+    }
+
+    fn swap_exact_in(
+        &mut self,
+        pool: &Pool,
+        amount_in: U256,
+        token_in: H160,
+        commit: bool,
+    ) -> Result<(U256, U256)> {
+        self.swap_exact_in(pool, amount_in, token_in, commit)
+    }
+
+    fn run_pending_tx(
+        &mut self,
+        tx: &Transaction,
+        enforce_base_fee: bool,
+    ) -> Result<TxResult, SimError> {
+        self.run_pending_tx(tx, enforce_base_fee)
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+
+    // Constant product pools and plain balance maps behind SwapSimulator,
+    // so the logic generic over it can be tested without a fork
+    #[derive(Clone)]
+    pub struct MockSimulator {
+        pub simulator_address: H160,
+        pub weth_address: H160,
+        pub gas_used: u64,
+        // gas charged for every committed swap
+        pub swap_gas: u64,
+        // pool address -> (pool, reserve0, reserve1)
+        pub pools: HashMap<H160, (Pool, u128, u128)>,
+        // (token, account) -> balance
+        pub balances: HashMap<(H160, H160), U256>,
+        // pending tx hash -> (pool, token in, amount in) swapped when the tx runs
+        pub pending_swaps: HashMap<H256, (H160, H160, U256)>,
+    }
+
+    impl MockSimulator {
+        pub fn new(weth_address: H160) -> Self {
+            Self {
+                simulator_address: H160::random(),
+                weth_address,
+                gas_used: 0,
+                swap_gas: 100000,
+                pools: HashMap::new(),
+                balances: HashMap::new(),
+                pending_swaps: HashMap::new(),
+            }
+        }
+
+        pub fn add_pool(&mut self, pool: Pool, reserve0: u128, reserve1: u128) {
+            self.pools.insert(pool.address, (pool, reserve0, reserve1));
+        }
+
+        pub fn add_pending_swap(
+            &mut self,
+            tx: &Transaction,
+            pool: H160,
+            token_in: H160,
+            amount_in: U256,
+        ) {
+            self.pending_swaps
+                .insert(tx.hash, (pool, token_in, amount_in));
+        }
+
+        fn swap(
+            &mut self,
+            account: Option<H160>,
+            pool: H160,
+            token_in: H160,
+            amount_in: U256,
+            commit: bool,
+        ) -> Result<U256, SimError> {
+            let revert = SimError::Revert {
+                output: Bytes::new(),
+                gas_used: 0,
+            };
+            let (pool, reserve0, reserve1) =
+                self.pools.get(&pool).cloned().ok_or(revert.clone())?;
+            let token_out = pool.token_out(token_in).ok_or(revert.clone())?;
+            let amount_out = pool.amount_out(amount_in, (reserve0, reserve1), token_in);
+            if !commit {
+                return Ok(amount_out);
+            }
+            if let Some(account) = account {
+                let balance_in = self.balances.entry((token_in, account)).or_default();
+                if *balance_in < amount_in {
+                    return Err(revert);
+                }
+                *balance_in -= amount_in;
+                *self.balances.entry((token_out, account)).or_default() += amount_out;
+            }
+            let (reserve0, reserve1) = if token_in == pool.token0 {
+                (
+                    reserve0 + amount_in.as_u128(),
+                    reserve1 - amount_out.as_u128(),
+                )
+            } else {
+                (
+                    reserve0 - amount_out.as_u128(),
+                    reserve1 + amount_in.as_u128(),
+                )
+            };
+            self.pools.insert(pool.address, (pool, reserve0, reserve1));
+            Ok(amount_out)
+        }
+    }
+
+    impl SwapSimulator for MockSimulator {
+        fn simulator_address(&self) -> H160 {
+            self.simulator_address
+        }
+
+        fn weth_address(&self) -> H160 {
+            self.weth_address
+        }
+
+        fn gas_used(&self) -> u64 {
+            self.gas_used
+        }
+
+        fn set_token_balance(
+            &mut self,
+            account: H160,
+            token: H160,
+            decimals: u8,
+            _slot: u32,
+            balance: u32,
+        ) {
+            let balance = U256::from(balance) * token_unit(decimals).unwrap();
+            self.balances.insert((token, account), balance);
+        }
+
+        fn token_balance_of(&mut self, token: H160, account: H160) -> Result<U256> {
+            Ok(self
+                .balances
+                .get(&(token, account))
+                .cloned()
+                .unwrap_or_default())
+        }
+
+        fn v2_pool_get_reserves(&mut self, pool: H160) -> Result<(u128, u128, u32)> {
+            let (_, reserve0, reserve1) = self
+                .pools
+                .get(&pool)
+                .ok_or(anyhow!("unknown pool {:?}", pool))?;
+            Ok((*reserve0, *reserve1, 0))
+        }
+
+        fn v2_simulate_swap(
+            &mut self,
+            amount_in: U256,
+            target_pool: H160,
+            input_token: H160,
+            _output_token: H160,
+            _fee_bps: u32,
+            commit: bool,
+        ) -> Result<(U256, U256), SimError> {
+            let amount_out = self.swap(
+                Some(self.simulator_address),
+                target_pool,
+                input_token,
+                amount_in,
+                commit,
+            )?;
+            if commit {
+                self.gas_used += self.swap_gas;
+            }
+            Ok((amount_out, amount_out))
+        }
+
+        fn swap_exact_in(
+            &mut self,
+            pool: &Pool,
+            amount_in: U256,
+            token_in: H160,
+            commit: bool,
+        ) -> Result<(U256, U256)> {
+            let token_out = pool.token_out(token_in).ok_or(anyhow!(
+                "{:?} is not traded in pool {:?}",
+                token_in,
+                pool.address
+            ))?;
+            Ok(self.v2_simulate_swap(
+                amount_in,
+                pool.address,
+                token_in,
+                token_out,
+                pool.fee_bps(),
+                commit,
+            )?)
+        }
+
+        fn run_pending_tx(
+            &mut self,
+            tx: &Transaction,
+            _enforce_base_fee: bool,
+        ) -> Result<TxResult, SimError> {
+            // the victim's own balances aren't tracked, only the pool it swaps on moves
+            if let Some((pool, token_in, amount_in)) = self.pending_swaps.get(&tx.hash).cloned() {
+                self.swap(None, pool, token_in, amount_in, true)?;
+            }
+            Ok(TxResult {
+                output: Bytes::new(),
+                gas_used: self.swap_gas,
+                gas_refunded: 0,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{JsonRpcClient, MockError, MockProvider, Provider, Ws};
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn mocked_fork() -> EvmSimulator<Provider<MockProvider>> {
+        // Nothing is served by the mock, tests insert every account they touch into the CacheDB
+        let (provider, _) = Provider::mocked();
+        EvmSimulator::new(Arc::new(provider), H160::random(), U64::from(1))
+    }
+
+    fn insert_eoa(simulator: &mut EvmSimulator<Provider<MockProvider>>, address: H160, eth: u64) {
+        let balance = rU256::from(eth) * rU256::from(10).pow(rU256::from(18));
+        simulator.evm.db.as_mut().unwrap().insert_account_info(
+            address.into(),
+            AccountInfo::new(balance, 0, Bytecode::default()),
+        );
+    }
+
+    // Counts every RPC request sent to the mock
+    #[derive(Debug)]
+    struct CountingClient {
+        inner: MockProvider,
+        requests: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl JsonRpcClient for CountingClient {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, method: &str, params: T) -> Result<R, MockError>
+        where
+            T: fmt::Debug + Serialize + Send + Sync,
+            R: DeserializeOwned + Send,
+        {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.inner.request(method, params).await
+        }
+    }
+
+    async fn mainnet_fork() -> EvmSimulator<Provider<Ws>> {
+        // Needs a mainnet WSS_URL, these run with `cargo test -- --ignored`
+        dotenv::dotenv().ok();
+        let ws = Ws::connect(std::env::var("WSS_URL").unwrap())
+            .await
+            .unwrap();
+        let provider = Arc::new(Provider::new(ws));
+        let block_number = provider.get_block_number().await.unwrap();
+        EvmSimulator::new(provider, H160::random(), block_number)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_curve_swap_moves_balances() {
+        let mut simulator = mainnet_fork().await;
+        simulator.deploy_simulator();
+        let simulator_address = simulator.simulator_address;
+
+        // 3pool's coins are DAI/USDC/USDT, so USDC -> USDT is coins(1) -> coins(2)
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let usdt = H160::from_str("0xdAC17F958D2ee523a2206206994597C13D831ec7").unwrap();
+        let pool = Pool {
+            address: H160::from_str("0xbEbc44782C7dB0a1A60Cb6fe97d0b483032FF1C7").unwrap(),
+            version: DexVariant::Curve,
+            token0: usdc,
+            token1: usdt,
+            decimals0: 6,
+            decimals1: 6,
+            fee: 0,
+        };
+        assert_eq!(simulator.curve_coin_index(pool.address, usdc).unwrap(), 1);
+        assert_eq!(simulator.curve_coin_index(pool.address, usdt).unwrap(), 2);
+
+        simulator.set_token_balance(simulator_address, usdc, 6, 9, 1000);
+        let amount_in = U256::from(1000) * token_unit(6).unwrap();
+        let usdt_before = simulator.token_balance_of(usdt, simulator_address).unwrap();
+
+        let (quoted, received) = simulator
+            .swap_exact_in(&pool, amount_in, usdc, true)
+            .unwrap();
+
+        let usdc_after = simulator.token_balance_of(usdc, simulator_address).unwrap();
+        let usdt_after = simulator.token_balance_of(usdt, simulator_address).unwrap();
+        assert_eq!(usdc_after, U256::zero());
+        assert!(!received.is_zero());
+        assert_eq!(usdt_after, usdt_before + received);
+        assert_eq!(received, quoted);
+    }
+
+    #[test]
+    fn test_prestate_lets_the_meat_tx_succeed() {
+        // reverts unless slot 0 is set, like a transferFrom without the approval
+        // an earlier tx in the block sent: PUSH1 0 SLOAD PUSH1 10 JUMPI PUSH1 0 DUP1 REVERT JUMPDEST STOP
+        let code = Bytes::from_static(&[
+            0x60, 0x00, 0x54, 0x60, 0x0a, 0x57, 0x60, 0x00, 0x80, 0xfd, 0x5b, 0x00,
+        ]);
+        let (provider, mock) = Provider::mocked();
+        let mut simulator = EvmSimulator::new(Arc::new(provider), H160::random(), U64::from(1));
+        let (sender, contract) = (H160::random(), H160::random());
+        insert_eoa(&mut simulator, sender, 1);
+        insert_eoa(&mut simulator, H160::zero(), 0);
+        simulator.evm.db.as_mut().unwrap().insert_account_info(
+            contract.into(),
+            AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code)),
+        );
+        // slot 0 is empty on the fork, fetched once and cached by the backend
+        mock.push::<H256, _>(H256::zero()).unwrap();
+
+        let meat_tx = Transaction {
+            from: sender,
+            to: Some(contract),
+            gas: U256::from(100000),
+            ..Default::default()
+        };
+        assert!(matches!(
+            simulator.run_pending_tx(&meat_tx, false),
+            Err(SimError::Revert { .. })
+        ));
+
+        // the slot as the tx was traced with
+        let prestate: BTreeMap<H160, AccountState> = BTreeMap::from([(
+            contract,
+            serde_json::from_value(serde_json::json!({
+                "storage": {
+                    format!("{:?}", H256::zero()): format!("{:?}", H256::from_low_u64_be(1)),
+                }
+            }))
+            .unwrap(),
+        )]);
+        assert!(simulator
+            .run_pending_tx_with_prestate(&meat_tx, &prestate, false)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_call_as_lifts_eip3607_for_the_call() {
+        let mut simulator = mocked_fork();
+        let simulator_address = simulator.simulator_address;
+        let receiver = H160::random();
+        simulator.deploy_simulator();
+        insert_eoa(&mut simulator, receiver, 0);
+
+        // a plain call from an account with code is rejected
+        let tx = Tx {
+            caller: simulator_address,
+            transact_to: receiver,
+            data: Bytes::new(),
+            value: U256::zero(),
+            gas_limit: 0,
+        };
+        assert!(simulator.call(tx).is_err());
+
+        let sent = simulator.call_as(
+            simulator_address,
+            simulator_address,
+            receiver,
+            Bytes::new(),
+            U256::zero(),
+            true,
+        );
+        assert!(sent.is_ok());
+        assert!(!simulator.evm.env.cfg.disable_eip3607);
+    }
+
+    #[test]
+    fn test_storage_round_trips_and_is_seen_by_contracts() {
+        let mut simulator = mocked_fork();
+        let owner = simulator.owner;
+        let contract = H160::random();
+        insert_eoa(&mut simulator, owner, 1);
+        // returns whatever is in slot 7: PUSH1 7 SLOAD PUSH1 0 MSTORE PUSH1 32 PUSH1 0 RETURN
+        simulator.evm.db.as_mut().unwrap().insert_account_info(
+            contract.into(),
+            AccountInfo::new(
+                rU256::ZERO,
+                0,
+                Bytecode::new_raw(Bytes::from_static(&[
+                    0x60, 0x07, 0x54, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3,
+                ])),
+            ),
+        );
+
+        let value = rU256::from(0xdeadbeefu64);
+        simulator
+            .set_storage(contract, rU256::from(7), value)
+            .unwrap();
+        assert_eq!(
+            simulator.get_storage(contract, rU256::from(7)).unwrap(),
+            value
+        );
+
+        let read = simulator
+            .call_as(owner, owner, contract, Bytes::new(), U256::zero(), false)
+            .unwrap();
+        assert_eq!(
+            U256::from_big_endian(&read.output),
+            U256::from(0xdeadbeefu64)
+        );
+    }
+
+    #[test]
+    fn test_second_deploy_simulator_is_a_no_op() {
+        let mut simulator = mocked_fork();
+        let simulator_address = simulator.simulator_address;
+        assert!(!simulator.is_simulator_deployed());
+        simulator.deploy_simulator();
+        assert!(simulator.is_simulator_deployed());
+
+        // state the simulator accrued since the first deploy
+        let (slot, value) = (rU256::from(5), rU256::from(42));
+        simulator
+            .set_storage(simulator_address, slot, value)
+            .unwrap();
+        let db = simulator.evm.db.as_mut().unwrap();
+        let mut info = db.basic(simulator_address.into()).unwrap().unwrap();
+        info.balance = rU256::from(1000);
+        db.insert_account_info(simulator_address.into(), info);
+
+        simulator.deploy_simulator();
+        assert!(simulator.is_simulator_deployed());
+        assert_eq!(
+            simulator.get_storage(simulator_address, slot).unwrap(),
+            value
+        );
+        let info = simulator
+            .evm
+            .db
+            .as_mut()
+            .unwrap()
+            .basic(simulator_address.into())
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.balance, rU256::from(1000));
+    }
+
+    #[test]
+    fn test_permit_sets_the_allowance() {
+        use crate::constants::TEST_WALLET;
+
+        // A minimal EIP-2612 token: DOMAIN_SEPARATOR() is slot 2, nonces at keccak(owner . 3),
+        // allowances at keccak(spender . keccak(owner . 1)). permit checks the deadline, recovers
+        // the signer with ecrecover, then bumps the nonce and sets the allowance
+        let code: ethers::types::Bytes = "0x60003560e01c8063dd62ed3e146100325780633644e5151461005a5780637ecebe00146100665763d505accf1461008057005b6004356000526001602052604060002060205260243560005260406000205460005260206000f35b60025460005260206000f35b600435600052600360205260406000205460005260206000f35b606435421161016a576004356000526003602052604060002080547f6e71edae12b1b97f4d1f60370fef10105fa2faae0126114a169c64845d6126c96103005260043561032052602435610340526044356103605280610380526064356103a05260c061030020611901610100526002546101205261014052604261011e20610200526084356102205260a4356102405260c435610260526000610280526020610280608061020060015afa5061028051801561016a57600435141561016a5760010190556004356000526001602052604060002060205260243560005260406000206044359055005b600080fd"
+            .parse()
+            .unwrap();
+
+        let mut simulator = mocked_fork();
+        let owner = simulator.owner;
+        let (token, spender) = (H160::random(), H160::random());
+        insert_eoa(&mut simulator, owner, 1);
+        // the ecrecover precompile's account is loaded like any other
+        insert_eoa(&mut simulator, H160::from_low_u64_be(1), 0);
+        simulator.evm.db.as_mut().unwrap().insert_account_info(
+            token.into(),
+            AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+        );
+        let domain_separator = H256::random();
+        simulator
+            .set_storage(
+                token,
+                rU256::from(2),
+                U256::from_big_endian(domain_separator.as_bytes()).into(),
+            )
+            .unwrap();
+
+        let wallet = TEST_WALLET.clone();
+        let value = U256::from(1000) * U256::exp10(18);
+        simulator
+            .token_permit(token, &wallet, spender, value, U256::MAX)
+            .unwrap();
+        assert_eq!(
+            simulator
+                .token_allowance(token, wallet.address(), spender)
+                .unwrap(),
+            value
+        );
+
+        // the nonce moved on, so the next permit is signed over the new one
+        simulator
+            .token_permit(token, &wallet, spender, U256::from(7), U256::MAX)
+            .unwrap();
+        assert_eq!(
+            simulator
+                .token_allowance(token, wallet.address(), spender)
+                .unwrap(),
+            U256::from(7)
+        );
+    }
+
+    #[test]
+    fn test_call_as_restores_caller_code() {
+        let mut simulator = mocked_fork();
+        let origin = H160::random();
+        let receiver = H160::random();
+        insert_eoa(&mut simulator, origin, 1);
+        insert_eoa(&mut simulator, receiver, 0);
+        simulator.deploy_simulator();
+
+        // the simulator contract stands in as msg.sender, through the forwarder
+        let sent = simulator.call_as(
+            origin,
+            simulator.simulator_address,
+            receiver,
+            Bytes::new(),
+            U256::zero(),
+            true,
+        );
+        assert!(sent.is_ok());
+        assert!(simulator.is_simulator_deployed());
+    }
+
+    #[test]
+    fn test_estimate_gas_restores_tx_fees() {
+        let mut simulator = mocked_fork();
+        let sender = H160::random();
+        let receiver = H160::random();
+        insert_eoa(&mut simulator, sender, 1);
+        insert_eoa(&mut simulator, receiver, 0);
+        simulator.evm.env.block.basefee = rU256::from(10_000_000_000u64);
+        simulator.evm.env.block.gas_limit = rU256::from(30_000_000u64);
+        simulator.evm.env.tx.gas_price = rU256::from(7);
+        simulator.evm.env.tx.gas_priority_fee = Some(rU256::from(3));
+
+        let gas_used = simulator
+            .estimate_gas(Tx {
+                caller: sender,
+                transact_to: receiver,
+                data: Bytes::new(),
+                value: U256::one(),
+                gas_limit: 0,
+            })
+            .unwrap();
+        assert_eq!(gas_used, 21000);
+        assert_eq!(simulator.evm.env.tx.gas_price, rU256::from(7));
+        assert_eq!(simulator.evm.env.tx.gas_priority_fee, Some(rU256::from(3)));
+    }
+
+    #[test]
+    fn test_enforce_base_fee_rejects_underpriced_meat_tx() {
+        let (sender, receiver) = (H160::random(), H160::random());
+        let meat_tx = Transaction {
+            from: sender,
+            to: Some(receiver),
+            value: U256::one(),
+            gas: U256::from(21000),
+            transaction_type: Some(U64::from(2)),
+            max_fee_per_gas: Some(U256::from(1_000_000_000u64)),
+            max_priority_fee_per_gas: Some(U256::from(1_000_000_000u64)),
+            ..Default::default()
+        };
+        let mut simulator = mocked_fork();
+        insert_eoa(&mut simulator, sender, 1);
+        insert_eoa(&mut simulator, receiver, 0);
+        let coinbase = H160::random();
+        insert_eoa(&mut simulator, coinbase, 0);
+        simulator.evm.env.block.coinbase = coinbase.into();
+        simulator.evm.env.block.basefee = rU256::from(10_000_000_000u64);
+        simulator.evm.env.block.gas_limit = rU256::from(30_000_000u64);
+
+        // max_fee_per_gas of 1 gwei can't pay the 10 gwei base fee
+        match simulator.run_pending_tx(&meat_tx, true) {
+            Err(SimError::Invalid(_)) => {}
+            other => panic!("expected the meat tx to be rejected, got {:?}", other),
+        }
+        // the flag only applies to that call, the fork's setting is left as it was
+        assert!(simulator.evm.env.cfg.disable_base_fee);
+        assert!(simulator.run_pending_tx(&meat_tx, false).is_ok());
+    }
+
+    #[test]
+    fn test_reverting_call_returns_the_revert_output() {
+        let mut simulator = mocked_fork();
+        let owner = simulator.owner;
+        insert_eoa(&mut simulator, owner, 1);
+        // PUSH1 0xaa PUSH1 0 MSTORE PUSH1 0x20 PUSH1 0 REVERT
+        let reverter = H160::random();
+        simulator.evm.db.as_mut().unwrap().insert_account_info(
+            reverter.into(),
+            AccountInfo::new(
+                rU256::ZERO,
+                0,
+                Bytecode::new_raw(Bytes::from_static(&[
+                    0x60, 0xaa, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xfd,
+                ])),
+            ),
+        );
+
+        let result = simulator._call(
+            Tx {
+                caller: owner,
+                transact_to: reverter,
+                data: Bytes::new(),
+                value: U256::zero(),
+                gas_limit: 0,
+            },
+            false,
+        );
+        match result {
+            Err(SimError::Revert { output, gas_used }) => {
+                let mut expected = [0u8; 32];
+                expected[31] = 0xaa;
+                assert_eq!(output.as_ref(), &expected);
+                assert!(gas_used > 0);
+            }
+            other => panic!("expected a revert, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_meat_tx_above_the_gas_cap_is_skipped() {
+        let (sender, receiver) = (H160::random(), H160::random());
+        let griefing = Transaction {
+            from: sender,
+            to: Some(receiver),
+            value: U256::one(),
+            gas: U256::from(30_000_000u64),
+            ..Default::default()
+        };
+        let run = |max_pending_tx_gas: Option<u64>| {
+            let (provider, _) = Provider::mocked();
+            let config = SimulatorConfig {
+                max_pending_tx_gas,
+                ..SimulatorConfig::default()
+            };
+            let mut simulator = EvmSimulator::new_with_config(
+                Arc::new(provider),
+                H160::random(),
+                U64::from(1),
+                config,
+            );
+            insert_eoa(&mut simulator, sender, 1);
+            insert_eoa(&mut simulator, receiver, 0);
+            simulator.run_pending_tx(&griefing, false)
+        };
+
+        // without a cap the 30M gas tx is replayed
+        assert!(run(None).is_ok());
+        // with one it's rejected before touching the EVM
+        match run(Some(2_000_000)) {
+            Err(SimError::Invalid(reason)) => assert!(reason.contains("above cap")),
+            other => panic!("expected the tx to be skipped, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_block_offset() {
+        let fork_block = U64::from(100);
+        let new = |block_offset: u64| {
+            let (provider, _) = Provider::mocked();
+            let config = SimulatorConfig {
+                block_offset,
+                ..SimulatorConfig::default()
+            };
+            EvmSimulator::new_with_config(Arc::new(provider), H160::random(), fork_block, config)
+        };
+
+        // by default simulations run in the next block
+        assert_eq!(new(1).evm.env.block.number, rU256::from(101));
+        assert_eq!(SimulatorConfig::default().block_offset, 1);
+
+        // offset 0 simulates the fork block itself, with its own base fee
+        let mut simulator = new(0);
+        assert_eq!(simulator.evm.env.block.number, rU256::from(100));
+        let context = BlockContext {
+            number: fork_block,
+            timestamp: U256::from(1000),
+            base_fee: U256::from(10),
+            next_base_fee: U256::from(11),
+            gas_limit: U256::from(30000000),
+            gas_used: U256::from(15000000),
+        };
+        simulator.set_block_context(&context);
+        assert_eq!(simulator.evm.env.block.number, rU256::from(100));
+        assert_eq!(simulator.evm.env.block.timestamp, rU256::from(1000));
+        assert_eq!(simulator.evm.env.block.basefee, rU256::from(10));
+
+        // the setter moves an existing simulator
+        simulator.set_block_offset(2);
+        assert_eq!(simulator.evm.env.block.number, rU256::from(102));
+        simulator.set_block_context(&context);
+        let elapsed = simulator.config.chain.block_time.as_millis() as u64 * 2 / 1000;
+        assert_eq!(
+            simulator.evm.env.block.timestamp,
+            rU256::from(1000 + elapsed)
+        );
+        assert_eq!(simulator.evm.env.block.basefee, rU256::from(11));
+    }
+
+    #[test]
+    fn test_realistic_config_rejects_underpriced_txs() {
+        let (sender, receiver) = (H160::random(), H160::random());
+        let underpriced = Transaction {
+            from: sender,
+            to: Some(receiver),
+            value: U256::one(),
+            gas: U256::from(21000),
+            transaction_type: Some(U64::from(2)),
+            max_fee_per_gas: Some(U256::from(1_000_000_000u64)),
+            max_priority_fee_per_gas: Some(U256::from(1_000_000_000u64)),
+            ..Default::default()
+        };
+        let run = |config: SimulatorConfig| {
+            let (provider, _) = Provider::mocked();
+            let mut simulator = EvmSimulator::new_with_config(
+                Arc::new(provider),
+                H160::random(),
+                U64::from(1),
+                config,
+            );
+            insert_eoa(&mut simulator, sender, 1);
+            insert_eoa(&mut simulator, receiver, 0);
+            // the tip is paid to the coinbase, the mock can't serve it
+            let coinbase = H160::random();
+            insert_eoa(&mut simulator, coinbase, 0);
+            simulator.evm.env.block.coinbase = coinbase.into();
+            simulator.evm.env.block.basefee = rU256::from(10_000_000_000u64);
+            simulator.evm.env.block.gas_limit = rU256::from(30_000_000u64);
+            simulator.run_pending_tx(&underpriced, false)
+        };
+
+        // by default the base fee isn't checked, a 1 gwei tx runs in a 10 gwei block
+        assert!(run(SimulatorConfig::default()).is_ok());
+        // the realistic config rejects it like the chain would
+        match run(SimulatorConfig::realistic()) {
+            Err(SimError::Invalid(_)) => {}
+            other => panic!("expected the tx to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_simulator_contract_applies_fee() {
+        let mut simulator = mocked_fork();
+        let owner = simulator.owner;
+        insert_eoa(&mut simulator, owner, 0);
+        simulator.deploy_simulator();
+
+        let amount_in = U256::from(10).pow(U256::from(18));
+        let reserve_in = U256::from(1000) * amount_in;
+        let reserve_out = U256::from(5_000_000) * amount_in;
+        // the contract's getAmountOut is the 0.3% fee math, other fees are computed in Rust
+        let calldata = simulator
+            .simulator
+            .get_amount_out_input(amount_in, reserve_in, reserve_out)
+            .unwrap();
+        let value = simulator
+            .staticcall(Tx {
+                caller: owner,
+                transact_to: simulator.simulator_address,
+                data: calldata.0,
+                value: U256::zero(),
+                gas_limit: 5000000,
+            })
+            .unwrap();
+        let out = simulator
+            .simulator
+            .get_amount_out_output(value.output)
+            .unwrap();
+        let at_30_bps = simulator
+            .get_amount_out(amount_in, reserve_in, reserve_out, DEFAULT_V2_FEE_BPS)
+            .unwrap();
+        assert_eq!(out, at_30_bps);
+
+        // a cheaper pool pays out more for the same reserves
+        let at_25_bps = simulator
+            .get_amount_out(amount_in, reserve_in, reserve_out, 25)
+            .unwrap();
+        assert!(at_25_bps > at_30_bps);
+        // 5 bps more of amount_in reaches the pool, before price impact
+        let diff = at_25_bps - at_30_bps;
+        let expected = reserve_out * amount_in * U256::from(5) / U256::from(10000) / reserve_in;
+        assert!(diff <= expected && diff * U256::from(100) >= expected * U256::from(99));
+
+        // a fee of 100% or more is rejected instead of underflowing
+        assert!(simulator
+            .get_amount_out(amount_in, reserve_in, reserve_out, 10000)
+            .is_err());
+        assert!(simulator
+            .get_amount_in(amount_in, reserve_in, reserve_out, 10001)
+            .is_err());
+    }
+
+    #[test]
+    fn test_token_reserves_follow_the_pool_tokens() {
+        // getReserves is (slot 0, slot 1, 0), token0() and token1() return slots 2 and 3
+        let code: ethers::types::Bytes = "0x60003560e01c80630902f1ac1460255780630dfe1681146037578063d21220a714604357005b60005460005260015460205260606000f35b60025460005260206000f35b60035460005260206000f3"
+            .parse()
+            .unwrap();
+        let mut simulator = mocked_fork();
+        let owner = simulator.owner;
+        insert_eoa(&mut simulator, owner, 0);
+
+        let (low, high) = (
+            H160::from_low_u64_be(1),
+            H160::from_str("0xffffffffffffffffffffffffffffffffffffffff").unwrap(),
+        );
+        // the pool's own token0 decides which reserve is whose, sorted or not
+        for (token0, token1) in [(low, high), (high, low)] {
+            let pool = H160::random();
+            simulator.evm.db.as_mut().unwrap().insert_account_info(
+                pool.into(),
+                AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+            );
+            let word = |address: H160| -> rU256 {
+                U256::from_big_endian(H256::from(address).as_bytes()).into()
+            };
+            for (slot, value) in [
+                (0, rU256::from(111)),
+                (1, rU256::from(222)),
+                (2, word(token0)),
+                (3, word(token1)),
+            ] {
+                simulator
+                    .set_storage(pool, rU256::from(slot), value)
+                    .unwrap();
+            }
+
+            let reserves = simulator.v2_pool_get_token_reserves(pool).unwrap();
+            assert_eq!(reserves.len(), 2);
+            assert_eq!(reserves[&token0], 111);
+            assert_eq!(reserves[&token1], 222);
+        }
+    }
+
+    #[test]
+    fn test_state_overrides_set_up_a_swap() {
+        // A token and V2 pair in one. As a token: transfer reverts above the cap in slot 2 and adds
+        // the amount to slot 3, which balanceOf returns for every holder. As a pair: getReserves is
+        // (slot 0, slot 1, 0) and swap transfers the non zero amount of token0 (slot 4) or token1 (slot 5)
+        let code: ethers::types::Bytes = "0x60003560e01c8063a9059cbb14602f5780630902f1ac14604c57806370a0823114605e578063022c0d9f14606a57005b602435806002541060a95760035401600355600160005260206000f35b60005460005260015460205260606000f35b60035460005260206000f35b60043580156079576004546081565b506024356005545b63a9059cbb60e01b6000526044356004529060245260206000604460006000855af11560a957005b600080fd"
+            .parse()
+            .unwrap();
+
+        let mut simulator = mocked_fork();
+        let owner = simulator.owner;
+        let (token_in, token_out, pair) = (H160::random(), H160::random(), H160::random());
+        // the mock can't serve fetches, so the accounts exist before they're overridden
+        for address in [owner, token_in, token_out, pair] {
+            insert_eoa(&mut simulator, address, 0);
+        }
+        let (token0, token1) = if token_in < token_out {
+            (token_in, token_out)
+        } else {
+            (token_out, token_in)
+        };
+        let e18 = U256::exp10(18);
+        let (reserve0, reserve1) = (U256::from(1000) * e18, U256::from(2000) * e18);
+        let reserve_in = if token_in == token0 {
+            reserve0
+        } else {
+            reserve1
+        };
+        let word = |address: H160| U256::from_big_endian(H256::from(address).as_bytes());
+
+        // the pool's reserves and the input token's balance, in one go
+        let overrides = HashMap::from([
+            (
+                owner,
+                AccountOverride {
+                    balance: Some(e18),
+                    ..Default::default()
+                },
+            ),
+            (
+                pair,
+                AccountOverride {
+                    code: Some(code.0.clone()),
+                    storage: HashMap::from([
+                        (U256::from(0), reserve0),
+                        (U256::from(1), reserve1),
+                        (U256::from(4), word(token0)),
+                        (U256::from(5), word(token1)),
+                    ]),
+                    ..Default::default()
+                },
+            ),
+            (
+                token_in,
+                AccountOverride {
+                    code: Some(code.0.clone()),
+                    storage: HashMap::from([
+                        (U256::from(2), U256::MAX),
+                        (U256::from(3), reserve_in),
+                    ]),
+                    ..Default::default()
+                },
+            ),
+            (
+                token_out,
+                AccountOverride {
+                    code: Some(code.0.clone()),
+                    storage: HashMap::from([(U256::from(2), U256::MAX)]),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        simulator.apply_state_overrides(overrides).unwrap();
+        assert_eq!(
+            simulator.v2_pool_get_reserves(pair).unwrap(),
+            (reserve0.as_u128(), reserve1.as_u128(), 0)
+        );
+        simulator.deploy_simulator();
+
+        // the swap only adds up if both the reserves and the pair's input balance were overridden
+        let pool = Pool {
+            address: pair,
+            version: DexVariant::UniswapV2,
+            token0,
+            token1,
+            decimals0: 18,
+            decimals1: 18,
+            fee: 300,
+        };
+        let expected = pool.amount_out(e18, (reserve0.as_u128(), reserve1.as_u128()), token_in);
+        let (amount_out, received) = simulator
+            .v2_simulate_swap(e18, pair, token_in, token_out, DEFAULT_V2_FEE_BPS, false)
+            .unwrap();
+        assert_eq!(amount_out, expected);
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_swap_verification_reports_a_fee_on_transfer_divergence() {
+        // The max tx token/pair mock (see test_state_overrides_set_up_a_swap) with token0() and
+        // token1() returning slots 4 and 5, and a transfer fee: when tx.origin isn't msg.sender,
+        // slot 8 (bps) of the amount never arrives, like anti-bot taxes on contract callers
+        let code: ethers::types::Bytes = "0x60003560e01c8063a9059cbb14605b5780630902f1ac14608a57806370a0823114609c578063022c0d9f1460a85780630dfe1681146043578063d21220a714604f57005b60045460005260206000f35b60055460005260206000f35b602435806002541060e75732331460785761271060085482020490035b60035401600355600160005260206000f35b60005460005260015460205260606000f35b60035460005260206000f35b600435801560b75760045460bf565b506024356005545b63a9059cbb60e01b6000526044356004529060245260206000604460006000855af11560e757005b600080fd"
+            .parse()
+            .unwrap();
+
+        let swap = |tax_bps: u64| {
+            let (provider, _) = Provider::mocked();
+            let config = SimulatorConfig {
+                swap_tolerance_bps: Some(50),
+                ..SimulatorConfig::default()
+            };
+            let mut simulator = EvmSimulator::new_with_config(
+                Arc::new(provider),
+                H160::random(),
+                U64::from(1),
+                config,
+            );
+            let owner = simulator.owner;
+            insert_eoa(&mut simulator, owner, 0);
+            let (token_in, token_out, pair) = (H160::random(), H160::random(), H160::random());
+            for address in [token_in, token_out, pair] {
+                simulator.evm.db.as_mut().unwrap().insert_account_info(
+                    address.into(),
+                    AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.0.clone())),
+                );
+            }
+            simulator.deploy_simulator();
+
+            let (token0, token1) = if token_in < token_out {
+                (token_in, token_out)
+            } else {
+                (token_out, token_in)
+            };
+            let reserve = rU256::from(1000) * rU256::from(10).pow(rU256::from(18));
+            let word = |address: H160| -> rU256 {
+                U256::from_big_endian(H256::from(address).as_bytes()).into()
+            };
+            for (address, slot, value) in [
+                (pair, 0, reserve),
+                (pair, 1, reserve),
+                (pair, 4, word(token0)),
+                (pair, 5, word(token1)),
+                (token_in, 2, rU256::MAX),
+                (token_in, 3, reserve),
+                (token_in, 8, rU256::from(tax_bps)),
+                (token_out, 2, rU256::MAX),
+            ] {
+                simulator
+                    .set_storage(address, rU256::from(slot), value)
+                    .unwrap();
+            }
+            simulator.v2_simulate_swap(
+                U256::exp10(18),
+                pair,
+                token_in,
+                token_out,
+                DEFAULT_V2_FEE_BPS,
+                false,
+            )
+        };
+
+        // an untaxed token pays the same through the simulator contract and the pool
+        assert!(swap(0).is_ok());
+        // the simulator contract's transfer is taxed 10%, the direct one isn't
+        match swap(1000) {
+            Err(SimError::Invalid(reason)) => assert!(reason.contains("bps apart"), "{}", reason),
+            other => panic!("expected the divergence to be reported, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pool_estimates_match_the_simulator_contract() {
+        let mut simulator = mocked_fork();
+        let owner = simulator.owner;
+        insert_eoa(&mut simulator, owner, 0);
+        simulator.deploy_simulator();
+
+        // 2,000,000 USDC / 1,000 WETH, 2000 USDC per WETH
+        let (usdc, weth) = (H160::random(), H160::random());
+        let pool = Pool {
+            address: H160::random(),
+            version: DexVariant::UniswapV2,
+            token0: usdc,
+            token1: weth,
+            decimals0: 6,
+            decimals1: 18,
+            fee: 300,
+        };
+        let reserves = (2_000_000 * 10u128.pow(6), 1000 * 10u128.pow(18));
+        assert_eq!(pool.spot_price(reserves.0, reserves.1, weth), 2000.0);
+        assert_eq!(pool.spot_price(reserves.0, reserves.1, usdc), 0.0005);
+        assert_eq!(pool.spot_price(0, reserves.1, weth), 0.0);
+
+        for (amount_in, token_in) in [
+            (token_unit(18).unwrap(), weth),
+            (U256::from(5000) * token_unit(6).unwrap(), usdc),
+        ] {
+            let (reserve_in, reserve_out) = pool.ordered_reserves(reserves, token_in);
+            let calldata = simulator
+                .simulator
+                .get_amount_out_input(amount_in, reserve_in, reserve_out)
+                .unwrap();
+            let value = simulator
+                .staticcall(Tx {
+                    caller: owner,
+                    transact_to: simulator.simulator_address,
+                    data: calldata.0,
+                    value: U256::zero(),
+                    gas_limit: 5000000,
+                })
+                .unwrap();
+            let expected = simulator
+                .simulator
+                .get_amount_out_output(value.output)
+                .unwrap();
+            assert_eq!(pool.amount_out(amount_in, reserves, token_in), expected);
+        }
+    }
+
+    #[test]
+    fn test_amount_in_code_matches_get_amount_in() {
+        let mut simulator = mocked_fork();
+        let owner = simulator.owner;
+        insert_eoa(&mut simulator, owner, 0);
+        simulator.deploy_amount_in_code();
+
+        let unit = U256::from(10).pow(U256::from(18));
+        let reserve_in = U256::from(1000) * unit;
+        let reserve_out = U256::from(5_000_000) * unit;
+        let mut get_amount_in = |amount_out: U256, reserve_out: U256, fee_bps: u32| {
+            let calldata = simulator
+                .simulator
+                .get_amount_in_input(amount_out, reserve_in, reserve_out, fee_bps)
+                .unwrap();
+            simulator
+                .staticcall(Tx {
+                    caller: owner,
+                    transact_to: simulator.amount_in_address,
+                    data: calldata.0,
+                    value: U256::zero(),
+                    gas_limit: 5000000,
+                })
+                .map(|value| {
+                    simulator
+                        .simulator
+                        .get_amount_in_output(value.output)
+                        .unwrap()
+                })
+        };
+
+        for (amount_out, fee_bps) in [(unit, 30), (U256::from(1234) * unit, 25), (U256::one(), 0)] {
+            let expected = reserve_in * amount_out * U256::from(10000)
+                / ((reserve_out - amount_out) * U256::from(10000 - fee_bps))
+                + U256::one();
+            assert_eq!(
+                get_amount_in(amount_out, reserve_out, fee_bps).unwrap(),
+                expected
+            );
+        }
+
+        // more than the pool holds, a fee of 100% or reserves beyond uint112 revert
+        assert!(get_amount_in(reserve_out, reserve_out, 30).is_err());
+        assert!(get_amount_in(unit, reserve_out, 10000).is_err());
+        assert!(get_amount_in(unit, U256::one() << 112, 30).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_exact_out_input_swaps_for_the_requested_output() {
+        let mut simulator = mainnet_fork().await;
+        simulator.deploy_simulator();
+        let simulator_address = simulator.simulator_address;
+
+        // Uniswap V2 USDC/WETH
+        let pool = H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc").unwrap();
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+        let amount_out = U256::from(1000) * token_unit(6).unwrap();
+
+        let amount_in = simulator
+            .v2_simulate_swap_exact_out(amount_out, pool, weth, usdc, DEFAULT_V2_FEE_BPS)
+            .unwrap();
+        simulator.set_token_balance(simulator_address, weth, 18, 3, 100);
+        let (_, received) = simulator
+            .v2_simulate_swap(amount_in, pool, weth, usdc, DEFAULT_V2_FEE_BPS, true)
+            .unwrap();
+        // getAmountIn rounds up, so the output is at least the requested amount
+        assert!(received >= amount_out);
+        assert!(received - amount_out <= amount_out / U256::from(10000));
+
+        // the pool can't pay out its whole reserve
+        let reserves = simulator.v2_pool_get_token_reserves(pool).unwrap();
+        let usdc_reserve = U256::from(*reserves.get(&usdc).unwrap());
+        assert!(simulator
+            .v2_simulate_swap_exact_out(usdc_reserve, pool, weth, usdc, DEFAULT_V2_FEE_BPS)
+            .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_swap_exact_in_matches_the_manual_ordering() {
+        let mut simulator = mainnet_fork().await;
+        simulator.deploy_simulator();
+        let simulator_address = simulator.simulator_address;
+
+        // Uniswap V2 USDC/WETH, token0 is USDC
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+        let pool = Pool {
+            address: H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc").unwrap(),
+            version: DexVariant::UniswapV2,
+            token0: usdc,
+            token1: weth,
+            decimals0: 6,
+            decimals1: 18,
+            fee: 300,
+        };
+        simulator.set_token_balance(simulator_address, usdc, 6, 9, 10000);
+        simulator.set_token_balance(simulator_address, weth, 18, 3, 10);
+
+        for (token_in, token_out, amount_in) in [
+            (usdc, weth, U256::from(1000) * token_unit(6).unwrap()),
+            (weth, usdc, token_unit(18).unwrap()),
+        ] {
+            let manual = simulator
+                .v2_simulate_swap(
+                    amount_in,
+                    pool.address,
+                    token_in,
+                    token_out,
+                    pool.fee_bps(),
+                    false,
+                )
+                .unwrap();
+            let swapped = simulator
+                .swap_exact_in(&pool, amount_in, token_in, false)
+                .unwrap();
+            assert!(!swapped.1.is_zero());
+            assert_eq!(swapped, manual);
+        }
+
+        // a token the pool doesn't trade is an error, not a swap in the wrong direction
+        assert!(simulator
+            .swap_exact_in(&pool, U256::one(), H160::random(), false)
+            .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_pending_swap_moves_the_reserves_we_simulate_on() {
+        let mut simulator = mainnet_fork().await;
+        simulator.deploy_simulator();
+        let simulator_address = simulator.simulator_address;
+
+        let router = H160::from_str("0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D").unwrap();
+        let pool = H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc").unwrap();
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+        let amount_in = token_unit(18).unwrap();
+        simulator.set_token_balance(simulator_address, weth, 18, 3, 10);
+
+        let reserves_before = simulator.v2_pool_get_token_reserves(pool).unwrap();
+        let (_, out_before) = simulator
+            .v2_simulate_swap(amount_in, pool, weth, usdc, DEFAULT_V2_FEE_BPS, false)
+            .unwrap();
+
+        // someone else buys USDC with 100 ETH through the router ahead of us
+        let buyer = H160::random();
+        simulator.evm.db.as_mut().unwrap().insert_account_info(
+            buyer.into(),
+            AccountInfo::new(
+                rU256::from(1000) * rU256::from(10).pow(rU256::from(18)),
+                0,
+                Bytecode::default(),
+            ),
+        );
+        let router_abi = abi::parse_abi(&[
+            "function swapExactETHForTokens(uint256,address[],address,uint256) external payable returns (uint256[])",
+        ])
+        .unwrap();
+        let calldata = router_abi
+            .function("swapExactETHForTokens")
+            .unwrap()
+            .encode_input(&[
+                abi::Token::Uint(U256::zero()),
+                abi::Token::Array(vec![abi::Token::Address(weth), abi::Token::Address(usdc)]),
+                abi::Token::Address(buyer),
+                abi::Token::Uint(U256::MAX),
+            ])
+            .unwrap();
+        let pending_swap = Transaction {
+            from: buyer,
+            to: Some(router),
+            value: U256::from(100) * amount_in,
+            input: calldata.into(),
+            gas: U256::from(300000),
+            gas_price: Some(U256::from(100) * U256::exp10(9)),
+            ..Default::default()
+        };
+        let results = simulator.apply_pending_txs(&[pending_swap]);
+        assert!(results[0].is_ok());
+
+        // the pending block holds more WETH and less USDC, so the same swap pays out less
+        let reserves_after = simulator.v2_pool_get_token_reserves(pool).unwrap();
+        assert_eq!(
+            reserves_after.get(&weth).unwrap() - reserves_before.get(&weth).unwrap(),
+            (U256::from(100) * amount_in).as_u128()
+        );
+        assert!(reserves_after.get(&usdc).unwrap() < reserves_before.get(&usdc).unwrap());
+        let (_, out_after) = simulator
+            .v2_simulate_swap(amount_in, pool, weth, usdc, DEFAULT_V2_FEE_BPS, false)
+            .unwrap();
+        assert!(out_after < out_before);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_wrap_and_unwrap_through_weth() {
+        let mut simulator = mainnet_fork().await;
+        let owner = simulator.owner;
+        let weth = simulator.weth_address;
+        simulator.set_eth_balance(10);
+        let amount = U256::from(3) * token_unit(18).unwrap();
+
+        let weth_before = simulator.token_balance_of(weth, owner).unwrap();
+        simulator.wrap_eth(amount).unwrap();
+        let weth_after = simulator.token_balance_of(weth, owner).unwrap();
+        assert_eq!(weth_after - weth_before, amount);
+        // gas isn't charged on the fork, the ETH went into WETH as is
+        assert_eq!(
+            simulator.get_eth_balance(),
+            U256::from(7) * token_unit(18).unwrap()
+        );
+
+        simulator.unwrap_weth(amount).unwrap();
+        assert_eq!(
+            simulator.token_balance_of(weth, owner).unwrap(),
+            weth_before
+        );
+        assert_eq!(
+            simulator.get_eth_balance(),
+            U256::from(10) * token_unit(18).unwrap()
+        );
+
+        // more than we hold can't be unwrapped
+        assert!(simulator.unwrap_weth(amount).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_v3_two_hop_quote_matches_onchain_quoter() {
+        let mut simulator = mainnet_fork().await;
+
+        // USDC -(0.05%)-> WETH -(0.3%)-> DAI
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+        let dai = H160::from_str("0x6B175474E89094C44Da98b954EedeAC495271d0F").unwrap();
+        let mut path = usdc.as_bytes().to_vec();
+        path.extend_from_slice(&500u32.to_be_bytes()[1..]);
+        path.extend_from_slice(weth.as_bytes());
+        path.extend_from_slice(&3000u32.to_be_bytes()[1..]);
+        path.extend_from_slice(dai.as_bytes());
+        let amount_in = U256::from(10000) * token_unit(6).unwrap();
+
+        let (amount_out, amounts) = simulator.v3_quote_path(&path, amount_in).unwrap();
+        assert_eq!(amounts.len(), 2);
+        assert_eq!(amounts[1], amount_out);
+
+        // the same quote from the deployed QuoterV2, at the block the fork was taken
+        let calldata = simulator
+            .v3_quoter
+            .quote_exact_input_input(&path, amount_in)
+            .unwrap();
+        let tx: ethers::types::transaction::eip2718::TypedTransaction =
+            ethers::types::TransactionRequest::new()
+                .to(simulator.v3_quoter_address)
+                .data(calldata)
+                .into();
+        let output = simulator
+            .provider
+            .call(&tx, Some(simulator.block_number.into()))
+            .await
+            .unwrap();
+        let onchain = simulator
+            .v3_quoter
+            .quote_exact_input_output(output.0)
+            .unwrap();
+        let diff = if amount_out > onchain {
+            amount_out - onchain
+        } else {
+            onchain - amount_out
+        };
+        assert!(diff <= onchain / U256::from(10000));
+    }
+
+    #[test]
+    fn test_call_bundle_reports_coinbase_payment() {
+        let mut simulator = mocked_fork();
+        let sender = H160::random();
+        let coinbase = H160::random();
+        insert_eoa(&mut simulator, sender, 10);
+        insert_eoa(&mut simulator, coinbase, 0);
+        simulator.evm.env.block.coinbase = coinbase.into();
+
+        let gwei = U256::from(10).pow(U256::from(9));
+        let ether = U256::from(10).pow(U256::from(18));
+        let base_fee = U256::from(10) * gwei;
+        let tx = Transaction {
+            from: sender,
+            to: Some(coinbase),
+            value: ether,
+            gas: U256::from(21000),
+            nonce: U256::zero(),
+            transaction_type: Some(U64::from(2)),
+            max_fee_per_gas: Some(base_fee + gwei * 2),
+            max_priority_fee_per_gas: Some(gwei * 2),
+            ..Default::default()
+        };
+
+        let result = simulator
+            .call_bundle(&[tx], U64::from(2), base_fee)
+            .unwrap();
+        let tx_result = &result.results[0];
+        assert!(tx_result.error.is_none());
+        assert_eq!(tx_result.gas_used, 21000);
+        assert_eq!(tx_result.gas_fees, U256::from(21000) * gwei * 2);
+        assert_eq!(tx_result.eth_sent_to_coinbase, ether);
+        assert_eq!(result.coinbase_diff, ether + tx_result.gas_fees);
+
+        // the bundle ran on a snapshot, the payment never reached the fork
+        let coinbase_balance = simulator.coinbase_balance(coinbase.into()).unwrap();
+        assert_eq!(coinbase_balance, U256::zero());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dead_provider_fails_fast() {
+        // the mock has nothing to serve, like an RPC that's down
+        let mut simulator = mocked_fork();
+        let started = std::time::Instant::now();
+        assert!(simulator
+            .health_check(Duration::from_millis(100))
+            .await
+            .is_err());
+        assert!(simulator.warmup().is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_dropped_simulator_flushes_its_fetches() {
+        let cache_dir = std::env::temp_dir().join(format!("fork-cache-{:?}", H160::random()));
+        let config = SimulatorConfig {
+            cache_dir: Some(cache_dir.clone()),
+            ..SimulatorConfig::default()
+        };
+        let owner = H160::random();
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+
+        // the mock answers exactly one account fetch (balance, nonce then code, served last in first out),
+        // any RPC request after that fails
+        mock.push::<ethers::types::Bytes, _>(ethers::types::Bytes::default())
+            .unwrap();
+        mock.push::<U256, _>(U256::from(7)).unwrap();
+        mock.push::<U256, _>(U256::from(100)).unwrap();
+
+        let mut first =
+            EvmSimulator::new_with_config(provider.clone(), owner, U64::from(1), config.clone());
+        first.warmup().unwrap();
+        drop(first);
+
+        // same block: the account comes from the cache file, without a single RPC request
+        let mut second =
+            EvmSimulator::new_with_config(provider.clone(), owner, U64::from(1), config.clone());
+        second.warmup().unwrap();
+        let account = second
+            .evm
+            .db
+            .as_mut()
+            .unwrap()
+            .basic(owner.into())
+            .unwrap()
+            .unwrap();
+        assert_eq!(account.balance, rU256::from(100));
+        assert_eq!(account.nonce, 7);
+
+        // another block isn't cached, it goes to the RPC which has nothing left to serve
+        let mut other = EvmSimulator::new_with_config(provider, owner, U64::from(2), config);
+        assert!(other.warmup().is_err());
+
+        drop(second);
+        drop(other);
+        _ = std::fs::remove_dir_all(cache_dir);
+    }
+
+    #[test]
+    fn test_shared_cache_dir_saves_fetches() {
+        let cache_dir = std::env::temp_dir().join(format!("fork-cache-{:?}", H160::random()));
+        let config = SimulatorConfig {
+            cache_dir: Some(cache_dir.clone()),
+            ..SimulatorConfig::default()
+        };
+        let owner = H160::random();
+        let mock = MockProvider::new();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let provider = Arc::new(Provider::new(CountingClient {
+            inner: mock.clone(),
+            requests: requests.clone(),
+        }));
+
+        // one account fetch: balance, nonce then code, served last in first out
+        mock.push::<ethers::types::Bytes, _>(ethers::types::Bytes::default())
+            .unwrap();
+        mock.push::<U256, _>(U256::from(7)).unwrap();
+        mock.push::<U256, _>(U256::from(100)).unwrap();
+
+        let mut first =
+            EvmSimulator::new_with_config(provider.clone(), owner, U64::from(1), config.clone());
+        first.warmup().unwrap();
+        drop(first);
+        let first_requests = requests.load(Ordering::SeqCst);
+        assert!(first_requests > 0);
+
+        // same block and cache dir: the second simulator reads the account from disk
+        let mut second = EvmSimulator::new_with_config(provider, owner, U64::from(1), config);
+        second.warmup().unwrap();
+        drop(second);
+        assert!(requests.load(Ordering::SeqCst) - first_requests < first_requests);
+
+        _ = std::fs::remove_dir_all(cache_dir);
+    }
 }
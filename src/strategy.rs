@@ -1,156 +1,253 @@
-use anvil::eth::fees::calculate_next_block_base_fee;
-use anyhow::Result;
-use cfmms::dex::DexVariant;
+use anyhow::{anyhow, Result};
 use colored::Colorize;
 use ethers::{
     prelude::*,
-    providers::{Middleware, Provider, Ws},
-    types::{BlockId, BlockNumber, H160, U256, U64},
+    providers::Middleware,
+    types::{BlockId, BlockNumber, H160, U256},
 };
 use foundry_evm::revm::primitives::keccak256;
-use log::info;
-use std::{collections::HashMap, str::FromStr, sync::Arc};
-use tokio::sync::broadcast::Sender;
+use log::{info, warn};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{
+    broadcast::{error::RecvError, Sender},
+    Semaphore,
+};
 
-use crate::constants::Env;
-use crate::honeypot::HoneypotFilter;
-use crate::pools::{load_all_pools, Pool};
-use crate::sandwich::{simulate_sandwich_bundle, Sandwich, SandwichSimulator};
-use crate::streams::{Event, NewBlock};
+use crate::arbitrage::{simulate_backrun_arbitrage, ArbCache, TriangularArbitrage};
+use crate::breaker::CircuitBreaker;
+use crate::bundle::{net_of_gas, wei_to_usd};
+use crate::constants::{Env, WEI};
+#[cfg(feature = "control")]
+use crate::control::serve_control;
+use crate::control::CONTROL;
+use crate::honeypot::{unix_timestamp, HoneypotFilter};
+use crate::ledger::{LedgerRecord, ProfitLedger};
+use crate::mempool::{effective_priority_fee, PendingTxQueue, TxClassifier};
+use crate::metrics::{Metrics, METRICS};
+use crate::paths::{generate_triangular_paths, index_paths_by_pool, ArbPath};
+use crate::pools::{build_verified_pools, load_all_pools, Pool};
+use crate::pricing::{EthPriceOracle, UsdPricer};
+use crate::route::{most_slipped_leg, reconstruct_victim_path};
+use crate::sandwich::{
+    build_pending_block_state, fetch_pending_block, find_eth_pool, get_frontrun_amount,
+    simulate_multi_pool_sandwich, simulate_sandwich_bundle, Sandwich, SandwichSimulator,
+};
+use crate::simulator::{SimError, SimulatorConfig};
+use crate::streams::{BlockContext, Event, OpportunityKind};
+use crate::tokens::load_tokens_csv;
+use crate::trace::{tracing_options, TouchedAccounts, TraceMode};
+use crate::utils::{correlation_id, join_all_bounded};
+use crate::watchdog::BlockWatchdog;
 
 #[macro_export]
 macro_rules! log_info_warning {
+    (id = $id:expr; $($arg:tt)*) => {
+        info!("[{}] {}", $id, format_args!($($arg)*).to_string().magenta());
+    };
     ($($arg:tt)*) => {
         info!("{}", format_args!($($arg)*).to_string().magenta());
     };
 }
 
+#[macro_export]
+macro_rules! log_with_id {
+    ($id:expr, $($arg:tt)*) => {
+        info!("[{}] {}", $id, format_args!($($arg)*))
+    };
+}
+
 pub async fn get_touched_pools<M: Middleware + 'static>(
-    provider: Arc<Provider<Ws>>,
+    provider: Arc<M>,
     tx: &Transaction,
-    block_number: U64,
     verified_pools_map: &HashMap<H160, Pool>,
     honeypot_filter: &HoneypotFilter<M>,
-) -> Result<HashMap<H160, Option<H160>>> {
+    timeout: Duration,
+    trace_mode: TraceMode,
+) -> Result<(
+    HashMap<H160, Option<(H160, U256)>>,
+    BTreeMap<H160, AccountState>,
+)> {
     // you don't know what transaction will touch the pools you're interested in
     // thus, you need to trace all pending transactions you receive
     // evm tracing can sometimes take a very long time as can be seen from:
     // https://banteg.mirror.xyz/3dbuIlaHh30IPITWzfT1MFfSg6fxSssMqJ7TcjaWecM
 
+    let mut sandwichable_pools = HashMap::new();
+
     // Also check: https://github.com/ethereum/go-ethereum/pull/25422#discussion_r978789901 for diffMode
-    let trace = provider
-        .debug_trace_call(
-            tx,
-            Some(BlockId::Number(BlockNumber::Number(block_number))),
-            GethDebugTracingCallOptions {
-                tracing_options: GethDebugTracingOptions {
-                    disable_storage: None,
-                    disable_stack: None,
-                    enable_memory: None,
-                    enable_return_data: None,
-                    tracer: Some(GethDebugTracerType::BuiltInTracer(
-                        GethDebugBuiltInTracerType::PreStateTracer,
-                    )),
-                    tracer_config: Some(GethDebugTracerConfig::BuiltInTracer(
-                        GethDebugBuiltInTracerConfig::PreStateTracer(PreStateConfig {
-                            diff_mode: Some(true),
-                        }),
-                    )),
-                    timeout: None,
-                },
-                state_overrides: None,
-            },
-        )
-        .await?;
+    // The node aborts the trace after the timeout, but a slow response is also cut off locally
+    // so that a single trace can't stall the event loop
+    // Traced on the node's pending block, so the tx sees the earlier pending txs it depends on,
+    // the simulations fork the latest block and replay the prestate for that
+    // Nodes without a pending block trace on the latest one, the replay is then a no-op
+    let trace_call = provider.debug_trace_call(
+        tx,
+        Some(BlockId::Number(BlockNumber::Pending)),
+        GethDebugTracingCallOptions {
+            tracing_options: tracing_options(trace_mode, Some(timeout)),
+            state_overrides: None,
+        },
+    );
+    let trace = match tokio::time::timeout(timeout, trace_call).await {
+        Ok(trace) => trace.map_err(|e| anyhow!("debug_traceCall failed: {:?}", e))?,
+        Err(_) => return Ok((sandwichable_pools, BTreeMap::new())),
+    };
+    Metrics::add(&METRICS.traced_txs, 1);
+    // touched.pre is what the tx read on the pending block, replayed before simulating it
+    let touched = TouchedAccounts::from_trace(trace);
 
-    let mut sandwichable_pools = HashMap::new();
+    // Step 1: Check if any of the pools I'm monitoring were touched
+    let mut touched_pools = Vec::new();
+    for acc in &touched.accounts {
+        if verified_pools_map.contains_key(&acc) {
+            touched_pools.push(*acc);
+            sandwichable_pools.insert(*acc, None);
+        }
+    }
 
-    match trace {
-        GethTrace::Known(known) => match known {
-            GethTraceFrame::PreStateTracer(prestate) => match prestate {
-                PreStateFrame::Diff(diff) => {
-                    // Step 1: Check if any of the pools I'm monitoring were touched
-                    let mut touched_pools = Vec::new();
-                    for (acc, _) in &diff.post {
-                        if verified_pools_map.contains_key(&acc) {
-                            touched_pools.push(*acc);
-                            sandwichable_pools.insert(*acc, None);
-                        }
-                    }
+    if touched_pools.is_empty() {
+        return Ok((sandwichable_pools, touched.pre));
+    }
+    Metrics::add(&METRICS.touched_pools, touched_pools.len() as u64);
 
-                    if touched_pools.is_empty() {
-                        return Ok(sandwichable_pools);
-                    }
+    // The direction of the swap is only known from the balances changed, which needs diff mode,
+    // with other tracers the touched pools are still returned for backrun arbitrage
+    let (pre, post) = match &touched.post {
+        Some(post) => (&touched.pre, post),
+        None => return Ok((sandwichable_pools, touched.pre)),
+    };
 
-                    let safe_token_info = &honeypot_filter.safe_token_info;
-                    let balance_slots = &honeypot_filter.balance_slots;
-
-                    // Step 2: Check if the transaction increases the pool's safe token balance (weth/usdt/usdc/dai)
-                    // This means that the safe token price will go down, and the other token price will go up
-                    // Thus, we buy the token in our frontrunning tx, and sell the token in our backrunning tx
-                    for (_, safe_token) in safe_token_info {
-                        let token_prestate = diff.pre.get(&safe_token.address);
-                        match token_prestate {
-                            Some(prestate) => match &prestate.storage {
-                                Some(pre_storage) => {
-                                    let slot = *balance_slots.get(&safe_token.address).unwrap();
-                                    for pool in &touched_pools {
-                                        let balance_slot = keccak256(&abi::encode(&[
-                                            abi::Token::Address((*pool).into()),
-                                            abi::Token::Uint(U256::from(slot)),
-                                        ]));
-                                        if pre_storage.contains_key(&balance_slot.into()) {
-                                            let pre_balance = U256::from(
-                                                pre_storage
-                                                    .get(&balance_slot.into())
-                                                    .unwrap()
-                                                    .to_fixed_bytes(),
-                                            );
+    let safe_token_info = &honeypot_filter.safe_token_info;
+    let balance_slots = &honeypot_filter.balance_slots;
 
-                                            let token_poststate =
-                                                diff.post.get(&safe_token.address).unwrap();
-                                            let post_storage = &token_poststate.storage;
-                                            let post_balance = U256::from(
-                                                post_storage
-                                                    .as_ref()
-                                                    .unwrap()
-                                                    .get(&balance_slot.into())
-                                                    .unwrap()
-                                                    .to_fixed_bytes(),
-                                            );
+    // Step 2: Check if the transaction increases the pool's safe token balance (weth/usdt/usdc/dai)
+    // This means that the safe token price will go down, and the other token price will go up
+    // Thus, we buy the token in our frontrunning tx, and sell the token in our backrunning tx
+    // Pools pairing two safe tokens (ex. USDC/WETH) are collected per pool first,
+    // so that the direction is decided by which of the two balances went up
+    let mut balance_increases: HashMap<H160, Vec<(H160, U256)>> = HashMap::new();
+    for (_, safe_token) in safe_token_info {
+        let token_prestate = pre.get(&safe_token.address);
+        match token_prestate {
+            Some(prestate) => match &prestate.storage {
+                Some(pre_storage) => {
+                    let slot = *balance_slots.get(&safe_token.address).unwrap();
+                    for pool in &touched_pools {
+                        let balance_slot = keccak256(&abi::encode(&[
+                            abi::Token::Address((*pool).into()),
+                            abi::Token::Uint(U256::from(slot)),
+                        ]));
+                        if pre_storage.contains_key(&balance_slot.into()) {
+                            let pre_balance = U256::from(
+                                pre_storage
+                                    .get(&balance_slot.into())
+                                    .unwrap()
+                                    .to_fixed_bytes(),
+                            );
 
-                                            if pre_balance < post_balance {
-                                                sandwichable_pools
-                                                    .insert(*pool, Some(safe_token.address));
-                                            }
-                                        }
-                                    }
-                                }
-                                None => {}
-                            },
-                            None => {}
+                            let token_poststate = post.get(&safe_token.address).unwrap();
+                            let post_storage = &token_poststate.storage;
+                            let post_balance = U256::from(
+                                post_storage
+                                    .as_ref()
+                                    .unwrap()
+                                    .get(&balance_slot.into())
+                                    .unwrap()
+                                    .to_fixed_bytes(),
+                            );
+
+                            // Keep the victim's balance delta to size the frontrun
+                            if pre_balance < post_balance {
+                                balance_increases
+                                    .entry(*pool)
+                                    .or_default()
+                                    .push((safe_token.address, post_balance - pre_balance));
+                            }
                         }
                     }
                 }
-                _ => {}
+                None => {}
             },
-            _ => {}
-        },
-        _ => {}
+            None => {}
+        }
+    }
+
+    for (pool, increases) in balance_increases {
+        // Both safe token balances going up is a liquidity add, not a swap
+        if increases.len() == 1 {
+            sandwichable_pools.insert(pool, Some(increases[0]));
+        }
+    }
+
+    // Step 3: A multi-hop victim moves every pool on its route,
+    // only the leg with the most slippage is sandwiched
+    let route = reconstruct_victim_path(pre, post, verified_pools_map);
+    if route.len() > 1 {
+        let target = most_slipped_leg(&route, |leg| {
+            matches!(
+                sandwichable_pools.get(&leg.pool),
+                Some(Some((safe_token, _))) if *safe_token == leg.token_in
+            )
+        });
+        if let Some(target) = target {
+            for leg in &route {
+                if leg.pool != target.pool {
+                    sandwichable_pools.insert(leg.pool, None);
+                }
+            }
+        }
     }
 
-    Ok(sandwichable_pools)
+    Ok((sandwichable_pools, touched.pre))
+}
+
+fn record_lagged(dropped: u64) {
+    // the receiver fell more than the channel's capacity behind, the oldest events are gone
+    Metrics::add(&METRICS.dropped_events, dropped);
+    warn!(
+        "Event handler lagged behind, {} events were dropped. Consider raising EVENT_CHANNEL_CAPACITY",
+        dropped
+    );
+}
+
+fn publish_opportunity(
+    opportunity_sender: &Sender<Event>,
+    ledger: &mut Option<ProfitLedger>,
+    record: LedgerRecord,
+    detail: String,
+) {
+    // a failing ledger is logged, it never stops the handler
+    if let Some(ledger) = ledger.as_mut() {
+        if let Err(e) = ledger.record(&record) {
+            info!("Ledger write failed: {:?}", e);
+        }
+    }
+    // nobody may be subscribed, that's not an error
+    _ = opportunity_sender.send(Event::Opportunity {
+        kind: record.kind,
+        profit: record.profit,
+        block_number: record.block_number,
+        detail,
+    });
 }
 
-pub async fn event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
+pub async fn event_handler<M: Middleware + 'static>(
+    provider: Arc<M>,
+    event_sender: Sender<Event>,
+    opportunity_sender: Sender<Event>,
+) {
+    // Opportunities go out on their own channel: published on event_sender, they'd come back
+    // to this handler and take up room meant for blocks and pending txs
     let env = Env::new();
-    let factories = vec![(
-        // Sushiswap V2
-        "0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac",
-        DexVariant::UniswapV2,
-        10794229u64,
-    )];
-    let pools = load_all_pools(env.wss_url.clone(), factories)
+    let sim_config = SimulatorConfig::from_env(&env).unwrap();
+    let chain = sim_config.chain.clone();
+    let pools = load_all_pools(env.wss_url.clone(), chain.factories.clone())
         .await
         .unwrap();
 
@@ -160,132 +257,905 @@ pub async fn event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Eve
         .unwrap()
         .unwrap();
 
-    let mut honeypot_filter = HoneypotFilter::new(provider.clone(), block.clone());
-    honeypot_filter.setup().await;
-    honeypot_filter
-        .filter_tokens(&pools[0..3000].to_vec())
-        .await;
+    let mut honeypot_filter =
+        HoneypotFilter::new(provider.clone(), block.clone(), sim_config.clone());
+    for (token, test_amount) in &env.extra_safe_tokens {
+        honeypot_filter.add_safe_token(*token, *test_amount);
+    }
+    honeypot_filter.set_balance_slot_overrides(env.balance_slot_overrides.clone());
+    honeypot_filter.setup().await.unwrap();
+    match &env.trusted_tokens_file {
+        Some(path) => honeypot_filter.trust_tokens(load_tokens_csv(Path::new(path)).unwrap()),
+        None => {
+            honeypot_filter
+                .filter_tokens(&pools[0..3000].to_vec())
+                .await
+        }
+    }
 
-    // filter out pools that use unverified tokens
-    let verified_pools: Vec<Pool> = pools
-        .into_iter()
-        .filter(|pool| {
-            let token0_verified = honeypot_filter.safe_token_info.contains_key(&pool.token0)
-                || honeypot_filter.token_info.contains_key(&pool.token0);
-            let token1_verified = honeypot_filter.safe_token_info.contains_key(&pool.token1)
-                || honeypot_filter.token_info.contains_key(&pool.token1);
-            token0_verified && token1_verified
-        })
-        .collect();
+    let (verified_pools, verified_pools_map) = build_verified_pools(pools, &honeypot_filter);
     info!("Verified pools only: {:?} pools", verified_pools.len());
 
-    let mut verified_pools_map = HashMap::new();
+    {
+        let mut control = CONTROL.write().unwrap();
+        control.verified_pools = verified_pools.len();
+        control.tested_tokens = honeypot_filter.token_info.len() + honeypot_filter.honeypot.len();
+        control.honeypots = honeypot_filter.honeypot.len();
+        control.last_block = block.number.unwrap_or_default();
+        control.min_profit = env.min_profit;
+        control.max_tax_escalation_bps = honeypot_filter.max_tax_escalation_bps;
+        control.min_sell_fraction_bps = honeypot_filter.min_sell_fraction_bps;
+    }
+    #[cfg(feature = "control")]
+    {
+        let control_addr = env.control_addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_control(control_addr).await {
+                warn!("Control server stopped: {:?}", e);
+            }
+        });
+    }
+
+    let usd_pricer = Arc::new(UsdPricer::new(&chain, &verified_pools));
+    // prices ETH on each simulation's own fork: tokens without a WETH pool are valued in wei
+    // through their USD price, and gas-adjusted profits (in wei) are reported in USD
+    let eth_oracle = Arc::new(EthPriceOracle::new(&chain, &verified_pools));
+
+    // backrun arbitrage starts and ends in WETH, so its profit is already in wei
+    let weth = chain.wrapped_native;
+    let weth_token = honeypot_filter.safe_token_info.get(&weth).unwrap().clone();
+    let weth_slot = *honeypot_filter.balance_slots.get(&weth).unwrap();
+    let weth_account = honeypot_filter.balance_account(weth);
+    let arb_paths = generate_triangular_paths(&verified_pools, weth);
+    let paths_by_pool = index_paths_by_pool(&arb_paths);
+    let arb_amount_in = U256::from(env.arb_capital) * *WEI;
+    // victims delivered again within a block don't re-run the same backruns
+    let mut arb_cache = ArbCache::new();
+
+    // direct calls to the pools we monitor are relevant too
+    let mut tx_classifier = TxClassifier::default_mainnet();
     for pool in &verified_pools {
-        verified_pools_map.insert(pool.address, pool.clone());
+        tx_classifier.add_router(pool.address);
     }
 
     let mut event_receiver = event_sender.subscribe();
 
-    let mut new_block = NewBlock {
-        block_number: block.number.unwrap(),
-        base_fee: block.base_fee_per_gas.unwrap_or_default(),
-        next_base_fee: U256::from(calculate_next_block_base_fee(
-            block.gas_used.as_u64(),
-            block.gas_limit.as_u64(),
-            block.base_fee_per_gas.unwrap_or_default().as_u64(),
-        )),
-    };
+    // stop hammering the node when tracing/fetching state keeps failing
+    let mut breaker = CircuitBreaker::new(20, Duration::from_secs(10), Duration::from_secs(30));
+
+    // simulators run in the block after block_context, new_block keeps the fields used for fees
+    let mut block_context = BlockContext::from_block(&block);
+    let mut new_block = block_context.new_block();
+    // bounds the traces running at once, each holds an RPC connection until it returns
+    let semaphore = Semaphore::new(env.max_in_flight);
+
+    let mut watchdog = BlockWatchdog::new(Duration::from_secs(env.block_timeout_secs));
+
+    let mut ledger = env.ledger_file.as_ref().map(|path| {
+        ProfitLedger::open(Path::new(path), Duration::from_secs(env.ledger_flush_secs)).unwrap()
+    });
+
+    // the next block's top pending txs, refetched every block when PENDING_BLOCK_TXS is set
+    // each sandwich is simulated on top of the ones tipping more than its victim
+    let mut pending_block: Option<Vec<Transaction>> = None;
+
+    // pending txs are traced by how much they could be worth, not by arrival order
+    // tokens trading in more pools are weighted as more liquid
+    let mut pending_queue = PendingTxQueue::new(env.pending_queue_size);
+    for pool in &verified_pools {
+        pending_queue.add_liquidity(pool.address, U256::one());
+        pending_queue.add_liquidity(pool.token0, U256::one());
+        pending_queue.add_liquidity(pool.token1, U256::one());
+    }
 
     loop {
         match event_receiver.recv().await {
             Ok(event) => match event {
                 Event::Block(block) => {
-                    new_block = block;
-                    info!("⛓ New Block: {:?}", block);
+                    block_context = block;
+                    new_block = block.new_block();
+                    watchdog.on_block();
+                    // txs queued during the last block may already be included
+                    pending_queue.clear();
+                    arb_cache.set_block(block_context.number);
+                    info!("⛓ New Block: {:?}", new_block);
+                    METRICS.log();
+                    if env.pending_block_txs > 0 {
+                        pending_block = match fetch_pending_block(
+                            provider.clone(),
+                            &block,
+                            env.pending_block_txs,
+                        )
+                        .await
+                        {
+                            Ok(txs) => {
+                                info!("Pending block: {} txs", txs.len());
+                                Some(txs)
+                            }
+                            Err(e) => {
+                                info!("Pending block failed: {:?}", e);
+                                None
+                            }
+                        };
+                    }
+                    if let Some(ledger) = ledger.as_mut() {
+                        if let Err(e) = ledger.flush_if_due() {
+                            info!("Ledger flush failed: {:?}", e);
+                        }
+                    }
+
+                    {
+                        let mut control = CONTROL.write().unwrap();
+                        control.last_block = block.number;
+                        // tax criteria changed through the control server apply to the next tokens tested
+                        honeypot_filter.max_tax_escalation_bps = control.max_tax_escalation_bps;
+                        honeypot_filter.min_sell_fraction_bps = control.min_sell_fraction_bps;
+                    }
                 }
                 Event::PendingTx(tx) => {
+                    Metrics::add(&METRICS.pending_txs, 1);
                     let base_fee_condition =
-                        tx.max_fee_per_gas.unwrap_or_default() < new_block.base_fee;
+                        tx.max_fee_per_gas.unwrap_or_default() >= new_block.base_fee;
 
-                    if base_fee_condition {
-                        continue;
+                    // skip transfers, mints, etc. before running an expensive trace
+                    let relevant = tx_classifier.is_relevant(&tx);
+
+                    // griefing txs declare huge gas limits to make tracing/simulation expensive
+                    let gas_condition = tx.gas <= U256::from(env.max_meat_tx_gas);
+
+                    // no `continue` here, txs already queued still have to be traced
+                    if base_fee_condition && relevant && gas_condition {
+                        pending_queue.push(tx);
                     }
+                }
+                Event::Log(_) => {}
+                // opportunities are published on opportunity_sender, never on this channel
+                Event::Opportunity { .. } => {}
+            },
+            Err(RecvError::Lagged(n)) => record_lagged(n),
+            Err(RecvError::Closed) => {
+                warn!("Event channel closed, stopping the event handler");
+                break;
+            }
+        }
+
+        // Pending txs keep arriving when the block stream stalls,
+        // poll the latest block so simulations don't keep running on a stale one
+        if watchdog.is_stale() {
+            if let Ok(Some(block)) = provider.get_block(BlockNumber::Latest).await {
+                if block.number.unwrap_or_default() > new_block.block_number {
+                    // the chain moved on, so the watchdog is reset like on a block event
+                    watchdog.on_block();
+                    block_context = BlockContext::from_block(&block);
+                    new_block = block_context.new_block();
+                    pending_queue.clear();
+                    arb_cache.set_block(block_context.number);
+                    // fetched for the stale block, refetched with the next block event
+                    pending_block = None;
+                    info!("⛓ Polled Block: {:?}", new_block);
+                }
+            }
+        }
+
+        // Queue up everything already received before tracing, so the best candidates go first
+        if !event_receiver.is_empty() {
+            continue;
+        }
 
-                    match get_touched_pools(
+        while !pending_queue.is_empty() {
+            if breaker.is_open() {
+                pending_queue.clear();
+                break;
+            }
+
+            // The best txs are traced together, the semaphore caps how many traces are in flight
+            let batch: Vec<Transaction> = (0..env.trace_batch_size)
+                .filter_map(|_| pending_queue.pop())
+                .collect();
+            let timeout = Duration::from_millis(env.trace_timeout_ms);
+            let trace_mode = env.trace_mode;
+            let traces = join_all_bounded(
+                &semaphore,
+                batch.iter().map(|tx| {
+                    get_touched_pools(
                         provider.clone(),
-                        &tx,
-                        new_block.block_number,
+                        tx,
                         &verified_pools_map,
                         &honeypot_filter,
+                        timeout,
+                        trace_mode,
                     )
-                    .await
-                    {
-                        Ok(touched_pools) => {
-                            if touched_pools.len() > 0 {
-                                info!(
-                                    "[🌯🥪🌯🥪🌯] Sandwichable pools detected: {:?}",
-                                    touched_pools
-                                );
+                }),
+            )
+            .await;
+
+            // profitable sandwiches of the batch, bundled together once it's done
+            let mut profitable_sandwiches: Vec<Sandwich> = Vec::new();
+            for (tx, trace) in batch.into_iter().zip(traces) {
+                match trace {
+                    Ok((touched_pools, meat_prestate)) => {
+                        breaker.record_success();
+                        if touched_pools.len() > 0 {
+                            let id = correlation_id(tx.hash.as_bytes());
+                            log_with_id!(
+                                id,
+                                "[🌯🥪🌯🥪🌯] Sandwichable pools detected: {:?}",
+                                touched_pools
+                            );
+
+                            let owner =
+                                H160::from_str("0x001a06BF8cE4afdb3f5618f6bafe35e9Fc09F187")
+                                    .unwrap();
+
+                            // built once for the victim, every touched pool's sandwich starts from it
+                            let pending_state = pending_block
+                                .as_ref()
+                                .filter(|_| touched_pools.values().any(Option::is_some))
+                                .map(|pending| {
+                                    build_pending_block_state(
+                                        provider.clone(),
+                                        &sim_config,
+                                        &block_context,
+                                        pending,
+                                        &tx,
+                                    )
+                                });
+
+                            for (touched_pool, use_token) in &touched_pools {
+                                match use_token {
+                                    Some((safe_token, victim_amount)) => {
+                                        let target_token = honeypot_filter
+                                            .safe_token_info
+                                            .get(safe_token)
+                                            .unwrap();
+                                        let target_pool =
+                                            verified_pools_map.get(touched_pool).unwrap();
+                                        let balance_slot =
+                                            honeypot_filter.balance_slots.get(safe_token).unwrap();
+                                        let amount_in = get_frontrun_amount(
+                                            *victim_amount,
+                                            target_token.decimals,
+                                            env.sandwich_capital,
+                                        );
 
-                                let owner =
-                                    H160::from_str("0x001a06BF8cE4afdb3f5618f6bafe35e9Fc09F187")
-                                        .unwrap();
-
-                                for (touched_pool, use_token) in &touched_pools {
-                                    match use_token {
-                                        Some(safe_token) => {
-                                            let target_token = honeypot_filter
-                                                .safe_token_info
-                                                .get(safe_token)
-                                                .unwrap();
-                                            let target_pool =
-                                                verified_pools_map.get(touched_pool).unwrap();
-                                            let balance_slot = honeypot_filter
-                                                .balance_slots
-                                                .get(safe_token)
-                                                .unwrap();
-                                            let amount_in = U256::from(1)
-                                                .checked_mul(
-                                                    U256::from(10)
-                                                        .pow(U256::from(target_token.decimals)),
-                                                )
-                                                .unwrap();
-
-                                            let sandwich = Sandwich {
-                                                amount_in,
-                                                balance_slot: *balance_slot,
-                                                target_token: target_token.clone(),
-                                                target_pool: target_pool.clone(),
-                                                meat_tx: tx.clone(),
-                                            };
-
-                                            match simulate_sandwich_bundle(
-                                                sandwich,
-                                                provider.clone(),
-                                                owner,
-                                                new_block.block_number,
-                                                None,
-                                            ) {
-                                                Ok(profit) => info!(
+                                        let sandwich = Sandwich {
+                                            amount_in,
+                                            balance_slot: *balance_slot,
+                                            balance_account: honeypot_filter
+                                                .balance_account(*safe_token),
+                                            target_token: target_token.clone(),
+                                            target_pool: target_pool.clone(),
+                                            eth_pool: find_eth_pool(
+                                                target_token.address,
+                                                chain.wrapped_native,
+                                                &verified_pools_map,
+                                            ),
+                                            usd_pricer: Some(usd_pricer.clone()),
+                                            eth_oracle: Some(eth_oracle.clone()),
+                                            meat_tx: tx.clone(),
+                                            meat_prestate: meat_prestate.clone(),
+                                            token_taxes: target_pool
+                                                .token_out(*safe_token)
+                                                .and_then(|token| {
+                                                    honeypot_filter.token_taxes.get(&token).copied()
+                                                })
+                                                .unwrap_or((0, 0)),
+                                        };
+
+                                        // a clean fork isn't the block we're competing for,
+                                        // so without a pending block the sandwich is skipped
+                                        let fork_db = match &pending_state {
+                                            Some(state) => Some(state.db.clone()),
+                                            None if env.pending_block_txs > 0 => {
+                                                log_with_id!(
+                                                    id,
+                                                    "No pending block to simulate on, skipping"
+                                                );
+                                                continue;
+                                            }
+                                            None => None,
+                                        };
+                                        match simulate_sandwich_bundle(
+                                            sandwich.clone(),
+                                            provider.clone(),
+                                            owner,
+                                            &sim_config,
+                                            &block_context,
+                                            fork_db,
+                                        ) {
+                                            Ok(result) => {
+                                                log_with_id!(
+                                                    id,
                                                     "Simulation was successful. Profit: {:?}",
-                                                    profit
-                                                ),
-                                                Err(e) => {
-                                                    info!("Simulation failed. Error: {:?}", e)
+                                                    result
+                                                );
+                                                // our txs pay the next block's base fee plus a tip
+                                                let net_profit = net_of_gas(
+                                                    result.profit_eth,
+                                                    result.gas_used,
+                                                    new_block.next_base_fee,
+                                                    U256::from(env.priority_fee),
+                                                );
+                                                let min_profit = CONTROL.read().unwrap().min_profit;
+                                                if net_profit > min_profit {
+                                                    if let Some(eth_price) = result.eth_price_usd {
+                                                        log_with_id!(
+                                                            id,
+                                                            "Net profit: ${:.2}",
+                                                            wei_to_usd(net_profit, eth_price)
+                                                        );
+                                                    }
+                                                    let detail = format!(
+                                                        "tx: {:?} / pool: {:?} / token: {}",
+                                                        tx.hash, touched_pool, target_token.symbol
+                                                    );
+                                                    publish_opportunity(
+                                                        &opportunity_sender,
+                                                        &mut ledger,
+                                                        LedgerRecord {
+                                                            timestamp: unix_timestamp(),
+                                                            block_number: new_block.block_number,
+                                                            kind: OpportunityKind::Sandwich,
+                                                            pool: *touched_pool,
+                                                            token: target_token.address,
+                                                            amount_in,
+                                                            profit: net_profit,
+                                                            gas_used: result.gas_used,
+                                                        },
+                                                        detail,
+                                                    );
+                                                    profitable_sandwiches.push(sandwich);
+                                                }
+                                            }
+                                            Err(e) => {
+                                                log_with_id!(
+                                                    id,
+                                                    "Simulation failed. Error: {:?}",
+                                                    e
+                                                );
+                                                // reverts are expected, only backend errors count
+                                                if let Some(SimError::Backend(_)) =
+                                                    e.downcast_ref::<SimError>()
+                                                {
+                                                    breaker.record_failure();
                                                 }
                                             }
                                         }
-                                        None => {}
+                                    }
+                                    None => {}
+                                }
+                            }
+
+                            // the victim's swap moves prices, paths through the touched pools
+                            // may be arbitraged right after it
+                            let mut backrun_paths: HashMap<H256, ArbPath> = HashMap::new();
+                            for touched_pool in touched_pools.keys() {
+                                for path in paths_by_pool.get(touched_pool).into_iter().flatten() {
+                                    backrun_paths.insert(path.hash(), path.clone());
+                                }
+                            }
+                            if !backrun_paths.is_empty() {
+                                let arbs = backrun_paths
+                                    .into_values()
+                                    .map(|path| TriangularArbitrage {
+                                        amount_in: arb_amount_in,
+                                        path,
+                                        balance_slot: weth_slot,
+                                        balance_account: weth_account,
+                                        target_token: weth_token.clone(),
+                                        token_taxes: HashMap::new(),
+                                        usd_pricer: Some(usd_pricer.clone()),
+                                        eth_oracle: Some(eth_oracle.clone()),
+                                        flashloan_fee_bps: None,
+                                        max_hop_slippage_bps: env.max_hop_slippage_bps,
+                                    })
+                                    .collect();
+                                match simulate_backrun_arbitrage(
+                                    arbs,
+                                    provider.clone(),
+                                    owner,
+                                    &sim_config,
+                                    &block_context,
+                                    &tx,
+                                    &meat_prestate,
+                                    &mut arb_cache,
+                                ) {
+                                    Ok(results) => {
+                                        if let Some((path, result)) = results.first() {
+                                            let net_profit = net_of_gas(
+                                                result.profit,
+                                                result.gas_used,
+                                                new_block.next_base_fee,
+                                                U256::from(env.priority_fee),
+                                            );
+                                            let min_profit = CONTROL.read().unwrap().min_profit;
+                                            if net_profit > min_profit {
+                                                log_with_id!(
+                                                    id,
+                                                    "Backrun arbitrage found. Profit: {:?}",
+                                                    result
+                                                );
+                                                if let Some(eth_price) = result.eth_price_usd {
+                                                    log_with_id!(
+                                                        id,
+                                                        "Net profit: ${:.2}",
+                                                        wei_to_usd(net_profit, eth_price)
+                                                    );
+                                                }
+                                                let detail = format!(
+                                                    "tx: {:?} / path: {:?} -> {:?} -> {:?}",
+                                                    tx.hash,
+                                                    path.pool_1.address,
+                                                    path.pool_2.address,
+                                                    path.pool_3.address
+                                                );
+                                                publish_opportunity(
+                                                    &opportunity_sender,
+                                                    &mut ledger,
+                                                    LedgerRecord {
+                                                        timestamp: unix_timestamp(),
+                                                        block_number: new_block.block_number,
+                                                        kind: OpportunityKind::Arbitrage,
+                                                        pool: path.pool_1.address,
+                                                        token: weth_token.address,
+                                                        amount_in: arb_amount_in,
+                                                        profit: net_profit,
+                                                        gas_used: result.gas_used,
+                                                    },
+                                                    detail,
+                                                );
+                                            }
+                                        }
+                                    }
+                                    Err(e) => {
+                                        log_with_id!(
+                                            id,
+                                            "Backrun simulation failed. Error: {:?}",
+                                            e
+                                        );
+                                        if let Some(SimError::Backend(_)) =
+                                            e.downcast_ref::<SimError>()
+                                        {
+                                            breaker.record_failure();
+                                        }
                                     }
                                 }
                             }
                         }
-                        Err(_) => {}
                     }
+                    Err(_) => breaker.record_failure(),
                 }
-                Event::Log(_) => {}
-            },
-            Err(_) => {}
+            }
+
+            // victims traced together can land in the same block, one bundle can sandwich them all
+            if profitable_sandwiches.len() > 1 {
+                // meat txs run in mempool order, the highest tipping victim first
+                profitable_sandwiches.sort_by_key(|sandwich| {
+                    std::cmp::Reverse(
+                        effective_priority_fee(&sandwich.meat_tx, new_block.next_base_fee)
+                            .unwrap_or_default(),
+                    )
+                });
+                // the txs ahead of the first victim, the ones between victims are left out
+                let fork_db = match &pending_block {
+                    Some(pending) => Some(
+                        build_pending_block_state(
+                            provider.clone(),
+                            &sim_config,
+                            &block_context,
+                            pending,
+                            &profitable_sandwiches[0].meat_tx,
+                        )
+                        .db,
+                    ),
+                    None => None,
+                };
+                if fork_db.is_some() || env.pending_block_txs == 0 {
+                    let owner =
+                        H160::from_str("0x001a06BF8cE4afdb3f5618f6bafe35e9Fc09F187").unwrap();
+                    let victims: Vec<H256> = profitable_sandwiches
+                        .iter()
+                        .map(|sandwich| sandwich.meat_tx.hash)
+                        .collect();
+                    let pools: Vec<H160> = profitable_sandwiches
+                        .iter()
+                        .map(|sandwich| sandwich.target_pool.address)
+                        .collect();
+                    match simulate_multi_pool_sandwich(
+                        profitable_sandwiches,
+                        provider.clone(),
+                        owner,
+                        &sim_config,
+                        &block_context,
+                        fork_db,
+                    ) {
+                        Ok(result) => {
+                            let net_profit = net_of_gas(
+                                result.profit_eth,
+                                result.gas_used,
+                                new_block.next_base_fee,
+                                U256::from(env.priority_fee),
+                            );
+                            let min_profit = CONTROL.read().unwrap().min_profit;
+                            if net_profit > min_profit {
+                                info!(
+                                    "Multi pool sandwich found. Victims: {:?} / Profit: {:?}",
+                                    victims, result
+                                );
+                                // not recorded in the ledger, it's an alternative to the single sandwiches
+                                _ = opportunity_sender.send(Event::Opportunity {
+                                    kind: OpportunityKind::Sandwich,
+                                    profit: net_profit,
+                                    block_number: new_block.block_number,
+                                    detail: format!("txs: {:?} / pools: {:?}", victims, pools),
+                                });
+                            }
+                        }
+                        Err(e) => {
+                            info!("Multi pool sandwich simulation failed. Error: {:?}", e);
+                            if let Some(SimError::Backend(_)) = e.downcast_ref::<SimError>() {
+                                breaker.record_failure();
+                            }
+                        }
+                    }
+                }
+            }
+
+            // new arrivals are scored against what's left in the queue
+            if !event_receiver.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::load_ledger;
+    use ethers::providers::{JsonRpcClient, MockError, MockProvider, Provider};
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::{
+        fmt,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Instant,
+    };
+    use tokio::sync::broadcast;
+
+    // Answers every request only after a delay, like a node stuck on a slow trace
+    #[derive(Debug)]
+    struct DelayedClient {
+        inner: MockProvider,
+        delay: Duration,
+    }
+
+    #[async_trait::async_trait]
+    impl JsonRpcClient for DelayedClient {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, method: &str, params: T) -> Result<R, MockError>
+        where
+            T: fmt::Debug + Serialize + Send + Sync,
+            R: DeserializeOwned + Send,
+        {
+            tokio::time::sleep(self.delay).await;
+            self.inner.request(method, params).await
         }
     }
+
+    #[tokio::test]
+    async fn test_lagged_events_are_counted() {
+        let (event_sender, mut event_receiver) = broadcast::channel(2);
+        for _ in 0..5 {
+            event_sender
+                .send(Event::Block(BlockContext::default()))
+                .unwrap();
+        }
+
+        // only the newest 2 events fit, the 3 before them are reported as lagged
+        let dropped = match event_receiver.recv().await {
+            Err(RecvError::Lagged(n)) => n,
+            other => panic!("expected the receiver to lag, got {:?}", other),
+        };
+        assert_eq!(dropped, 3);
+        let before = METRICS.snapshot().dropped_events;
+        record_lagged(dropped);
+        assert!(METRICS.snapshot().dropped_events >= before + dropped);
+
+        assert!(event_receiver.recv().await.is_ok());
+        assert!(event_receiver.recv().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_published_opportunity_reaches_subscribers() {
+        let (opportunity_sender, mut opportunity_receiver) = broadcast::channel(4);
+        let file_path = std::env::temp_dir().join(format!("ledger-{:?}.csv", H160::random()));
+        let mut ledger = Some(ProfitLedger::open(&file_path, Duration::ZERO).unwrap());
+        let record = LedgerRecord {
+            timestamp: unix_timestamp(),
+            block_number: U64::from(18000000),
+            kind: OpportunityKind::Arbitrage,
+            pool: H160::random(),
+            token: H160::random(),
+            amount_in: U256::exp10(18),
+            profit: 5000000000000000,
+            gas_used: 250000,
+        };
+
+        publish_opportunity(
+            &opportunity_sender,
+            &mut ledger,
+            record.clone(),
+            "path".to_string(),
+        );
+        match opportunity_receiver.recv().await.unwrap() {
+            Event::Opportunity {
+                kind,
+                profit,
+                block_number,
+                detail,
+            } => {
+                assert_eq!(kind, OpportunityKind::Arbitrage);
+                assert_eq!(profit, record.profit);
+                assert_eq!(block_number, record.block_number);
+                assert_eq!(detail, "path");
+            }
+            other => panic!("expected an opportunity, got {:?}", other),
+        }
+        let recorded = load_ledger(&file_path).unwrap();
+        std::fs::remove_file(&file_path).unwrap();
+        assert_eq!(recorded, vec![record]);
+
+        // without subscribers or a ledger, publishing is a no-op
+        drop(opportunity_receiver);
+        publish_opportunity(
+            &opportunity_sender,
+            &mut None,
+            recorded[0].clone(),
+            String::new(),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_trace_times_out() {
+        let provider = Arc::new(Provider::new(DelayedClient {
+            inner: MockProvider::new(),
+            delay: Duration::from_secs(5),
+        }));
+        let block = Block {
+            number: Some(U64::from(1)),
+            gas_limit: U256::from(30000000),
+            ..Default::default()
+        };
+        let honeypot_filter =
+            HoneypotFilter::new(provider.clone(), block, SimulatorConfig::default());
+        let tx = Transaction {
+            to: Some(H160::random()),
+            ..Default::default()
+        };
+
+        // the trace would take 5 seconds, the loop moves on after the timeout with nothing touched
+        let started = Instant::now();
+        let (touched_pools, prestate) = get_touched_pools(
+            provider,
+            &tx,
+            &HashMap::new(),
+            &honeypot_filter,
+            Duration::from_millis(100),
+            TraceMode::PrestateDiff,
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(touched_pools.is_empty());
+        assert!(prestate.is_empty());
+    }
+
+    // Tracks how many requests are being answered at once, each takes the delay
+    #[derive(Debug)]
+    struct InFlightClient {
+        inner: MockProvider,
+        delay: Duration,
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl JsonRpcClient for InFlightClient {
+        type Error = MockError;
+
+        async fn request<T, R>(&self, method: &str, params: T) -> Result<R, MockError>
+        where
+            T: fmt::Debug + Serialize + Send + Sync,
+            R: DeserializeOwned + Send,
+        {
+            let running = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(running, Ordering::SeqCst);
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.inner.request(method, params).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_burst_stays_under_the_in_flight_limit() {
+        let (in_flight, max_in_flight, requests) = (
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let provider = Arc::new(Provider::new(InFlightClient {
+            inner: MockProvider::new(),
+            delay: Duration::from_millis(20),
+            in_flight,
+            max_in_flight: max_in_flight.clone(),
+            requests: requests.clone(),
+        }));
+        let block = Block {
+            number: Some(U64::from(1)),
+            gas_limit: U256::from(30000000),
+            ..Default::default()
+        };
+        let honeypot_filter =
+            HoneypotFilter::new(provider.clone(), block, SimulatorConfig::default());
+        let verified_pools_map = HashMap::new();
+        let batch: Vec<Transaction> = (0..20)
+            .map(|_| Transaction {
+                to: Some(H160::random()),
+                ..Default::default()
+            })
+            .collect();
+
+        // a burst of 20 pending txs traced with at most 3 in flight, as event_handler does
+        let semaphore = Semaphore::new(3);
+        let traces = join_all_bounded(
+            &semaphore,
+            batch.iter().map(|tx| {
+                get_touched_pools(
+                    provider.clone(),
+                    tx,
+                    &verified_pools_map,
+                    &honeypot_filter,
+                    Duration::from_secs(1),
+                    TraceMode::PrestateDiff,
+                )
+            }),
+        )
+        .await;
+
+        assert_eq!(traces.len(), 20);
+        assert_eq!(requests.load(Ordering::SeqCst), 20);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    fn balance_key(owner: H160, slot: u32) -> H256 {
+        H256::from(ethers::utils::keccak256(&abi::encode(&[
+            abi::Token::Address(owner),
+            abi::Token::Uint(U256::from(slot)),
+        ])))
+    }
+
+    fn balance_state(owner: H160, slot: u32, balance: u64) -> serde_json::Value {
+        let mut storage = serde_json::Map::new();
+        storage.insert(
+            format!("{:?}", balance_key(owner, slot)),
+            serde_json::json!(H256::from_low_u64_be(balance)),
+        );
+        serde_json::json!({ "storage": storage })
+    }
+
+    #[tokio::test]
+    async fn test_safe_safe_pool_direction() {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        let usdc: H160 = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"
+            .parse()
+            .unwrap();
+        let weth: H160 = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse()
+            .unwrap();
+        let pool = Pool {
+            address: H160::random(),
+            version: crate::pools::DexVariant::UniswapV2,
+            token0: usdc,
+            token1: weth,
+            decimals0: 6,
+            decimals1: 18,
+            fee: 300,
+        };
+        let verified_pools_map = HashMap::from([(pool.address, pool.clone())]);
+
+        let block = Block {
+            number: Some(U64::from(1)),
+            gas_limit: U256::from(30000000),
+            ..Default::default()
+        };
+        let mut honeypot_filter =
+            HoneypotFilter::new(provider.clone(), block, SimulatorConfig::default());
+        for (address, symbol, decimals, slot) in [(usdc, "USDC", 6, 9), (weth, "WETH", 18, 3)] {
+            honeypot_filter.safe_token_info.insert(
+                address,
+                crate::tokens::Token {
+                    address,
+                    implementation: None,
+                    name: symbol.to_string(),
+                    symbol: symbol.to_string(),
+                    decimals,
+                },
+            );
+            honeypot_filter.balance_slots.insert(address, slot);
+        }
+        let tx = Transaction {
+            to: Some(pool.address),
+            ..Default::default()
+        };
+
+        // Both pool balances in a diff mode prestate trace, pre -> post
+        let trace = |usdc_balances: (u64, u64), weth_balances: (u64, u64)| {
+            let side = |usdc_balance, weth_balance| {
+                serde_json::json!({
+                    format!("{:?}", usdc): balance_state(pool.address, 9, usdc_balance),
+                    format!("{:?}", weth): balance_state(pool.address, 3, weth_balance),
+                    format!("{:?}", pool.address): { "nonce": 1 },
+                })
+            };
+            serde_json::json!({
+                "pre": side(usdc_balances.0, weth_balances.0),
+                "post": side(usdc_balances.1, weth_balances.1),
+            })
+        };
+
+        // the victim sells 3000 USDC for 1 WETH, so we frontrun in the USDC -> WETH direction
+        mock.push::<serde_json::Value, _>(trace((1_000_000, 1_003_000), (500, 499)))
+            .unwrap();
+        let (touched_pools, _) = get_touched_pools(
+            provider.clone(),
+            &tx,
+            &verified_pools_map,
+            &honeypot_filter,
+            Duration::from_secs(1),
+            TraceMode::PrestateDiff,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            touched_pools.get(&pool.address),
+            Some(&Some((usdc, U256::from(3000))))
+        );
+
+        // and the other way around when WETH is sold into the pool
+        mock.push::<serde_json::Value, _>(trace((1_000_000, 997_000), (500, 501)))
+            .unwrap();
+        let (touched_pools, _) = get_touched_pools(
+            provider.clone(),
+            &tx,
+            &verified_pools_map,
+            &honeypot_filter,
+            Duration::from_secs(1),
+            TraceMode::PrestateDiff,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            touched_pools.get(&pool.address),
+            Some(&Some((weth, U256::from(1))))
+        );
+
+        // both balances going up is a liquidity add, the pool is touched but not sandwichable
+        mock.push::<serde_json::Value, _>(trace((1_000_000, 1_003_000), (500, 501)))
+            .unwrap();
+        let (touched_pools, _) = get_touched_pools(
+            provider,
+            &tx,
+            &verified_pools_map,
+            &honeypot_filter,
+            Duration::from_secs(1),
+            TraceMode::PrestateDiff,
+        )
+        .await
+        .unwrap();
+        assert_eq!(touched_pools.get(&pool.address), Some(&None));
+    }
 }
@@ -1,7 +1,7 @@
 use anvil::eth::fees::calculate_next_block_base_fee;
 use ethers::{
     providers::{Provider, Ws},
-    types::{Log, Transaction, U256, U64},
+    types::{Block, Log, Transaction, H256, U256, U64},
 };
 use ethers_providers::Middleware;
 use std::sync::Arc;
@@ -15,25 +15,81 @@ pub struct NewBlock {
     pub next_base_fee: U256,
 }
 
+#[derive(Default, Debug, Clone, Copy)]
+pub struct BlockContext {
+    pub number: U64,
+    pub timestamp: U256,
+    pub base_fee: U256,
+    pub next_base_fee: U256,
+    pub gas_limit: U256,
+    pub gas_used: U256,
+}
+
+impl BlockContext {
+    pub fn from_block(block: &Block<H256>) -> Self {
+        let base_fee = block.base_fee_per_gas.unwrap_or_default();
+        Self {
+            number: block.number.unwrap_or_default(),
+            timestamp: block.timestamp,
+            base_fee,
+            next_base_fee: U256::from(calculate_next_block_base_fee(
+                block.gas_used.as_u64(),
+                block.gas_limit.as_u64(),
+                base_fee.as_u64(),
+            )),
+            gas_limit: block.gas_limit,
+            gas_used: block.gas_used,
+        }
+    }
+
+    pub fn projected_base_fees(&self, blocks: u64) -> Vec<(U64, U256)> {
+        // (block number, base fee) for the next `blocks` blocks, starting with the next block
+        // Blocks after it are projected assuming they use as much gas as this one
+        let mut projected = Vec::new();
+        let mut base_fee = self.next_base_fee;
+        for offset in 1..=blocks {
+            projected.push((self.number + offset, base_fee));
+            base_fee = U256::from(calculate_next_block_base_fee(
+                self.gas_used.as_u64(),
+                self.gas_limit.as_u64(),
+                base_fee.as_u64(),
+            ));
+        }
+        projected
+    }
+
+    pub fn new_block(&self) -> NewBlock {
+        NewBlock {
+            block_number: self.number,
+            base_fee: self.base_fee,
+            next_base_fee: self.next_base_fee,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpportunityKind {
+    Sandwich,
+    Arbitrage,
+}
+
 #[derive(Debug, Clone)]
 pub enum Event {
-    Block(NewBlock),
+    Block(BlockContext),
     PendingTx(Transaction),
     Log(Log),
+    Opportunity {
+        kind: OpportunityKind,
+        profit: i128,
+        block_number: U64,
+        detail: String,
+    },
 }
 
 pub async fn stream_new_blocks(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
     let stream = provider.subscribe_blocks().await.unwrap();
     let mut stream = stream.filter_map(|block| match block.number {
-        Some(number) => Some(NewBlock {
-            block_number: number,
-            base_fee: block.base_fee_per_gas.unwrap_or_default(),
-            next_base_fee: U256::from(calculate_next_block_base_fee(
-                block.gas_used.as_u64(),
-                block.gas_limit.as_u64(),
-                block.base_fee_per_gas.unwrap_or_default().as_u64(),
-            )),
-        }),
+        Some(_) => Some(BlockContext::from_block(&block)),
         None => None,
     });
 
@@ -59,3 +115,54 @@ pub async fn stream_pending_transactions(provider: Arc<Provider<Ws>>, event_send
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(gas_used: u64, base_fee: u64) -> Block<H256> {
+        // the fields a fetched header carries that BlockContext reads
+        serde_json::from_value(serde_json::json!({
+            "number": "0x112a880",
+            "timestamp": "0x6553f100",
+            "gasLimit": U256::from(30000000),
+            "gasUsed": U256::from(gas_used),
+            "baseFeePerGas": U256::from(base_fee),
+            "transactions": [],
+        }))
+        .unwrap()
+    }
+
+    // EIP-1559: the base fee moves by up to 1/8, in proportion to how far gas used is from the target
+    fn eip1559_next_base_fee(gas_used: u64, gas_limit: u64, base_fee: u64) -> u64 {
+        let target = gas_limit / 2;
+        if gas_used > target {
+            let delta = base_fee as u128 * (gas_used - target) as u128 / target as u128 / 8;
+            base_fee + std::cmp::max(delta as u64, 1)
+        } else {
+            let delta = base_fee as u128 * (target - gas_used) as u128 / target as u128 / 8;
+            base_fee - delta as u64
+        }
+    }
+
+    #[test]
+    fn test_block_context_next_base_fee() {
+        let base_fee = 20_000_000_000;
+        // full, at target, empty and just above the target
+        for gas_used in [30000000, 15000000, 0, 15000001] {
+            let context = BlockContext::from_block(&block(gas_used, base_fee));
+            assert_eq!(context.number, U64::from(18000000));
+            assert_eq!(context.base_fee, U256::from(base_fee));
+            assert_eq!(
+                context.next_base_fee,
+                U256::from(eip1559_next_base_fee(gas_used, 30000000, base_fee))
+            );
+        }
+
+        // a full block raises it by 12.5%, an empty one lowers it by 12.5%
+        let full = BlockContext::from_block(&block(30000000, base_fee));
+        assert_eq!(full.next_base_fee, U256::from(22_500_000_000u64));
+        let empty = BlockContext::from_block(&block(0, base_fee));
+        assert_eq!(empty.next_base_fee, U256::from(17_500_000_000u64));
+    }
+}
@@ -3,7 +3,7 @@ use csv::StringRecord;
 use ethers::{abi::parse_abi, prelude::*};
 use ethers_contract::{Contract, Multicall};
 use ethers_core::types::{BlockId, BlockNumber, TxHash, H160, U256};
-use std::{str::FromStr, sync::Arc};
+use std::{collections::HashSet, path::Path, str::FromStr, sync::Arc};
 use tokio::task::JoinSet;
 
 use crate::constants::ZERO_ADDRESS;
@@ -54,6 +54,46 @@ impl Token {
     }
 }
 
+pub fn load_tokens_csv(path: &Path) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    if !path.exists() {
+        return Ok(tokens);
+    }
+
+    let mut reader = csv::Reader::from_path(path)?;
+    for row in reader.records() {
+        tokens.push(Token::from(row?));
+    }
+    Ok(tokens)
+}
+
+pub fn save_tokens_csv<'a>(path: &Path, tokens: impl IntoIterator<Item = &'a Token>) -> Result<()> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(&["address", "implementation", "name", "symbol", "decimals"])?;
+    for token in tokens {
+        writer.serialize(token.cache_row())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load_address_list(path: &Path) -> Result<HashSet<H160>> {
+    // One address per line, empty lines and lines starting with # are ignored
+    let mut addresses = HashSet::new();
+    if !path.exists() {
+        return Ok(addresses);
+    }
+
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        addresses.insert(H160::from_str(line)?);
+    }
+    Ok(addresses)
+}
+
 pub async fn get_implementation<M: Middleware + 'static>(
     provider: Arc<M>,
     token: H160,
@@ -138,3 +178,45 @@ pub async fn get_token_info<M: Middleware + 'static>(
 
     Ok(token_info)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_csv_round_trip() {
+        let dir = std::env::temp_dir().join(format!("tokens-{:?}", H160::random()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(".cached-tokens.csv");
+        let tokens = vec![
+            Token {
+                address: H160::random(),
+                implementation: None,
+                name: "Wrapped Ether".to_string(),
+                symbol: "WETH".to_string(),
+                decimals: 18,
+            },
+            Token {
+                // proxied, with a name the writer has to quote
+                address: H160::random(),
+                implementation: Some(H160::random()),
+                name: "USD, \"Coin\"".to_string(),
+                symbol: "USDC".to_string(),
+                decimals: 6,
+            },
+        ];
+
+        save_tokens_csv(&path, &tokens).unwrap();
+        let loaded = load_tokens_csv(&path).unwrap();
+        let rows: Vec<_> = loaded.iter().map(|token| token.cache_row()).collect();
+        let expected: Vec<_> = tokens.iter().map(|token| token.cache_row()).collect();
+        assert_eq!(rows, expected);
+
+        // a missing cache is empty rather than an error
+        assert!(load_tokens_csv(&dir.join("missing.csv"))
+            .unwrap()
+            .is_empty());
+
+        _ = std::fs::remove_dir_all(dir);
+    }
+}
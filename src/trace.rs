@@ -2,19 +2,132 @@ use anyhow::{anyhow, Result};
 use ethers::{
     abi::{self, parse_abi},
     prelude::*,
-    types::transaction::eip2930::AccessList,
+    types::{
+        spoof,
+        transaction::{eip2718::TypedTransaction, eip2930::AccessList},
+    },
 };
 use ethers_providers::Middleware;
 use foundry_evm::revm::primitives::keccak256;
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceMode {
+    // prestateTracer with diffMode, the only mode that shows which balances changed
+    PrestateDiff,
+    // prestateTracer, everything the tx read or wrote before it ran
+    Prestate,
+    // callTracer, for nodes without the prestate tracer
+    Call,
+}
+
+impl FromStr for TraceMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "prestate-diff" => Ok(TraceMode::PrestateDiff),
+            "prestate" => Ok(TraceMode::Prestate),
+            "call" => Ok(TraceMode::Call),
+            _ => Err(anyhow!("Unknown trace mode: {}", s)),
+        }
+    }
+}
+
+pub fn tracing_options(mode: TraceMode, timeout: Option<Duration>) -> GethDebugTracingOptions {
+    let (tracer, tracer_config) = match mode {
+        TraceMode::PrestateDiff => (
+            GethDebugBuiltInTracerType::PreStateTracer,
+            Some(GethDebugBuiltInTracerConfig::PreStateTracer(
+                PreStateConfig {
+                    diff_mode: Some(true),
+                },
+            )),
+        ),
+        TraceMode::Prestate => (GethDebugBuiltInTracerType::PreStateTracer, None),
+        TraceMode::Call => (GethDebugBuiltInTracerType::CallTracer, None),
+    };
+    GethDebugTracingOptions {
+        disable_storage: None,
+        disable_stack: None,
+        enable_memory: None,
+        enable_return_data: None,
+        tracer: Some(GethDebugTracerType::BuiltInTracer(tracer)),
+        tracer_config: tracer_config.map(GethDebugTracerConfig::BuiltInTracer),
+        timeout: timeout.map(|timeout| format!("{}ms", timeout.as_millis())),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TouchedAccounts {
+    // written accounts with diff mode, every account accessed with prestate, every callee with call
+    pub accounts: BTreeSet<H160>,
+    // account states before the tx, empty with the call tracer
+    pub pre: BTreeMap<H160, AccountState>,
+    // only available with diff mode
+    pub post: Option<BTreeMap<H160, AccountState>>,
+}
+
+impl TouchedAccounts {
+    pub fn from_trace(trace: GethTrace) -> Self {
+        let mut touched = TouchedAccounts::default();
+        match trace {
+            GethTrace::Known(GethTraceFrame::PreStateTracer(PreStateFrame::Diff(diff))) => {
+                touched.accounts = diff.post.keys().cloned().collect();
+                touched.pre = diff.pre;
+                touched.post = Some(diff.post);
+            }
+            GethTrace::Known(GethTraceFrame::PreStateTracer(PreStateFrame::Default(prestate))) => {
+                touched.accounts = prestate.0.keys().cloned().collect();
+                touched.pre = prestate.0;
+            }
+            GethTrace::Known(GethTraceFrame::CallTracer(frame)) => {
+                collect_callees(&frame, &mut touched.accounts);
+            }
+            _ => {}
+        }
+        touched
+    }
+}
+
+pub fn allowance_key(owner: H160, spender: H160, slot: u32) -> H256 {
+    let inner = keccak256(&abi::encode(&[
+        abi::Token::Address(owner.into()),
+        abi::Token::Uint(U256::from(slot)),
+    ]));
+    let key = keccak256(&abi::encode(&[
+        abi::Token::Address(spender.into()),
+        abi::Token::FixedBytes(inner.to_vec()),
+    ]));
+    H256::from(key.0)
+}
+
+fn collect_callees(frame: &CallFrame, accounts: &mut BTreeSet<H160>) {
+    if let Some(NameOrAddress::Address(to)) = &frame.to {
+        accounts.insert(*to);
+    }
+    for call in frame.calls.iter().flatten() {
+        collect_callees(call, accounts);
+    }
+}
 
 pub struct EvmTracer<M> {
     provider: Arc<M>,
+    // Balance mappings are searched in slots 0..max_balance_slot
+    pub max_balance_slot: u32,
 }
 
 impl<M: Middleware + 'static> EvmTracer<M> {
     pub fn new(provider: Arc<M>) -> Self {
-        Self { provider }
+        Self {
+            provider,
+            max_balance_slot: 20,
+        }
     }
 
     pub async fn get_state_diff(
@@ -28,17 +141,7 @@ impl<M: Middleware + 'static> EvmTracer<M> {
                 tx,
                 Some(BlockId::Number(BlockNumber::Number(block_number.into()))),
                 GethDebugTracingCallOptions {
-                    tracing_options: GethDebugTracingOptions {
-                        disable_storage: None,
-                        disable_stack: None,
-                        enable_memory: None,
-                        enable_return_data: None,
-                        tracer: Some(GethDebugTracerType::BuiltInTracer(
-                            GethDebugBuiltInTracerType::PreStateTracer,
-                        )),
-                        tracer_config: None,
-                        timeout: None,
-                    },
+                    tracing_options: tracing_options(TraceMode::Prestate, None),
                     state_overrides: None,
                 },
             )
@@ -48,17 +151,41 @@ impl<M: Middleware + 'static> EvmTracer<M> {
         Ok(trace)
     }
 
+    pub async fn generate_access_list(
+        &self,
+        tx: Eip1559TransactionRequest,
+        block_number: u64,
+    ) -> AccessList {
+        // Uses eth_createAccessList, falls back to an empty list if the node doesn't support it
+        let tx: TypedTransaction = tx.into();
+        match self
+            .provider
+            .create_access_list(
+                &tx,
+                Some(BlockId::Number(BlockNumber::Number(block_number.into()))),
+            )
+            .await
+        {
+            Ok(access_list) => access_list.access_list,
+            Err(_) => AccessList::default(),
+        }
+    }
+
     pub async fn find_balance_slot(
         &self,
         token: H160,
+        implementation: Option<H160>,
         owner: H160,
         nonce: U256,
         chain_id: U64,
         block_number: u64,
-    ) -> Result<(bool, u32)> {
+    ) -> Result<(bool, H160, u32)> {
         // A brute force way of finding the storage slot value of an ERC-20 token
         // Calling balanceOf and tracing the call using "debug_traceCall" will give us access to the
         // storage slot of "balances"
+        // Proxies normally keep balances in their own storage, but some forward the call to a
+        // separate logic/state contract, so the implementation's storage is scanned as well.
+        // The returned address is the account whose storage holds the balance mapping
         let erc20_contract = BaseContract::from(
             parse_abi(&["function balanceOf(address) external view returns (uint256)"]).unwrap(),
         );
@@ -75,39 +202,193 @@ impl<M: Middleware + 'static> EvmTracer<M> {
             nonce: Some(nonce),
             access_list: AccessList::default(),
         };
-        let trace = self.get_state_diff(tx, block_number).await.unwrap();
+        let trace = self.get_state_diff(tx.clone(), block_number).await.unwrap();
         match trace {
             GethTrace::Known(known) => match known {
                 GethTraceFrame::PreStateTracer(prestate) => match prestate {
                     PreStateFrame::Default(prestate_mode) => {
-                        let token_info =
-                            prestate_mode.0.get(&token).ok_or(anyhow!("no token key"))?;
-                        let touched_storage = token_info
-                            .storage
-                            .clone()
-                            .ok_or(anyhow!("no storage values"))?;
-                        for i in 0..20 {
-                            let slot = keccak256(&abi::encode(&[
-                                abi::Token::Address(owner.into()),
-                                abi::Token::Uint(U256::from(i)),
-                            ]));
-                            match touched_storage.get(&slot.into()) {
-                                Some(_) => {
-                                    return Ok((true, i));
+                        let mut accounts = vec![token];
+                        if let Some(implementation) = implementation {
+                            accounts.push(implementation);
+                        }
+
+                        for account in &accounts {
+                            let touched_storage = match prestate_mode.0.get(account) {
+                                Some(account_state) => match &account_state.storage {
+                                    Some(storage) => storage,
+                                    None => continue,
+                                },
+                                None => continue,
+                            };
+                            for i in 0..self.max_balance_slot {
+                                let slot = keccak256(&abi::encode(&[
+                                    abi::Token::Address(owner.into()),
+                                    abi::Token::Uint(U256::from(i)),
+                                ]));
+                                match touched_storage.get(&slot.into()) {
+                                    Some(_) => {
+                                        return Ok((true, *account, i));
+                                    }
+                                    None => {}
+                                }
+                            }
+                        }
+
+                        // The quick scan missed, so find the touched key that really holds the balance:
+                        // mock each one and see if balanceOf returns the mocked value
+                        for account in &accounts {
+                            let touched_keys: Vec<H256> = match prestate_mode.0.get(account) {
+                                Some(account_state) => match &account_state.storage {
+                                    Some(storage) => storage.keys().cloned().collect(),
+                                    None => continue,
+                                },
+                                None => continue,
+                            };
+                            for key in touched_keys {
+                                let is_balance = self
+                                    .is_balance_key(&tx, *account, key, block_number)
+                                    .await
+                                    .unwrap_or(false);
+                                if !is_balance {
+                                    continue;
                                 }
-                                None => {}
+                                return match self.find_mapping_index(owner, key) {
+                                    Some(i) => Ok((true, *account, i)),
+                                    None => Err(anyhow!(
+                                        "balance of {:?} is stored at {:?} of {:?}, which is not a plain mapping slot",
+                                        token,
+                                        key,
+                                        account
+                                    )),
+                                };
                             }
                         }
-                        Ok((false, 0))
+                        Ok((false, token, 0))
                     }
-                    _ => Ok((false, 0)),
+                    _ => Ok((false, token, 0)),
                 },
-                _ => Ok((false, 0)),
+                _ => Ok((false, token, 0)),
             },
+            _ => Ok((false, token, 0)),
+        }
+    }
+
+    pub async fn find_allowance_slot(
+        &self,
+        token: H160,
+        owner: H160,
+        spender: H160,
+        nonce: U256,
+        chain_id: U64,
+        block_number: u64,
+    ) -> Result<(bool, u32)> {
+        // Same as find_balance_slot, but for allowances: mapping(owner => mapping(spender => uint256))
+        // Tracing allowance(owner, spender) touches keccak(spender . keccak(owner . slot))
+        let erc20_contract = BaseContract::from(
+            parse_abi(&["function allowance(address,address) external view returns (uint256)"])
+                .unwrap(),
+        );
+        let calldata = erc20_contract.encode("allowance", (owner, spender))?;
+        let tx = Eip1559TransactionRequest {
+            to: Some(NameOrAddress::Address(token)),
+            from: Some(owner),
+            data: Some(calldata.0.into()),
+            value: Some(U256::zero()),
+            chain_id: Some(chain_id),
+            max_priority_fee_per_gas: None,
+            max_fee_per_gas: None,
+            gas: None,
+            nonce: Some(nonce),
+            access_list: AccessList::default(),
+        };
+        let trace = self.get_state_diff(tx, block_number).await?;
+        match trace {
+            GethTrace::Known(GethTraceFrame::PreStateTracer(PreStateFrame::Default(prestate))) => {
+                let touched_storage = match prestate.0.get(&token) {
+                    Some(AccountState {
+                        storage: Some(storage),
+                        ..
+                    }) => storage,
+                    _ => return Ok((false, 0)),
+                };
+                for i in 0..self.max_balance_slot {
+                    if touched_storage.contains_key(&allowance_key(owner, spender, i)) {
+                        return Ok((true, i));
+                    }
+                }
+                Ok((false, 0))
+            }
             _ => Ok((false, 0)),
         }
     }
 
+    pub async fn is_balance_key(
+        &self,
+        balance_of_tx: &Eip1559TransactionRequest,
+        account: H160,
+        key: H256,
+        block_number: u64,
+    ) -> Result<bool> {
+        // Re-runs balanceOf with the storage key overridden, the key holds the balance
+        // only if balanceOf returns exactly the mocked value
+        let mock_balance = U256::from(0x1234567890u64);
+        let mut state = spoof::State::default();
+        state
+            .account(account)
+            .store(key, H256::from_uint(&mock_balance));
+
+        let trace = self
+            .provider
+            .debug_trace_call(
+                balance_of_tx.clone(),
+                Some(BlockId::Number(BlockNumber::Number(block_number.into()))),
+                GethDebugTracingCallOptions {
+                    tracing_options: GethDebugTracingOptions {
+                        disable_storage: Some(true),
+                        disable_stack: Some(true),
+                        enable_memory: None,
+                        enable_return_data: Some(true),
+                        tracer: None,
+                        tracer_config: None,
+                        timeout: None,
+                    },
+                    state_overrides: Some(state),
+                },
+            )
+            .await?;
+
+        match trace {
+            GethTrace::Known(GethTraceFrame::Default(frame)) => {
+                if frame.failed || frame.return_value.len() < 32 {
+                    return Ok(false);
+                }
+                Ok(U256::from_big_endian(&frame.return_value[..32]) == mock_balance)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn find_mapping_index(&self, owner: H160, key: H256) -> Option<u32> {
+        // Exponential search for the mapping slot behind a balance key, for tokens with a large base slot
+        // Each round doubles the upper bound, up to 2^20 slots
+        let mut start = 0u32;
+        let mut end = std::cmp::max(self.max_balance_slot, 1);
+        while start < (1 << 20) {
+            for i in start..end {
+                let slot = keccak256(&abi::encode(&[
+                    abi::Token::Address(owner.into()),
+                    abi::Token::Uint(U256::from(i)),
+                ]));
+                if H256::from(slot.0) == key {
+                    return Some(i);
+                }
+            }
+            start = end;
+            end = end.saturating_mul(2);
+        }
+        None
+    }
+
     pub async fn find_v2_reserves_slot(
         &self,
         pool: H160,
@@ -158,3 +439,338 @@ impl<M: Middleware + 'static> EvmTracer<M> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::EvmSimulator;
+    use crate::tokens::get_implementation;
+    use ethers::providers::{MockProvider, Provider, Ws};
+
+    fn call_frame(to: H160, calls: Vec<serde_json::Value>) -> serde_json::Value {
+        serde_json::json!({
+            "type": "CALL",
+            "from": H160::random(),
+            "to": to,
+            "gas": "0x30000",
+            "gasUsed": "0x10000",
+            "input": "0x",
+            "calls": calls,
+        })
+    }
+
+    #[test]
+    fn test_trace_mode_from_str() {
+        assert_eq!(
+            TraceMode::from_str("prestate-diff").unwrap(),
+            TraceMode::PrestateDiff
+        );
+        assert_eq!(
+            TraceMode::from_str(" Prestate ").unwrap(),
+            TraceMode::Prestate
+        );
+        assert_eq!(TraceMode::from_str("CALL").unwrap(), TraceMode::Call);
+        assert!(TraceMode::from_str("struct").is_err());
+    }
+
+    #[test]
+    fn test_tracing_options() {
+        let options = tracing_options(TraceMode::Call, Some(Duration::from_millis(1500)));
+        assert_eq!(
+            options.tracer,
+            Some(GethDebugTracerType::BuiltInTracer(
+                GethDebugBuiltInTracerType::CallTracer
+            ))
+        );
+        assert!(options.tracer_config.is_none());
+        assert_eq!(options.timeout, Some("1500ms".to_string()));
+
+        let options = tracing_options(TraceMode::PrestateDiff, None);
+        assert_eq!(
+            options.tracer,
+            Some(GethDebugTracerType::BuiltInTracer(
+                GethDebugBuiltInTracerType::PreStateTracer
+            ))
+        );
+        assert!(options.tracer_config.is_some());
+        assert!(options.timeout.is_none());
+    }
+
+    #[test]
+    fn test_touched_accounts_from_call_frame() {
+        // router -> pair -> token, the router also calls the second pair directly
+        let (router, pair, token, other_pair) = (
+            H160::random(),
+            H160::random(),
+            H160::random(),
+            H160::random(),
+        );
+        let frame: CallFrame = serde_json::from_value(call_frame(
+            router,
+            vec![
+                call_frame(pair, vec![call_frame(token, vec![])]),
+                call_frame(other_pair, vec![]),
+            ],
+        ))
+        .unwrap();
+
+        let touched =
+            TouchedAccounts::from_trace(GethTrace::Known(GethTraceFrame::CallTracer(frame)));
+        assert_eq!(
+            touched.accounts,
+            BTreeSet::from([router, pair, token, other_pair])
+        );
+        // the call tracer doesn't show any state
+        assert!(touched.pre.is_empty());
+        assert!(touched.post.is_none());
+    }
+
+    #[test]
+    fn test_allowance_key() {
+        // keccak(spender . keccak(owner . slot)), each word left padded to 32 bytes
+        let (owner, spender) = (H160::random(), H160::random());
+        let word = |address: H160| H256::from(address).to_fixed_bytes().to_vec();
+        let mut slot = [0u8; 32];
+        slot[31] = 1;
+        let inner = keccak256(&[word(owner), slot.to_vec()].concat());
+        let key = keccak256(&[word(spender), inner.to_vec()].concat());
+        assert_eq!(allowance_key(owner, spender, 1), H256::from(key.0));
+        assert_ne!(allowance_key(spender, owner, 1), H256::from(key.0));
+    }
+
+    #[tokio::test]
+    async fn test_find_allowance_slot() {
+        // OpenZeppelin's ERC20 keeps _allowances in slot 1,
+        // allowance(owner, spender) only reads the nested mapping's key
+        let (token, owner, spender) = (H160::random(), H160::random(), H160::random());
+        let (provider, mock) = Provider::mocked();
+        let storage = serde_json::json!({
+            format!("{:?}", allowance_key(owner, spender, 1)): format!("{:?}", H256::from_low_u64_be(500)),
+        });
+        mock.push::<serde_json::Value, _>(serde_json::json!({
+            format!("{:?}", token): { "balance": "0x0", "storage": storage },
+        }))
+        .unwrap();
+
+        let tracer = EvmTracer::new(Arc::new(provider));
+        let found = tracer
+            .find_allowance_slot(token, owner, spender, U256::zero(), U64::from(1), 1)
+            .await
+            .unwrap();
+        assert_eq!(found, (true, 1));
+    }
+
+    #[tokio::test]
+    async fn test_find_balance_slot_in_the_implementation_storage() {
+        // the proxy forwards balanceOf to a contract that keeps the balances in its own storage
+        let (token, implementation, owner) = (H160::random(), H160::random(), H160::random());
+        let key = H256::from(
+            keccak256(&abi::encode(&[
+                abi::Token::Address(owner.into()),
+                abi::Token::Uint(U256::from(3)),
+            ]))
+            .0,
+        );
+        let (provider, mock) = Provider::mocked();
+        mock.push::<serde_json::Value, _>(serde_json::json!({
+            format!("{:?}", token): { "balance": "0x0" },
+            format!("{:?}", implementation): {
+                "balance": "0x0",
+                "storage": { format!("{:?}", key): format!("{:?}", H256::zero()) },
+            },
+        }))
+        .unwrap();
+
+        let tracer = EvmTracer::new(Arc::new(provider));
+        let found = tracer
+            .find_balance_slot(
+                token,
+                Some(implementation),
+                owner,
+                U256::zero(),
+                U64::from(1),
+                1,
+            )
+            .await
+            .unwrap();
+        assert_eq!(found, (true, implementation, 3));
+    }
+
+    #[tokio::test]
+    async fn test_find_balance_slot_past_the_default_range() {
+        // some tokens keep their balances far past the first 20 slots
+        let (token, owner) = (H160::random(), H160::random());
+        let (provider, mock) = Provider::mocked();
+        mock.push::<serde_json::Value, _>(serde_json::json!({
+            format!("{:?}", token): {
+                "balance": "0x0",
+                "storage": { format!("{:?}", balance_key(owner, 51)): format!("{:?}", H256::zero()) },
+            },
+        }))
+        .unwrap();
+
+        let mut tracer = EvmTracer::new(Arc::new(provider));
+        assert_eq!(tracer.max_balance_slot, 20);
+        tracer.max_balance_slot = 64;
+        let found = tracer
+            .find_balance_slot(token, None, owner, U256::zero(), U64::from(1), 1)
+            .await
+            .unwrap();
+        assert_eq!(found, (true, token, 51));
+    }
+
+    #[tokio::test]
+    async fn test_find_balance_slot_falls_back_to_the_touched_keys() {
+        // slot 51 is past the quick scan, balanceOf also reads slot 0 (say a paused flag)
+        let (token, owner) = (H160::random(), H160::random());
+        let (provider, mock) = Provider::mocked();
+        let balance_of = |value: u64| {
+            serde_json::json!({
+                "failed": false,
+                "gas": 30000,
+                "returnValue": format!("{:?}", H256::from_low_u64_be(value)),
+                "structLogs": [],
+            })
+        };
+        // the mock answers last in first out: the prestate trace, then slot 0 and the balance key
+        // in key order, only the balance key's override shows up in balanceOf
+        mock.push::<serde_json::Value, _>(balance_of(0x1234567890))
+            .unwrap();
+        mock.push::<serde_json::Value, _>(balance_of(0)).unwrap();
+        mock.push::<serde_json::Value, _>(serde_json::json!({
+            format!("{:?}", token): {
+                "balance": "0x0",
+                "storage": {
+                    format!("{:?}", H256::zero()): format!("{:?}", H256::zero()),
+                    format!("{:?}", balance_key(owner, 51)): format!("{:?}", H256::zero()),
+                },
+            },
+        }))
+        .unwrap();
+
+        let tracer = EvmTracer::new(Arc::new(provider));
+        assert_eq!(tracer.max_balance_slot, 20);
+        let found = tracer
+            .find_balance_slot(token, None, owner, U256::zero(), U64::from(1), 1)
+            .await
+            .unwrap();
+        assert_eq!(found, (true, token, 51));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_proxy_token_balance_reads_back() {
+        // Needs a mainnet WSS_URL, USDC is an upgradeable proxy that keeps balances in slot 9
+        dotenv::dotenv().ok();
+        let ws = Ws::connect(std::env::var("WSS_URL").unwrap())
+            .await
+            .unwrap();
+        let provider = Arc::new(Provider::new(ws));
+        let block_number = provider.get_block_number().await.unwrap();
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let owner = H160::random();
+
+        let implementation = get_implementation(provider.clone(), usdc, block_number)
+            .await
+            .unwrap();
+        assert!(implementation.is_some());
+
+        let tracer = EvmTracer::new(provider.clone());
+        let (found, account, slot) = tracer
+            .find_balance_slot(
+                usdc,
+                implementation,
+                owner,
+                U256::zero(),
+                U64::from(1),
+                block_number.as_u64(),
+            )
+            .await
+            .unwrap();
+        assert!(found);
+        assert_eq!((account, slot), (usdc, 9));
+
+        let mut simulator = EvmSimulator::new(provider, owner, block_number);
+        if account != usdc {
+            simulator.balance_accounts.insert(usdc, account);
+        }
+        simulator.set_token_balance(owner, usdc, 6, slot, 1000);
+        assert_eq!(
+            simulator.token_balance_of(usdc, owner).unwrap(),
+            U256::from(1000) * U256::exp10(6)
+        );
+    }
+
+    fn balance_key(owner: H160, slot: u64) -> H256 {
+        H256::from(
+            keccak256(&abi::encode(&[
+                abi::Token::Address(owner),
+                abi::Token::Uint(U256::from(slot)),
+            ]))
+            .0,
+        )
+    }
+
+    fn transfer_tx(token: H160, from: H160, to: H160, amount: U256) -> Eip1559TransactionRequest {
+        let erc20 = BaseContract::from(
+            parse_abi(&["function transfer(address,uint256) external returns (bool)"]).unwrap(),
+        );
+        Eip1559TransactionRequest::new()
+            .from(from)
+            .to(token)
+            .data(erc20.encode("transfer", (to, amount)).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_generate_access_list() {
+        let (token, from, to) = (H160::random(), H160::random(), H160::random());
+        let (provider, mock) = Provider::mocked();
+        mock.push::<serde_json::Value, _>(serde_json::json!({
+            "accessList": [{
+                "address": token,
+                "storageKeys": [balance_key(from, 0), balance_key(to, 0)],
+            }],
+            "gasUsed": "0x7d00",
+        }))
+        .unwrap();
+
+        let tracer = EvmTracer::new(Arc::new(provider));
+        let tx = transfer_tx(token, from, to, U256::from(100));
+        let access_list = tracer.generate_access_list(tx.clone(), 1).await;
+        assert_eq!(access_list.0.len(), 1);
+        assert_eq!(access_list.0[0].address, token);
+        assert!(access_list.0[0].storage_keys.contains(&balance_key(to, 0)));
+
+        // nothing left in the mock, like a node without eth_createAccessList
+        assert!(tracer.generate_access_list(tx, 1).await.0.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn test_generate_access_list_for_a_transfer() {
+        // Needs a mainnet WSS_URL, sends USDC out of the Uniswap V2 USDC/WETH pair
+        dotenv::dotenv().ok();
+        let ws = Ws::connect(std::env::var("WSS_URL").unwrap())
+            .await
+            .unwrap();
+        let provider = Arc::new(Provider::new(ws));
+        let block_number = provider.get_block_number().await.unwrap();
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let pair = H160::from_str("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc").unwrap();
+        let recipient = H160::random();
+
+        let tracer = EvmTracer::new(provider);
+        let tx = transfer_tx(usdc, pair, recipient, U256::from(1000000));
+        let access_list = tracer.generate_access_list(tx, block_number.as_u64()).await;
+
+        // USDC keeps balances in the proxy's slot 9, both sides of the transfer are touched
+        let usdc_keys = &access_list
+            .0
+            .iter()
+            .find(|item| item.address == usdc)
+            .unwrap()
+            .storage_keys;
+        assert!(usdc_keys.contains(&balance_key(pair, 9)));
+        assert!(usdc_keys.contains(&balance_key(recipient, 9)));
+    }
+}
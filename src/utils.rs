@@ -1,6 +1,13 @@
-use anyhow::{self, Result};
+use anyhow::{self, anyhow, Result};
+use ethers::types::U256;
+use ethers::utils::{format_units, keccak256};
 use fern::colors::{Color, ColoredLevelConfig};
-use log::LevelFilter;
+use futures::future::join_all;
+use log::{info, LevelFilter};
+use std::{fmt::Debug, future::Future};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::constants::MAX_DECIMALS;
 
 pub fn setup_logger() -> Result<()> {
     let colors = ColoredLevelConfig {
@@ -28,3 +35,171 @@ pub fn setup_logger() -> Result<()> {
 
     Ok(())
 }
+
+pub fn correlation_id(seed: &[u8]) -> String {
+    // Short id shared by every log line of one opportunity, ex) a pending tx hash or a path's pools
+    hex::encode(&keccak256(seed)[..4])
+}
+
+pub fn token_unit(decimals: u8) -> Result<U256> {
+    // 10^decimals, 1 for 0-decimal tokens
+    if decimals > MAX_DECIMALS {
+        return Err(anyhow!(
+            "Unsupported decimals: {} (max {})",
+            decimals,
+            MAX_DECIMALS
+        ));
+    }
+    Ok(U256::from(10).pow(U256::from(decimals)))
+}
+
+pub fn to_i128(amount: U256) -> Result<i128> {
+    // Raw amounts can exceed u64 (ex. 24-decimal tokens), so convert with a bound check
+    if amount > U256::from(i128::MAX as u128) {
+        return Err(anyhow!("Amount does not fit in i128: {:?}", amount));
+    }
+    Ok(amount.as_u128() as i128)
+}
+
+pub fn format_token_amount(amount: i128, decimals: u8) -> String {
+    // Exact decimal string of a raw amount, f64 loses precision past ~15 digits
+    format_units(amount, decimals as u32).unwrap_or_else(|_| amount.to_string())
+}
+
+pub async fn join_all_bounded<F: Future>(
+    semaphore: &Semaphore,
+    futures: impl IntoIterator<Item = F>,
+) -> Vec<F::Output> {
+    // Like join_all, but a future only starts once it holds a permit, outputs keep the input order
+    join_all(futures.into_iter().map(|future| async move {
+        let _permit = semaphore.acquire().await;
+        future.await
+    }))
+    .await
+}
+
+pub async fn run_until_shutdown<T: Debug + 'static>(set: JoinSet<T>) {
+    run_until(set, async {
+        _ = tokio::signal::ctrl_c().await;
+    })
+    .await
+}
+
+pub async fn run_until<T: Debug + 'static, F: Future<Output = ()>>(
+    mut set: JoinSet<T>,
+    shutdown: F,
+) {
+    // Runs the tasks until they all finish or shutdown resolves
+    // Aborting the tasks drops their broadcast senders, providers and SharedBackend handles,
+    // which lets the websocket subscriptions and backend threads shut down too
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            res = set.join_next() => match res {
+                Some(res) => info!("{:?}", res),
+                None => break,
+            },
+            _ = &mut shutdown => {
+                info!("Shutting down...");
+                break;
+            }
+        }
+    }
+    set.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::RecvError;
+
+    #[test]
+    fn test_token_unit() {
+        assert_eq!(token_unit(0).unwrap(), U256::one());
+        assert_eq!(token_unit(6).unwrap(), U256::from(1000000));
+        assert_eq!(token_unit(18).unwrap(), U256::exp10(18));
+        assert_eq!(token_unit(MAX_DECIMALS).unwrap(), U256::exp10(36));
+        assert!(token_unit(MAX_DECIMALS + 1).is_err());
+    }
+
+    #[test]
+    fn test_format_token_amount() {
+        assert_eq!(format_token_amount(1500000, 6), "1.500000");
+        assert_eq!(format_token_amount(-1500000, 6), "-1.500000");
+        // digits an f64 would round away
+        assert_eq!(
+            format_token_amount(123456789012345678901234567, 18),
+            "123456789.012345678901234567"
+        );
+    }
+
+    #[test]
+    fn test_to_i128() {
+        assert_eq!(to_i128(U256::from(42)).unwrap(), 42);
+        assert_eq!(to_i128(U256::from(i128::MAX as u128)).unwrap(), i128::MAX);
+        assert!(to_i128(U256::from(i128::MAX as u128) + 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_join_all_bounded() {
+        let semaphore = Semaphore::new(3);
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+
+        let outputs = join_all_bounded(
+            &semaphore,
+            (0..20).map(|i| {
+                let in_flight = &in_flight;
+                let max_in_flight = &max_in_flight;
+                async move {
+                    let running = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(running, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    i
+                }
+            }),
+        )
+        .await;
+
+        assert_eq!(outputs, (0..20).collect::<Vec<_>>());
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_running_tasks() {
+        let (event_sender, mut event_receiver) = tokio::sync::broadcast::channel::<u64>(4);
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+
+        // a stream-like task that never finishes on its own
+        let mut set = JoinSet::new();
+        set.spawn(async move {
+            loop {
+                _ = event_sender.send(1);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert_eq!(event_receiver.recv().await.unwrap(), 1);
+
+        shutdown_sender.send(()).unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            run_until(set, async {
+                _ = shutdown_receiver.await;
+            }),
+        )
+        .await
+        .unwrap();
+
+        // the aborted task dropped its sender, subscribers see the channel close
+        loop {
+            match event_receiver.recv().await {
+                Err(RecvError::Closed) => break,
+                // whatever was sent before the abort is still buffered
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            }
+        }
+    }
+}
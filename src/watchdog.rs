@@ -0,0 +1,71 @@
+use log::warn;
+use std::time::{Duration, Instant};
+
+// The block stream can stall silently while pending txs keep flowing,
+// leaving simulations to run against an old block and base fee
+#[derive(Debug, Clone)]
+pub struct BlockWatchdog {
+    pub interval: Duration,
+    last_block: Instant,
+    warned: bool,
+}
+
+impl BlockWatchdog {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_block: Instant::now(),
+            warned: false,
+        }
+    }
+
+    pub fn on_block(&mut self) {
+        self.last_block = Instant::now();
+        self.warned = false;
+    }
+
+    pub fn is_stale(&mut self) -> bool {
+        self.check(Instant::now())
+    }
+
+    pub fn check(&mut self, now: Instant) -> bool {
+        // Returns true while no block has arrived within the interval, warns once per stall
+        let elapsed = now.saturating_duration_since(self.last_block);
+        if elapsed <= self.interval {
+            return false;
+        }
+        if !self.warned {
+            warn!(
+                "No new block for {:?} (expected one every {:?}), the block stream may have stalled",
+                elapsed, self.interval
+            );
+            self.warned = true;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_goes_stale_after_interval() {
+        let mut watchdog = BlockWatchdog::new(Duration::from_secs(12));
+        let start = Instant::now();
+        assert!(!watchdog.check(start));
+        assert!(!watchdog.check(start + Duration::from_secs(5)));
+        assert!(watchdog.check(start + Duration::from_secs(30)));
+        // stays stale until a block arrives
+        assert!(watchdog.check(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_on_block_resets_staleness() {
+        let mut watchdog = BlockWatchdog::new(Duration::from_secs(12));
+        assert!(watchdog.check(Instant::now() + Duration::from_secs(30)));
+        watchdog.on_block();
+        assert!(!watchdog.warned);
+        assert!(!watchdog.check(Instant::now()));
+    }
+}